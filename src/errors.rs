@@ -20,6 +20,22 @@ impl NotSupportIpTypeForArpScan {
 }
 impl Error for NotSupportIpTypeForArpScan {}
 
+#[derive(Debug, Clone)]
+pub struct NotSupportIpTypeForNdpScan {
+    target_type: TargetType,
+}
+impl fmt::Display for NotSupportIpTypeForNdpScan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ndp scan not support {:?}", self.target_type)
+    }
+}
+impl NotSupportIpTypeForNdpScan {
+    pub fn new(target_type: TargetType) -> NotSupportIpTypeForNdpScan {
+        NotSupportIpTypeForNdpScan { target_type }
+    }
+}
+impl Error for NotSupportIpTypeForNdpScan {}
+
 #[derive(Debug, Clone)]
 pub struct CanNotFoundRouterAddress;
 impl fmt::Display for CanNotFoundRouterAddress {
@@ -179,6 +195,24 @@ impl OsDetectPortError {
 }
 impl Error for OsDetectPortError {}
 
+#[derive(Debug, Clone)]
+pub struct Ipv6ModelError {
+    reason: String,
+}
+impl fmt::Display for Ipv6ModelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ipv6 os detection model error: {}", self.reason)
+    }
+}
+impl Ipv6ModelError {
+    pub fn new(reason: &str) -> Ipv6ModelError {
+        Ipv6ModelError {
+            reason: reason.to_string(),
+        }
+    }
+}
+impl Error for Ipv6ModelError {}
+
 /* layer */
 
 #[derive(Debug, Clone)]
@@ -251,7 +285,24 @@ impl fmt::Display for IllegalTarget {
 }
 impl IllegalTarget {
     pub fn new(addr: IpAddr) -> IllegalTarget {
-        IllegalTarget {addr}
+        IllegalTarget { addr }
     }
 }
 impl Error for IllegalTarget {}
+
+#[derive(Debug, Clone)]
+pub struct IdleScanNoZombie;
+impl fmt::Display for IdleScanNoZombie {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "idle scan requires a zombie, please set the zombie address"
+        )
+    }
+}
+impl IdleScanNoZombie {
+    pub fn new() -> IdleScanNoZombie {
+        IdleScanNoZombie {}
+    }
+}
+impl Error for IdleScanNoZombie {}
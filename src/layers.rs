@@ -74,6 +74,8 @@ pub const ICMPV6_ER_HEADER_SIZE: usize = 8;
 pub const ICMPV6_NI_HEADER_SIZE: usize = 32;
 
 const NEIGNBOUR_MAX_TRY: usize = 3;
+// the receiver read timeout when capturing, so the capture loop can check the elapsed time
+const CAPTURE_READ_TIMEOUT_MS: u64 = 100;
 
 #[derive(Debug, Clone, Copy)]
 pub struct Layer2Match {
@@ -496,8 +498,13 @@ impl LayersMatch {
 
 fn datalink_channel(
     interface: &NetworkInterface,
+    read_timeout: Option<Duration>,
 ) -> Result<Option<(Box<dyn DataLinkSender>, Box<dyn DataLinkReceiver>)>> {
-    match datalink::channel(&interface, Default::default()) {
+    let config = datalink::Config {
+        read_timeout,
+        ..Default::default()
+    };
+    match datalink::channel(&interface, config) {
         Ok(Ethernet(tx, rx)) => Ok(Some((tx, rx))),
         Ok(_) => Ok(None),
         Err(e) => Err(e.into()),
@@ -523,18 +530,12 @@ pub fn _print_packet_as_wireshark_format(buff: &[u8]) {
     println!("");
 }
 
-pub fn layer2_send(
+fn ethernet_frame(
     dst_mac: MacAddr,
-    interface: NetworkInterface,
+    interface: &NetworkInterface,
     send_buff: &[u8],
     ethernet_type: EtherType,
-    layers_match: Vec<LayersMatch>,
-    timeout: Duration,
-) -> Result<(Option<Vec<u8>>, Option<Duration>)> {
-    let (mut sender, mut receiver) = match datalink_channel(&interface)? {
-        Some((s, r)) => (s, r),
-        None => return Err(CreateDatalinkChannelFailed::new().into()),
-    };
+) -> Result<Vec<u8>> {
     let src_mac = if dst_mac == MacAddr::zero() {
         MacAddr::zero()
     } else {
@@ -552,7 +553,192 @@ pub fn layer2_send(
     ethernet_packet.set_ethertype(ethernet_type);
     ethernet_packet.set_payload(send_buff);
 
-    let final_buff = ethernet_buff[..(ETHERNET_HEADER_SIZE + send_buff.len())].to_vec();
+    Ok(ethernet_buff[..(ETHERNET_HEADER_SIZE + send_buff.len())].to_vec())
+}
+
+/// Receive the matched packets until the `duration`, or until `done` returns true for a matched packet.
+fn layer2_recv_all<F>(
+    receiver: &mut Box<dyn DataLinkReceiver>,
+    layers_match: &[LayersMatch],
+    start_time: Instant,
+    duration: Duration,
+    grace: Duration,
+    mut done: F,
+) -> Vec<(Vec<u8>, Duration)>
+where
+    F: FnMut(&[u8]) -> bool,
+{
+    let mut duration = duration;
+    let mut ret = Vec::new();
+    while start_time.elapsed() < duration {
+        let buff = match receiver.next() {
+            Ok(b) => b,
+            Err(_) => continue, // read timeout
+        };
+        for m in layers_match {
+            if m.do_match(buff) {
+                ret.push((buff.to_vec(), start_time.elapsed()));
+                // keep receiving the late responses for the grace time
+                if done(buff) {
+                    duration = duration.min(start_time.elapsed() + grace);
+                }
+                break;
+            }
+        }
+    }
+    ret
+}
+
+/// Same as `layer2_send`, but does not stop at the first matched response,
+/// it keeps receiving until the timeout and returns every matched response with its rtt.
+pub fn layer2_send_all(
+    dst_mac: MacAddr,
+    interface: NetworkInterface,
+    send_buff: &[u8],
+    ethernet_type: EtherType,
+    layers_match: Vec<LayersMatch>,
+    timeout: Duration,
+) -> Result<Vec<(Vec<u8>, Duration)>> {
+    layer2_multi_send_all(
+        vec![(dst_mac, send_buff.to_vec())],
+        interface,
+        ethernet_type,
+        layers_match,
+        timeout,
+    )
+}
+
+/// Send all the `(dst_mac, send_buff)` packets in turn, then receive as `layer2_send_all`,
+/// the rtt is counted from the first packet sent.
+pub fn layer2_multi_send_all(
+    send_buffs: Vec<(MacAddr, Vec<u8>)>,
+    interface: NetworkInterface,
+    ethernet_type: EtherType,
+    layers_match: Vec<LayersMatch>,
+    timeout: Duration,
+) -> Result<Vec<(Vec<u8>, Duration)>> {
+    let (_, rets) = layer2_multi_send_until(
+        send_buffs,
+        interface,
+        ethernet_type,
+        layers_match,
+        timeout,
+        Duration::ZERO,
+        |_| false,
+    )?;
+    Ok(rets)
+}
+
+/// Same as `layer2_multi_send_all`, but once `done` returns true for a matched response,
+/// it only keeps receiving for the `grace` time instead of waiting for the timeout.
+/// Also returns the time each packet was sent, counted from the first packet sent as the responses,
+/// so the caller can get the rtt of each request.
+pub fn layer2_multi_send_until<F>(
+    send_buffs: Vec<(MacAddr, Vec<u8>)>,
+    interface: NetworkInterface,
+    ethernet_type: EtherType,
+    layers_match: Vec<LayersMatch>,
+    timeout: Duration,
+    grace: Duration,
+    done: F,
+) -> Result<(Vec<Duration>, Vec<(Vec<u8>, Duration)>)>
+where
+    F: FnMut(&[u8]) -> bool,
+{
+    let read_timeout = Duration::from_millis(CAPTURE_READ_TIMEOUT_MS);
+    let (mut sender, mut receiver) = match datalink_channel(&interface, Some(read_timeout))? {
+        Some((s, r)) => (s, r),
+        None => return Err(CreateDatalinkChannelFailed::new().into()),
+    };
+    let mut final_buffs = Vec::new();
+    for (dst_mac, send_buff) in send_buffs {
+        let final_buff = ethernet_frame(dst_mac, &interface, &send_buff, ethernet_type)?;
+        final_buffs.push(final_buff);
+    }
+    let send_time = Instant::now();
+    let mut send_times = Vec::new();
+    for final_buff in final_buffs {
+        send_times.push(send_time.elapsed());
+        match sender.send_to(&final_buff, Some(interface.clone())) {
+            Some(r) => match r {
+                Err(e) => return Err(e.into()),
+                _ => (),
+            },
+            None => (),
+        }
+    }
+    let rets = layer2_recv_all(
+        &mut receiver,
+        &layers_match,
+        send_time,
+        timeout,
+        grace,
+        done,
+    );
+    Ok((send_times, rets))
+}
+
+/// Listen on the interface without sending anything,
+/// returns every matched packet and the time it arrived (relative to the start of the capture).
+pub fn layer2_capture(
+    interface: NetworkInterface,
+    layers_match: Vec<LayersMatch>,
+    duration: Duration,
+) -> Result<Vec<(Vec<u8>, Duration)>> {
+    let read_timeout = Duration::from_millis(CAPTURE_READ_TIMEOUT_MS);
+    let (_, mut receiver) = match datalink_channel(&interface, Some(read_timeout))? {
+        Some((s, r)) => (s, r),
+        None => return Err(CreateDatalinkChannelFailed::new().into()),
+    };
+    let start_time = Instant::now();
+    Ok(layer2_recv_all(
+        &mut receiver,
+        &layers_match,
+        start_time,
+        duration,
+        Duration::ZERO,
+        |_| false,
+    ))
+}
+
+/// Same as `layer2_capture`, but nothing is kept, every received packet is given to `handler`
+/// with the time it arrived as soon as it is received.
+pub fn layer2_capture_with<F>(
+    interface: NetworkInterface,
+    duration: Duration,
+    mut handler: F,
+) -> Result<()>
+where
+    F: FnMut(&[u8], Duration),
+{
+    let read_timeout = Duration::from_millis(CAPTURE_READ_TIMEOUT_MS);
+    let (_, mut receiver) = match datalink_channel(&interface, Some(read_timeout))? {
+        Some((s, r)) => (s, r),
+        None => return Err(CreateDatalinkChannelFailed::new().into()),
+    };
+    let start_time = Instant::now();
+    while start_time.elapsed() < duration {
+        match receiver.next() {
+            Ok(buff) => handler(buff, start_time.elapsed()),
+            Err(_) => (), // read timeout
+        }
+    }
+    Ok(())
+}
+
+pub fn layer2_send(
+    dst_mac: MacAddr,
+    interface: NetworkInterface,
+    send_buff: &[u8],
+    ethernet_type: EtherType,
+    layers_match: Vec<LayersMatch>,
+    timeout: Duration,
+) -> Result<(Option<Vec<u8>>, Option<Duration>)> {
+    let (mut sender, mut receiver) = match datalink_channel(&interface, None)? {
+        Some((s, r)) => (s, r),
+        None => return Err(CreateDatalinkChannelFailed::new().into()),
+    };
+    let final_buff = ethernet_frame(dst_mac, &interface, send_buff, ethernet_type)?;
     // _print_packet_as_wireshark_format(&final_buff);
     let send_time = Instant::now();
     match sender.send_to(&final_buff, Some(interface)) {
@@ -870,6 +1056,23 @@ fn arp(src_ipv4: Ipv4Addr, dst_ipv4: Ipv4Addr) -> Result<(Option<MacAddr>, Optio
     }
 }
 
+/// Returns the mac address of the neighbour `dst_ipv4`,
+/// from the system neighbour cache or the arp reply.
+pub fn neighbour_mac(src_ipv4: Ipv4Addr, dst_ipv4: Ipv4Addr) -> Result<Option<MacAddr>> {
+    match search_system_neighbour_cache(dst_ipv4.into())? {
+        Some(m) => Ok(Some(m)),
+        None => {
+            for _ in 0..NEIGNBOUR_MAX_TRY {
+                match arp(src_ipv4, dst_ipv4)? {
+                    (Some(m), Some(_rtt)) => return Ok(Some(m)),
+                    (_, _) => (),
+                }
+            }
+            Ok(None)
+        }
+    }
+}
+
 pub fn layer3_ipv4_send(
     src_ipv4: Ipv4Addr,
     dst_ipv4: Ipv4Addr,
@@ -885,24 +1088,9 @@ pub fn layer3_ipv4_send(
             dst_loopbook = true;
             MacAddr::zero()
         } else {
-            match search_system_neighbour_cache(dst_ipv4.into())? {
+            match neighbour_mac(src_ipv4, dst_ipv4)? {
                 Some(m) => m,
-                None => {
-                    let mut mac: Option<MacAddr> = None;
-                    for _ in 0..NEIGNBOUR_MAX_TRY {
-                        match arp(src_ipv4, dst_ipv4)? {
-                            (Some(m), Some(_rtt)) => {
-                                mac = Some(m);
-                                break;
-                            }
-                            (_, _) => (),
-                        }
-                    }
-                    match mac {
-                        Some(m) => m,
-                        None => return Err(CanNotFoundMacAddress::new().into()),
-                    }
-                }
+                None => return Err(CanNotFoundMacAddress::new().into()),
             }
         }
     } else {
@@ -963,6 +1151,13 @@ pub fn multicast_mac(ip: Ipv6Addr) -> MacAddr {
     MacAddr::new(0x33, 0x33, 0xFF, ip[13], ip[14], ip[15])
 }
 
+/// Returns the mac address of the ipv6 multicast address (RFC 2464), such as ff02::1 => 33:33:00:00:00:01.
+pub fn ipv6_multicast_mac(multicast_ipv6: Ipv6Addr) -> MacAddr {
+    let ip = multicast_ipv6.octets();
+    // 33:33:xx:xx:xx:xx
+    MacAddr::new(0x33, 0x33, ip[12], ip[13], ip[14], ip[15])
+}
+
 fn get_mac_from_ndp_ns(buff: &[u8]) -> Option<MacAddr> {
     // return mac address from ndp
     let ethernet_packet = EthernetPacket::new(buff).unwrap();
@@ -978,17 +1173,12 @@ fn get_mac_from_ndp_ns(buff: &[u8]) -> Option<MacAddr> {
     None
 }
 
-fn ndp_ns(src_ipv6: Ipv6Addr, dst_ipv6: Ipv6Addr) -> Result<(Option<MacAddr>, Option<Duration>)> {
-    // same as arp in ipv4
-    let interface = match find_interface_by_ipv6(src_ipv6) {
-        Some(i) => i,
-        None => return Err(CanNotFoundInterface::new().into()),
-    };
-    let src_mac = match interface.mac {
-        Some(m) => m,
-        None => return Err(CanNotFoundMacAddress::new().into()),
-    };
-
+/// Build the neighbor solicitation packet (ipv6 header included) which will be sent to the solicited-node multicast address of `dst_ipv6`.
+pub fn ndp_ns_packet(
+    src_ipv6: Ipv6Addr,
+    src_mac: MacAddr,
+    dst_ipv6: Ipv6Addr,
+) -> [u8; IPV6_HEADER_SIZE + ICMPV6_NS_HEADER_SIZE] {
     // ipv6
     let mut ipv6_buff = [0u8; IPV6_HEADER_SIZE + ICMPV6_NS_HEADER_SIZE];
     let mut ipv6_header = MutableIpv6Packet::new(&mut ipv6_buff).unwrap();
@@ -1020,6 +1210,21 @@ fn ndp_ns(src_ipv6: Ipv6Addr, dst_ipv6: Ipv6Addr) -> Result<(Option<MacAddr>, Op
     let mut icmpv6_header = MutableIcmpv6Packet::new(&mut ipv6_buff[IPV6_HEADER_SIZE..]).unwrap();
     let checksum = icmpv6::checksum(&icmpv6_header.to_immutable(), &src_ipv6, &dst_multicast);
     icmpv6_header.set_checksum(checksum);
+    ipv6_buff
+}
+
+fn ndp_ns(src_ipv6: Ipv6Addr, dst_ipv6: Ipv6Addr) -> Result<(Option<MacAddr>, Option<Duration>)> {
+    // same as arp in ipv4
+    let interface = match find_interface_by_ipv6(src_ipv6) {
+        Some(i) => i,
+        None => return Err(CanNotFoundInterface::new().into()),
+    };
+    let src_mac = match interface.mac {
+        Some(m) => m,
+        None => return Err(CanNotFoundMacAddress::new().into()),
+    };
+
+    let ipv6_buff = ndp_ns_packet(src_ipv6, src_mac, dst_ipv6);
 
     let layer3 = Layer3Match {
         layer2: None,
@@ -1067,18 +1272,12 @@ fn get_mac_from_ndp_rs(buff: &[u8]) -> Option<MacAddr> {
     }
 }
 
-fn ndp_rs(src_ipv6: Ipv6Addr) -> Result<(Option<MacAddr>, Option<Duration>)> {
-    // router solicitation
+/// Build the router solicitation packet (ipv6 header included) which will be sent to all routers (ff02::2).
+pub fn ndp_rs_packet(
+    src_ipv6: Ipv6Addr,
+    src_mac: MacAddr,
+) -> [u8; IPV6_HEADER_SIZE + ICMPV6_RS_HEADER_SIZE] {
     let dst_ipv6_all_router = Ipv6Addr::new(0xFF02, 0, 0, 0, 0, 0, 0, 2);
-    let interface = match find_interface_by_ipv6(src_ipv6) {
-        Some(i) => i,
-        None => return Err(CanNotFoundInterface::new().into()),
-    };
-    let src_mac = match interface.mac {
-        Some(m) => m,
-        None => return Err(CanNotFoundMacAddress::new().into()),
-    };
-
     // ipv6
     let mut ipv6_buff = [0u8; IPV6_HEADER_SIZE + ICMPV6_RS_HEADER_SIZE];
     let mut ipv6_header = MutableIpv6Packet::new(&mut ipv6_buff).unwrap();
@@ -1112,6 +1311,22 @@ fn ndp_rs(src_ipv6: Ipv6Addr) -> Result<(Option<MacAddr>, Option<Duration>)> {
         &dst_ipv6_all_router,
     );
     icmpv6_header.set_checksum(checksum);
+    ipv6_buff
+}
+
+fn ndp_rs(src_ipv6: Ipv6Addr) -> Result<(Option<MacAddr>, Option<Duration>)> {
+    // router solicitation
+    let dst_ipv6_all_router = Ipv6Addr::new(0xFF02, 0, 0, 0, 0, 0, 0, 2);
+    let interface = match find_interface_by_ipv6(src_ipv6) {
+        Some(i) => i,
+        None => return Err(CanNotFoundInterface::new().into()),
+    };
+    let src_mac = match interface.mac {
+        Some(m) => m,
+        None => return Err(CanNotFoundMacAddress::new().into()),
+    };
+
+    let ipv6_buff = ndp_rs_packet(src_ipv6, src_mac);
 
     let layer3 = Layer3Match {
         layer2: None,
@@ -1151,6 +1366,23 @@ fn layer2_payload(buff: &[u8]) -> Vec<u8> {
     ethernet_packet.payload().to_vec()
 }
 
+/// Returns the mac address of the neighbour `dst_ipv6`,
+/// from the system neighbour cache or the ndp neighbor advertisement.
+pub fn neighbour_mac6(src_ipv6: Ipv6Addr, dst_ipv6: Ipv6Addr) -> Result<Option<MacAddr>> {
+    match search_system_neighbour_cache6(dst_ipv6.into())? {
+        Some(m) => Ok(Some(m)),
+        None => {
+            for _ in 0..NEIGNBOUR_MAX_TRY {
+                match ndp_ns(src_ipv6, dst_ipv6)? {
+                    (Some(m), Some(_rtt)) => return Ok(Some(m)),
+                    (_, _) => (),
+                }
+            }
+            Ok(None)
+        }
+    }
+}
+
 pub fn layer3_ipv6_send(
     src_ipv6: Ipv6Addr,
     dst_ipv6: Ipv6Addr,
@@ -1165,25 +1397,10 @@ pub fn layer3_ipv6_send(
             dst_loopback = true;
             MacAddr::zero()
         } else {
-            match search_system_neighbour_cache6(dst_ipv6.into())? {
+            // found neighbourhood mac
+            match neighbour_mac6(src_ipv6, dst_ipv6)? {
                 Some(m) => m,
-                None => {
-                    let mut mac: Option<MacAddr> = None;
-                    for _ in 0..NEIGNBOUR_MAX_TRY {
-                        // found neighbourhood mac
-                        match ndp_ns(src_ipv6, dst_ipv6)? {
-                            (Some(m), Some(_rtt)) => {
-                                mac = Some(m);
-                                break;
-                            }
-                            (_, _) => (),
-                        }
-                    }
-                    match mac {
-                        Some(m) => m,
-                        None => return Err(CanNotFoundMacAddress::new().into()),
-                    }
-                }
+                None => return Err(CanNotFoundMacAddress::new().into()),
             }
         }
     } else {
@@ -103,10 +103,21 @@ impl fmt::Display for PingResults {
 pub struct Host {
     pub addr: Ipv4Addr,
     pub ports: Vec<u16>,
+    /// The UDP ports for the service detection, only `vs_scan` uses them,
+    /// the `ports` are treated as TCP ports there.
+    pub udp_ports: Vec<u16>,
 }
 
 impl Host {
     pub fn new(addr: Ipv4Addr, ports: Option<Vec<u16>>) -> Result<Host> {
+        Host::new_with_udp_ports(addr, ports, None)
+    }
+    /// Same as `new`, the `udp_ports` are only used by the service detection.
+    pub fn new_with_udp_ports(
+        addr: Ipv4Addr,
+        ports: Option<Vec<u16>>,
+        udp_ports: Option<Vec<u16>>,
+    ) -> Result<Host> {
         // Check the dst addr when init the Host.
         if !addr.is_global_x() && !addr.is_loopback() {
             match utils::find_source_ipv4(None, addr)? {
@@ -114,12 +125,10 @@ impl Host {
                 None => return Err(IllegalTarget::new(IpAddr::V4(addr)).into()),
             }
         }
-        let h = match ports {
-            Some(p) => Host { addr, ports: p },
-            None => Host {
-                addr,
-                ports: vec![],
-            },
+        let h = Host {
+            addr,
+            ports: ports.unwrap_or(vec![]),
+            udp_ports: udp_ports.unwrap_or(vec![]),
         };
         Ok(h)
     }
@@ -127,7 +136,10 @@ impl Host {
 
 impl fmt::Display for Host {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let result_str = format!("{} {:?}", self.addr, self.ports);
+        let mut result_str = format!("{} {:?}", self.addr, self.ports);
+        if self.udp_ports.len() > 0 {
+            result_str += &format!(" udp {:?}", self.udp_ports);
+        }
         write!(f, "{}", result_str)
     }
 }
@@ -136,10 +148,21 @@ impl fmt::Display for Host {
 pub struct Host6 {
     pub addr: Ipv6Addr,
     pub ports: Vec<u16>,
+    /// The UDP ports for the service detection, only `vs_scan` uses them,
+    /// the `ports` are treated as TCP ports there.
+    pub udp_ports: Vec<u16>,
 }
 
 impl Host6 {
     pub fn new(addr: Ipv6Addr, ports: Option<Vec<u16>>) -> Result<Host6> {
+        Host6::new_with_udp_ports(addr, ports, None)
+    }
+    /// Same as `new`, the `udp_ports` are only used by the service detection.
+    pub fn new_with_udp_ports(
+        addr: Ipv6Addr,
+        ports: Option<Vec<u16>>,
+        udp_ports: Option<Vec<u16>>,
+    ) -> Result<Host6> {
         // Check the dst addr when init the Host.
         if !addr.is_global_x() {
             match utils::find_source_ipv6(None, addr)? {
@@ -147,12 +170,10 @@ impl Host6 {
                 None => return Err(errors::IllegalTarget::new(IpAddr::V6(addr)).into()),
             }
         }
-        let h = match ports {
-            Some(p) => Host6 { addr, ports: p },
-            None => Host6 {
-                addr,
-                ports: vec![],
-            },
+        let h = Host6 {
+            addr,
+            ports: ports.unwrap_or(vec![]),
+            udp_ports: udp_ports.unwrap_or(vec![]),
         };
         Ok(h)
     }
@@ -160,7 +181,10 @@ impl Host6 {
 
 impl fmt::Display for Host6 {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let result_str = format!("{} {:?}", self.addr, self.ports);
+        let mut result_str = format!("{} {:?}", self.addr, self.ports);
+        if self.udp_ports.len() > 0 {
+            result_str += &format!(" udp {:?}", self.udp_ports);
+        }
         write!(f, "{}", result_str)
     }
 }
@@ -259,6 +283,19 @@ impl Target {
 /// ARP Scan.
 /// This will sends ARP packets to hosts on the local network and displays any responses that are received.
 pub use scan::arp_scan;
+/// Passive ARP Scan.
+/// Nothing will be sent, it just listens to the ARP traffic on the interface for a while,
+/// hosts that send gratuitous ARP and addresses claimed by more than one MAC address are also reported.
+pub use scan::arp_scan_passive;
+/// IPv6 Local-Link Host Discovery.
+/// IPv6 subnets are too large to sweep, instead it sends ICMPv6 echo requests to all nodes (ff02::1) and all routers (ff02::2),
+/// a MLD general query and a router solicitation, then collects every link-local and global address that answered,
+/// the results can be converted to the target of `scan6` or `os_detect6`.
+pub use scan::ndp_multicast_scan;
+/// NDP Scan.
+/// The IPv6 version of ARP scan, sends neighbor solicitations to the solicited-node multicast address of each host
+/// and collects the neighbor advertisements.
+pub use scan::ndp_scan;
 
 /// TCP Connect() Scan.
 /// This is the most basic form of TCP scanning.
@@ -365,7 +402,19 @@ pub use scan::tcp_maimon_scan6;
 /// Instead, a clever side-channel attack allows for the scan to be bounced off a dumb "zombie host".
 /// Intrusion detection system (IDS) reports will finger the innocent zombie as the attacker.
 /// Besides being extraordinarily stealthy, this scan type permits discovery of IP-based trust relationships between machines.
+/// The port is `Unknown` if every try was disturbed by the other traffic of the zombie.
 pub use scan::tcp_idle_scan;
+/// Ipv6 version.
+/// There is no ip id in the ipv6 header, the fragment id is used instead.
+/// After receiving an ICMPv6 packet too big with a mtu less than 1280, some stacks add a fragment header to every packet they send,
+/// if the fragment id comes from a global counter, the zombie can be used like the ipv4 one.
+pub use scan::tcp_idle_scan6;
+
+/// Check whether the host can be used as the zombie of the idle scan.
+/// The ip id sequence of the zombie is classified (incremental, broken incremental, random, zero or per host) and its background traffic is estimated.
+pub use scan::probe_zombie;
+/// Ipv6 version, the fragment id is checked instead.
+pub use scan::probe_zombie6;
 
 /// UDP Scan.
 /// While most popular services on the Internet run over the TCP protocol, UDP services are widely deployed.
@@ -474,14 +523,47 @@ pub use flood::udp_flood6;
 pub use os::dbparser::nmap_os_db_parser;
 
 /// Detect target machine OS.
+/// Every attribute is weighted by the `MatchPoints` of nmap-os-db, and the guesses below the threshold are dropped.
 pub use os::os_detect;
 
 /// Detect target machine OS on IPv6.
+/// The ports are found the same as `os_detect` if they are not given, but an open tcp port is required.
 pub use os::os_detect6;
 
+/// Match the nmap format fingerprint (the `OS:` lines) offline, with the built-in or a given nmap-os-db.
+pub use os::os_match_fingerprint;
+
+/// Fingerprint and match the probes and responses of `os_detect` saved before (`AllPacketRR::save`), without the network.
+pub use os::os_detect_replay;
+
+/// Ipv6 version.
+pub use os::os_detect6_replay;
+
+/// Train the IPv6 OS detection model from the labelled fingerprints, the model can be used by `os_detect6`.
+pub use os::trainer6::train6;
+
+/// Passive OS Detect.
+/// Nothing will be sent, it listens to the TCP SYN and SYN+ACK packets on the interface for a while,
+/// and matches them with the p0f style signatures to guess the OS of every host seen.
+pub use os::os_detect_passive;
+
+/// Process the signatures in the p0f v3 format, the result can be used by `os_detect_passive`.
+pub use os::passive::passive_os_db_parser;
+
+/// Combine the OS hints of one host from `os_detect`, `os_detect6`, `vs_scan` and `arp_scan` into one weighted verdict,
+/// with the supporting evidence listed.
+pub use os::consensus::os_consensus;
+
 /// Detect target port service.
+/// The results are grouped by the host and the port/protocol,
+/// the services that are not recognized come with the service fingerprint.
+/// The user-defined probes are merged into the nmap database before the scan.
+/// The open ports found by the tcp and udp scans before can be probed too.
 pub use vs::vs_scan;
 
+/// Process the user-defined service probes in the `nmap-service-probes` syntax.
+pub use vs::dbparser::nsp_user_parser;
+
 /* Work with domain */
 /// Queries the IP address of a domain name and returns.
 pub use layers::dns_query;
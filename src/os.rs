@@ -1,28 +1,57 @@
 /* Remote OS Detection */
 use anyhow::Result;
+use chrono::DateTime;
+use chrono::Local;
 use serde::Deserialize;
 use serde::Serialize;
 use std::collections::HashMap;
 use std::fmt;
+use std::fs;
+use std::net::IpAddr;
 use std::net::Ipv4Addr;
 use std::net::Ipv6Addr;
 use std::sync::mpsc::channel;
 use std::time::Duration;
 
+use crate::errors::CanNotFoundInterface;
 use crate::errors::CanNotFoundSourceAddress;
+use crate::errors::Ipv6ModelError;
 use crate::errors::OsDetectPortError;
+use crate::layers::system_route;
+use crate::os::dbparser::MatchPoints;
 use crate::os::dbparser::NmapOsDb;
+use crate::os::operator::tcp_uptime;
 use crate::os::osscan::PistolFingerprint;
+use crate::os::osscan::SEQX;
 use crate::os::osscan6::PistolFingerprint6;
+use crate::os::passive::passive_os_db_parser;
+use crate::os::passive::recv_tcp_syn_packets;
+use crate::os::passive::PassiveFingerprint;
+use crate::os::passive::PassiveSignature;
+use crate::os::rr::AllPacketRR;
+use crate::os::rr::AllPacketRR6;
+use crate::scan::tcp_syn_scan;
+use crate::scan::tcp_syn_scan6;
+use crate::scan::PortStatus;
+use crate::scan::TargetScanStatus;
+use crate::scan::TcpUdpScanResults;
+use crate::utils::find_interface_by_ipv4;
 use crate::utils::find_source_ipv4;
 use crate::utils::find_source_ipv6;
 use crate::utils::get_default_timeout;
 use crate::utils::get_threads_pool;
+use crate::utils::random_port;
+use crate::Host;
+use crate::Host6;
 use crate::Target;
 
+use self::osscan::os_match;
 use self::osscan::os_probe;
+use self::osscan::os_replay;
 use self::osscan6::os_probe6;
+use self::osscan6::os_replay6;
 
+pub mod consensus;
 pub mod dbparser;
 pub mod operator;
 pub mod operator6;
@@ -30,19 +59,213 @@ pub mod osscan;
 pub mod osscan6;
 pub mod packet;
 pub mod packet6;
+pub mod passive;
 pub mod rr;
+pub mod trainer6;
+
+/// The os classes of the candidates with the same vendor, family and device type.
+#[derive(Debug, Clone)]
+pub struct OsClassGuess {
+    pub vendor: String,
+    pub family: String,
+    /// The generations in the order of the candidates, the unknown generation is not included.
+    pub generations: Vec<String>,
+    pub device_type: String,
+    /// The best accuracy of the candidates in this class.
+    pub accuracy: f64,
+}
+
+impl fmt::Display for OsClassGuess {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Linux | Linux => Linux, Microsoft | Windows => Microsoft Windows
+        let mut name = if self.vendor == self.family {
+            self.vendor.clone()
+        } else {
+            format!("{} {}", self.vendor, self.family)
+        };
+        if self.generations.len() > 0 {
+            name += &format!(" {}", self.generations.join("|"));
+        }
+        write!(
+            f,
+            "{}, {}, {}%",
+            name,
+            self.device_type,
+            (self.accuracy * 100.0).floor()
+        )
+    }
+}
+
+/// Aggregate the os classes of the candidates into the ranked guesses,
+/// the first one is the best answer.
+pub fn os_class_guess(detects: &[NmapOsDetectRet]) -> Vec<OsClassGuess> {
+    let mut ret: Vec<OsClassGuess> = Vec::new();
+    for d in detects {
+        for c in &d.db.classes {
+            let exist = ret.iter_mut().find(|g| {
+                g.vendor == c.vendor && g.family == c.family && g.device_type == c.device_type
+            });
+            match exist {
+                Some(g) => {
+                    if c.generation.len() > 0 && !g.generations.contains(&c.generation) {
+                        g.generations.push(c.generation.clone());
+                    }
+                    if d.accuracy > g.accuracy {
+                        g.accuracy = d.accuracy;
+                    }
+                }
+                None => {
+                    let generations = if c.generation.len() > 0 {
+                        vec![c.generation.clone()]
+                    } else {
+                        Vec::new()
+                    };
+                    let g = OsClassGuess {
+                        vendor: c.vendor.clone(),
+                        family: c.family.clone(),
+                        generations,
+                        device_type: c.device_type.clone(),
+                        accuracy: d.accuracy,
+                    };
+                    ret.push(g);
+                }
+            }
+        }
+    }
+    // the sort is stable, the classes with the same accuracy keep the order of the candidates
+    ret.sort_by(|a, b| b.accuracy.total_cmp(&a.accuracy));
+    ret
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct UptimeGuess {
+    pub uptime: Duration,
+    /// `None` if the time of the probes is unknown, such as the replayed probes.
+    pub last_boot: Option<DateTime<Local>>,
+}
+
+impl fmt::Display for UptimeGuess {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let days = self.uptime.as_secs_f64() / 86400.0;
+        match self.last_boot {
+            Some(l) => write!(
+                f,
+                "Uptime guess: {:.3} days (since {})",
+                days,
+                l.format("%a %b %e %H:%M:%S %Y")
+            ),
+            None => write!(f, "Uptime guess: {:.3} days", days),
+        }
+    }
+}
+
+/// The TCP sequence prediction and IP ID sequence generation, computed from the SEQ test.
+#[derive(Debug, Clone)]
+pub struct SeqPrediction {
+    /// The TCP ISN sequence predictability index (SP).
+    pub difficulty: u32,
+    pub rating: String,
+    /// The IP ID sequence generation class of the TCP responses (TI).
+    pub ip_id_class: String,
+}
+
+impl SeqPrediction {
+    /// Returns `None` if the SEQ test is not performed (no open port).
+    pub fn new(seqx: &SEQX) -> Option<SeqPrediction> {
+        if seqx.r.len() == 0 {
+            return None;
+        }
+        let difficulty = seqx.sp;
+        // same as nmap
+        let rating = if difficulty < 3 {
+            "Trivial joke"
+        } else if difficulty < 6 {
+            "Easy"
+        } else if difficulty < 11 {
+            "Medium"
+        } else if difficulty < 12 {
+            "Formidable"
+        } else if difficulty < 16 {
+            "Worthy challenge"
+        } else {
+            "Good luck!"
+        };
+        let ip_id_class = match seqx.ti.as_str() {
+            "Z" => "All zeros",
+            "RD" => "Randomized",
+            "RI" => "Random positive increments",
+            "BI" => "Broken little-endian incremental",
+            "I" => "Incremental",
+            "" => "Unknown",
+            // the constant value in hex
+            _ => "Constant",
+        };
+        Some(SeqPrediction {
+            difficulty,
+            rating: rating.to_string(),
+            ip_id_class: ip_id_class.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for SeqPrediction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "TCP Sequence Prediction: Difficulty={} ({})\nIP ID Sequence Generation: {}",
+            self.difficulty, self.rating, self.ip_id_class
+        )
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct OsStatus {
     pub fingerprint: PistolFingerprint,
     pub detects: Vec<NmapOsDetectRet>,
+    /// The os classes aggregated from the `detects`, see `os_class_guess`.
+    pub classes: Vec<OsClassGuess>,
+    /// All the probes and responses, it can be saved and replayed later (`os_detect_replay`).
+    /// `None` if the fingerprint is not from the probes.
+    pub rr: Option<AllPacketRR>,
+    /// From the TCP timestamps of the SEQ responses, `None` if the probes are unknown or the timestamp is not supported.
+    pub uptime: Option<UptimeGuess>,
+    pub seq_prediction: Option<SeqPrediction>,
 }
 
 impl OsStatus {
-    pub fn new(fingerprint: PistolFingerprint, detects: Vec<NmapOsDetectRet>) -> OsStatus {
+    /// The `probe_time` is when the probes were sent, it is used to estimate the last boot time.
+    pub fn new(
+        fingerprint: PistolFingerprint,
+        detects: Vec<NmapOsDetectRet>,
+        rr: Option<AllPacketRR>,
+        probe_time: Option<DateTime<Local>>,
+    ) -> OsStatus {
+        let classes = os_class_guess(&detects);
+        let uptime = match &rr {
+            // the broken responses mean no timestamps
+            Some(ap) => match tcp_uptime(&ap.seq).unwrap_or(None) {
+                Some(uptime) => {
+                    let last_boot = match probe_time {
+                        Some(t) => match chrono::Duration::from_std(uptime) {
+                            Ok(u) => Some(t - u),
+                            Err(_) => None,
+                        },
+                        None => None,
+                    };
+                    Some(UptimeGuess { uptime, last_boot })
+                }
+                None => None,
+            },
+            None => None,
+        };
+        let seq_prediction = SeqPrediction::new(&fingerprint.seqx);
         OsStatus {
             fingerprint,
             detects,
+            classes,
+            rr,
+            uptime,
+            seq_prediction,
         }
     }
 }
@@ -68,6 +291,18 @@ impl fmt::Display for OsDetectResults {
             let detect_ret = &oss.detects;
             output += &format!(">>> IP:\n{ipv4}");
             output += &format!(">>> Pistol fingerprint:\n{fingerprint}");
+            match oss.classes.first() {
+                Some(c) => output += &format!(">>> OS:\n{}\n", c),
+                None => output += ">>> OS:\nNo OS matches\n",
+            }
+            match &oss.uptime {
+                Some(u) => output += &format!("{}\n", u),
+                None => (),
+            }
+            match &oss.seq_prediction {
+                Some(s) => output += &format!("{}\n", s),
+                None => (),
+            }
             output += &format!(">>> Details:");
             for d in detect_ret {
                 output += &format!("{}", d);
@@ -81,13 +316,20 @@ impl fmt::Display for OsDetectResults {
 pub struct OsStatus6 {
     pub fingerprint: PistolFingerprint6,
     pub detects: Vec<NmapOsDetectRet6>,
+    /// All the probes and responses, it can be saved and replayed later (`os_detect6_replay`).
+    pub rr: Option<AllPacketRR6>,
 }
 
 impl OsStatus6 {
-    pub fn new(fingerprint: PistolFingerprint6, detects: Vec<NmapOsDetectRet6>) -> OsStatus6 {
+    pub fn new(
+        fingerprint: PistolFingerprint6,
+        detects: Vec<NmapOsDetectRet6>,
+        rr: Option<AllPacketRR6>,
+    ) -> OsStatus6 {
         OsStatus6 {
             fingerprint,
             detects,
+            rr,
         }
     }
 }
@@ -121,17 +363,166 @@ impl fmt::Display for OsDetectResults6 {
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct PassiveOsGuess {
+    pub class: String,
+    pub name: String,
+    /// How many packets of the host match this os.
+    pub count: usize,
+    pub last_seen: Duration,
+}
+
+impl fmt::Display for PassiveOsGuess {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}) seen {} times, last at {:.1}s",
+            self.name,
+            self.class,
+            self.count,
+            self.last_seen.as_secs_f32()
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PassiveObserved {
+    pub fingerprint: PassiveFingerprint,
+    /// `None` if no signature matches.
+    pub signature: Option<PassiveSignature>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PassiveOsStatus {
+    /// All the SYN and SYN+ACK packets sent by the host, in time order.
+    pub observed: Vec<PassiveObserved>,
+}
+
+impl PassiveOsStatus {
+    pub fn new() -> PassiveOsStatus {
+        PassiveOsStatus {
+            observed: Vec::new(),
+        }
+    }
+    /// The os guesses of all the packets seen so far,
+    /// the most seen one first, and the latest seen one first if the counts are the same.
+    pub fn guesses(&self) -> Vec<PassiveOsGuess> {
+        let mut ret: Vec<PassiveOsGuess> = Vec::new();
+        for o in &self.observed {
+            let s = match &o.signature {
+                Some(s) => s,
+                None => continue,
+            };
+            let time = o.fingerprint.time;
+            match ret
+                .iter_mut()
+                .find(|g| g.class == s.class && g.name == s.name)
+            {
+                Some(g) => {
+                    g.count += 1;
+                    if time > g.last_seen {
+                        g.last_seen = time;
+                    }
+                }
+                None => ret.push(PassiveOsGuess {
+                    class: s.class.clone(),
+                    name: s.name.clone(),
+                    count: 1,
+                    last_seen: time,
+                }),
+            }
+        }
+        ret.sort_by(|a, b| b.count.cmp(&a.count).then(b.last_seen.cmp(&a.last_seen)));
+        ret
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PassiveOsResults {
+    pub results: HashMap<Ipv4Addr, PassiveOsStatus>,
+    /// How long it has been listening, the time of the packets is relative to the start.
+    pub elapsed: Duration,
+}
+
+impl PassiveOsResults {
+    pub fn new() -> PassiveOsResults {
+        PassiveOsResults {
+            results: HashMap::new(),
+            elapsed: Duration::new(0, 0),
+        }
+    }
+    /// Match the packet with the first matched signature and add it to the host who sent it.
+    pub fn update(&mut self, fingerprint: PassiveFingerprint, passive_db: &[PassiveSignature]) {
+        let signature = passive_db
+            .iter()
+            .find(|s| s.do_match(&fingerprint))
+            .cloned();
+        let status = self
+            .results
+            .entry(fingerprint.src_addr)
+            .or_insert(PassiveOsStatus::new());
+        status.observed.push(PassiveObserved {
+            fingerprint,
+            signature,
+        });
+    }
+}
+
+impl fmt::Display for PassiveOsResults {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut output = String::new();
+        for (ipv4, oss) in &self.results {
+            output += &format!(">>> IP:\n{ipv4}\n");
+            match oss.guesses().first() {
+                Some(g) => output += &format!(">>> OS:\n{}\n", g),
+                None => output += ">>> OS:\nNo OS matches\n",
+            }
+            output += ">>> Details:\n";
+            for o in &oss.observed {
+                let f = &o.fingerprint;
+                let name = match &o.signature {
+                    Some(s) => s.name.clone(),
+                    None => String::from("unknown"),
+                };
+                output += &format!(
+                    "{:.1}s {} {}:{} {} {}\n",
+                    f.time.as_secs_f32(),
+                    f.direction,
+                    f.src_addr,
+                    f.src_port,
+                    f,
+                    name
+                );
+            }
+        }
+        write!(f, "{}", output)
+    }
+}
+
+/// Like nmap, the guess whose accuracy is lower than this is not reported.
+pub const OSSCAN_GUESS_THRESHOLD: f64 = 0.85;
+
 #[derive(Debug, Clone)]
 pub struct NmapOsDetectRet {
+    /// The `MatchPoints` of the matched attributes.
     pub score: usize,
+    /// The `MatchPoints` of all the compared attributes.
     pub total: usize,
+    /// The `score / total`, nmap prints it as the percentage.
+    pub accuracy: f64,
     pub db: NmapOsDb,
 }
 
 impl fmt::Display for NmapOsDetectRet {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let mut output = String::new();
-        let s = format!(">>> Score:\n{}/{}\n", self.score, self.total);
+        // nmap truncates the percentage
+        let s = format!(
+            ">>> Score:\n{}/{} ({}%)\n",
+            self.score,
+            self.total,
+            (self.accuracy * 100.0).floor()
+        );
         output += &s;
 
         let info_split: Vec<&str> = self.db.info.split("\n").collect();
@@ -226,21 +617,148 @@ pub struct CPE {
     pub cpe: Vec<String>,
 }
 
-#[derive(Debug, Clone)]
+/// The ipv6 os detection model (logistic regression), built-in or trained by `trainer6::train6`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Linear {
     pub namelist: Vec<String>,
+    /// [classes, features]
     pub w: Vec<Vec<f64>>,
+    /// [features, 2], the feature is scaled by (f + scale[0]) * scale[1].
     pub scale: Vec<Vec<f64>>,
+    /// [classes, features], for the novelty of the scaled features.
     pub mean: Vec<Vec<f64>>,
     pub variance: Vec<Vec<f64>>,
     pub cpe: Vec<CPE>,
 }
 
+impl Linear {
+    /// Check the shapes of the model.
+    pub fn check(&self) -> Result<()> {
+        let classes = self.namelist.len();
+        let features = self.scale.len();
+        if classes == 0 || features == 0 {
+            return Err(Ipv6ModelError::new("empty model").into());
+        }
+        if self.w.len() != classes
+            || self.mean.len() != classes
+            || self.variance.len() != classes
+            || self.cpe.len() != classes
+        {
+            return Err(Ipv6ModelError::new("the number of classes does not match").into());
+        }
+        for s in &self.scale {
+            if s.len() != 2 {
+                return Err(Ipv6ModelError::new("the scale must be pairs").into());
+            }
+        }
+        for i in 0..classes {
+            if self.w[i].len() != features
+                || self.mean[i].len() != features
+                || self.variance[i].len() != features
+            {
+                let reason = format!(
+                    "the number of features of {} does not match",
+                    self.namelist[i]
+                );
+                return Err(Ipv6ModelError::new(&reason).into());
+            }
+        }
+        Ok(())
+    }
+    /// Save the model to the file (json), it can be loaded by `Linear::load` and used by `os_detect6`.
+    pub fn save(&self, path: &str) -> Result<()> {
+        let data = serde_json::to_string(self)?;
+        fs::write(path, data)?;
+        Ok(())
+    }
+    pub fn load(path: &str) -> Result<Linear> {
+        let data = fs::read_to_string(path)?;
+        let linear: Linear = serde_json::from_str(&data)?;
+        linear.check()?;
+        Ok(linear)
+    }
+}
+
+/// The ports probed to find the open and closed tcp port when they are not given.
+pub const OS_DETECT_COMMON_PORTS: [u16; 18] = [
+    21, 22, 23, 25, 53, 80, 110, 135, 139, 143, 443, 445, 993, 995, 3306, 3389, 5900, 8080,
+];
+
+/// Returns the open tcp port (if any), the closed tcp port and the closed udp port.
+/// The first open (closed) port in the order of `candidates` is used,
+/// a random high port is used as the closed port when no closed port is found.
+fn select_os_detect_ports(candidates: &[u16], port_status: &PortStatus) -> (Option<u16>, u16, u16) {
+    let mut dst_open_tcp_port = None;
+    let mut dst_closed_tcp_port = None;
+    for p in candidates {
+        match port_status.status.get(p) {
+            Some(TargetScanStatus::Open) => {
+                if dst_open_tcp_port.is_none() {
+                    dst_open_tcp_port = Some(*p);
+                }
+            }
+            Some(TargetScanStatus::Closed) => {
+                if dst_closed_tcp_port.is_none() {
+                    dst_closed_tcp_port = Some(*p);
+                }
+            }
+            _ => (),
+        }
+    }
+    let dst_closed_tcp_port = match dst_closed_tcp_port {
+        Some(c) => c,
+        None => random_port(),
+    };
+    (dst_open_tcp_port, dst_closed_tcp_port, random_port())
+}
+
+/// The ports of the host are tried first, then the common ports.
+fn os_detect_candidates(ports: &[u16]) -> Vec<u16> {
+    let mut candidates = ports.to_vec();
+    for p in OS_DETECT_COMMON_PORTS {
+        if !candidates.contains(&p) {
+            candidates.push(p);
+        }
+    }
+    candidates
+}
+
+/// Select the ports of the host from `scan_results`, or from `syn_results` if the host is not in it.
+fn select_os_detect_ports_from_results(
+    addr: IpAddr,
+    candidates: Vec<u16>,
+    scan_results: &TcpUdpScanResults,
+    syn_results: &TcpUdpScanResults,
+) -> (Option<u16>, u16, u16) {
+    let port_status = match scan_results.results.get(&addr) {
+        Some(p) => p.clone(),
+        None => match syn_results.results.get(&addr) {
+            Some(p) => p.clone(),
+            None => PortStatus::new(),
+        },
+    };
+    // try the host ports and the common ports first, then the rest ports in the results
+    let mut candidates = candidates;
+    let mut rest: Vec<u16> = port_status.status.keys().cloned().collect();
+    rest.sort();
+    for p in rest {
+        if !candidates.contains(&p) {
+            candidates.push(p);
+        }
+    }
+    select_os_detect_ports(&candidates, &port_status)
+}
+
+/// The ports of the host are used as `[open tcp port, closed tcp port, closed udp port]` if there are at least 3 of them,
+/// otherwise they are found from `scan_results` or a quick syn scan of the common ports.
+/// Only the reduced tests are performed if no open tcp port is found.
 pub fn os_detect(
     target: Target,
     src_ipv4: Option<Ipv4Addr>,
     src_port: Option<u16>,
+    scan_results: Option<TcpUdpScanResults>,
     top_k: usize,
+    guess_threshold: Option<f64>,
     threads_num: usize,
     timeout: Option<Duration>,
 ) -> Result<OsDetectResults> {
@@ -248,54 +766,91 @@ pub fn os_detect(
         Some(t) => t,
         None => get_default_timeout(),
     };
-    let nmap_os_file = include_str!("./db/nmap-os-db");
-    let mut nmap_os_file_lines = Vec::new();
-    for l in nmap_os_file.lines() {
-        nmap_os_file_lines.push(l.to_string());
+    let guess_threshold = match guess_threshold {
+        Some(g) => g,
+        None => OSSCAN_GUESS_THRESHOLD,
+    };
+    let (nmap_os_db, match_points) = builtin_nmap_os_db()?;
+    let scan_results = match scan_results {
+        Some(s) => s,
+        None => TcpUdpScanResults::new(),
+    };
+
+    let mut hosts_ports = Vec::new();
+    let mut need_scan_hosts = Vec::new();
+    for t in target.hosts {
+        if t.ports.len() >= 3 {
+            let ports = (Some(t.ports[0]), t.ports[1], t.ports[2]);
+            hosts_ports.push((t.addr, Some(ports), vec![]));
+        } else {
+            let candidates = os_detect_candidates(&t.ports);
+            if !scan_results.results.contains_key(&t.addr.into()) {
+                let mut scan_ports = candidates.to_vec();
+                scan_ports.push(random_port());
+                let h = Host {
+                    addr: t.addr,
+                    ports: scan_ports,
+                    udp_ports: vec![],
+                };
+                need_scan_hosts.push(h);
+            }
+            hosts_ports.push((t.addr, None, candidates));
+        }
     }
-    let nmap_os_db = dbparser::nmap_os_db_parser(nmap_os_file_lines)?;
+    let syn_results = if need_scan_hosts.len() > 0 {
+        let syn_target = Target::new(need_scan_hosts);
+        tcp_syn_scan(syn_target, src_ipv4, src_port, threads_num, Some(timeout))?
+    } else {
+        TcpUdpScanResults::new()
+    };
+
     let (tx, rx) = channel();
     let pool = get_threads_pool(threads_num);
     let mut recv_size = 0;
-    for t in target.hosts {
-        let dst_ipv4 = t.addr;
+    for (dst_ipv4, ports, candidates) in hosts_ports {
         let src_ipv4 = match find_source_ipv4(src_ipv4, dst_ipv4)? {
             Some(s) => s,
             None => return Err(CanNotFoundSourceAddress::new().into()),
         };
-        if t.ports.len() >= 3 {
-            recv_size += 1;
-            let dst_open_tcp_port = t.ports[0];
-            let dst_closed_tcp_port = t.ports[1];
-            let dst_closed_udp_port = t.ports[2];
-            let tx = tx.clone();
-            let nmap_os_db = nmap_os_db.to_vec();
-            pool.execute(move || {
-                let os_detect_ret = os_probe(
-                    src_ipv4,
-                    src_port,
-                    dst_ipv4,
-                    dst_open_tcp_port,
-                    dst_closed_tcp_port,
-                    dst_closed_udp_port,
-                    nmap_os_db,
-                    top_k,
-                    timeout,
-                );
-                match tx.send((dst_ipv4, os_detect_ret)) {
-                    _ => (),
-                }
-            });
-        } else {
-            return Err(OsDetectPortError::new().into());
-        }
+        let (dst_open_tcp_port, dst_closed_tcp_port, dst_closed_udp_port) = match ports {
+            Some(ports) => ports,
+            None => select_os_detect_ports_from_results(
+                dst_ipv4.into(),
+                candidates,
+                &scan_results,
+                &syn_results,
+            ),
+        };
+        recv_size += 1;
+        let tx = tx.clone();
+        let nmap_os_db = nmap_os_db.to_vec();
+        let match_points = match_points.clone();
+        pool.execute(move || {
+            let probe_time = Local::now();
+            let os_detect_ret = os_probe(
+                src_ipv4,
+                src_port,
+                dst_ipv4,
+                dst_open_tcp_port,
+                dst_closed_tcp_port,
+                dst_closed_udp_port,
+                nmap_os_db,
+                match_points,
+                top_k,
+                guess_threshold,
+                timeout,
+            );
+            match tx.send((dst_ipv4, probe_time, os_detect_ret)) {
+                _ => (),
+            }
+        });
     }
     let mut ret = OsDetectResults::new();
     let iter = rx.into_iter().take(recv_size);
-    for (ipv4, r) in iter {
+    for (ipv4, probe_time, r) in iter {
         match r {
-            Ok((fingerprint, detect_ret)) => {
-                let oss = OsStatus::new(fingerprint, detect_ret);
+            Ok((fingerprint, detect_ret, ap)) => {
+                let oss = OsStatus::new(fingerprint, detect_ret, Some(ap), Some(probe_time));
                 ret.results.insert(ipv4, oss);
             }
             Err(e) => return Err(e),
@@ -304,6 +859,124 @@ pub fn os_detect(
     Ok(ret)
 }
 
+fn builtin_nmap_os_db() -> Result<(Vec<NmapOsDb>, MatchPoints)> {
+    let nmap_os_file = include_str!("./db/nmap-os-db");
+    let mut nmap_os_file_lines = Vec::new();
+    for l in nmap_os_file.lines() {
+        nmap_os_file_lines.push(l.to_string());
+    }
+    dbparser::nmap_os_db_parser(nmap_os_file_lines)
+}
+
+/// Fingerprint and match the probes and responses saved by `AllPacketRR::save` again, without the network.
+/// The `nmap_os_db` is the result of `nmap_os_db_parser`, `None` means the built-in nmap-os-db.
+pub fn os_detect_replay(
+    ap: &AllPacketRR,
+    nmap_os_db: Option<(Vec<NmapOsDb>, MatchPoints)>,
+    top_k: usize,
+    guess_threshold: Option<f64>,
+) -> Result<OsStatus> {
+    let guess_threshold = match guess_threshold {
+        Some(g) => g,
+        None => OSSCAN_GUESS_THRESHOLD,
+    };
+    let (nmap_os_db, match_points) = match nmap_os_db {
+        Some(n) => n,
+        None => builtin_nmap_os_db()?,
+    };
+    let (fingerprint, detects) = os_replay(ap, &nmap_os_db, &match_points, top_k, guess_threshold)?;
+    Ok(OsStatus::new(fingerprint, detects, Some(ap.clone()), None))
+}
+
+/// Ipv6 version of `os_detect_replay`, the probes and responses are saved by `AllPacketRR6::save`.
+/// The `model` is the same as `os_detect6`.
+pub fn os_detect6_replay(
+    ap: &AllPacketRR6,
+    model: Option<Linear>,
+    top_k: usize,
+) -> Result<OsStatus6> {
+    let linear = match model {
+        Some(m) => {
+            m.check()?;
+            m
+        }
+        None => gen_linear()?,
+    };
+    let (fingerprint, detects) = os_replay6(ap, top_k, linear)?;
+    Ok(OsStatus6::new(fingerprint, detects, Some(ap.clone())))
+}
+
+/// Match the fingerprint in nmap format (the `OS:` lines) without probing the target again.
+/// The `nmap_os_db` is the result of `nmap_os_db_parser`, `None` means the built-in nmap-os-db.
+pub fn os_match_fingerprint(
+    fingerprint: &str,
+    nmap_os_db: Option<(Vec<NmapOsDb>, MatchPoints)>,
+    top_k: usize,
+    guess_threshold: Option<f64>,
+) -> Result<OsStatus> {
+    let guess_threshold = match guess_threshold {
+        Some(g) => g,
+        None => OSSCAN_GUESS_THRESHOLD,
+    };
+    let (nmap_os_db, match_points) = match nmap_os_db {
+        Some(n) => n,
+        None => builtin_nmap_os_db()?,
+    };
+    let fingerprint = PistolFingerprint::parser(fingerprint)?;
+    let detects = os_match(
+        &fingerprint,
+        &nmap_os_db,
+        &match_points,
+        top_k,
+        guess_threshold,
+    );
+    Ok(OsStatus::new(fingerprint, detects, None, None))
+}
+
+fn builtin_passive_os_db() -> Result<Vec<PassiveSignature>> {
+    let passive_os_file = include_str!("./db/passive-os-db");
+    let mut passive_os_file_lines = Vec::new();
+    for l in passive_os_file.lines() {
+        passive_os_file_lines.push(l.to_string());
+    }
+    passive_os_db_parser(passive_os_file_lines)
+}
+
+/// Guess the os of the hosts from the SYN and SYN+ACK packets they send, nothing will be sent.
+/// The `passive_db` is the result of `passive_os_db_parser`, `None` means the built-in one.
+/// Pass the `results` of the last call to keep guessing over time.
+pub fn os_detect_passive(
+    src_ipv4: Option<Ipv4Addr>,
+    duration: Duration,
+    passive_db: Option<Vec<PassiveSignature>>,
+    results: Option<PassiveOsResults>,
+) -> Result<PassiveOsResults> {
+    let route_ipv4 = system_route()?;
+    let src_ipv4 = match find_source_ipv4(src_ipv4, route_ipv4)? {
+        Some(s) => s,
+        None => return Err(CanNotFoundSourceAddress::new().into()),
+    };
+    let interface = match find_interface_by_ipv4(src_ipv4) {
+        Some(i) => i,
+        None => return Err(CanNotFoundInterface::new().into()),
+    };
+    let passive_db = match passive_db {
+        Some(p) => p,
+        None => builtin_passive_os_db()?,
+    };
+
+    let mut ret = match results {
+        Some(r) => r,
+        None => PassiveOsResults::new(),
+    };
+    for mut fingerprint in recv_tcp_syn_packets(interface, duration)? {
+        fingerprint.time += ret.elapsed;
+        ret.update(fingerprint, &passive_db);
+    }
+    ret.elapsed += duration;
+    Ok(ret)
+}
+
 fn gen_linear() -> Result<Linear> {
     let variance_json_data = include_str!("./db/nmap-os-db-ipv6/variance.json");
     let variance_json: Vec<NmapJsonParameters> = serde_json::from_str(variance_json_data)?;
@@ -314,8 +987,6 @@ fn gen_linear() -> Result<Linear> {
         variance.push(v.value);
         namelist.push(v.name);
     }
-    assert_eq!(namelist.len(), 92);
-    assert_eq!(variance.len(), 92);
 
     let mean_json_data = include_str!("./db/nmap-os-db-ipv6/mean.json");
     let mean_json: Vec<NmapJsonParameters> = serde_json::from_str(mean_json_data)?;
@@ -323,7 +994,6 @@ fn gen_linear() -> Result<Linear> {
     for m in mean_json {
         mean.push(m.value);
     }
-    assert_eq!(mean.len(), 92);
 
     let scale_json_data = include_str!("./db/nmap-os-db-ipv6/scale.json"); // static
     let scale_json: Vec<NmapJsonParameters> = serde_json::from_str(scale_json_data)?;
@@ -331,15 +1001,17 @@ fn gen_linear() -> Result<Linear> {
     for s in scale_json {
         scale.push(s.value)
     }
-    assert_eq!(scale.len(), 695);
 
     let w_json_data = include_str!("./db/nmap-os-db-ipv6/w.json"); // static
     let w_json: Vec<NmapJsonParameters> = serde_json::from_str(w_json_data)?;
-    assert_eq!(w_json.len(), 695);
 
     let mut w = Vec::new();
-    // [695, 92] => [92, 695]
-    for i in 0..w_json[0].value.len() {
+    // [features, classes] => [classes, features]
+    let classes = match w_json.first() {
+        Some(x) => x.value.len(),
+        None => 0,
+    };
+    for i in 0..classes {
         let mut tmp = Vec::new();
         for x in &w_json {
             tmp.push(x.value[i]);
@@ -349,7 +1021,6 @@ fn gen_linear() -> Result<Linear> {
 
     let cpe_json_data = include_str!("./db/nmap-os-db-ipv6/cpe.json"); // static
     let cpe: Vec<CPE> = serde_json::from_str(cpe_json_data)?;
-    assert_eq!(cpe.len(), 92);
 
     let linear = Linear {
         namelist,
@@ -359,13 +1030,19 @@ fn gen_linear() -> Result<Linear> {
         variance,
         cpe,
     };
+    linear.check()?;
     Ok(linear)
 }
 
+/// The `model` is loaded by `Linear::load` (see `trainer6`), `None` means the built-in model.
+/// The ports are chosen the same as `os_detect` if the host has less than 3 ports,
+/// but the IPv6 tests need an open tcp port, `OsDetectPortError` is returned if none is found.
 pub fn os_detect6(
     target: Target,
     src_ipv6: Option<Ipv6Addr>,
     src_port: Option<u16>,
+    scan_results: Option<TcpUdpScanResults>,
+    model: Option<Linear>,
     top_k: usize,
     threads_num: usize,
     timeout: Option<Duration>,
@@ -377,47 +1054,91 @@ pub fn os_detect6(
     let (tx, rx) = channel();
     let pool = get_threads_pool(threads_num);
     let mut recv_size = 0;
-    let linear = gen_linear()?;
+    let linear = match model {
+        Some(m) => {
+            m.check()?;
+            m
+        }
+        None => gen_linear()?,
+    };
+    let scan_results = match scan_results {
+        Some(s) => s,
+        None => TcpUdpScanResults::new(),
+    };
+
+    let mut hosts_ports = Vec::new();
+    let mut need_scan_hosts = Vec::new();
     for t in target.hosts6 {
-        let dst_ipv6 = t.addr;
+        if t.ports.len() >= 3 {
+            let ports = (Some(t.ports[0]), t.ports[1], t.ports[2]);
+            hosts_ports.push((t.addr, Some(ports), vec![]));
+        } else {
+            let candidates = os_detect_candidates(&t.ports);
+            if !scan_results.results.contains_key(&t.addr.into()) {
+                let mut scan_ports = candidates.to_vec();
+                scan_ports.push(random_port());
+                let h = Host6 {
+                    addr: t.addr,
+                    ports: scan_ports,
+                    udp_ports: vec![],
+                };
+                need_scan_hosts.push(h);
+            }
+            hosts_ports.push((t.addr, None, candidates));
+        }
+    }
+    let syn_results = if need_scan_hosts.len() > 0 {
+        let syn_target = Target::new6(need_scan_hosts);
+        tcp_syn_scan6(syn_target, src_ipv6, src_port, threads_num, Some(timeout))?
+    } else {
+        TcpUdpScanResults::new()
+    };
+
+    for (dst_ipv6, ports, candidates) in hosts_ports {
         let src_ipv6 = match find_source_ipv6(src_ipv6, dst_ipv6)? {
             Some(s) => s,
             None => return Err(CanNotFoundSourceAddress::new().into()),
         };
-        if t.ports.len() >= 3 {
-            recv_size += 1;
-            let dst_open_tcp_port = t.ports[0];
-            let dst_closed_tcp_port = t.ports[1];
-            let dst_closed_udp_port = t.ports[2];
-            let tx = tx.clone();
-            let linear = linear.clone();
-            pool.execute(move || {
-                let os_detect_ret = os_probe6(
-                    src_ipv6,
-                    src_port,
-                    dst_ipv6,
-                    dst_open_tcp_port,
-                    dst_closed_tcp_port,
-                    dst_closed_udp_port,
-                    top_k,
-                    linear,
-                    timeout,
-                );
-                match tx.send((dst_ipv6, os_detect_ret)) {
-                    _ => (),
-                }
-            });
-        } else {
-            return Err(OsDetectPortError::new().into());
-        }
+        let (dst_open_tcp_port, dst_closed_tcp_port, dst_closed_udp_port) = match ports {
+            Some(ports) => ports,
+            None => select_os_detect_ports_from_results(
+                dst_ipv6.into(),
+                candidates,
+                &scan_results,
+                &syn_results,
+            ),
+        };
+        let dst_open_tcp_port = match dst_open_tcp_port {
+            Some(o) => o,
+            None => return Err(OsDetectPortError::new().into()),
+        };
+        recv_size += 1;
+        let tx = tx.clone();
+        let linear = linear.clone();
+        pool.execute(move || {
+            let os_detect_ret = os_probe6(
+                src_ipv6,
+                src_port,
+                dst_ipv6,
+                dst_open_tcp_port,
+                dst_closed_tcp_port,
+                dst_closed_udp_port,
+                top_k,
+                linear,
+                timeout,
+            );
+            match tx.send((dst_ipv6, os_detect_ret)) {
+                _ => (),
+            }
+        });
     }
 
     let mut ret = OsDetectResults6::new();
     let iter = rx.into_iter().take(recv_size);
     for (ipv6, r) in iter {
         match r {
-            Ok((fingerprint, detect_ret)) => {
-                let oss = OsStatus6::new(fingerprint, detect_ret);
+            Ok((fingerprint, detect_ret, ap)) => {
+                let oss = OsStatus6::new(fingerprint, detect_ret, Some(ap));
                 ret.results.insert(ipv6, oss);
             }
             Err(e) => return Err(e),
@@ -466,7 +1187,17 @@ mod tests {
         let top_k = 3;
         let threads_num = 8;
 
-        let ret = os_detect6(target, src_ipv6, src_port, top_k, threads_num, timeout).unwrap();
+        let ret = os_detect6(
+            target,
+            src_ipv6,
+            src_port,
+            None,
+            None,
+            top_k,
+            threads_num,
+            timeout,
+        )
+        .unwrap();
         println!("{}", ret);
         Ok(())
     }
@@ -490,9 +1221,20 @@ mod tests {
         let target = Target::new(vec![host]);
         let timeout = Some(Duration::new(1, 0));
         let top_k = 1;
+        let guess_threshold = None;
         let threads_num = 8;
 
-        let ret = os_detect(target, src_ipv4, src_port, top_k, threads_num, timeout).unwrap();
+        let ret = os_detect(
+            target,
+            src_ipv4,
+            src_port,
+            None,
+            top_k,
+            guess_threshold,
+            threads_num,
+            timeout,
+        )
+        .unwrap();
         println!("{}", ret.results.get(&dst_ipv4).unwrap().fingerprint);
         Ok(())
     }
@@ -505,7 +1247,7 @@ mod tests {
         for l in nmap_os_file.lines() {
             nmap_os_file_lines.push(l.to_string());
         }
-        let ret = nmap_os_db_parser(nmap_os_file_lines).unwrap();
+        let (ret, _) = nmap_os_db_parser(nmap_os_file_lines).unwrap();
         for i in 0..5 {
             let r = &ret[i];
             println!("{:?}", r.info);
@@ -518,4 +1260,199 @@ mod tests {
         // let mut file_write = File::create("nmap-os-db.pistol").unwrap();
         // file_write.write_all(serialized.as_bytes()).unwrap();
     }
+    #[test]
+    fn test_match_points() {
+        let lines: Vec<String> = vec![
+            "MatchPoints",
+            "SEQ(SP=25%GCD=75%ISR=25%TI=100%CI=50%II=100%SS=80%TS=100)",
+            "IE(R=50%DFI=40%T=15%TG=15%CD=100)",
+            "",
+            "Fingerprint Test OS",
+        ]
+        .iter()
+        .map(|l| l.to_string())
+        .collect();
+        let (ret, match_points) = nmap_os_db_parser(lines).unwrap();
+        assert_eq!(ret.len(), 0);
+        assert_eq!(match_points.get("SEQ", "GCD"), 75);
+        assert_eq!(match_points.get("IE", "CD"), 100);
+        // not in the section
+        assert_eq!(match_points.get("SEQ", "R"), 0);
+
+        // the malformed lines are errors, not panics
+        let mut match_points = MatchPoints::new();
+        assert!(match_points.parser("SEQ").is_err());
+        assert!(match_points.parser("SEQ(SP)").is_err());
+        assert!(match_points.parser("SEQ(SP=x)").is_err());
+    }
+    #[test]
+    fn test_os_match_fingerprint() {
+        let lines: Vec<String> = vec![
+            "Fingerprint Linux 2.6.32",
+            "Class Linux | Linux | 2.6.X | general purpose",
+            "SEQ(SP=C0-D2%GCD=1-6%ISR=C8-D8%TI=Z%CI=Z%II=I%TS=A)",
+            "OPS(O1=M400CST11NW5%O2=M400CST11NW5%O3=M400CNNT11NW5%O4=M400CST11NW5%O5=M400CST11NW5%O6=M400CST11)",
+            "WIN(W1=8000%W2=8000%W3=8000%W4=8000%W5=8000%W6=8000)",
+            "ECN(R=Y%DF=Y%T=3B-45%TG=40%W=8018%O=M400CNNSNW5%CC=N%Q=)",
+            "T1(R=Y%DF=Y%T=3B-45%TG=40%S=O%A=S+%F=AS%RD=0%Q=)",
+            "T2(R=N)",
+            "T3(R=Y%DF=Y%T=3B-45%TG=40%W=8000%S=O%A=S+%F=AS%O=M400CST11NW5%RD=0%Q=)",
+            "T4(R=Y%DF=Y%T=3B-45%TG=40%W=0%S=A%A=Z%F=R%O=%RD=0%Q=)",
+            "T5(R=Y%DF=Y%T=3B-45%TG=40%W=0%S=Z%A=S+%F=AR%O=%RD=0%Q=)",
+            "T6(R=Y%DF=Y%T=3B-45%TG=40%W=0%S=A%A=Z%F=R%O=%RD=0%Q=)",
+            "T7(R=Y%DF=Y%T=3B-45%TG=40%W=0%S=Z%A=S+%F=AR%O=%RD=0%Q=)",
+            "U1(R=Y%DF=N%T=3B-45%TG=40%IPL=164%UN=0%RIPL=G%RID=G%RIPCK=G%RUCK=G%RUD=G)",
+            "IE(R=Y%DFI=N%T=3B-45%TG=40%CD=S)",
+            "",
+            "Fingerprint Microsoft Windows 10",
+            "Class Microsoft | Windows | 10 | general purpose",
+            "SEQ(SP=F5-FF%GCD=1-6%ISR=10A-114%TI=I%CI=I%II=I%SS=S%TS=A)",
+            "OPS(O1=M5B4NW8ST11%O2=M5B4NW8ST11%O3=M5B4NW8NNT11%O4=M5B4NW8ST11%O5=M5B4NW8ST11%O6=M5B4ST11)",
+            "WIN(W1=FFFF%W2=FFFF%W3=FFFF%W4=FFFF%W5=FFFF%W6=FFFF)",
+            "ECN(R=Y%DF=Y%T=7B-85%TG=80%W=FFFF%O=M5B4NW8NNS%CC=N%Q=)",
+            "T1(R=Y%DF=Y%T=7B-85%TG=80%S=O%A=S+%F=AS%RD=0%Q=)",
+            "T2(R=N)",
+            "T3(R=N)",
+            "T4(R=N)",
+            "T5(R=Y%DF=Y%T=7B-85%TG=80%W=0%S=Z%A=S+%F=AR%O=%RD=0%Q=)",
+            "T6(R=N)",
+            "T7(R=N)",
+            "U1(R=N)",
+            "IE(R=Y%DFI=N%T=7B-85%TG=80%CD=Z)",
+        ]
+        .iter()
+        .map(|l| l.to_string())
+        .collect();
+        let nmap_os_db = nmap_os_db_parser(lines).unwrap();
+        let fingerprint =
+            "OS:SCAN(V=5.05BETA1%D=8/23%OT=22%CT=1%CU=42341%PV=N%DS=0%DC=L%G=Y%TM=4A91C
+OS:B90%P=i686-pc-linux-gnu)SEQ(SP=C9%GCD=1%ISR=CF%TI=Z%CI=Z%II=I%TS=A)OPS(O1=M4
+OS:00CST11NW5%O2=M400CST11NW5%O3=M400CNNT11NW5%O4=M400CST11NW5%O5=M400CST11NW5%
+OS:O6=M400CST11)WIN(W1=8000%W2=8000%W3=8000%W4=8000%W5=8000%W6=8000)ECN(R=Y%DF=
+OS:Y%T=40%W=8018%O=M400CNNSNW5%CC=N%Q=)T1(R=Y%DF=Y%T=40%S=O%A=S+%F=AS%RD=0%Q=)T
+OS:2(R=N)T3(R=Y%DF=Y%T=40%W=8000%S=O%A=S+%F=AS%O=M400CST11NW5%RD=0%Q=)T4(R=Y%DF
+OS:=Y%T=40%W=0%S=A%A=Z%F=R%O=%RD=0%Q=)T5(R=Y%DF=Y%T=40%W=0%S=Z%A=S+%F=AR%O=%RD=
+OS:0%Q=)T6(R=Y%DF=Y%T=40%W=0%S=A%A=Z%F=R%O=%RD=0%Q=)T7(R=Y%DF=Y%T=40%W=0%S=Z%A=
+OS:S+%F=AR%O=%RD=0%Q=)U1(R=Y%DF=N%T=40%IPL=164%UN=0%RIPL=G%RID=G%RIPCK=G%RUCK=G
+OS:%RUD=G)IE(R=Y%DFI=N%T=40%CD=S)";
+        let ret = os_match_fingerprint(fingerprint, Some(nmap_os_db), 3, None).unwrap();
+        // the windows one is only 50%
+        assert_eq!(ret.detects.len(), 1);
+        assert_eq!(ret.detects[0].db.fingerprint, "Fingerprint Linux 2.6.32");
+        assert_eq!(ret.detects[0].score, ret.detects[0].total);
+        // SP=C9%TI=Z
+        let seq_prediction = ret.seq_prediction.unwrap();
+        assert_eq!(seq_prediction.difficulty, 0xC9);
+        assert_eq!(seq_prediction.rating, "Good luck!");
+        assert_eq!(seq_prediction.ip_id_class, "All zeros");
+        assert!(ret.uptime.is_none());
+    }
+    #[test]
+    fn test_os_match_absent_attributes() {
+        // the SS and TS are not in the db line, they are not scored
+        let lines: Vec<String> = vec![
+            "Fingerprint Linux 2.6.32",
+            "Class Linux | Linux | 2.6.X | general purpose",
+            "SEQ(SP=C0-D2%GCD=1-6%ISR=C8-D8%TI=Z%CI=Z%II=I)",
+            "OPS(O1=M400CST11NW5%O2=M400CST11NW5%O3=M400CNNT11NW5%O4=M400CST11NW5%O5=M400CST11NW5%O6=M400CST11)",
+            "WIN(W1=8000%W2=8000%W3=8000%W4=8000%W5=8000%W6=8000)",
+            "ECN(R=Y%DF=Y%T=3B-45%TG=40%W=8018%O=M400CNNSNW5%CC=N%Q=)",
+            "T1(R=Y%DF=Y%T=3B-45%TG=40%S=O%A=S+%F=AS%RD=0%Q=)",
+            "T2(R=N)",
+            "T3(R=Y%DF=Y%T=3B-45%TG=40%W=8000%S=O%A=S+%F=AS%O=M400CST11NW5%RD=0%Q=)",
+            "T4(R=Y%DF=Y%T=3B-45%TG=40%W=0%S=A%A=Z%F=R%O=%RD=0%Q=)",
+            "T5(R=Y%DF=Y%T=3B-45%TG=40%W=0%S=Z%A=S+%F=AR%O=%RD=0%Q=)",
+            "T6(R=Y%DF=Y%T=3B-45%TG=40%W=0%S=A%A=Z%F=R%O=%RD=0%Q=)",
+            "T7(R=Y%DF=Y%T=3B-45%TG=40%W=0%S=Z%A=S+%F=AR%O=%RD=0%Q=)",
+            "U1(R=Y%DF=N%T=3B-45%TG=40%IPL=164%UN=0%RIPL=G%RID=G%RIPCK=G%RUCK=G%RUD=G)",
+            "IE(R=Y%DFI=N%T=3B-45%TG=40%CD=S)",
+        ]
+        .iter()
+        .map(|l| l.to_string())
+        .collect();
+        let nmap_os_db = nmap_os_db_parser(lines).unwrap();
+        let fingerprint =
+            "OS:SCAN(V=5.05BETA1%D=8/23%OT=22%CT=1%CU=42341%PV=N%DS=0%DC=L%G=Y%TM=4A91C
+OS:B90%P=i686-pc-linux-gnu)SEQ(SP=C9%GCD=1%ISR=CF%TI=Z%CI=Z%II=I%SS=S%TS=A)OPS(
+OS:O1=M400CST11NW5%O2=M400CST11NW5%O3=M400CNNT11NW5%O4=M400CST11NW5%O5=M400CST1
+OS:1NW5%O6=M400CST11)WIN(W1=8000%W2=8000%W3=8000%W4=8000%W5=8000%W6=8000)ECN(R=
+OS:Y%DF=Y%T=40%W=8018%O=M400CNNSNW5%CC=N%Q=)T1(R=Y%DF=Y%T=40%S=O%A=S+%F=AS%RD=0
+OS:%Q=)T2(R=N)T3(R=Y%DF=Y%T=40%W=8000%S=O%A=S+%F=AS%O=M400CST11NW5%RD=0%Q=)T4(R
+OS:=Y%DF=Y%T=40%W=0%S=A%A=Z%F=R%O=%RD=0%Q=)T5(R=Y%DF=Y%T=40%W=0%S=Z%A=S+%F=AR%O
+OS:=%RD=0%Q=)T6(R=Y%DF=Y%T=40%W=0%S=A%A=Z%F=R%O=%RD=0%Q=)T7(R=Y%DF=Y%T=40%W=0%S
+OS:=Z%A=S+%F=AR%O=%RD=0%Q=)U1(R=Y%DF=N%T=40%IPL=164%UN=0%RIPL=G%RID=G%RIPCK=G%R
+OS:UCK=G%RUD=G)IE(R=Y%DFI=N%T=40%CD=S)";
+        let ret = os_match_fingerprint(fingerprint, Some(nmap_os_db), 1, None).unwrap();
+        assert_eq!(ret.detects.len(), 1);
+        assert_eq!(ret.detects[0].score, ret.detects[0].total);
+    }
+    #[test]
+    fn test_select_os_detect_ports() {
+        let mut port_status = PortStatus::new();
+        port_status.status.insert(22, TargetScanStatus::Closed);
+        port_status.status.insert(80, TargetScanStatus::Open);
+        port_status.status.insert(443, TargetScanStatus::Open);
+        port_status.status.insert(8080, TargetScanStatus::Filtered);
+        let (o, c, _) = select_os_detect_ports(&[443, 22, 80, 8080], &port_status);
+        assert_eq!(o, Some(443));
+        assert_eq!(c, 22);
+
+        let mut port_status = PortStatus::new();
+        port_status.status.insert(22, TargetScanStatus::Filtered);
+        let (o, c, u) = select_os_detect_ports(&[22], &port_status);
+        assert_eq!(o, None);
+        assert!(c >= 1024 && u >= 1024);
+    }
+    #[test]
+    fn test_os_class_guess() {
+        let fingerprint = |name: &str, classes: &[&str]| -> Vec<String> {
+            let mut lines = vec![format!("Fingerprint {}", name)];
+            for c in classes {
+                lines.push(format!("Class {}", c));
+            }
+            let tests = [
+                "SEQ(SP=1)",
+                "OPS(O1=M5B4)",
+                "WIN(W1=1)",
+                "ECN(R=Y)",
+                "T1(R=Y)",
+                "T2(R=N)",
+                "T3(R=N)",
+                "T4(R=Y)",
+                "T5(R=Y)",
+                "T6(R=Y)",
+                "T7(R=Y)",
+                "U1(R=N)",
+                "IE(R=N)",
+                "",
+            ];
+            lines.extend(tests.iter().map(|t| t.to_string()));
+            lines
+        };
+        let mut lines = fingerprint("Linux 4.15", &["Linux | Linux | 4.X | general purpose"]);
+        lines.extend(fingerprint(
+            "Linux 5.0 - 5.6",
+            &[
+                "Linux | Linux | 5.X | general purpose",
+                "Linux | Linux | | WAP",
+            ],
+        ));
+        let (dbs, _) = nmap_os_db_parser(lines).unwrap();
+        assert_eq!(dbs[1].classes[1].generation, "");
+        let accuracy = [0.96, 0.9];
+        let detects: Vec<NmapOsDetectRet> = dbs
+            .into_iter()
+            .zip(accuracy)
+            .map(|(db, accuracy)| NmapOsDetectRet {
+                score: 0,
+                total: 0,
+                accuracy,
+                db,
+            })
+            .collect();
+        let ret = os_class_guess(&detects);
+        assert_eq!(ret.len(), 2);
+        assert_eq!(ret[0].to_string(), "Linux 4.X|5.X, general purpose, 96%");
+        assert_eq!(ret[1].to_string(), "Linux, WAP, 90%");
+    }
 }
@@ -0,0 +1,587 @@
+/* Combine the os hints of the different sources into one verdict */
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::os::NmapOsDetectRet6;
+use crate::os::OsClassGuess;
+use crate::os::OsStatus;
+use crate::os::OsStatus6;
+use crate::scan::ArpAliveHosts;
+use crate::vs::dbparser::ProbesProtocol;
+use crate::vs::ServiceResult;
+
+/// cpe:/o:<vendor>:<product> => the vendor and family used by nmap-os-db,
+/// the product is matched by prefix (windows_10, windows_server_2019...).
+const CPE_OS_CLASSES: [(&str, &str, &str, &str); 11] = [
+    ("linux", "linux_kernel", "Linux", "Linux"),
+    ("microsoft", "windows", "Microsoft", "Windows"),
+    ("apple", "mac_os_x", "Apple", "Mac OS X"),
+    ("apple", "macos", "Apple", "Mac OS X"),
+    ("apple", "iphone_os", "Apple", "iOS"),
+    ("google", "android", "Google", "Android"),
+    ("freebsd", "freebsd", "FreeBSD", "FreeBSD"),
+    ("openbsd", "openbsd", "OpenBSD", "OpenBSD"),
+    ("netbsd", "netbsd", "NetBSD", "NetBSD"),
+    ("oracle", "solaris", "Oracle", "Solaris"),
+    ("cisco", "ios", "Cisco", "IOS"),
+];
+
+/// o/operatingsystem/ of the service match => the vendor and family, matched by keyword.
+const SERVICE_OS_CLASSES: [(&str, &str, &str); 10] = [
+    ("windows", "Microsoft", "Windows"),
+    ("android", "Google", "Android"),
+    ("linux", "Linux", "Linux"),
+    ("mac os x", "Apple", "Mac OS X"),
+    ("macos", "Apple", "Mac OS X"),
+    ("freebsd", "FreeBSD", "FreeBSD"),
+    ("openbsd", "OpenBSD", "OpenBSD"),
+    ("netbsd", "NetBSD", "NetBSD"),
+    ("solaris", "Oracle", "Solaris"),
+    ("sunos", "Oracle", "Solaris"),
+];
+
+/// The mac address vendor => the os vendor and family.
+/// Most of the vendors (VMware, Intel...) say nothing about the os, and Apple makes more than one os.
+const MAC_OS_VENDORS: [(&str, &str, Option<&str>); 2] = [
+    ("apple", "Apple", None),
+    ("raspberry pi", "Linux", Some("Linux")),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OsEvidenceSource {
+    /// `os_detect`
+    Ipv4,
+    /// `os_detect6`
+    Ipv6,
+    /// The `o/` and `cpe:/o:` fields of `vs_scan`
+    Service,
+    /// The mac address vendor of `arp_scan`
+    Mac,
+}
+
+impl fmt::Display for OsEvidenceSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OsEvidenceSource::Ipv4 => write!(f, "ipv4"),
+            OsEvidenceSource::Ipv6 => write!(f, "ipv6"),
+            OsEvidenceSource::Service => write!(f, "service"),
+            OsEvidenceSource::Mac => write!(f, "mac"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OsEvidence {
+    pub source: OsEvidenceSource,
+    pub vendor: String,
+    /// `None` if only the vendor is known, it supports every family of the vendor.
+    pub family: Option<String>,
+    /// From 0.0 to 1.0.
+    pub confidence: f64,
+    /// What the evidence is, such as `22/tcp ssh cpe:/o:linux:linux_kernel`.
+    pub detail: String,
+}
+
+impl fmt::Display for OsEvidence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {}, {}%",
+            self.source,
+            self.detail,
+            (self.confidence * 100.0).floor()
+        )
+    }
+}
+
+impl OsEvidence {
+    fn new(
+        source: OsEvidenceSource,
+        vendor: &str,
+        family: Option<&str>,
+        confidence: f64,
+        detail: &str,
+    ) -> OsEvidence {
+        OsEvidence {
+            source,
+            vendor: vendor.to_string(),
+            family: family.map(|f| f.to_string()),
+            confidence,
+            detail: detail.to_string(),
+        }
+    }
+    fn supports(&self, vendor: &str, family: Option<&str>) -> bool {
+        if !self.vendor.eq_ignore_ascii_case(vendor) {
+            return false;
+        }
+        match (&self.family, family) {
+            (None, _) => true,
+            (Some(a), Some(b)) => a.eq_ignore_ascii_case(b),
+            (Some(_), None) => false,
+        }
+    }
+}
+
+/// The weight of each source, the evidence of a source is multiplied by it.
+#[derive(Debug, Clone, Copy)]
+pub struct ConsensusWeights {
+    pub ipv4: f64,
+    /// The ipv6 model knows much fewer systems than nmap-os-db.
+    pub ipv6: f64,
+    pub service: f64,
+    /// The mac address vendor is only a weak hint.
+    pub mac: f64,
+}
+
+impl ConsensusWeights {
+    pub fn new() -> ConsensusWeights {
+        ConsensusWeights {
+            ipv4: 1.0,
+            ipv6: 0.7,
+            service: 0.8,
+            mac: 0.3,
+        }
+    }
+    fn weight(&self, source: OsEvidenceSource) -> f64 {
+        match source {
+            OsEvidenceSource::Ipv4 => self.ipv4,
+            OsEvidenceSource::Ipv6 => self.ipv6,
+            OsEvidenceSource::Service => self.service,
+            OsEvidenceSource::Mac => self.mac,
+        }
+    }
+}
+
+/// One `cpe:/o:` name => vendor and family.
+fn cpe_os_class(cpe: &str) -> Option<(String, String)> {
+    let items: Vec<&str> = cpe.trim_start_matches("cpe:/").split(':').collect();
+    if items.len() < 3 || items[0] != "o" {
+        return None;
+    }
+    let (vendor, product) = (items[1], items[2]);
+    for (v, p, nmap_vendor, nmap_family) in CPE_OS_CLASSES {
+        if vendor == v && product.starts_with(p) {
+            return Some((nmap_vendor.to_string(), nmap_family.to_string()));
+        }
+    }
+    // not in the table, the names of the cpe are used as they are
+    Some((vendor.to_string(), product.replace('_', " ")))
+}
+
+/// One `o/` value => vendor and family.
+fn service_os_class(ostype: &str) -> Option<(String, String)> {
+    // nmap uses the case to tell them apart
+    match ostype {
+        "iOS" => return Some((String::from("Apple"), String::from("iOS"))),
+        "IOS" => return Some((String::from("Cisco"), String::from("IOS"))),
+        _ => (),
+    }
+    let ostype = ostype.to_lowercase();
+    for (keyword, vendor, family) in SERVICE_OS_CLASSES {
+        if ostype.contains(keyword) {
+            return Some((vendor.to_string(), family.to_string()));
+        }
+    }
+    None
+}
+
+/// The os classes of `os_detect`, the confidence is the accuracy.
+pub fn ipv4_evidence(classes: &[OsClassGuess]) -> Vec<OsEvidence> {
+    let mut ret = Vec::new();
+    for c in classes {
+        // same as the Class line of nmap-os-db
+        let detail = format!(
+            "{} | {} | {} | {}",
+            c.vendor,
+            c.family,
+            c.generations.join("|"),
+            c.device_type
+        );
+        ret.push(OsEvidence::new(
+            OsEvidenceSource::Ipv4,
+            &c.vendor,
+            Some(&c.family),
+            c.accuracy,
+            &detail,
+        ));
+    }
+    ret
+}
+
+/// The os classes of `os_detect6`, the confidence is the score of the model.
+pub fn ipv6_evidence(detects: &[NmapOsDetectRet6]) -> Vec<OsEvidence> {
+    let mut ret = Vec::new();
+    for d in detects {
+        for c in &d.osclass {
+            // [vendor, family, generation, device type]
+            if c.len() < 2 {
+                continue;
+            }
+            ret.push(OsEvidence::new(
+                OsEvidenceSource::Ipv6,
+                &c[0],
+                Some(&c[1]),
+                d.score,
+                &d.name,
+            ));
+        }
+    }
+    ret
+}
+
+/// The `cpe:/o:` names of the service matches, or the `o/` field if there is no `cpe:/o:` name.
+pub fn service_evidence(
+    services: &HashMap<(u16, ProbesProtocol), ServiceResult>,
+) -> Vec<OsEvidence> {
+    let mut keys: Vec<&(u16, ProbesProtocol)> = services.keys().collect();
+    keys.sort_by_key(|(port, protocol)| (*port, *protocol == ProbesProtocol::Udp));
+    let mut ret = Vec::new();
+    for key in keys {
+        let (port, protocol) = key;
+        let sr = &services[key];
+        let protocol = match protocol {
+            ProbesProtocol::Tcp => "tcp",
+            ProbesProtocol::Udp => "udp",
+        };
+        let service = match &sr.service {
+            Some(s) => s.to_string(),
+            None => String::from("unknown"),
+        };
+        let mut os_classes = Vec::new();
+        for cpe in &sr.version.cpe {
+            match cpe_os_class(cpe) {
+                Some((vendor, family)) => os_classes.push((vendor, family, cpe.to_string())),
+                None => (),
+            }
+        }
+        if os_classes.len() == 0 {
+            match &sr.version.ostype {
+                Some(o) => match service_os_class(o) {
+                    Some((vendor, family)) => {
+                        os_classes.push((vendor, family, format!("o/{}/", o)))
+                    }
+                    None => (),
+                },
+                None => (),
+            }
+        }
+        for (vendor, family, from) in os_classes {
+            let detail = format!("{port}/{protocol} {service} {from}");
+            ret.push(OsEvidence::new(
+                OsEvidenceSource::Service,
+                &vendor,
+                Some(&family),
+                1.0,
+                &detail,
+            ));
+        }
+    }
+    ret
+}
+
+/// The mac address vendor of `arp_scan`, only a few vendors tell something about the os.
+pub fn mac_evidence(host: &ArpAliveHosts) -> Vec<OsEvidence> {
+    let ouis = host.ouis.to_lowercase();
+    let mut ret = Vec::new();
+    for (keyword, vendor, family) in MAC_OS_VENDORS {
+        if ouis.contains(keyword) {
+            let detail = format!("{} {}", host.mac_addr, host.ouis);
+            ret.push(OsEvidence::new(
+                OsEvidenceSource::Mac,
+                vendor,
+                family,
+                1.0,
+                &detail,
+            ));
+        }
+    }
+    ret
+}
+
+#[derive(Debug, Clone)]
+pub struct OsVerdict {
+    pub vendor: String,
+    /// `None` if there is only the vendor evidence.
+    pub family: Option<String>,
+    /// The sum of the best evidence of each source multiplied by the source weight.
+    pub score: f64,
+    /// The score divided by the weights of all the sources which have evidence.
+    pub confidence: f64,
+    /// The evidence which supports this verdict.
+    pub evidence: Vec<OsEvidence>,
+}
+
+impl fmt::Display for OsVerdict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // the same as `OsClassGuess`
+        let name = match &self.family {
+            Some(family) if *family != self.vendor => format!("{} {}", self.vendor, family),
+            _ => self.vendor.clone(),
+        };
+        write!(f, "{}, {}%", name, (self.confidence * 100.0).floor())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OsConsensus {
+    /// The best verdict first.
+    pub verdicts: Vec<OsVerdict>,
+    /// The best guesses of `os_detect` and `os_detect6` are not the same family.
+    pub dual_stack_conflict: bool,
+}
+
+impl OsConsensus {
+    /// Weigh all the evidence of one host, every source votes once for each os family with its best evidence.
+    pub fn new(evidence: Vec<OsEvidence>, weights: &ConsensusWeights) -> OsConsensus {
+        // the candidates are the families, or the vendors if no family is known
+        let mut candidates: Vec<(String, Option<String>)> = Vec::new();
+        for e in &evidence {
+            if e.family.is_some() && !candidates.iter().any(|(v, f)| e.supports(v, f.as_deref())) {
+                candidates.push((e.vendor.clone(), e.family.clone()));
+            }
+        }
+        if candidates.len() == 0 {
+            for e in &evidence {
+                if !candidates.iter().any(|(v, _)| e.supports(v, None)) {
+                    candidates.push((e.vendor.clone(), None));
+                }
+            }
+        }
+
+        let mut sources: Vec<OsEvidenceSource> = Vec::new();
+        for e in &evidence {
+            if !sources.contains(&e.source) {
+                sources.push(e.source);
+            }
+        }
+        let total_weight: f64 = sources.iter().map(|s| weights.weight(*s)).sum();
+
+        let mut verdicts = Vec::new();
+        for (vendor, family) in candidates {
+            let supports: Vec<OsEvidence> = evidence
+                .iter()
+                .filter(|e| e.supports(&vendor, family.as_deref()))
+                .cloned()
+                .collect();
+            let mut score = 0.0;
+            for s in &sources {
+                let best = supports
+                    .iter()
+                    .filter(|e| e.source == *s)
+                    .map(|e| e.confidence)
+                    .fold(0.0, f64::max);
+                score += best * weights.weight(*s);
+            }
+            let confidence = if total_weight > 0.0 {
+                score / total_weight
+            } else {
+                0.0
+            };
+            verdicts.push(OsVerdict {
+                vendor,
+                family,
+                score,
+                confidence,
+                evidence: supports,
+            });
+        }
+        verdicts.sort_by(|a, b| b.score.total_cmp(&a.score));
+
+        let best_family = |source: OsEvidenceSource| -> Option<&OsEvidence> {
+            evidence
+                .iter()
+                .filter(|e| e.source == source && e.family.is_some())
+                .max_by(|a, b| a.confidence.total_cmp(&b.confidence))
+        };
+        let dual_stack_conflict = match (
+            best_family(OsEvidenceSource::Ipv4),
+            best_family(OsEvidenceSource::Ipv6),
+        ) {
+            (Some(a), Some(b)) => !a.supports(&b.vendor, b.family.as_deref()),
+            _ => false,
+        };
+        OsConsensus {
+            verdicts,
+            dual_stack_conflict,
+        }
+    }
+    pub fn best(&self) -> Option<&OsVerdict> {
+        self.verdicts.first()
+    }
+}
+
+impl fmt::Display for OsConsensus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut output = String::new();
+        match self.best() {
+            Some(v) => {
+                output += &format!(">>> OS:\n{}\n", v);
+                output += ">>> Evidence:\n";
+                for e in &v.evidence {
+                    output += &format!("{}\n", e);
+                }
+            }
+            None => output += ">>> OS:\nNo OS evidence\n",
+        }
+        if self.dual_stack_conflict {
+            output += ">>> Warning:\nThe ipv4 and ipv6 guesses disagree\n";
+        }
+        if self.verdicts.len() > 1 {
+            output += ">>> Others:\n";
+            for v in &self.verdicts[1..] {
+                output += &format!("{}\n", v);
+            }
+        }
+        write!(f, "{}", output.trim_end())
+    }
+}
+
+/// Combine the results of one host from the different sources into one os verdict,
+/// the sources which are not scanned are `None`.
+pub fn os_consensus(
+    ipv4: Option<&OsStatus>,
+    ipv6: Option<&OsStatus6>,
+    services: Option<&HashMap<(u16, ProbesProtocol), ServiceResult>>,
+    mac: Option<&ArpAliveHosts>,
+    weights: Option<ConsensusWeights>,
+) -> OsConsensus {
+    let weights = match weights {
+        Some(w) => w,
+        None => ConsensusWeights::new(),
+    };
+    let mut evidence = Vec::new();
+    match ipv4 {
+        Some(oss) => evidence.extend(ipv4_evidence(&oss.classes)),
+        None => (),
+    }
+    match ipv6 {
+        Some(oss) => evidence.extend(ipv6_evidence(&oss.detects)),
+        None => (),
+    }
+    match services {
+        Some(s) => evidence.extend(service_evidence(s)),
+        None => (),
+    }
+    match mac {
+        Some(m) => evidence.extend(mac_evidence(m)),
+        None => (),
+    }
+    OsConsensus::new(evidence, &weights)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vs::dbparser::ServiceVersion;
+    use pnet::datalink::MacAddr;
+    fn service(service: &str, ostype: Option<&str>, cpe: Vec<&str>) -> ServiceResult {
+        let mut version = ServiceVersion::default();
+        version.ostype = ostype.map(|o| o.to_string());
+        version.cpe = cpe.iter().map(|c| c.to_string()).collect();
+        ServiceResult {
+            service: Some(service.to_string()),
+            version,
+            kind: None,
+            probename: None,
+            rtt: None,
+            matched: None,
+            fingerprint: None,
+        }
+    }
+    #[test]
+    fn test_os_class_names() {
+        let c = cpe_os_class("cpe:/o:microsoft:windows_server_2019").unwrap();
+        assert_eq!(c, (String::from("Microsoft"), String::from("Windows")));
+        let c = cpe_os_class("cpe:/o:vmware:esxi:6.7").unwrap();
+        assert_eq!(c, (String::from("vmware"), String::from("esxi")));
+        assert!(cpe_os_class("cpe:/a:openbsd:openssh:8.9p1").is_none());
+        let c = service_os_class("Linux 2.6").unwrap();
+        assert_eq!(c, (String::from("Linux"), String::from("Linux")));
+        assert_eq!(service_os_class("IOS").unwrap().0, "Cisco");
+        assert!(service_os_class("Unix").is_none());
+    }
+    #[test]
+    fn test_os_consensus() {
+        let classes = vec![
+            OsClassGuess {
+                vendor: String::from("Linux"),
+                family: String::from("Linux"),
+                generations: vec![String::from("5.X")],
+                device_type: String::from("general purpose"),
+                accuracy: 0.9,
+            },
+            OsClassGuess {
+                vendor: String::from("Microsoft"),
+                family: String::from("Windows"),
+                generations: vec![String::from("10")],
+                device_type: String::from("general purpose"),
+                accuracy: 0.86,
+            },
+        ];
+        let detects = vec![NmapOsDetectRet6 {
+            name: String::from("Microsoft Windows 10"),
+            osclass: vec![vec![
+                String::from("Microsoft"),
+                String::from("Windows"),
+                String::from("10"),
+                String::from("general purpose"),
+            ]],
+            cpe: vec![String::from("cpe:/o:microsoft:windows_10")],
+            score: 0.8,
+            label: 0,
+        }];
+        let mut services = HashMap::new();
+        services.insert(
+            (22, ProbesProtocol::Tcp),
+            service(
+                "ssh",
+                Some("Linux"),
+                vec!["cpe:/a:openbsd:openssh:8.9p1", "cpe:/o:linux:linux_kernel"],
+            ),
+        );
+        services.insert(
+            (80, ProbesProtocol::Tcp),
+            service("http", Some("Linux"), vec![]),
+        );
+        let mac = ArpAliveHosts {
+            mac_addr: MacAddr::new(0xdc, 0xa6, 0x32, 0x01, 0x02, 0x03),
+            ouis: String::from("Raspberry Pi Trading"),
+            rtt: None,
+            gratuitous: false,
+        };
+
+        let mut evidence = ipv4_evidence(&classes);
+        evidence.extend(ipv6_evidence(&detects));
+        let service_evidence = service_evidence(&services);
+        // the cpe wins over the o/ field, one for each service
+        assert_eq!(service_evidence.len(), 2);
+        evidence.extend(service_evidence);
+        evidence.extend(mac_evidence(&mac));
+
+        let weights = ConsensusWeights::new();
+        let ret = OsConsensus::new(evidence, &weights);
+        println!("{}", ret);
+        assert!(ret.dual_stack_conflict);
+        let best = ret.best().unwrap();
+        assert_eq!(best.vendor, "Linux");
+        // 0.9 * 1.0 + 1.0 * 0.8 + 1.0 * 0.3
+        assert!((best.score - 2.0).abs() < 1e-9);
+        assert!((best.confidence - 2.0 / 2.8).abs() < 1e-9);
+        assert_eq!(best.evidence.len(), 4);
+        assert_eq!(ret.verdicts[1].family.as_deref(), Some("Windows"));
+
+        // only the vendor is known
+        let mac = ArpAliveHosts {
+            mac_addr: MacAddr::new(0xf0, 0x18, 0x98, 0x01, 0x02, 0x03),
+            ouis: String::from("Apple"),
+            rtt: None,
+            gratuitous: false,
+        };
+        let ret = OsConsensus::new(mac_evidence(&mac), &weights);
+        let best = ret.best().unwrap();
+        assert_eq!(best.vendor, "Apple");
+        assert_eq!(best.family, None);
+        assert!(!ret.dual_stack_conflict);
+        assert!(OsConsensus::new(vec![], &weights).best().is_none());
+    }
+}
@@ -2,6 +2,8 @@ use anyhow::Result;
 use serde::Deserialize;
 use serde::Serialize;
 use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
 
 use crate::utils::Hex;
 
@@ -14,6 +16,28 @@ use super::osscan::TXX;
 use super::osscan::U1X;
 use super::osscan::WINX;
 
+/* MatchPointsParseError */
+#[derive(Debug, Clone)]
+struct MatchPointsParseError {
+    line: String,
+}
+
+impl fmt::Display for MatchPointsParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "can not parse the MatchPoints line: {}", self.line)
+    }
+}
+
+impl MatchPointsParseError {
+    pub fn new(line: &str) -> MatchPointsParseError {
+        MatchPointsParseError {
+            line: line.to_string(),
+        }
+    }
+}
+
+impl Error for MatchPointsParseError {}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum DbRangeValueTypes {
     Left,  // 10 <= x
@@ -69,6 +93,15 @@ impl DbEmptyValue {
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DbAbsentValue {}
+
+impl DbAbsentValue {
+    pub fn new() -> DbAbsentValue {
+        DbAbsentValue {}
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DbMixValue {
     pub range_values: Vec<DbRangeValue>,
@@ -88,13 +121,42 @@ impl DbMixValue {
 pub enum NmapOsDbValueTypes {
     DbMixStringValue(DbStringValue),
     DbEmptyValue(DbEmptyValue),
-    DbMixValue(DbMixValue), // u32, example: GCD=1-6|>5000
+    DbMixValue(DbMixValue),       // u32, example: GCD=1-6|>5000
+    DbAbsentValue(DbAbsentValue), // the attribute is not in the line
 }
 
 impl NmapOsDbValueTypes {
     pub fn empty() -> NmapOsDbValueTypes {
         NmapOsDbValueTypes::DbEmptyValue(DbEmptyValue::new())
     }
+    pub fn absent() -> NmapOsDbValueTypes {
+        NmapOsDbValueTypes::DbAbsentValue(DbAbsentValue::new())
+    }
+    /// Same as nmap, the attribute which is absent from the db line is not scored (`None`).
+    pub fn compare_usize(&self, input: usize) -> Option<bool> {
+        match self {
+            NmapOsDbValueTypes::DbAbsentValue(_) => None,
+            _ => Some(self.check_usize(input)),
+        }
+    }
+    /// The empty string in the fingerprint means the attribute is absent from it,
+    /// so it is not scored either.
+    pub fn compare_string(&self, input: &str) -> Option<bool> {
+        match self {
+            NmapOsDbValueTypes::DbAbsentValue(_) => None,
+            _ => match input.len() {
+                0 => None,
+                _ => Some(self.check_string(input)),
+            },
+        }
+    }
+    /// For the O and Q, the empty string is a valid value (no options or no quirks).
+    pub fn compare_string_allow_empty(&self, input: &str) -> Option<bool> {
+        match self {
+            NmapOsDbValueTypes::DbAbsentValue(_) => None,
+            _ => Some(self.check_string(input)),
+        }
+    }
     pub fn check_usize(&self, input: usize) -> bool {
         match self {
             NmapOsDbValueTypes::DbEmptyValue(_) => match input {
@@ -134,8 +196,9 @@ impl NmapOsDbValueTypes {
     }
     pub fn check_string(&self, input: &str) -> bool {
         match self {
+            // the empty input only matches the empty alternative, such as `O=|M5B4`
             NmapOsDbValueTypes::DbMixStringValue(v) => match input.len() {
-                0 => false,
+                0 => v.value.iter().any(|s| s.len() == 0),
                 _ => {
                     let mut ret = false;
                     for s in &v.value {
@@ -176,15 +239,81 @@ impl NmapOsDbValueTypes {
     }
 }
 
-fn bool_score(bool_vec: Vec<bool>) -> usize {
+/// The `MatchPoints` section of nmap-os-db, how many points each attribute of the test is worth.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct MatchPoints {
+    /// test name (SEQ, T1...) => attribute name (SP, GCD...) => points
+    pub points: HashMap<String, HashMap<String, usize>>,
+}
+
+impl MatchPoints {
+    pub fn new() -> MatchPoints {
+        MatchPoints {
+            points: HashMap::new(),
+        }
+    }
+    /// Without the `MatchPoints` section every attribute is worth 1 point,
+    /// otherwise the attribute which is not in the section is not counted.
+    pub fn get(&self, test: &str, attr: &str) -> usize {
+        if self.points.len() == 0 {
+            return 1;
+        }
+        match self.points.get(test) {
+            Some(p) => match p.get(attr) {
+                Some(p) => *p,
+                None => 0,
+            },
+            None => 0,
+        }
+    }
+    /// The line looks like `SEQ(SP=25%GCD=75%ISR=25)`, the points are decimal.
+    pub fn parser(&mut self, line: &str) -> Result<()> {
+        let (test, values) = match line.split_once("(") {
+            Some(s) => s,
+            None => return Err(MatchPointsParseError::new(line).into()),
+        };
+        let values = match values.split_once(")") {
+            Some((v, _)) => v,
+            None => return Err(MatchPointsParseError::new(line).into()),
+        };
+        let mut map = HashMap::new();
+        for info in values.split("%") {
+            let (attr, points) = match info.split_once("=") {
+                Some(s) => s,
+                None => return Err(MatchPointsParseError::new(line).into()),
+            };
+            let points: usize = match points.parse() {
+                Ok(p) => p,
+                Err(_) => return Err(MatchPointsParseError::new(line).into()),
+            };
+            map.insert(attr.to_string(), points);
+        }
+        self.points.insert(test.to_string(), map);
+        Ok(())
+    }
+}
+
+/// Returns the points of the matched attributes and the points of all the attributes.
+fn points_score(
+    test: &str,
+    checks: Vec<(&str, Option<bool>)>,
+    match_points: &MatchPoints,
+) -> (usize, usize) {
     let mut score = 0;
-    for i in bool_vec {
-        match i {
-            true => score += 1,
-            _ => (),
+    let mut total = 0;
+    for (attr, check) in checks {
+        // the attribute is absent from the db line or the fingerprint
+        let check = match check {
+            Some(c) => c,
+            None => continue,
+        };
+        let points = match_points.get(test, attr);
+        total += points;
+        if check {
+            score += points;
         }
     }
-    score
+    (score, total)
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -201,73 +330,70 @@ pub struct SEQDB {
 }
 
 impl SEQDB {
-    pub fn check(&self, seqx: &SEQX) -> (usize, usize) {
+    pub fn check(&self, seqx: &SEQX, match_points: &MatchPoints) -> (usize, usize) {
+        // the test is not performed
+        if seqx.r.is_empty() {
+            return (0, 0);
+        }
         let r_check = self.r.check_r(&seqx.r);
-        if r_check {
-            match seqx.r.as_str() {
-                "N" => (),
-                _ => {
-                    let sp_check = self.sp.check_usize(seqx.sp as usize);
-                    let gcd_check = self.gcd.check_usize(seqx.gcd as usize);
-                    let isr_check = self.isr.check_usize(seqx.isr as usize);
-                    let ti_check = self.ti.check_string(&seqx.ti);
-                    let ci_check = self.ci.check_string(&seqx.ci);
-                    let ii_check = self.ii.check_string(&seqx.ii);
-                    let ss_check = self.ss.check_string(&seqx.ss);
-                    let ts_check = self.ts.check_string(&seqx.ts);
-
-                    // println!(">>> {}", sp_check);
-                    // println!(">>> {}", gcd_check);
-                    // println!(">>> {}", isr_check);
-                    // println!(">>> {}", ti_check);
-                    // println!(">>> {}", ci_check);
-                    // println!(">>> {}", ii_check);
-                    // println!(">>> {}", ss_check);
-                    // println!(">>> {}", ts_check);
-
-                    let bool_vec = vec![
-                        sp_check, gcd_check, isr_check, ti_check, ci_check, ii_check, ss_check,
-                        ts_check,
-                    ];
-
-                    return (bool_score(bool_vec), 8);
-                }
-            }
+        if !r_check || seqx.r == "N" {
+            // only the R is compared if there is no response
+            return points_score("SEQ", vec![("R", Some(r_check))], match_points);
         }
-        (0, 8)
+        let sp_check = self.sp.compare_usize(seqx.sp as usize);
+        let gcd_check = self.gcd.compare_usize(seqx.gcd as usize);
+        let isr_check = self.isr.compare_usize(seqx.isr as usize);
+        let ti_check = self.ti.compare_string(&seqx.ti);
+        let ci_check = self.ci.compare_string(&seqx.ci);
+        let ii_check = self.ii.compare_string(&seqx.ii);
+        let ss_check = self.ss.compare_string(&seqx.ss);
+        let ts_check = self.ts.compare_string(&seqx.ts);
+
+        let checks = vec![
+            ("R", Some(r_check)),
+            ("SP", sp_check),
+            ("GCD", gcd_check),
+            ("ISR", isr_check),
+            ("TI", ti_check),
+            ("CI", ci_check),
+            ("II", ii_check),
+            ("SS", ss_check),
+            ("TS", ts_check),
+        ];
+        points_score("SEQ", checks, match_points)
     }
     pub fn new(map: HashMap<&str, NmapOsDbValueTypes>) -> SEQDB {
         let sp = match map.get("sp") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let gcd = match map.get("gcd") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let isr = match map.get("isr") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let ti = match map.get("ti") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let ci = match map.get("ci") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let ii = match map.get("ii") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let ss = match map.get("ss") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let ts = match map.get("ts") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let r = match map.get("r") {
             Some(v) => v.clone(),
@@ -341,49 +467,49 @@ pub struct OPSDB {
 }
 
 impl OPSDB {
-    pub fn check(&self, opsx: &OPSX) -> (usize, usize) {
+    pub fn check(&self, opsx: &OPSX, match_points: &MatchPoints) -> (usize, usize) {
+        if opsx.r.is_empty() {
+            return (0, 0);
+        }
         let r_check = self.r.check_r(&opsx.r);
-        if r_check {
-            match opsx.r.as_str() {
-                "N" => (),
-                _ => {
-                    let o1_check = self.o1.check_string(&opsx.o1);
-                    let o2_check = self.o2.check_string(&opsx.o2);
-                    let o3_check = self.o3.check_string(&opsx.o3);
-                    let o4_check = self.o4.check_string(&opsx.o4);
-                    let o5_check = self.o5.check_string(&opsx.o5);
-                    let o6_check = self.o6.check_string(&opsx.o6);
-                    let bool_vec = vec![o1_check, o2_check, o3_check, o4_check, o5_check, o6_check];
-                    return (bool_score(bool_vec), 6);
-                }
-            }
+        if !r_check || opsx.r == "N" {
+            return points_score("OPS", vec![("R", Some(r_check))], match_points);
         }
-        (0, 6)
+        let checks = vec![
+            ("R", Some(r_check)),
+            ("O1", self.o1.compare_string_allow_empty(&opsx.o1)),
+            ("O2", self.o2.compare_string_allow_empty(&opsx.o2)),
+            ("O3", self.o3.compare_string_allow_empty(&opsx.o3)),
+            ("O4", self.o4.compare_string_allow_empty(&opsx.o4)),
+            ("O5", self.o5.compare_string_allow_empty(&opsx.o5)),
+            ("O6", self.o6.compare_string_allow_empty(&opsx.o6)),
+        ];
+        points_score("OPS", checks, match_points)
     }
     pub fn new(map: HashMap<&str, NmapOsDbValueTypes>) -> OPSDB {
         let o1 = match map.get("o1") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let o2 = match map.get("o2") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let o3 = match map.get("o3") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let o4 = match map.get("o4") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let o5 = match map.get("o5") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let o6 = match map.get("o6") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let r = match map.get("r") {
             Some(v) => v.clone(),
@@ -451,49 +577,49 @@ pub struct WINDB {
 }
 
 impl WINDB {
-    pub fn check(&self, winx: &WINX) -> (usize, usize) {
+    pub fn check(&self, winx: &WINX, match_points: &MatchPoints) -> (usize, usize) {
+        if winx.r.is_empty() {
+            return (0, 0);
+        }
         let r_check = self.r.check_r(&winx.r);
-        if r_check {
-            match winx.r.as_str() {
-                "N" => (),
-                _ => {
-                    let w1_check = self.w1.check_usize(winx.w1 as usize);
-                    let w2_check = self.w2.check_usize(winx.w2 as usize);
-                    let w3_check = self.w3.check_usize(winx.w3 as usize);
-                    let w4_check = self.w4.check_usize(winx.w4 as usize);
-                    let w5_check = self.w5.check_usize(winx.w5 as usize);
-                    let w6_check = self.w6.check_usize(winx.w6 as usize);
-                    let bool_vec = vec![w1_check, w2_check, w3_check, w4_check, w5_check, w6_check];
-                    return (bool_score(bool_vec), 6);
-                }
-            }
+        if !r_check || winx.r == "N" {
+            return points_score("WIN", vec![("R", Some(r_check))], match_points);
         }
-        (0, 6)
+        let checks = vec![
+            ("R", Some(r_check)),
+            ("W1", self.w1.compare_usize(winx.w1 as usize)),
+            ("W2", self.w2.compare_usize(winx.w2 as usize)),
+            ("W3", self.w3.compare_usize(winx.w3 as usize)),
+            ("W4", self.w4.compare_usize(winx.w4 as usize)),
+            ("W5", self.w5.compare_usize(winx.w5 as usize)),
+            ("W6", self.w6.compare_usize(winx.w6 as usize)),
+        ];
+        points_score("WIN", checks, match_points)
     }
     pub fn new(map: HashMap<&str, NmapOsDbValueTypes>) -> WINDB {
         let w1 = match map.get("w1") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let w2 = match map.get("w2") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let w3 = match map.get("w3") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let w4 = match map.get("w4") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let w5 = match map.get("w5") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let w6 = match map.get("w6") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let r = match map.get("r") {
             Some(v) => v.clone(),
@@ -560,28 +686,30 @@ pub struct ECNDB {
 }
 
 impl ECNDB {
-    pub fn check(&self, ecnx: &ECNX) -> (usize, usize) {
+    pub fn check(&self, ecnx: &ECNX, match_points: &MatchPoints) -> (usize, usize) {
+        if ecnx.r.is_empty() {
+            return (0, 0);
+        }
         let r_check = self.r.check_r(&ecnx.r);
-        if r_check {
-            match ecnx.r.as_str() {
-                "N" => (),
-                _ => {
-                    let df_check = self.df.check_string(&ecnx.df);
-                    let t_check = if ecnx.t > 0 {
-                        self.t.check_usize(ecnx.t as usize)
-                    } else {
-                        self.tg.check_usize(ecnx.tg as usize)
-                    };
-                    let w_check = self.w.check_usize(ecnx.w as usize);
-                    let o_check = self.o.check_string(&ecnx.o);
-                    let cc_check = self.cc.check_string(&ecnx.cc);
-                    let q_check = self.q.check_string(&ecnx.q);
-                    let bool_vec = vec![df_check, t_check, w_check, o_check, cc_check, q_check];
-                    return (bool_score(bool_vec), 6);
-                }
-            }
+        if !r_check || ecnx.r == "N" {
+            return points_score("ECN", vec![("R", Some(r_check))], match_points);
         }
-        (0, 6)
+        // the T is measured or only the TG is guessed
+        let t_check = if ecnx.t > 0 {
+            ("T", self.t.compare_usize(ecnx.t as usize))
+        } else {
+            ("TG", self.tg.compare_usize(ecnx.tg as usize))
+        };
+        let checks = vec![
+            ("R", Some(r_check)),
+            ("DF", self.df.compare_string(&ecnx.df)),
+            t_check,
+            ("W", self.w.compare_usize(ecnx.w as usize)),
+            ("O", self.o.compare_string_allow_empty(&ecnx.o)),
+            ("CC", self.cc.compare_string(&ecnx.cc)),
+            ("Q", self.q.compare_string_allow_empty(&ecnx.q)),
+        ];
+        points_score("ECN", checks, match_points)
     }
     pub fn new(map: HashMap<&str, NmapOsDbValueTypes>) -> ECNDB {
         let r = match map.get("r") {
@@ -590,31 +718,31 @@ impl ECNDB {
         };
         let df = match map.get("df") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let t = match map.get("t") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let tg = match map.get("tg") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let w = match map.get("w") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let o = match map.get("o") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let cc = match map.get("cc") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let q = match map.get("q") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
 
         ECNDB {
@@ -684,53 +812,35 @@ pub struct TX {
 }
 
 impl TX {
-    pub fn check(&self, txx: &TXX, name: &str) -> (usize, usize) {
+    pub fn check(&self, txx: &TXX, name: &str, match_points: &MatchPoints) -> (usize, usize) {
+        if txx.r.is_empty() {
+            return (0, 0);
+        }
         let r_check = self.r.check_r(&txx.r);
-        if r_check {
-            match txx.r.as_str() {
-                "N" => (),
-                _ => {
-                    let df_check = self.df.check_string(&txx.df);
-                    let t_check = if txx.t > 0 {
-                        self.t.check_usize(txx.t as usize)
-                    } else {
-                        self.tg.check_usize(txx.tg as usize)
-                    };
-                    let w_check = if name != "T1" {
-                        self.w.check_usize(txx.w as usize)
-                    } else {
-                        true
-                    };
-                    let s_check = self.s.check_string(&txx.s);
-                    let a_check = self.a.check_string(&txx.a);
-                    let f_check = self.f.check_string(&txx.f);
-                    let o_check = if name != "T1" {
-                        self.o.check_string(&txx.o)
-                    } else {
-                        true
-                    };
-                    let rd_check = self.rd.check_usize(txx.rd as usize);
-                    let q_check = self.q.check_string(&txx.q);
-
-                    // println!("{}", df_check);
-                    // println!("{}", t_check);
-                    // println!("{}", w_check);
-                    // println!("{}", s_check);
-                    // println!("{}", a_check);
-                    // println!("{}", f_check);
-                    // println!("{}", o_check);
-                    // println!("{}", rd_check);
-                    // println!("{}", q_check);
-
-                    let bool_vec = vec![
-                        df_check, t_check, w_check, s_check, a_check, f_check, o_check, rd_check,
-                        q_check,
-                    ];
-                    return (bool_score(bool_vec), 9);
-                }
-            }
+        if !r_check || txx.r == "N" {
+            return points_score(name, vec![("R", Some(r_check))], match_points);
         }
-        (0, 9)
+        let t_check = if txx.t > 0 {
+            ("T", self.t.compare_usize(txx.t as usize))
+        } else {
+            ("TG", self.tg.compare_usize(txx.tg as usize))
+        };
+        let mut checks = vec![
+            ("R", Some(r_check)),
+            ("DF", self.df.compare_string(&txx.df)),
+            t_check,
+            ("S", self.s.compare_string(&txx.s)),
+            ("A", self.a.compare_string(&txx.a)),
+            ("F", self.f.compare_string(&txx.f)),
+            ("RD", self.rd.compare_usize(txx.rd as usize)),
+            ("Q", self.q.compare_string_allow_empty(&txx.q)),
+        ];
+        // the W and O of T1 are in the OPS and WIN
+        if name != "T1" {
+            checks.push(("W", self.w.compare_usize(txx.w as usize)));
+            checks.push(("O", self.o.compare_string_allow_empty(&txx.o)));
+        }
+        points_score(name, checks, match_points)
     }
     pub fn new(map: HashMap<&str, NmapOsDbValueTypes>) -> TX {
         let r = match map.get("r") {
@@ -739,43 +849,43 @@ impl TX {
         };
         let df = match map.get("df") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let t = match map.get("t") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let tg = match map.get("tg") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let w = match map.get("w") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let s = match map.get("s") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let a = match map.get("a") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let f = match map.get("f") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let o = match map.get("o") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let rd = match map.get("rd") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let q = match map.get("q") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
 
         TX {
@@ -858,52 +968,32 @@ pub struct U1DB {
 }
 
 impl U1DB {
-    pub fn check(&self, u1x: &U1X) -> (usize, usize) {
+    pub fn check(&self, u1x: &U1X, match_points: &MatchPoints) -> (usize, usize) {
+        if u1x.r.is_empty() {
+            return (0, 0);
+        }
         let r_check = self.r.check_r(&u1x.r);
-        if r_check {
-            match u1x.r.as_str() {
-                "N" => (),
-                _ => {
-                    let df_check = self.df.check_string(&u1x.df);
-                    let t_check = if u1x.t > 0 {
-                        self.t.check_usize(u1x.t as usize)
-                    } else {
-                        self.tg.check_usize(u1x.tg as usize)
-                    };
-                    let ipl_check = self.ipl.check_usize(u1x.ipl as usize);
-                    let un_check = self.un.check_usize(u1x.un as usize);
-                    let ripl_check = self.ripl.check_string(&u1x.ripl);
-                    let rid_check = self.rid.check_string(&u1x.rid);
-                    let ripck_check = self.ripck.check_string(&u1x.ripck);
-                    let ruck_check = self.ruck.check_string(&u1x.ruck);
-                    let rud_check = self.rud.check_string(&u1x.rud);
-
-                    // println!("{}", df_check);
-                    // println!("{}", t_check);
-                    // println!("{}", ipl_check);
-                    // println!("{}", un_check);
-                    // println!("{}", ripl_check);
-                    // println!("{}", rid_check);
-                    // println!("{}", ripck_check);
-                    // println!("{}", ruck_check);
-                    // println!("{}", rud_check);
-
-                    let bool_vec = vec![
-                        df_check,
-                        t_check,
-                        ipl_check,
-                        un_check,
-                        ripl_check,
-                        rid_check,
-                        ripck_check,
-                        ruck_check,
-                        rud_check,
-                    ];
-                    return (bool_score(bool_vec), 9);
-                }
-            }
+        if !r_check || u1x.r == "N" {
+            return points_score("U1", vec![("R", Some(r_check))], match_points);
         }
-        (0, 9)
+        let t_check = if u1x.t > 0 {
+            ("T", self.t.compare_usize(u1x.t as usize))
+        } else {
+            ("TG", self.tg.compare_usize(u1x.tg as usize))
+        };
+        let checks = vec![
+            ("R", Some(r_check)),
+            ("DF", self.df.compare_string(&u1x.df)),
+            t_check,
+            ("IPL", self.ipl.compare_usize(u1x.ipl as usize)),
+            ("UN", self.un.compare_usize(u1x.un as usize)),
+            ("RIPL", self.ripl.compare_string(&u1x.ripl)),
+            ("RID", self.rid.compare_string(&u1x.rid)),
+            ("RIPCK", self.ripck.compare_string(&u1x.ripck)),
+            ("RUCK", self.ruck.compare_string(&u1x.ruck)),
+            ("RUD", self.rud.compare_string(&u1x.rud)),
+        ];
+        points_score("U1", checks, match_points)
     }
     pub fn new(map: HashMap<&str, NmapOsDbValueTypes>) -> U1DB {
         let r = match map.get("r") {
@@ -912,43 +1002,43 @@ impl U1DB {
         };
         let df = match map.get("df") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let t = match map.get("t") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let tg = match map.get("tg") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let ipl = match map.get("ipl") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let un = match map.get("un") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let ripl = match map.get("ripl") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let rid = match map.get("rid") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let ripck = match map.get("ripck") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let ruck = match map.get("ruck") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let rud = match map.get("rud") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
 
         U1DB {
@@ -1024,25 +1114,26 @@ pub struct IEDB {
 }
 
 impl IEDB {
-    pub fn check(&self, iex: &IEX) -> (usize, usize) {
+    pub fn check(&self, iex: &IEX, match_points: &MatchPoints) -> (usize, usize) {
+        if iex.r.is_empty() {
+            return (0, 0);
+        }
         let r_check = self.r.check_r(&iex.r);
-        if r_check {
-            match iex.r.as_str() {
-                "N" => (),
-                _ => {
-                    let dfi_check = self.dfi.check_string(&iex.dfi);
-                    let t_check = if iex.t > 0 {
-                        self.t.check_usize(iex.t as usize)
-                    } else {
-                        self.tg.check_usize(iex.tg as usize)
-                    };
-                    let cd_check = self.cd.check_string(&iex.cd);
-                    let bool_vec = vec![dfi_check, t_check, cd_check];
-                    return (bool_score(bool_vec), 3);
-                }
-            }
+        if !r_check || iex.r == "N" {
+            return points_score("IE", vec![("R", Some(r_check))], match_points);
         }
-        (0, 3)
+        let t_check = if iex.t > 0 {
+            ("T", self.t.compare_usize(iex.t as usize))
+        } else {
+            ("TG", self.tg.compare_usize(iex.tg as usize))
+        };
+        let checks = vec![
+            ("R", Some(r_check)),
+            ("DFI", self.dfi.compare_string(&iex.dfi)),
+            t_check,
+            ("CD", self.cd.compare_string(&iex.cd)),
+        ];
+        points_score("IE", checks, match_points)
     }
     pub fn new(map: HashMap<&str, NmapOsDbValueTypes>) -> IEDB {
         let r = match map.get("r") {
@@ -1051,19 +1142,19 @@ impl IEDB {
         };
         let dfi = match map.get("dfi") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let t = match map.get("t") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let tg = match map.get("tg") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
         let cd = match map.get("cd") {
             Some(v) => v.clone(),
-            _ => NmapOsDbValueTypes::empty(),
+            _ => NmapOsDbValueTypes::absent(),
         };
 
         IEDB { r, dfi, t, tg, cd }
@@ -1099,11 +1190,54 @@ impl IEDB {
     }
 }
 
+/// The `Class` line of nmap-os-db.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+pub struct OsClass {
+    pub vendor: String,
+    pub family: String,
+    /// Empty if the generation is unknown.
+    pub generation: String,
+    pub device_type: String,
+}
+
+impl fmt::Display for OsClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} | {} | {} | {}",
+            self.vendor, self.family, self.generation, self.device_type
+        )
+    }
+}
+
+impl OsClass {
+    /// The line looks like `Class Linux | Linux | 4.X | general purpose`.
+    pub fn parser(line: &str) -> OsClass {
+        let line = line.trim_start_matches("Class").trim();
+        let items: Vec<String> = line.split("|").map(|i| i.trim().to_string()).collect();
+        let item = |i: usize| -> String {
+            match items.get(i) {
+                Some(s) => s.to_string(),
+                None => String::new(),
+            }
+        };
+        OsClass {
+            vendor: item(0),
+            family: item(1),
+            generation: item(2),
+            device_type: item(3),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NmapOsDb {
     pub info: String,
     pub fingerprint: String,
     pub class: String,
+    /// The `class` lines parsed.
+    #[serde(default)]
+    pub classes: Vec<OsClass>,
     pub cpe: String,
     pub seq: SEQDB,
     pub ops: OPSDB,
@@ -1121,62 +1255,34 @@ pub struct NmapOsDb {
 }
 
 impl NmapOsDb {
-    pub fn check(&self, probe_ret: &PistolFingerprint) -> (usize, usize) {
-        let (seq_score, seq_total) = self.seq.check(&probe_ret.seqx);
-        // println!("SEQ: {}", seq_check);
-        let (ops_score, ops_total) = self.ops.check(&probe_ret.opsx);
-        // println!("OPS: {}", ops_check);
-        let (win_score, win_total) = self.win.check(&probe_ret.winx);
-        // println!("WIN: {}", win_check);
-        let (ecn_score, ecn_total) = self.ecn.check(&probe_ret.ecnx);
-        // println!("ECN: {}", ecn_check);
-        let (t1_score, t1_total) = self.t1.check(&probe_ret.t1x, "T1");
-        // println!("T1: {}", t1_check);
-        let (t2_score, t2_total) = self.t2.check(&probe_ret.t2x, "T2");
-        // println!("T2: {}", t2_check);
-        let (t3_score, t3_total) = self.t3.check(&probe_ret.t3x, "T3");
-        // println!("T3: {}", t3_check);
-        let (t4_score, t4_total) = self.t4.check(&probe_ret.t4x, "T4");
-        // println!("T4: {}", t4_check);
-        let (t5_score, t5_total) = self.t5.check(&probe_ret.t5x, "T5");
-        // println!("T5: {}", t5_check);
-        let (t6_score, t6_total) = self.t6.check(&probe_ret.t6x, "T6");
-        // println!("T6: {}", t6_check);
-        let (t7_score, t7_total) = self.t7.check(&probe_ret.t7x, "T7");
-        // println!("T7: {}", t7_check);
-        let (u1_score, u1_total) = self.u1.check(&probe_ret.u1x);
-        // println!("U1: {}", u1_check);
-        let (ie_score, ie_total) = self.ie.check(&probe_ret.iex);
-        // println!("IE: {}", ie_check);
-
-        let score = seq_score
-            + ops_score
-            + win_score
-            + ecn_score
-            + t1_score
-            + t2_score
-            + t3_score
-            + t4_score
-            + t5_score
-            + t6_score
-            + t7_score
-            + u1_score
-            + ie_score;
-
-        let total = seq_total
-            + ops_total
-            + win_total
-            + ecn_total
-            + t1_total
-            + t2_total
-            + t3_total
-            + t4_total
-            + t5_total
-            + t6_total
-            + t7_total
-            + u1_total
-            + ie_total;
-
+    /// Returns the points of the matched attributes and the points of all the attributes,
+    /// every attribute is weighted by the `MatchPoints`.
+    pub fn check(
+        &self,
+        probe_ret: &PistolFingerprint,
+        match_points: &MatchPoints,
+    ) -> (usize, usize) {
+        let rets = vec![
+            self.seq.check(&probe_ret.seqx, match_points),
+            self.ops.check(&probe_ret.opsx, match_points),
+            self.win.check(&probe_ret.winx, match_points),
+            self.ecn.check(&probe_ret.ecnx, match_points),
+            self.t1.check(&probe_ret.t1x, "T1", match_points),
+            self.t2.check(&probe_ret.t2x, "T2", match_points),
+            self.t3.check(&probe_ret.t3x, "T3", match_points),
+            self.t4.check(&probe_ret.t4x, "T4", match_points),
+            self.t5.check(&probe_ret.t5x, "T5", match_points),
+            self.t6.check(&probe_ret.t6x, "T6", match_points),
+            self.t7.check(&probe_ret.t7x, "T7", match_points),
+            self.u1.check(&probe_ret.u1x, match_points),
+            self.ie.check(&probe_ret.iex, match_points),
+        ];
+        let mut score = 0;
+        let mut total = 0;
+        for (s, t) in rets {
+            score += s;
+            total += t;
+        }
         (score, total)
     }
 }
@@ -1244,7 +1350,8 @@ fn value_parser_str(info: &str) -> NmapOsDbValueTypes {
 
 /// Each item in the input vec `lines` represents a line of nmap-os-db file content.
 /// So just read the nmap file line by line and store it in vec for input.
-pub fn nmap_os_db_parser(lines: Vec<String>) -> Result<Vec<NmapOsDb>> {
+/// The `MatchPoints` section at the beginning of the file is returned too.
+pub fn nmap_os_db_parser(lines: Vec<String>) -> Result<(Vec<NmapOsDb>, MatchPoints)> {
     let option_string = |x: Option<String>| -> String {
         match x {
             Some(x) => x,
@@ -1270,9 +1377,22 @@ pub fn nmap_os_db_parser(lines: Vec<String>) -> Result<Vec<NmapOsDb>> {
     let mut t6 = None;
     let mut t7 = None;
     let mut u1 = None;
+    let mut match_points = MatchPoints::new();
+    let mut in_match_points = false;
     for l in lines {
         // println!("{}", l);
         let l = l.trim().to_string();
+        if l == "MatchPoints" {
+            in_match_points = true;
+            continue;
+        } else if in_match_points {
+            // the section ends with an empty line
+            if l.len() > 0 {
+                match_points.parser(&l)?;
+                continue;
+            }
+            in_match_points = false;
+        }
         if l.len() > 0 {
             if l.starts_with("#") {
                 let l = match l1 {
@@ -1337,10 +1457,17 @@ pub fn nmap_os_db_parser(lines: Vec<String>) -> Result<Vec<NmapOsDb>> {
             } else if l.starts_with("IE") {
                 // last line
                 let ie = Some(IEDB::parser(l)?);
+                let class = option_string(l3);
+                let classes = class
+                    .lines()
+                    .filter(|c| c.len() > 0)
+                    .map(|c| OsClass::parser(c))
+                    .collect();
                 let v = NmapOsDb {
                     info: option_string(l1),
                     fingerprint: option_string(l2),
-                    class: option_string(l3),
+                    class,
+                    classes,
                     cpe: option_string(l4),
                     seq: seq.clone().unwrap(),
                     ops: ops.clone().unwrap(),
@@ -1364,5 +1491,5 @@ pub fn nmap_os_db_parser(lines: Vec<String>) -> Result<Vec<NmapOsDb>> {
             }
         }
     }
-    Ok(result)
+    Ok((result, match_points))
 }
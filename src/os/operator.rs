@@ -9,6 +9,7 @@ use pnet::packet::tcp::TcpOptionNumbers;
 use pnet::packet::tcp::TcpPacket;
 use pnet::packet::udp::UdpPacket;
 use pnet::packet::Packet;
+use std::time::Duration;
 
 use super::rr::{IERR, SEQRR, TXRR, U1RR};
 use crate::errors::CalcDiffFailed;
@@ -38,6 +39,9 @@ const FIN_MASK: u8 = 0b00000001;
 const PROGRAM_ESTIMATION_ERROR_ISR: f32 = 0.0;
 const PROGRAM_ESTIMATION_ERROR_SP: f32 = 0.0;
 
+// Like nmap, the uptime longer than two years is ignored.
+const MAX_UPTIME_SECS: f64 = 63072000.0;
+
 fn get_ipv4_packet(ipv4_buff: &[u8]) -> Result<Option<Ipv4Packet>> {
     if ipv4_buff.len() > 0 {
         match Ipv4Packet::new(ipv4_buff) {
@@ -236,8 +240,10 @@ fn get_ip_id(ipv4_buff: &[u8]) -> Result<Option<u16>> {
     }
 }
 
-/// IP ID sequence generation algorithm (TI, CI, II)
-pub fn tcp_ti_ci_ii(seqrr: &SEQRR, t2t7rr: &TXRR, ierr: &IERR) -> Result<(String, String, String)> {
+/// IP ID sequence generation algorithm of the `ip_id_vec`, TI, CI and II use the same rules.
+/// The RD result needs enough samples to support it, so it is only checked when `check_rd` is true.
+/// Returns an empty string if none of the rules identify the generation algorithm.
+pub fn ip_id_class(ip_id_vec: &[u16], check_rd: bool) -> Result<String> {
     let z_judgement = |x: &[u16]| -> bool {
         let mut conditon = true; // all of the ID numbers are zero
         for v in x {
@@ -256,18 +262,9 @@ pub fn tcp_ti_ci_ii(seqrr: &SEQRR, t2t7rr: &TXRR, ierr: &IERR) -> Result<(String
         }
         condition
     };
-    let hex_judgement = |ip_id_vec: &[u16]| -> Result<bool> {
-        let v3 = get_diff_u16(ip_id_vec);
-        let mut sum = 0;
-        for v in v3 {
-            sum += v;
-        }
-
-        if sum == 0 {
-            Ok(true)
-        } else {
-            Ok(false)
-        }
+    let hex_judgement = |ip_id_vec: &[u16]| -> bool {
+        // all of the IP IDs are identical
+        ip_id_vec.iter().all(|x| *x == ip_id_vec[0])
     };
     let ri_judgement = |diff: &[u16]| -> bool {
         let mut condition_1 = true; // any of the differences exceeds 1000
@@ -315,6 +312,40 @@ pub fn tcp_ti_ci_ii(seqrr: &SEQRR, t2t7rr: &TXRR, ierr: &IERR) -> Result<(String
         condition
     };
 
+    let diff = get_diff_u16(ip_id_vec);
+    let class = if z_judgement(ip_id_vec) {
+        // If all of the ID numbers are zero, the value of the test is Z.
+        String::from("Z")
+    } else if check_rd && rd_judgement(&diff) {
+        // If the IP ID sequence ever increases by at least 20,000, the value is RD (random).
+        // This result isn't possible for II because there are not enough samples to support it.
+        String::from("RD")
+    } else if hex_judgement(ip_id_vec) {
+        // If all of the IP IDs are identical, the test is set to that value in hex.
+        format!("{:X}", ip_id_vec[0])
+    } else if ri_judgement(&diff) {
+        // If any of the differences between two consecutive IDs exceeds 1,000, and is not evenly divisible by 256,
+        // the test's value is RI (random positive increments).
+        // If the difference is evenly divisible by 256, it must be at least 256,000 to cause this RI result.
+        String::from("RI")
+    } else if bi_judgement(&diff) {
+        // If all of the differences are divisible by 256 and no greater than 5,120, the test is set to BI (broken increment).
+        // This happens on systems like Microsoft Windows where the IP ID is sent in host byte order rather than network byte order.
+        // It works fine and isn't any sort of RFC violation, though it does give away host architecture details which can be useful to attackers.
+        String::from("BI")
+    } else if i_judgement(&diff) {
+        // If all of the differences are less than ten, the value is I (incremental).
+        // We allow difference up to ten here (rather than requiring sequential ordering) because traffic from other hosts can cause sequence gaps.
+        String::from("I")
+    } else {
+        // If none of the previous steps identify the generation algorithm, the test is omitted from the fingerprint.
+        String::new()
+    };
+    Ok(class)
+}
+
+/// IP ID sequence generation algorithm (TI, CI, II)
+pub fn tcp_ti_ci_ii(seqrr: &SEQRR, t2t7rr: &TXRR, ierr: &IERR) -> Result<(String, String, String)> {
     let seq1_ip_id = get_ip_id(&seqrr.seq1.response)?;
     let seq2_ip_id = get_ip_id(&seqrr.seq2.response)?;
     let seq3_ip_id = get_ip_id(&seqrr.seq3.response)?;
@@ -341,37 +372,13 @@ pub fn tcp_ti_ci_ii(seqrr: &SEQRR, t2t7rr: &TXRR, ierr: &IERR) -> Result<(String
     if seq6_ip_id.is_some() {
         seq_ip_id_vec.push(seq6_ip_id.unwrap());
     }
-    let seq_diff = get_diff_u16(&seq_ip_id_vec);
 
     // TI is based on responses to the TCP SEQ probes.
+    // Unlike CI, TI has always given RD for the increases which match the RI rule, it is kept as before.
     let ti = if seq_ip_id_vec.len() >= 3 {
-        if z_judgement(&seq_ip_id_vec) {
-            // If all of the ID numbers are zero, the value of the test is Z.
-            String::from("Z")
-        } else if ri_judgement(&seq_diff) {
-            // If the IP ID sequence ever increases by at least 20,000, the value is RD (random).
-            // This result isn't possible for II because there are not enough samples to support it.
-            String::from("RD")
-        } else if hex_judgement(&seq_ip_id_vec)? {
-            // If all of the IP IDs are identical, the test is set to that value in hex.
-            format!("{:X}", seq_ip_id_vec[0])
-        } else if ri_judgement(&seq_diff) {
-            // If any of the differences between two consecutive IDs exceeds 1,000, and is not evenly divisible by 256,
-            // the test's value is RI (random positive increments).
-            // If the difference is evenly divisible by 256, it must be at least 256,000 to cause this RI result.
-            String::from("RI")
-        } else if bi_judgement(&seq_diff) {
-            // If all of the differences are divisible by 256 and no greater than 5,120, the test is set to BI (broken increment).
-            // This happens on systems like Microsoft Windows where the IP ID is sent in host byte order rather than network byte order.
-            // It works fine and isn't any sort of RFC violation, though it does give away host architecture details which can be useful to attackers.
-            String::from("BI")
-        } else if i_judgement(&seq_diff) {
-            // If all of the differences are less than ten, the value is I (incremental).
-            // We allow difference up to ten here (rather than requiring sequential ordering) because traffic from other hosts can cause sequence gaps.
-            String::from("I")
-        } else {
-            // If none of the previous steps identify the generation algorithm, the test is omitted from the fingerprint.
-            String::new()
+        match ip_id_class(&seq_ip_id_vec, false)?.as_str() {
+            "RI" => String::from("RD"),
+            class => class.to_string(),
         }
     } else {
         // For TI, at least three responses must be received for the test to be included.
@@ -393,37 +400,9 @@ pub fn tcp_ti_ci_ii(seqrr: &SEQRR, t2t7rr: &TXRR, ierr: &IERR) -> Result<(String
     if t7_ip_id.is_some() {
         t_ip_id_vec.push(t7_ip_id.unwrap());
     }
-    let t_diff = get_diff_u16(&t_ip_id_vec);
 
     let ci = if t_ip_id_vec.len() >= 2 {
-        if z_judgement(&t_ip_id_vec) {
-            // If all of the ID numbers are zero, the value of the test is Z.
-            String::from("Z")
-        } else if rd_judgement(&t_diff) {
-            // If the IP ID sequence ever increases by at least 20,000, the value is RD (random).
-            // This result isn't possible for II because there are not enough samples to support it.
-            String::from("RD")
-        } else if hex_judgement(&t_ip_id_vec)? {
-            // If all of the IP IDs are identical, the test is set to that value in hex.
-            format!("{:X}", t_ip_id_vec[0])
-        } else if ri_judgement(&t_diff) {
-            // If any of the differences between two consecutive IDs exceeds 1,000, and is not evenly divisible by 256,
-            // the test's value is RI (random positive increments).
-            // If the difference is evenly divisible by 256, it must be at least 256,000 to cause this RI result.
-            String::from("RI")
-        } else if bi_judgement(&t_diff) {
-            // If all of the differences are divisible by 256 and no greater than 5,120, the test is set to BI (broken increment).
-            // This happens on systems like Microsoft Windows where the IP ID is sent in host byte order rather than network byte order.
-            // It works fine and isn't any sort of RFC violation, though it does give away host architecture details which can be useful to attackers.
-            String::from("BI")
-        } else if i_judgement(&t_diff) {
-            // If all of the differences are less than ten, the value is I (incremental).
-            // We allow difference up to ten here (rather than requiring sequential ordering) because traffic from other hosts can cause sequence gaps.
-            String::from("I")
-        } else {
-            // If none of the previous steps identify the generation algorithm, the test is omitted from the fingerprint.
-            String::new()
-        }
+        ip_id_class(&t_ip_id_vec, true)?
     } else {
         // for CI, at least two responses are required.
         String::new()
@@ -439,36 +418,10 @@ pub fn tcp_ti_ci_ii(seqrr: &SEQRR, t2t7rr: &TXRR, ierr: &IERR) -> Result<(String
     if ie2_ip_id.is_some() {
         ie_ip_id_vec.push(ie2_ip_id.unwrap());
     }
-    let ie_diff = get_diff_u16(&ie_ip_id_vec);
-    // println!("{:?}", ie_ip_id_vec);
-    // println!("{:?}", ie_diff);
 
     // RD result isn't possible for II because there are not enough samples to support it.
     let ii = if ie_ip_id_vec.len() >= 2 {
-        if z_judgement(&ie_ip_id_vec) {
-            // If all of the ID numbers are zero, the value of the test is Z.
-            String::from("Z")
-        } else if hex_judgement(&ie_ip_id_vec)? {
-            // If all of the IP IDs are identical, the test is set to that value in hex.
-            format!("{:X}", ie_ip_id_vec[0])
-        } else if ri_judgement(&ie_diff) {
-            // If any of the differences between two consecutive IDs exceeds 1,000, and is not evenly divisible by 256,
-            // the test's value is RI (random positive increments).
-            // If the difference is evenly divisible by 256, it must be at least 256,000 to cause this RI result.
-            String::from("RI")
-        } else if bi_judgement(&ie_diff) {
-            // If all of the differences are divisible by 256 and no greater than 5,120, the test is set to BI (broken increment).
-            // This happens on systems like Microsoft Windows where the IP ID is sent in host byte order rather than network byte order.
-            // It works fine and isn't any sort of RFC violation, though it does give away host architecture details which can be useful to attackers.
-            String::from("BI")
-        } else if i_judgement(&ie_diff) {
-            // If all of the differences are less than ten, the value is I (incremental).
-            // We allow difference up to ten here (rather than requiring sequential ordering) because traffic from other hosts can cause sequence gaps.
-            String::from("I")
-        } else {
-            // If none of the previous steps identify the generation algorithm, the test is omitted from the fingerprint.
-            String::new()
-        }
+        ip_id_class(&ie_ip_id_vec, false)?
     } else {
         // and for II, both ICMP responses must be received.
         String::new()
@@ -680,6 +633,45 @@ pub fn tcp_ts(seqrr: &SEQRR) -> Result<String> {
     Ok(ts)
 }
 
+/// The uptime guess from the TCP timestamp rate (TS) and the first TSval, same as nmap.
+/// `None` if the timestamp is not supported, zero or the uptime is too long to believe.
+pub fn tcp_uptime(seqrr: &SEQRR) -> Result<Option<Duration>> {
+    let ts = tcp_ts(seqrr)?;
+    let hz = match ts.as_str() {
+        "" | "U" | "0" => return Ok(None),
+        "1" => 2.0,
+        "7" => 100.0,
+        "8" => 200.0,
+        // nmap treats 724-1448 Hz as 1000 Hz
+        "A" => 1000.0,
+        t => match u32::from_str_radix(t, 16) {
+            Ok(t) => 2.0f64.powi(t as i32),
+            Err(_) => return Ok(None),
+        },
+    };
+    let responses = [
+        &seqrr.seq1.response,
+        &seqrr.seq2.response,
+        &seqrr.seq3.response,
+        &seqrr.seq4.response,
+        &seqrr.seq5.response,
+        &seqrr.seq6.response,
+    ];
+    for r in responses {
+        match get_tsval(r)? {
+            Some(tsval) => {
+                let uptime = tsval as f64 / hz;
+                if uptime > MAX_UPTIME_SECS {
+                    return Ok(None);
+                }
+                return Ok(Some(Duration::from_secs_f64(uptime)));
+            }
+            None => (),
+        }
+    }
+    Ok(None)
+}
+
 /// TCP options (O, O1–O6)
 pub fn tcp_o(ipv4_response: &[u8]) -> Result<String> {
     let ipv4_packet = get_ipv4_packet(ipv4_response)?;
@@ -818,14 +810,20 @@ pub fn tcp_udp_df(ipv4_response: &[u8]) -> Result<String> {
 
 /// IP initial time-to-live (T)
 pub fn tcp_udp_icmp_t(ipv4_response: &[u8], u1rr: &U1RR) -> Result<u16> {
-    let hops = udp_hops(u1rr)?;
+    let hops = match udp_hops(u1rr)? {
+        Some(h) => h,
+        None => 0,
+    };
     let response = Ipv4Packet::new(ipv4_response).unwrap();
     let response_ttl = response.get_ttl();
     // Avoid overflow in integer addition.
     Ok(hops as u16 + response_ttl as u16)
 }
 
-fn udp_hops(u1rr: &U1RR) -> Result<u8> {
+/// The number of routers between us and the target, computed from the ttl of the
+/// original U1 probe and the ttl of it in the ICMP port unreachable response.
+/// Returns `None` if there is no response (the network distance is unknown).
+pub fn udp_hops(u1rr: &U1RR) -> Result<Option<u8>> {
     let request = get_ipv4_packet(&u1rr.u1.request)?.unwrap(); // must have request
     let ipv4_packet = get_ipv4_packet(&u1rr.u1.response)?;
     match ipv4_packet {
@@ -839,8 +837,8 @@ fn udp_hops(u1rr: &U1RR) -> Result<u8> {
                         Some(r_ipv4_packet) => {
                             let ttl_1 = request.get_ttl();
                             let ttl_2 = r_ipv4_packet.get_ttl();
-                            let hops = ttl_1 - ttl_2;
-                            return Ok(hops);
+                            // the ttl of the returned packet may be changed by the middle boxes
+                            return Ok(ttl_1.checked_sub(ttl_2));
                         }
                         None => (),
                     }
@@ -851,7 +849,7 @@ fn udp_hops(u1rr: &U1RR) -> Result<u8> {
         None => (),
     }
     // It is not uncommon for Nmap to receive no response to the U1 probe.
-    Ok(0)
+    Ok(None)
 }
 
 /// IP initial time-to-live guess (TG)
@@ -1370,6 +1368,78 @@ pub fn icmp_cd(ie: &IERR) -> Result<String> {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    #[test]
+    fn test_ip_id_class() {
+        assert_eq!(ip_id_class(&[0, 0, 0], true).unwrap(), "Z");
+        assert_eq!(ip_id_class(&[100, 101, 103, 104], true).unwrap(), "I");
+        assert_eq!(ip_id_class(&[0x100, 0x200, 0x400], true).unwrap(), "BI");
+        assert_eq!(ip_id_class(&[1000, 3001, 5003], true).unwrap(), "RI");
+        assert_eq!(ip_id_class(&[100, 30000, 60000], true).unwrap(), "RD");
+        // the increases less than 20,000 are not RD
+        assert_eq!(ip_id_class(&[100, 5000, 9900], true).unwrap(), "RI");
+        assert_eq!(ip_id_class(&[100, 30000], false).unwrap(), "RI");
+        assert_eq!(ip_id_class(&[0x1F, 0x1F], false).unwrap(), "1F");
+        // the large differences must not overflow
+        assert_eq!(ip_id_class(&[0, 60000, 120, 60120], false).unwrap(), "RI");
+    }
+    #[test]
+    fn test_tcp_uptime() {
+        use crate::os::rr::RequestAndResponse;
+        use pnet::packet::ip::IpNextHeaderProtocols;
+        use pnet::packet::ipv4::MutableIpv4Packet;
+        use pnet::packet::tcp::MutableTcpPacket;
+        use pnet::packet::tcp::TcpOption;
+
+        fn rr(tsval: Option<u32>) -> RequestAndResponse {
+            let mut buff = vec![0u8; 20 + 32];
+            let mut ip_header = MutableIpv4Packet::new(&mut buff).unwrap();
+            ip_header.set_version(4);
+            ip_header.set_header_length(5);
+            ip_header.set_total_length(52);
+            ip_header.set_next_level_protocol(IpNextHeaderProtocols::Tcp);
+            let mut tcp_header = MutableTcpPacket::new(&mut buff[20..]).unwrap();
+            tcp_header.set_data_offset(8);
+            match tsval {
+                Some(t) => tcp_header.set_options(&[
+                    TcpOption::nop(),
+                    TcpOption::nop(),
+                    TcpOption::timestamp(t, 0),
+                ]),
+                None => (),
+            }
+            RequestAndResponse {
+                request: vec![],
+                response: buff,
+            }
+        }
+        fn seqrr(tsvals: [Option<u32>; 6]) -> SEQRR {
+            SEQRR {
+                seq1: rr(tsvals[0]),
+                seq2: rr(tsvals[1]),
+                seq3: rr(tsvals[2]),
+                seq4: rr(tsvals[3]),
+                seq5: rr(tsvals[4]),
+                seq6: rr(tsvals[5]),
+                elapsed: 0.5,
+            }
+        }
+
+        // 1000 Hz (TS=A) and up for one day
+        let day = 86_400_000;
+        let tsvals = [0, 1, 2, 3, 4, 5].map(|i| Some(day + i * 100));
+        let uptime = tcp_uptime(&seqrr(tsvals)).unwrap();
+        assert_eq!(uptime, Some(Duration::from_secs(86_400)));
+        // 100 Hz (TS=7)
+        let tsvals = [0, 1, 2, 3, 4, 5].map(|i| Some(360_000 + i * 10));
+        let uptime = tcp_uptime(&seqrr(tsvals)).unwrap();
+        assert_eq!(uptime, Some(Duration::from_secs(3_600)));
+        // no timestamps
+        assert_eq!(tcp_uptime(&seqrr([None; 6])).unwrap(), None);
+        // too long to believe (TS=3)
+        let tsvals = [0, 1, 2, 3, 4, 5].map(|i| Some(0xF000_0000 + i));
+        assert_eq!(tcp_uptime(&seqrr(tsvals)).unwrap(), None);
+    }
     #[test]
     fn test_error() {
         // let nmap_sp_vec = vec![0x104, 0x105, 0xFC, 0xFF];
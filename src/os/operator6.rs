@@ -7,6 +7,7 @@ use pnet::packet::Packet;
 use std::iter::zip;
 
 use super::rr::AllPacketRR6;
+use super::rr::U1RR6;
 use crate::errors::GetIcmpv6PacketFailed;
 use crate::errors::GetIpv6PacketFailed;
 use crate::errors::GetTcpPacketFailed;
@@ -150,6 +151,32 @@ fn tcp_isr(ap: &AllPacketRR6) -> Result<f64> {
     Ok(sum as f64 / e as f64)
 }
 
+/// Same as `udp_hops` in ipv4, computed from the hop limit of the U1 probe
+/// and the hop limit of it in the ICMPv6 port unreachable response.
+pub fn udp_hops6(u1rr: &U1RR6) -> Result<Option<u8>> {
+    let request = match get_ipv6_packet(&u1rr.u1.request)? {
+        Some(r) => r,
+        None => return Ok(None),
+    };
+    match get_ipv6_packet(&u1rr.u1.response)? {
+        Some(ipv6_packet) => match get_icmpv6_packet(ipv6_packet.payload())? {
+            // 4 bytes unused, then the invoking packet
+            Some(icmpv6_packet) if icmpv6_packet.payload().len() > 4 => {
+                match get_ipv6_packet(&icmpv6_packet.payload()[4..])? {
+                    Some(r_ipv6_packet) => {
+                        let hlim_1 = request.get_hop_limit();
+                        let hlim_2 = r_ipv6_packet.get_hop_limit();
+                        Ok(hlim_1.checked_sub(hlim_2))
+                    }
+                    None => Ok(None),
+                }
+            }
+            _ => Ok(None),
+        },
+        None => Ok(None),
+    }
+}
+
 /// A guess at the original value of the IPv6 Hop Limit field.
 fn ipv6_hlim(ipv6_response: &[u8]) -> Result<f64> {
     let ipv6_response_packet = get_ipv6_packet(ipv6_response)?;
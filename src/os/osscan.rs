@@ -3,7 +3,13 @@ use chrono::DateTime;
 use chrono::Local;
 use chrono::Utc;
 use pnet::datalink::MacAddr;
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::tcp::TcpPacket;
+use pnet::packet::udp::UdpPacket;
+use pnet::packet::Packet;
 use rand::Rng;
+use std::collections::HashMap;
+use std::error::Error;
 use std::fmt;
 use std::net::IpAddr;
 use std::net::Ipv4Addr;
@@ -12,20 +18,23 @@ use std::thread::sleep;
 use std::time::Duration;
 use std::time::SystemTime;
 
-use crate::errors::CanNotFoundInterface;
-use crate::errors::CanNotFoundMacAddress;
+use crate::errors::GetIpv4PacketFailed;
+use crate::errors::GetTcpPacketFailed;
+use crate::errors::GetUdpPacketFailed;
 use crate::layers::layer3_ipv4_send;
+use crate::layers::neighbour_mac;
 use crate::layers::Layer3Match;
 use crate::layers::Layer4MatchIcmp;
 use crate::layers::Layer4MatchTcpUdp;
 use crate::layers::LayersMatch;
 use crate::os::NmapOsDetectRet;
-use crate::utils::find_interface_by_ipv4;
+use crate::utils::dst_ipv4_is_local_net;
 use crate::utils::get_threads_pool;
 use crate::utils::random_port;
 use crate::utils::random_port_multi;
 use crate::IpCheckMethods;
 
+use super::dbparser::MatchPoints;
 use super::dbparser::NmapOsDb;
 use super::operator::icmp_cd;
 use super::operator::icmp_dfi;
@@ -49,6 +58,7 @@ use super::operator::tcp_udp_icmp_t;
 use super::operator::tcp_udp_icmp_tg;
 use super::operator::tcp_w;
 use super::operator::tcp_wx;
+use super::operator::udp_hops;
 use super::operator::udp_ipl;
 use super::operator::udp_rid;
 use super::operator::udp_ripck;
@@ -65,6 +75,82 @@ use super::rr::SEQRR;
 use super::rr::TXRR;
 use super::rr::U1RR;
 
+/* FingerprintParseError */
+#[derive(Debug, Clone)]
+struct FingerprintParseError {
+    test: String,
+}
+
+impl fmt::Display for FingerprintParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "can not parse the fingerprint test: {}", self.test)
+    }
+}
+
+impl FingerprintParseError {
+    pub fn new(test: &str) -> FingerprintParseError {
+        FingerprintParseError {
+            test: test.to_string(),
+        }
+    }
+}
+
+impl Error for FingerprintParseError {}
+
+/// One test of the fingerprint, such as `T1(R=Y%DF=Y%T=40)`.
+struct FingerprintTest {
+    name: String,
+    values: HashMap<String, String>,
+}
+
+impl FingerprintTest {
+    fn parser(name: &str, body: &str) -> Result<FingerprintTest> {
+        let mut values = HashMap::new();
+        for kv in body.split('%') {
+            if kv.len() == 0 {
+                continue;
+            }
+            match kv.split_once('=') {
+                Some((k, v)) => {
+                    values.insert(k.trim().to_string(), v.trim().to_string());
+                }
+                None => {
+                    return Err(FingerprintParseError::new(&format!("{}({})", name, body)).into())
+                }
+            }
+        }
+        Ok(FingerprintTest {
+            name: name.to_string(),
+            values,
+        })
+    }
+    fn get_str(&self, key: &str) -> String {
+        match self.values.get(key) {
+            Some(v) => v.to_string(),
+            None => String::new(),
+        }
+    }
+    /// The test is performed but R is not printed if R == Y.
+    fn get_r(&self) -> String {
+        match self.values.get("R") {
+            Some(v) => v.to_string(),
+            None => String::from("Y"),
+        }
+    }
+    /// The numbers are in hex, the missing value is 0.
+    fn get_hex(&self, key: &str) -> Result<u64> {
+        match self.values.get(key) {
+            Some(v) if v.len() > 0 => match u64::from_str_radix(v, 16) {
+                Ok(n) => Ok(n),
+                Err(_) => {
+                    Err(FingerprintParseError::new(&format!("{}({}={})", self.name, key, v)).into())
+                }
+            },
+            _ => Ok(0),
+        }
+    }
+}
+
 // EXAMPLE
 // SCAN(V=5.05BETA1%D=8/23%OT=22%CT=1%CU=42341%PV=N%DS=0%DC=L%G=Y%TM=4A91CB90%P=i686-pc-linux-gnu)
 // SEQ(SP=C9%GCD=1%ISR=CF%TI=Z%CI=Z%II=I%TS=A)
@@ -102,37 +188,252 @@ pub struct PistolFingerprint {
 impl fmt::Display for PistolFingerprint {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let mut output = format!("{}", self.scan);
-        let seqx_str = format!("\n{}", self.seqx);
-        let opsx_str = format!("\n{}", self.opsx);
-        let winx_str = format!("\n{}", self.winx);
-        let ecnx_str = format!("\n{}", self.ecnx);
-        let t1x_str = format!("\n{}", self.t1x);
-        let t2x_str = format!("\n{}", self.t2x);
-        let t3x_str = format!("\n{}", self.t3x);
-        let t4x_str = format!("\n{}", self.t4x);
-        let t5x_str = format!("\n{}", self.t5x);
-        let t6x_str = format!("\n{}", self.t6x);
-        let t7x_str = format!("\n{}", self.t7x);
-        let u1x_str = format!("\n{}", self.u1x);
-        let iex_str = format!("\n{}", self.iex);
-        output += &seqx_str;
-        output += &opsx_str;
-        output += &winx_str;
-        output += &ecnx_str;
-        output += &t1x_str;
-        output += &t2x_str;
-        output += &t3x_str;
-        output += &t4x_str;
-        output += &t5x_str;
-        output += &t6x_str;
-        output += &t7x_str;
-        output += &u1x_str;
-        output += &iex_str;
+        let tests = vec![
+            (&self.seqx.r, format!("{}", self.seqx)),
+            (&self.opsx.r, format!("{}", self.opsx)),
+            (&self.winx.r, format!("{}", self.winx)),
+            (&self.ecnx.r, format!("{}", self.ecnx)),
+            (&self.t1x.r, format!("{}", self.t1x)),
+            (&self.t2x.r, format!("{}", self.t2x)),
+            (&self.t3x.r, format!("{}", self.t3x)),
+            (&self.t4x.r, format!("{}", self.t4x)),
+            (&self.t5x.r, format!("{}", self.t5x)),
+            (&self.t6x.r, format!("{}", self.t6x)),
+            (&self.t7x.r, format!("{}", self.t7x)),
+            (&self.u1x.r, format!("{}", self.u1x)),
+            (&self.iex.r, format!("{}", self.iex)),
+        ];
+        for (r, test_str) in tests {
+            // like nmap, the test which is not performed is omitted
+            if r.len() > 0 {
+                output += &format!("\n{}", test_str);
+            }
+        }
         write!(f, "{}", output)
     }
 }
 
 impl PistolFingerprint {
+    /// Parse the fingerprint printed by `nmap_format` (or nmap itself, the wrapped `OS:` lines)
+    /// or by `Display`, the tests which are not in the fingerprint are not performed (empty R).
+    pub fn parser(fingerprint: &str) -> Result<PistolFingerprint> {
+        let mut os_lines = Vec::new();
+        let mut other_lines = Vec::new();
+        for line in fingerprint.lines() {
+            let line = line.trim();
+            match line.strip_prefix("OS:") {
+                Some(l) => os_lines.push(l),
+                None => other_lines.push(line),
+            }
+        }
+        // the OS: lines are split without any separator
+        let fingerprint = if os_lines.len() > 0 {
+            os_lines.concat()
+        } else {
+            other_lines.concat()
+        };
+
+        let mut scan = String::new();
+        let mut tests: HashMap<String, FingerprintTest> = HashMap::new();
+        let mut rest = fingerprint.as_str();
+        while let Some(start) = rest.find('(') {
+            let end = match rest[start..].find(')') {
+                Some(e) => start + e,
+                None => return Err(FingerprintParseError::new(rest).into()),
+            };
+            let name = rest[..start].trim();
+            let body = &rest[start + 1..end];
+            if name == "SCAN" {
+                scan = rest[..end + 1].trim().to_string();
+            } else if !tests.contains_key(name) {
+                // newer nmap may print more than one SEQ test, the first one is used
+                let test = FingerprintTest::parser(name, body)?;
+                tests.insert(name.to_string(), test);
+            }
+            rest = &rest[end + 1..];
+        }
+
+        let seqx = match tests.get("SEQ") {
+            Some(t) => SEQX {
+                sp: t.get_hex("SP")? as u32,
+                gcd: t.get_hex("GCD")? as u32,
+                isr: t.get_hex("ISR")? as u32,
+                ti: t.get_str("TI"),
+                ci: t.get_str("CI"),
+                ii: t.get_str("II"),
+                ss: t.get_str("SS"),
+                ts: t.get_str("TS"),
+                r: t.get_r(),
+            },
+            None => SEQX {
+                sp: 0,
+                gcd: 0,
+                isr: 0,
+                ti: String::new(),
+                ci: String::new(),
+                ii: String::new(),
+                ss: String::new(),
+                ts: String::new(),
+                r: String::new(),
+            },
+        };
+        let opsx = match tests.get("OPS") {
+            Some(t) => OPSX {
+                o1: t.get_str("O1"),
+                o2: t.get_str("O2"),
+                o3: t.get_str("O3"),
+                o4: t.get_str("O4"),
+                o5: t.get_str("O5"),
+                o6: t.get_str("O6"),
+                r: t.get_r(),
+            },
+            None => OPSX {
+                o1: String::new(),
+                o2: String::new(),
+                o3: String::new(),
+                o4: String::new(),
+                o5: String::new(),
+                o6: String::new(),
+                r: String::new(),
+            },
+        };
+        let winx = match tests.get("WIN") {
+            Some(t) => WINX {
+                w1: t.get_hex("W1")? as u16,
+                w2: t.get_hex("W2")? as u16,
+                w3: t.get_hex("W3")? as u16,
+                w4: t.get_hex("W4")? as u16,
+                w5: t.get_hex("W5")? as u16,
+                w6: t.get_hex("W6")? as u16,
+                r: t.get_r(),
+            },
+            None => WINX {
+                w1: 0,
+                w2: 0,
+                w3: 0,
+                w4: 0,
+                w5: 0,
+                w6: 0,
+                r: String::new(),
+            },
+        };
+        let ecnx = match tests.get("ECN") {
+            Some(t) => ECNX {
+                r: t.get_r(),
+                df: t.get_str("DF"),
+                t: t.get_hex("T")? as u16,
+                tg: t.get_hex("TG")? as u8,
+                w: t.get_hex("W")? as u16,
+                o: t.get_str("O"),
+                cc: t.get_str("CC"),
+                q: t.get_str("Q"),
+            },
+            None => ECNX {
+                r: String::new(),
+                df: String::new(),
+                t: 0,
+                tg: 0,
+                w: 0,
+                o: String::new(),
+                cc: String::new(),
+                q: String::new(),
+            },
+        };
+        let txx = |name: &str| -> Result<TXX> {
+            let txx = match tests.get(name) {
+                Some(t) => TXX {
+                    name: name.to_string(),
+                    r: t.get_r(),
+                    df: t.get_str("DF"),
+                    t: t.get_hex("T")? as u16,
+                    tg: t.get_hex("TG")? as u8,
+                    w: t.get_hex("W")? as u16,
+                    s: t.get_str("S"),
+                    a: t.get_str("A"),
+                    f: t.get_str("F"),
+                    o: t.get_str("O"),
+                    rd: t.get_hex("RD")? as u32,
+                    q: t.get_str("Q"),
+                },
+                None => TXX {
+                    name: name.to_string(),
+                    r: String::new(),
+                    df: String::new(),
+                    t: 0,
+                    tg: 0,
+                    w: 0,
+                    s: String::new(),
+                    a: String::new(),
+                    f: String::new(),
+                    o: String::new(),
+                    rd: 0,
+                    q: String::new(),
+                },
+            };
+            Ok(txx)
+        };
+        let u1x = match tests.get("U1") {
+            Some(t) => U1X {
+                r: t.get_r(),
+                df: t.get_str("DF"),
+                t: t.get_hex("T")? as u16,
+                tg: t.get_hex("TG")? as u8,
+                ipl: t.get_hex("IPL")? as usize,
+                un: t.get_hex("UN")? as u32,
+                ripl: t.get_str("RIPL"),
+                rid: t.get_str("RID"),
+                ripck: t.get_str("RIPCK"),
+                ruck: t.get_str("RUCK"),
+                rud: t.get_str("RUD"),
+            },
+            None => U1X {
+                r: String::new(),
+                df: String::new(),
+                t: 0,
+                tg: 0,
+                ipl: 0,
+                un: 0,
+                ripl: String::new(),
+                rid: String::new(),
+                ripck: String::new(),
+                ruck: String::new(),
+                rud: String::new(),
+            },
+        };
+        let iex = match tests.get("IE") {
+            Some(t) => IEX {
+                r: t.get_r(),
+                dfi: t.get_str("DFI"),
+                t: t.get_hex("T")? as u16,
+                tg: t.get_hex("TG")? as u8,
+                cd: t.get_str("CD"),
+            },
+            None => IEX {
+                r: String::new(),
+                dfi: String::new(),
+                t: 0,
+                tg: 0,
+                cd: String::new(),
+            },
+        };
+
+        Ok(PistolFingerprint {
+            scan,
+            seqx,
+            opsx,
+            winx,
+            ecnx,
+            t1x: txx("T1")?,
+            t2x: txx("T2")?,
+            t3x: txx("T3")?,
+            t4x: txx("T4")?,
+            t5x: txx("T5")?,
+            t6x: txx("T6")?,
+            t7x: txx("T7")?,
+            u1x,
+            iex,
+        })
+    }
     pub fn nmap_format(&self) -> String {
         let interval = 72;
         let mut ret = String::new();
@@ -151,9 +452,11 @@ impl PistolFingerprint {
     }
 }
 
+/// The `dst_mac` is the mac address of the target if it is on the same ethernet network,
+/// the `hops` is the number of routers between us and the target from the U1 probe (`udp_hops`).
 pub fn get_scan_line(
     dst_mac: Option<MacAddr>,
-    dst_open_tcp_port: u16,
+    dst_open_tcp_port: Option<u16>,
     dst_closed_tcp_port: u16,
     dst_closed_udp_port: u16,
     dst_addr: IpAddr,
@@ -167,6 +470,11 @@ pub fn get_scan_line(
     let date = format!("{}", now.format("%-m/%-d"));
     // Private IP space (PV) is Y if the target is on the 10.0.0.0/8, 172.16.0.0/12, or 192.168.0.0/16 private networks (RFC 1918).
     // Otherwise it is N.
+    let pv = if dst_addr.is_loopback() || !dst_addr.is_global_x() {
+        "Y"
+    } else {
+        "N"
+    };
     // Network distance (DS) is the network hop distance from the target. It is 0 if the target is localhost, 1 if directly connected on an ethernet network, or the exact distance if discovered by Nmap.
     // If the distance is unknown, this test is omitted.
     // The distance calculation method (DC) indicates how the network distance (DS) was calculated.
@@ -174,57 +482,57 @@ pub fn get_scan_line(
     // L for localhost (DS=0);
     // D for a direct subnet connection (DS=1);
     // I for a TTL calculation based on an ICMP response to the U1 OS detection probe;
-    // and T for a count of traceroute hops (I don't particularly understand how this sentence is implemented).
+    // and T for a count of traceroute hops (we do not run the traceroute, so it is never used).
     // This test exists because it is possible for the ICMP TTL calculation to be incorrect when intermediate machines change the TTL;
     // it distinguishes between a host that is truly directly connected and what may be just a miscalculation.
-    let (pv, ds, dc) = if dst_addr.is_loopback() {
-        ("Y", 0, "L")
-    } else if !dst_addr.is_global_x() {
-        ("Y", 1, "D")
+    let distance = if dst_addr.is_loopback() {
+        Some((0, "L"))
+    } else if dst_mac.is_some() {
+        Some((1, "D"))
     } else {
-        ("N", hops.unwrap(), "I")
+        match hops {
+            Some(h) => Some((h as u16 + 1, "I")),
+            None => None,
+        }
+    };
+    let ds_dc = match distance {
+        Some((ds, dc)) => format!("DS={ds}%DC={dc}%"),
+        None => String::new(),
     };
     // Good results (G) is Y if conditions and results seem good enough to submit this fingerprint to Nmap.Org.
     // It is N otherwise. Unless you force them by enabling debugging (-d) or extreme verbosity (-vv), G=N fingerprints aren't printed by Nmap.
     let g = if good_results { "Y" } else { "N" };
     // Target MAC prefix (M) is the first six hex digits of the target MAC address, which correspond to the vendor name.
-    // Leading zeros are not included. This field is omitted unless the target is on the same ethernet network (DS=1).
-    let m = if ds == 1 {
-        let mut dst_mac_vec: [u8; 6] = dst_mac.unwrap().octets();
-        let mut dst_mac_str = String::new();
-        for m in &mut dst_mac_vec[0..3] {
-            dst_mac_str = format!("{}{:X}", dst_mac_str, m);
-        }
-        dst_mac_str
-    } else {
-        "".to_string()
+    // All the six digits are printed with the leading zeros (M=000C29), the same as the fingerprints nmap prints.
+    // This field is omitted unless the target is on the same ethernet network (DS=1).
+    let m = match distance {
+        Some((1, _)) => match dst_mac {
+            Some(dst_mac) => {
+                let o = dst_mac.octets();
+                format!("M={:02X}{:02X}{:02X}%", o[0], o[1], o[2])
+            }
+            None => String::new(),
+        },
+        _ => String::new(),
     };
     // The OS scan time (TM) is provided in Unix time_t format (in hexadecimal).
     let now: DateTime<Utc> = Utc::now();
     let tm = format!("{:X}", now.timestamp());
     // The platform Nmap was compiled for is given in the P field.
     let p = "RUST";
+    // The open port (OT) is omitted if no open port is found.
+    let ot = match dst_open_tcp_port {
+        Some(o) => format!("OT={}%", o),
+        None => String::new(),
+    };
+    // The IPv6 fingerprint has the E=6 field.
+    let e = match dst_addr {
+        IpAddr::V4(_) => "",
+        IpAddr::V6(_) => "E=6%",
+    };
 
     // SCAN(V=5.05BETA1%D=8/23%OT=22%CT=1%CU=42341%PV=N%DS=0%DC=L%G=Y%TM=4A91CB90%P=i686-pc-linux-gnu)
-    let info_str = match dst_addr {
-        IpAddr::V4(_) => {
-            let info_str = if m.len() > 0 {
-                format!("SCAN(V={v}%D={date}%OT={dst_open_tcp_port}%CT={dst_closed_tcp_port}%CU={dst_closed_udp_port}PV={pv}%DS={ds}%DC={dc}%G={g}%M={m}%TM={tm}%P={p})", )
-            } else {
-                format!("SCAN(V={v}%D={date}%OT={dst_open_tcp_port}%CT={dst_closed_tcp_port}%CU={dst_closed_udp_port}PV={pv}%DS={ds}%DC={dc}%G={g}%TM={tm}%P={p})", )
-            };
-            info_str
-        }
-        IpAddr::V6(_) => {
-            let info_str = if m.len() > 0 {
-                format!("SCAN(V={v}%E=6%D={date}%OT={dst_open_tcp_port}%CT={dst_closed_tcp_port}%CU={dst_closed_udp_port}PV={pv}%DS={ds}%DC={dc}%G={g}%M={m}%TM={tm}%P={p})", )
-            } else {
-                format!("SCAN(V={v}%E=6%D={date}%OT={dst_open_tcp_port}%CT={dst_closed_tcp_port}%CU={dst_closed_udp_port}PV={pv}%DS={ds}%DC={dc}%G={g}%TM={tm}%P={p})", )
-            };
-            info_str
-        }
-    };
-    info_str
+    format!("SCAN(V={v}%{e}D={date}%{ot}CT={dst_closed_tcp_port}%CU={dst_closed_udp_port}%PV={pv}%{ds_dc}G={g}%{m}TM={tm}%P={p})")
 }
 
 fn send_seq_probes(
@@ -628,6 +936,7 @@ fn send_all_probes(
         ecn,
         tx,
         u1,
+        scan: None,
     };
 
     Ok(ap)
@@ -644,7 +953,7 @@ pub struct SEQX {
     pub ii: String,
     pub ss: String,
     pub ts: String,
-    // Fit the db file.
+    // Fit the db file, empty if the test is not performed (no open port).
     pub r: String,
 }
 
@@ -686,6 +995,23 @@ impl fmt::Display for SEQX {
     }
 }
 
+impl SEQX {
+    /// The test is not performed, it is omitted from the fingerprint.
+    fn omitted() -> SEQX {
+        SEQX {
+            sp: 0,
+            gcd: 0,
+            isr: 0,
+            ti: String::new(),
+            ci: String::new(),
+            ii: String::new(),
+            ss: String::new(),
+            ts: String::new(),
+            r: String::new(),
+        }
+    }
+}
+
 pub fn seq_fingerprint(ap: &AllPacketRR) -> Result<SEQX> {
     let rynum = |rvec: Vec<String>| -> usize {
         let mut num = 0;
@@ -824,6 +1150,21 @@ impl fmt::Display for OPSX {
     }
 }
 
+impl OPSX {
+    /// The test is not performed, it is omitted from the fingerprint.
+    fn omitted() -> OPSX {
+        OPSX {
+            o1: String::new(),
+            o2: String::new(),
+            o3: String::new(),
+            o4: String::new(),
+            o5: String::new(),
+            o6: String::new(),
+            r: String::new(),
+        }
+    }
+}
+
 pub fn ops_fingerprint(ap: &AllPacketRR) -> Result<OPSX> {
     let rops = |rvec: Vec<String>| -> bool {
         let mut flag = true;
@@ -945,6 +1286,21 @@ impl fmt::Display for WINX {
     }
 }
 
+impl WINX {
+    /// The test is not performed, it is omitted from the fingerprint.
+    fn omitted() -> WINX {
+        WINX {
+            w1: 0,
+            w2: 0,
+            w3: 0,
+            w4: 0,
+            w5: 0,
+            w6: 0,
+            r: String::new(),
+        }
+    }
+}
+
 pub fn win_fingerprint(ap: &AllPacketRR) -> Result<WINX> {
     let rwin = |rvec: Vec<String>| -> bool {
         let mut flag = true;
@@ -1081,6 +1437,22 @@ impl fmt::Display for ECNX {
     }
 }
 
+impl ECNX {
+    /// The test is not performed, it is omitted from the fingerprint.
+    fn omitted() -> ECNX {
+        ECNX {
+            r: String::new(),
+            df: String::new(),
+            t: 0,
+            tg: 0,
+            w: 0,
+            o: String::new(),
+            cc: String::new(),
+            q: String::new(),
+        }
+    }
+}
+
 pub fn ecn_fingerprint(ap: &AllPacketRR) -> Result<ECNX> {
     let r = tcp_udp_icmp_r(&ap.ecn.ecn.response)?;
     let (df, t, tg, w, o, cc, q) = match r.as_str() {
@@ -1250,6 +1622,26 @@ impl fmt::Display for TXX {
     }
 }
 
+impl TXX {
+    /// The test is not performed, it is omitted from the fingerprint.
+    fn omitted(name: &str) -> TXX {
+        TXX {
+            name: name.to_string(),
+            r: String::new(),
+            df: String::new(),
+            t: 0,
+            tg: 0,
+            w: 0,
+            s: String::new(),
+            a: String::new(),
+            f: String::new(),
+            o: String::new(),
+            rd: 0,
+            q: String::new(),
+        }
+    }
+}
+
 fn _tx_fingerprint(tx: &RequestAndResponse, u1rr: &U1RR, name: &str) -> Result<TXX> {
     let r = tcp_udp_icmp_r(&tx.response)?;
     let (df, t, tg, w, s, a, f, o, rd, q) = match r.as_str() {
@@ -1361,9 +1753,9 @@ impl fmt::Display for U1X {
             // This TTL guess field is not printed in a subject fingerprint if the actual TTL (T) value was discovered.
             let tg_str = if first_elem {
                 first_elem = false;
-                format!("TG={}", self.tg)
+                format!("TG={:X}", self.tg)
             } else {
-                format!("%TG={}", self.tg)
+                format!("%TG={:X}", self.tg)
             };
             output += &tg_str;
         }
@@ -1525,9 +1917,9 @@ impl fmt::Display for IEX {
             // This TTL guess field is not printed in a subject fingerprint if the actual TTL (T) value was discovered.
             let tg_str = if first_elem {
                 first_elem = false;
-                format!("TG={}", self.tg)
+                format!("TG={:X}", self.tg)
             } else {
-                format!("%TG={}", self.tg)
+                format!("%TG={:X}", self.tg)
             };
             output += &tg_str;
         }
@@ -1570,84 +1962,204 @@ pub fn ie_fingerprint(ap: &AllPacketRR) -> Result<IEX> {
     Ok(IEX { r, dfi, t, tg, cd })
 }
 
-fn top_k_score(score_vec: &[usize], k: usize) -> Vec<usize> {
-    let find_position_one = |score_vec: &[usize], value: usize| -> Option<usize> {
-        for (i, s) in score_vec.iter().enumerate() {
-            if *s == value {
-                return Some(i);
-            }
+/// Match the fingerprint with the db, returns the `top_k` best results whose accuracy is not lower than `guess_threshold`.
+pub fn os_match(
+    fingerprint: &PistolFingerprint,
+    nmap_os_db: &[NmapOsDb],
+    match_points: &MatchPoints,
+    top_k: usize,
+    guess_threshold: f64,
+) -> Vec<NmapOsDetectRet> {
+    let mut dr_vec = Vec::new();
+    for n in nmap_os_db {
+        let (score, total) = n.check(fingerprint, match_points);
+        let accuracy = if total > 0 {
+            score as f64 / total as f64
+        } else {
+            0.0
+        };
+        if accuracy >= guess_threshold {
+            let dr = NmapOsDetectRet {
+                score,
+                total,
+                accuracy,
+                db: n.clone(),
+            };
+            dr_vec.push(dr);
         }
-        None
-    };
+    }
+    // the sort is stable, the entries with the same accuracy keep the order of the db
+    dr_vec.sort_by(|a, b| b.accuracy.total_cmp(&a.accuracy));
+    dr_vec.truncate(top_k);
+    dr_vec
+}
 
-    let mut score_vec = score_vec.to_vec();
-    let mut top_k_vec = Vec::new();
-    for _ in 0..k {
-        let mut max_score = 0;
-        for s in &score_vec {
-            if *s > max_score {
-                max_score = *s;
-            }
-        }
+/// Returns the fingerprint of all the probes and responses, from `send_all_probes` or a saved capture.
+/// Only the tests of the closed ports are performed if `reduced` (no open port).
+pub fn os_fingerprint(ap: &AllPacketRR, scan: String, reduced: bool) -> Result<PistolFingerprint> {
+    let (seqx, opsx, winx, ecnx, t1x, t2x, t3x, t4x, t5x, t6x, t7x) = if reduced {
+        // The probes of the open port were sent to the closed port, their responses are not computed.
+        let t5x = _tx_fingerprint(&ap.tx.t5, &ap.u1, "T5")?;
+        let t6x = _tx_fingerprint(&ap.tx.t6, &ap.u1, "T6")?;
+        let t7x = _tx_fingerprint(&ap.tx.t7, &ap.u1, "T7")?;
+        (
+            SEQX::omitted(),
+            OPSX::omitted(),
+            WINX::omitted(),
+            ECNX::omitted(),
+            TXX::omitted("T1"),
+            TXX::omitted("T2"),
+            TXX::omitted("T3"),
+            TXX::omitted("T4"),
+            t5x,
+            t6x,
+            t7x,
+        )
+    } else {
+        // Use seq to judge target is alive or not.
+        let seqx = seq_fingerprint(ap)?;
+        let opsx = ops_fingerprint(ap)?;
+        let winx = win_fingerprint(ap)?;
+        let ecnx = ecn_fingerprint(ap)?;
+        let (t1x, t2x, t3x, t4x, t5x, t6x, t7x) = tx_fingerprint(ap)?;
+        (seqx, opsx, winx, ecnx, t1x, t2x, t3x, t4x, t5x, t6x, t7x)
+    };
+    let u1x = u1_fingerprint(ap)?;
+    let iex = ie_fingerprint(ap)?;
+
+    Ok(PistolFingerprint {
+        scan,
+        seqx,
+        opsx,
+        winx,
+        ecnx,
+        t1x,
+        t2x,
+        t3x,
+        t4x,
+        t5x,
+        t6x,
+        t7x,
+        u1x,
+        iex,
+    })
+}
 
-        loop {
-            match find_position_one(&score_vec, max_score) {
-                Some(p) => {
-                    score_vec.remove(p);
-                }
-                None => break,
-            }
+/// Returns the target address, the open tcp port, the closed tcp port and the closed udp port of the probes.
+/// The open tcp port is `None` if the probes of it were sent to the closed port.
+fn capture_ports(ap: &AllPacketRR) -> Result<(Ipv4Addr, Option<u16>, u16, u16)> {
+    let tcp_dst = |request: &[u8]| -> Result<(Ipv4Addr, u16)> {
+        let ipv4_packet = match Ipv4Packet::new(request) {
+            Some(p) => p,
+            None => return Err(GetIpv4PacketFailed::new().into()),
+        };
+        match TcpPacket::new(ipv4_packet.payload()) {
+            Some(t) => Ok((ipv4_packet.get_destination(), t.get_destination())),
+            None => Err(GetTcpPacketFailed::new().into()),
         }
-
-        top_k_vec.push(max_score);
-    }
-    top_k_vec
+    };
+    let (dst_ipv4, open_tcp_port) = tcp_dst(&ap.seq.seq1.request)?;
+    let (_, dst_closed_tcp_port) = tcp_dst(&ap.tx.t5.request)?;
+    let dst_closed_udp_port = match Ipv4Packet::new(&ap.u1.u1.request) {
+        Some(ipv4_packet) => match UdpPacket::new(ipv4_packet.payload()) {
+            Some(u) => u.get_destination(),
+            None => return Err(GetUdpPacketFailed::new().into()),
+        },
+        None => return Err(GetIpv4PacketFailed::new().into()),
+    };
+    let dst_open_tcp_port = if open_tcp_port == dst_closed_tcp_port {
+        None
+    } else {
+        Some(open_tcp_port)
+    };
+    Ok((
+        dst_ipv4,
+        dst_open_tcp_port,
+        dst_closed_tcp_port,
+        dst_closed_udp_port,
+    ))
 }
 
-fn find_position_multi(score_vec: &[usize], value: usize) -> Vec<usize> {
-    let mut position = Vec::new();
-    for (i, s) in score_vec.iter().enumerate() {
-        if *s == value {
-            position.push(i)
+/// Fingerprint and match a saved capture again without the network,
+/// the SCAN line is rebuilt from the packets (the target mac address is unknown).
+pub fn os_replay(
+    ap: &AllPacketRR,
+    nmap_os_db: &[NmapOsDb],
+    match_points: &MatchPoints,
+    top_k: usize,
+    guess_threshold: f64,
+) -> Result<(PistolFingerprint, Vec<NmapOsDetectRet>)> {
+    let (dst_ipv4, dst_open_tcp_port, dst_closed_tcp_port, dst_closed_udp_port) =
+        capture_ports(ap)?;
+    // the mac address and the time are only known at the probing
+    let scan = match &ap.scan {
+        Some(scan) => scan.clone(),
+        None => {
+            let hops = udp_hops(&ap.u1)?;
+            let good_results = dst_open_tcp_port.is_some();
+            get_scan_line(
+                None,
+                dst_open_tcp_port,
+                dst_closed_tcp_port,
+                dst_closed_udp_port,
+                dst_ipv4.into(),
+                hops,
+                good_results,
+            )
         }
-    }
-    position
+    };
+    let fingerprint = os_fingerprint(ap, scan, dst_open_tcp_port.is_none())?;
+    let dr_vec = os_match(
+        &fingerprint,
+        nmap_os_db,
+        match_points,
+        top_k,
+        guess_threshold,
+    );
+    Ok((fingerprint, dr_vec))
 }
 
 pub fn os_probe(
     src_ipv4: Ipv4Addr,
     src_port: Option<u16>,
     dst_ipv4: Ipv4Addr,
-    dst_open_tcp_port: u16,
+    dst_open_tcp_port: Option<u16>,
     dst_closed_tcp_port: u16,
     dst_closed_udp_port: u16,
     nmap_os_db: Vec<NmapOsDb>,
+    match_points: MatchPoints,
     top_k: usize,
+    guess_threshold: f64,
     timeout: Duration,
-) -> Result<(PistolFingerprint, Vec<NmapOsDetectRet>)> {
-    // Check target.
-    let dst_mac = match find_interface_by_ipv4(src_ipv4) {
-        Some(interface) => match interface.mac {
-            Some(m) => m,
-            None => return Err(CanNotFoundMacAddress::new().into()),
-        },
-        None => return Err(CanNotFoundInterface::new().into()),
-    };
+) -> Result<(PistolFingerprint, Vec<NmapOsDetectRet>, AllPacketRR)> {
+    // The mac address of the target is only known if it is on the same ethernet network.
+    let dst_mac =
+        if dst_ipv4_is_local_net(dst_ipv4) && dst_ipv4 != src_ipv4 && !dst_ipv4.is_loopback() {
+            neighbour_mac(src_ipv4, dst_ipv4)?
+        } else {
+            None
+        };
 
-    let ap = send_all_probes(
+    // Without the open port, the probes of the open port are sent to the closed port,
+    // and the tests of them are dropped (SEQ, OPS, WIN, ECN and T1-T4).
+    let open_tcp_port = match dst_open_tcp_port {
+        Some(o) => o,
+        None => dst_closed_tcp_port,
+    };
+    let mut ap = send_all_probes(
         src_ipv4,
         src_port,
         dst_ipv4,
-        dst_open_tcp_port,
+        open_tcp_port,
         dst_closed_tcp_port,
         dst_closed_udp_port,
         timeout,
     )?;
 
-    let hops = None;
-    let good_results = true;
+    let hops = udp_hops(&ap.u1)?;
+    let good_results = dst_open_tcp_port.is_some();
     let scan = get_scan_line(
-        Some(dst_mac),
+        dst_mac,
         dst_open_tcp_port,
         dst_closed_tcp_port,
         dst_closed_udp_port,
@@ -1655,64 +2167,18 @@ pub fn os_probe(
         hops,
         good_results,
     );
-
-    // Use seq to judge target is alive or not.
-    let seqx = seq_fingerprint(&ap);
-    match seqx {
-        Ok(seqx) => {
-            let opsx = ops_fingerprint(&ap)?;
-            let winx = win_fingerprint(&ap)?;
-            let ecnx = ecn_fingerprint(&ap)?;
-            let (t1x, t2x, t3x, t4x, t5x, t6x, t7x) = tx_fingerprint(&ap)?;
-            let u1x = u1_fingerprint(&ap)?;
-            let iex = ie_fingerprint(&ap)?;
-
-            let fingerprint = PistolFingerprint {
-                scan,
-                seqx,
-                opsx,
-                winx,
-                ecnx,
-                t1x,
-                t2x,
-                t3x,
-                t4x,
-                t5x,
-                t6x,
-                t7x,
-                u1x,
-                iex,
-            };
-
-            let mut score_vec = Vec::new();
-            let mut total_vec = Vec::new();
-            for n in &nmap_os_db {
-                let (score, total) = n.check(&fingerprint);
-                score_vec.push(score);
-                total_vec.push(total);
-            }
-
-            let top_k_score_vec = top_k_score(&score_vec, top_k);
-            let mut top_k_index_vec = Vec::new();
-            for k in top_k_score_vec {
-                for p in find_position_multi(&score_vec, k) {
-                    top_k_index_vec.push(p);
-                }
-            }
-
-            let mut dr_vec = Vec::new();
-            for i in top_k_index_vec {
-                let dr = NmapOsDetectRet {
-                    score: score_vec[i],
-                    total: total_vec[i],
-                    db: nmap_os_db[i].clone(),
-                };
-                dr_vec.push(dr);
-            }
-            Ok((fingerprint, dr_vec))
-        }
-        Err(e) => Err(e),
-    }
+    // saved with the probes for the replay
+    ap.scan = Some(scan.clone());
+
+    let fingerprint = os_fingerprint(&ap, scan, dst_open_tcp_port.is_none())?;
+    let dr_vec = os_match(
+        &fingerprint,
+        &nmap_os_db,
+        &match_points,
+        top_k,
+        guess_threshold,
+    );
+    Ok((fingerprint, dr_vec, ap))
 }
 
 #[cfg(test)]
@@ -1726,6 +2192,119 @@ mod tests {
         println!("{c}");
     }
     #[test]
+    fn test_fingerprint_parser() {
+        let fingerprint = "SCAN(V=5.05BETA1%D=8/23%OT=22%CT=1%CU=42341%PV=N%DS=0%DC=L%G=Y%TM=4A91CB90%P=i686-pc-linux-gnu)
+SEQ(SP=C9%GCD=1%ISR=CF%TI=Z%CI=Z%II=I%TS=A)
+OPS(O1=M400CST11NW5%O2=M400CST11NW5%O3=M400CNNT11NW5%O4=M400CST11NW5%O5=M400CST11NW5%O6=M400CST11)
+WIN(W1=8000%W2=8000%W3=8000%W4=8000%W5=8000%W6=8000)
+ECN(R=Y%DF=Y%T=40%W=8018%O=M400CNNSNW5%CC=N%Q=)
+T1(R=Y%DF=Y%T=40%S=O%A=S+%F=AS%RD=0%Q=)
+T2(R=N)
+T3(R=Y%DF=Y%T=40%W=8000%S=O%A=S+%F=AS%O=M400CST11NW5%RD=0%Q=)
+T4(R=Y%DF=Y%T=40%W=0%S=A%A=Z%F=R%O=%RD=0%Q=)
+T5(R=Y%DF=Y%T=40%W=0%S=Z%A=S+%F=AR%O=%RD=0%Q=)
+T6(R=Y%DF=Y%T=40%W=0%S=A%A=Z%F=R%O=%RD=0%Q=)
+T7(R=Y%DF=Y%T=40%W=0%S=Z%A=S+%F=AR%O=%RD=0%Q=)
+U1(R=Y%DF=N%T=40%IPL=164%UN=0%RIPL=G%RID=G%RIPCK=G%RUCK=G%RUD=G)
+IE(R=Y%DFI=N%T=40%CD=S)";
+        let fp = PistolFingerprint::parser(fingerprint).unwrap();
+        assert_eq!(fp.seqx.isr, 0xCF);
+        assert_eq!(fp.opsx.o3, "M400CNNT11NW5");
+        assert_eq!(fp.ecnx.w, 0x8018);
+        assert_eq!(fp.t2x.r, "N");
+        assert_eq!(fp.t3x.a, "S+");
+        assert_eq!(fp.u1x.ipl, 0x164);
+        assert_eq!(fp.iex.cd, "S");
+
+        // the wrapped OS: lines give the same fingerprint
+        let nmap_format = fp.nmap_format();
+        println!("{}", nmap_format);
+        let fp_2 = PistolFingerprint::parser(&nmap_format).unwrap();
+        assert_eq!(format!("{}", fp), format!("{}", fp_2));
+
+        // the tests which are not in the fingerprint are not performed
+        let fp = PistolFingerprint::parser("OS:SCAN(V=7.94%D=1/2)T5(R=Y%DF=Y%T=40%W=0%S=Z%A=S+%F=AR%O=%RD\nOS:=0%Q=)U1(R=N)IE(R=N)").unwrap();
+        assert_eq!(fp.seqx.r, "");
+        assert_eq!(fp.t5x.f, "AR");
+        assert_eq!(fp.u1x.r, "N");
+
+        assert!(PistolFingerprint::parser("SEQ(SP=XYZ)").is_err());
+    }
+    #[test]
+    fn test_os_replay() {
+        let src_ipv4 = Ipv4Addr::new(192, 168, 72, 128);
+        let dst_ipv4 = Ipv4Addr::new(192, 168, 72, 135);
+        let (o, c, u) = (22, 8765, 9876);
+        let rr = |request: Vec<u8>| RequestAndResponse {
+            request,
+            response: vec![],
+        };
+        // the target does not respond to any probes
+        let ap = AllPacketRR {
+            seq: SEQRR {
+                seq1: rr(packet::seq_packet_1_layer3(src_ipv4, 40001, dst_ipv4, o).unwrap()),
+                seq2: rr(packet::seq_packet_2_layer3(src_ipv4, 40002, dst_ipv4, o).unwrap()),
+                seq3: rr(packet::seq_packet_3_layer3(src_ipv4, 40003, dst_ipv4, o).unwrap()),
+                seq4: rr(packet::seq_packet_4_layer3(src_ipv4, 40004, dst_ipv4, o).unwrap()),
+                seq5: rr(packet::seq_packet_5_layer3(src_ipv4, 40005, dst_ipv4, o).unwrap()),
+                seq6: rr(packet::seq_packet_6_layer3(src_ipv4, 40006, dst_ipv4, o).unwrap()),
+                elapsed: 0.5,
+            },
+            ie: IERR {
+                ie1: rr(packet::ie_packet_1_layer3(src_ipv4, dst_ipv4, 1).unwrap()),
+                ie2: rr(packet::ie_packet_2_layer3(src_ipv4, dst_ipv4, 2).unwrap()),
+            },
+            ecn: ECNRR {
+                ecn: rr(packet::ecn_packet_layer3(src_ipv4, 40007, dst_ipv4, o).unwrap()),
+            },
+            tx: TXRR {
+                t2: rr(packet::t2_packet_layer3(src_ipv4, 40008, dst_ipv4, o).unwrap()),
+                t3: rr(packet::t3_packet_layer3(src_ipv4, 40009, dst_ipv4, o).unwrap()),
+                t4: rr(packet::t4_packet_layer3(src_ipv4, 40010, dst_ipv4, o).unwrap()),
+                t5: rr(packet::t5_packet_layer3(src_ipv4, 40011, dst_ipv4, c).unwrap()),
+                t6: rr(packet::t6_packet_layer3(src_ipv4, 40012, dst_ipv4, c).unwrap()),
+                t7: rr(packet::t7_packet_layer3(src_ipv4, 40013, dst_ipv4, c).unwrap()),
+            },
+            u1: U1RR {
+                u1: rr(packet::udp_packet_layer3(src_ipv4, 40014, dst_ipv4, u).unwrap()),
+            },
+            scan: None,
+        };
+        let path = std::env::temp_dir().join("pistol_test_os_replay.json");
+        let path = path.to_str().unwrap();
+        ap.save(path).unwrap();
+        let ap_2 = AllPacketRR::load(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(ap.seq.seq3.request, ap_2.seq.seq3.request);
+        assert_eq!(ap.u1.u1.request, ap_2.u1.u1.request);
+
+        let (fingerprint, detects) = os_replay(&ap_2, &[], &MatchPoints::new(), 1, 0.85).unwrap();
+        println!("{}", fingerprint);
+        assert!(fingerprint
+            .scan
+            .contains("%OT=22%CT=8765%CU=9876%PV=Y%G=Y%TM="));
+        assert_eq!(fingerprint.seqx.r, "N");
+        assert_eq!(fingerprint.iex.r, "N");
+        assert_eq!(detects.len(), 0);
+
+        // the SCAN line saved at the probing is reused
+        let scan = "SCAN(V=PISTOL%D=8/23%OT=22%CT=8765%CU=9876%PV=Y%DS=1%DC=D%G=Y%M=000C29%TM=4A91CB90%P=RUST)";
+        let mut ap_3 = ap.clone();
+        ap_3.scan = Some(scan.to_string());
+        ap_3.save(path).unwrap();
+        let ap_3 = AllPacketRR::load(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        let (fingerprint, _) = os_replay(&ap_3, &[], &MatchPoints::new(), 1, 0.85).unwrap();
+        assert_eq!(fingerprint.scan, scan);
+
+        // without the open port, the tests of it are omitted
+        let reduced = os_fingerprint(&ap, String::from("SCAN()"), true).unwrap();
+        assert_eq!(reduced.seqx.r, "");
+        assert_eq!(reduced.t1x.r, "");
+        assert_eq!(reduced.t5x.r, "N");
+        assert!(!format!("{}", reduced).contains("SEQ("));
+    }
+    #[test]
     fn test_scan_str() {
         let dst_mac = MacAddr::new(01, 12, 34, 56, 00, 90);
         let dst_open_tcp_port = 22;
@@ -1734,7 +2313,7 @@ mod tests {
         let dst_ipv4 = Ipv4Addr::new(192, 168, 72, 135);
         let ret = get_scan_line(
             Some(dst_mac),
-            dst_open_tcp_port,
+            Some(dst_open_tcp_port),
             dst_closed_tcp_port,
             dst_closed_udp_port,
             dst_ipv4.into(),
@@ -1742,6 +2321,29 @@ mod tests {
             true,
         );
         println!("{}", ret);
+        assert!(ret.contains("%CT=1%CU=42341%PV=Y%DS=1%DC=D%G=Y%M=010C22%TM="));
+
+        // the global target without the mac address, the distance from the U1 probe
+        let dst_ipv4 = Ipv4Addr::new(1, 1, 1, 1);
+        let ret = get_scan_line(None, None, 1, 42341, dst_ipv4.into(), Some(9), false);
+        assert!(ret.contains("%D="));
+        assert!(!ret.contains("OT="));
+        assert!(ret.contains("%CT=1%CU=42341%PV=N%DS=10%DC=I%G=N%TM="));
+
+        // the distance is unknown
+        let ret = get_scan_line(None, None, 1, 42341, dst_ipv4.into(), None, false);
+        assert!(ret.contains("%PV=N%G=N%TM="));
+
+        let ret = get_scan_line(
+            None,
+            Some(22),
+            1,
+            42341,
+            Ipv4Addr::LOCALHOST.into(),
+            None,
+            true,
+        );
+        assert!(ret.contains("%OT=22%CT=1%CU=42341%PV=Y%DS=0%DC=L%G=Y%TM="));
     }
     #[test]
     fn test_seq_probes() {
@@ -1,4 +1,8 @@
 use anyhow::Result;
+use pnet::packet::ipv6::Ipv6Packet;
+use pnet::packet::tcp::TcpPacket;
+use pnet::packet::udp::UdpPacket;
+use pnet::packet::Packet;
 use std::fmt;
 use std::iter::zip;
 use std::net::Ipv6Addr;
@@ -8,20 +12,24 @@ use std::time::Duration;
 use std::time::Instant;
 use std::time::SystemTime;
 
-use crate::errors::CanNotFoundInterface;
-use crate::errors::CanNotFoundMacAddress;
+use crate::errors::GetIpv6PacketFailed;
+use crate::errors::GetTcpPacketFailed;
+use crate::errors::GetUdpPacketFailed;
+use crate::errors::Ipv6ModelError;
 use crate::layers::layer3_ipv6_send;
+use crate::layers::neighbour_mac6;
 use crate::layers::Layer3Match;
 use crate::layers::Layer4MatchIcmpv6;
 use crate::layers::Layer4MatchTcpUdp;
 use crate::layers::LayersMatch;
 
-use crate::utils::find_interface_by_ipv6;
+use crate::utils::dst_ipv6_is_local_net;
 use crate::utils::get_threads_pool;
 use crate::utils::random_port;
 use crate::utils::random_port_multi;
 
 use super::operator6::apply_scale;
+use super::operator6::udp_hops6;
 use super::operator6::vectorize;
 use super::osscan::get_scan_line;
 use super::packet6;
@@ -1023,10 +1031,10 @@ fn send_all_probes(
     Ok(ap)
 }
 
-fn predict_value(features: &[f64], wvec: &[Vec<f64>]) -> Vec<f64> {
+pub fn predict_value(features: &[f64], wvec: &[Vec<f64>]) -> Vec<f64> {
     /*
-       features [695]
-       wvec [92, 695]
+       features [features]
+       wvec [classes, features]
 
     */
     let vec_time = |x: &[f64], y: &[f64]| -> f64 {
@@ -1038,22 +1046,23 @@ fn predict_value(features: &[f64], wvec: &[Vec<f64>]) -> Vec<f64> {
         sum
     };
 
-    let mut dec_value = [0f64; 92];
+    let mut dec_value = vec![0f64; wvec.len()];
     for (idx, w) in wvec.iter().enumerate() {
         dec_value[idx] = vec_time(features, w);
     }
 
-    let dec_value = dec_value.map(|x| 1.0 / (1.0 + (-x as f64).exp()));
-    dec_value.to_vec()
+    dec_value
+        .iter()
+        .map(|x| 1.0 / (1.0 + (-x as f64).exp()))
+        .collect()
 }
 
 fn novelty_of(features: &[f64], mean: &[f64], variance: &[f64]) -> f64 {
-    assert_eq!(features.len(), 695);
-    assert_eq!(mean.len(), 695);
-    assert_eq!(variance.len(), 695);
+    assert_eq!(features.len(), mean.len());
+    assert_eq!(features.len(), variance.len());
 
     let mut sum = 0.0;
-    for i in 0..695 {
+    for i in 0..features.len() {
         let d = features[i] - mean[i];
         // print!("{:.3}, ", d);
         let mut v = variance[i];
@@ -1105,15 +1114,14 @@ pub fn os_probe6(
     top_k: usize,
     linear: Linear,
     timeout: Duration,
-) -> Result<(PistolFingerprint6, Vec<NmapOsDetectRet6>)> {
-    // Check target.
-    let dst_mac = match find_interface_by_ipv6(src_ipv6) {
-        Some(interface) => match interface.mac {
-            Some(m) => m,
-            None => return Err(CanNotFoundMacAddress::new().into()),
-        },
-        None => return Err(CanNotFoundInterface::new().into()),
-    };
+) -> Result<(PistolFingerprint6, Vec<NmapOsDetectRet6>, AllPacketRR6)> {
+    // The mac address of the target is only known if it is on the same ethernet network.
+    let dst_mac =
+        if dst_ipv6_is_local_net(dst_ipv6) && dst_ipv6 != src_ipv6 && !dst_ipv6.is_loopback() {
+            neighbour_mac6(src_ipv6, dst_ipv6)?
+        } else {
+            None
+        };
 
     let ap = send_all_probes(
         src_ipv6,
@@ -1125,11 +1133,11 @@ pub fn os_probe6(
         timeout,
     )?;
 
-    let hops = None;
+    let hops = udp_hops6(&ap.u1)?;
     let good_results = true;
     let scan = get_scan_line(
-        Some(dst_mac),
-        dst_open_tcp_port,
+        dst_mac,
+        Some(dst_open_tcp_port),
         dst_closed_tcp_port,
         dst_closed_udp_port,
         dst_ipv6.into(),
@@ -1137,7 +1145,77 @@ pub fn os_probe6(
         good_results,
     );
 
+    let (fingerprint, ret) = os_fingerprint6(ap.clone(), scan, top_k, linear)?;
+    Ok((fingerprint, ret, ap))
+}
+
+/// Returns the target address and the ports of the probes.
+fn capture_ports6(ap: &AllPacketRR6) -> Result<(Ipv6Addr, u16, u16, u16)> {
+    let tcp_dst = |request: &[u8]| -> Result<(Ipv6Addr, u16)> {
+        let ipv6_packet = match Ipv6Packet::new(request) {
+            Some(p) => p,
+            None => return Err(GetIpv6PacketFailed::new().into()),
+        };
+        match TcpPacket::new(ipv6_packet.payload()) {
+            Some(t) => Ok((ipv6_packet.get_destination(), t.get_destination())),
+            None => Err(GetTcpPacketFailed::new().into()),
+        }
+    };
+    let (dst_ipv6, dst_open_tcp_port) = tcp_dst(&ap.seq.seq1.request)?;
+    let (_, dst_closed_tcp_port) = tcp_dst(&ap.tx.t5.request)?;
+    let dst_closed_udp_port = match Ipv6Packet::new(&ap.u1.u1.request) {
+        Some(ipv6_packet) => match UdpPacket::new(ipv6_packet.payload()) {
+            Some(u) => u.get_destination(),
+            None => return Err(GetUdpPacketFailed::new().into()),
+        },
+        None => return Err(GetIpv6PacketFailed::new().into()),
+    };
+    Ok((
+        dst_ipv6,
+        dst_open_tcp_port,
+        dst_closed_tcp_port,
+        dst_closed_udp_port,
+    ))
+}
+
+/// Same as `os_replay` in ipv4, predict a saved capture again without the network.
+pub fn os_replay6(
+    ap: &AllPacketRR6,
+    top_k: usize,
+    linear: Linear,
+) -> Result<(PistolFingerprint6, Vec<NmapOsDetectRet6>)> {
+    let (dst_ipv6, dst_open_tcp_port, dst_closed_tcp_port, dst_closed_udp_port) =
+        capture_ports6(ap)?;
+    let hops = udp_hops6(&ap.u1)?;
+    let scan = get_scan_line(
+        None,
+        Some(dst_open_tcp_port),
+        dst_closed_tcp_port,
+        dst_closed_udp_port,
+        dst_ipv6.into(),
+        hops,
+        true,
+    );
+    os_fingerprint6(ap.clone(), scan, top_k, linear)
+}
+
+/// Returns the fingerprint and the prediction of all the probes and responses,
+/// from `send_all_probes` or a saved capture.
+pub fn os_fingerprint6(
+    ap: AllPacketRR6,
+    scan: String,
+    top_k: usize,
+    linear: Linear,
+) -> Result<(PistolFingerprint6, Vec<NmapOsDetectRet6>)> {
     let features = vectorize(&ap)?;
+    if features.len() != linear.scale.len() {
+        let reason = format!(
+            "the model has {} features but the probes have {}",
+            linear.scale.len(),
+            features.len()
+        );
+        return Err(Ipv6ModelError::new(&reason).into());
+    }
     // println!("{:?}", features);
     let features = apply_scale(&features, &linear.scale);
     // println!("{:?}", features[0..5].to_vec());
@@ -1159,7 +1237,8 @@ pub fn os_probe6(
 
     let detect_rets_sort = isort(&detect_rets);
     let mut perfect_match = 1;
-    for i in 1..36 {
+    // the model trained by ourselves may have less classes
+    for i in 1..detect_rets_sort.len().min(36) {
         if detect_rets_sort[i].score >= 0.9 * detect_rets_sort[0].score {
             perfect_match += 1;
         }
@@ -1314,7 +1393,7 @@ pub fn os_probe6(
     };
 
     let ret = if match_status {
-        let ret = detect_rets_sort[0..top_k].to_vec();
+        let ret = detect_rets_sort[0..top_k.min(detect_rets_sort.len())].to_vec();
         ret
     } else {
         let ret = vec![];
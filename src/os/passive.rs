@@ -0,0 +1,534 @@
+/* Passive OS fingerprinting with the p0f style signatures */
+use anyhow::Result;
+use pnet::datalink::NetworkInterface;
+use pnet::packet::ethernet::EtherTypes;
+use pnet::packet::ethernet::EthernetPacket;
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::tcp::TcpFlags;
+use pnet::packet::tcp::TcpPacket;
+use pnet::packet::Packet;
+use std::error::Error;
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+use crate::layers::layer2_capture_with;
+use crate::os::operator::tcp_o;
+use crate::os::operator::tcp_udp_df;
+use crate::os::operator::tcp_udp_icmp_tg;
+use crate::os::operator::tcp_w;
+
+/// The max hops between the host and us, same as p0f.
+const PASSIVE_MAX_DIST: u8 = 35;
+
+/* PassiveDbParseError */
+#[derive(Debug, Clone)]
+struct PassiveDbParseError {
+    line: String,
+}
+
+impl fmt::Display for PassiveDbParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "can not parse the passive os db line: {}", self.line)
+    }
+}
+
+impl PassiveDbParseError {
+    pub fn new(line: &str) -> PassiveDbParseError {
+        PassiveDbParseError {
+            line: line.to_string(),
+        }
+    }
+}
+
+impl Error for PassiveDbParseError {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PassiveDirection {
+    /// The SYN packet, the host is the client (`[tcp:request]` in p0f).
+    Syn,
+    /// The SYN+ACK packet, the host is the server (`[tcp:response]` in p0f).
+    SynAck,
+}
+
+impl fmt::Display for PassiveDirection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PassiveDirection::Syn => write!(f, "syn"),
+            PassiveDirection::SynAck => write!(f, "syn+ack"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PassiveWindow {
+    /// `*`
+    Any,
+    /// `8192`
+    Value(u16),
+    /// `mss*N`
+    Mss(u16),
+    /// `mtu*N`, the mtu is the mss plus the ipv4 and tcp headers.
+    Mtu(u16),
+    /// `%N`, the window is a multiple of N.
+    Mod(u16),
+}
+
+impl PassiveWindow {
+    fn parser(window: &str) -> Option<PassiveWindow> {
+        let w = if window == "*" {
+            PassiveWindow::Any
+        } else if let Some(n) = window.strip_prefix("mss*") {
+            PassiveWindow::Mss(n.parse().ok()?)
+        } else if let Some(n) = window.strip_prefix("mtu*") {
+            PassiveWindow::Mtu(n.parse().ok()?)
+        } else if let Some(n) = window.strip_prefix('%') {
+            PassiveWindow::Mod(n.parse().ok()?)
+        } else {
+            PassiveWindow::Value(window.parse().ok()?)
+        };
+        Some(w)
+    }
+    fn do_match(&self, window: u16, mss: Option<u16>) -> bool {
+        let window = window as u32;
+        match *self {
+            PassiveWindow::Any => true,
+            PassiveWindow::Value(v) => window == v as u32,
+            PassiveWindow::Mss(n) => match mss {
+                Some(m) => window == m as u32 * n as u32,
+                None => false,
+            },
+            PassiveWindow::Mtu(n) => match mss {
+                Some(m) => window == (m as u32 + 40) * n as u32,
+                None => false,
+            },
+            PassiveWindow::Mod(n) => n != 0 && window % n as u32 == 0,
+        }
+    }
+}
+
+/// One `sig` line of the passive os db.
+#[derive(Debug, Clone)]
+pub struct PassiveSignature {
+    pub direction: PassiveDirection,
+    /// The os class of the label, such as `unix` and `win`.
+    pub class: String,
+    /// The os name and flavor of the label, such as `Linux 3.x`.
+    pub name: String,
+    /// The initial ttl, `None` means any.
+    pub ttl: Option<u8>,
+    pub mss: Option<u16>,
+    pub window: PassiveWindow,
+    pub wscale: Option<u8>,
+    /// The tcp options order, such as `["mss", "nop", "ws"]`.
+    pub layout: Vec<String>,
+    pub df: bool,
+}
+
+impl PassiveSignature {
+    /// Parse the `ver:ittl:olen:mss:wsize,scale:olayout:quirks:pclass` signature.
+    pub fn parser(
+        direction: PassiveDirection,
+        class: &str,
+        name: &str,
+        sig: &str,
+    ) -> Result<PassiveSignature> {
+        let fields: Vec<&str> = sig.split(':').map(|s| s.trim()).collect();
+        if fields.len() != 8 {
+            return Err(PassiveDbParseError::new(sig).into());
+        }
+        let ttl = match fields[1].trim_end_matches('-') {
+            "*" => None,
+            t => match t.parse() {
+                Ok(t) => Some(t),
+                Err(_) => return Err(PassiveDbParseError::new(sig).into()),
+            },
+        };
+        let mss = match fields[3] {
+            "*" => None,
+            m => match m.parse() {
+                Ok(m) => Some(m),
+                Err(_) => return Err(PassiveDbParseError::new(sig).into()),
+            },
+        };
+        let (window, wscale) = match fields[4].split_once(',') {
+            Some((w, s)) => {
+                let window = match PassiveWindow::parser(w) {
+                    Some(w) => w,
+                    None => return Err(PassiveDbParseError::new(sig).into()),
+                };
+                let wscale = match s {
+                    "*" => None,
+                    s => match s.parse() {
+                        Ok(s) => Some(s),
+                        Err(_) => return Err(PassiveDbParseError::new(sig).into()),
+                    },
+                };
+                (window, wscale)
+            }
+            None => return Err(PassiveDbParseError::new(sig).into()),
+        };
+        let mut layout = Vec::new();
+        for o in fields[5].split(',') {
+            match o {
+                "" => (),
+                // the padding after eol is not matched
+                o if o.starts_with("eol") => {
+                    layout.push(String::from("eol"));
+                    break;
+                }
+                o => layout.push(o.to_string()),
+            }
+        }
+        let df = fields[6].split(',').any(|q| q == "df");
+        Ok(PassiveSignature {
+            direction,
+            class: class.to_string(),
+            name: name.to_string(),
+            ttl,
+            mss,
+            window,
+            wscale,
+            layout,
+            df,
+        })
+    }
+    pub fn do_match(&self, fingerprint: &PassiveFingerprint) -> bool {
+        if self.direction != fingerprint.direction {
+            return false;
+        }
+        match self.ttl {
+            Some(t) => {
+                if fingerprint.ttl > t || t - fingerprint.ttl > PASSIVE_MAX_DIST {
+                    return false;
+                }
+            }
+            None => (),
+        }
+        if self.mss.is_some() && self.mss != fingerprint.mss {
+            return false;
+        }
+        if self.wscale.is_some() && self.wscale != fingerprint.wscale {
+            return false;
+        }
+        if !self.window.do_match(fingerprint.window, fingerprint.mss) {
+            return false;
+        }
+        self.layout == fingerprint.layout && self.df == fingerprint.df
+    }
+}
+
+/// Process the signatures in the p0f v3 format (the `[tcp:request]` and `[tcp:response]` sections),
+/// the other sections and the IPv6 only signatures are skipped.
+pub fn passive_os_db_parser(lines: Vec<String>) -> Result<Vec<PassiveSignature>> {
+    let mut ret = Vec::new();
+    let mut direction = None;
+    let mut label = None;
+    for line in lines {
+        let line = line.trim();
+        if line.len() == 0 || line.starts_with(';') {
+            continue;
+        }
+        if line.starts_with('[') {
+            direction = match line {
+                "[tcp:request]" => Some(PassiveDirection::Syn),
+                "[tcp:response]" => Some(PassiveDirection::SynAck),
+                _ => None,
+            };
+            label = None;
+            continue;
+        }
+        let direction = match direction {
+            Some(d) => d,
+            None => continue,
+        };
+        let (key, value) = match line.split_once('=') {
+            Some((k, v)) => (k.trim(), v.trim()),
+            None => return Err(PassiveDbParseError::new(line).into()),
+        };
+        match key {
+            "label" => {
+                // s:unix:Linux:3.x
+                let l: Vec<&str> = value.splitn(4, ':').collect();
+                if l.len() != 4 {
+                    return Err(PassiveDbParseError::new(line).into());
+                }
+                let name = format!("{} {}", l[2], l[3]).trim().to_string();
+                label = Some((l[1].to_string(), name));
+            }
+            "sig" => {
+                // only ipv4 is supported now
+                if value.starts_with('6') {
+                    continue;
+                }
+                let (class, name) = match &label {
+                    Some(l) => l,
+                    None => return Err(PassiveDbParseError::new(line).into()),
+                };
+                let s = PassiveSignature::parser(direction, class, name, value)?;
+                ret.push(s);
+            }
+            _ => (),
+        }
+    }
+    Ok(ret)
+}
+
+/// Convert the options of `tcp_o` (such as `M5B4NW7SLL`) to the p0f layout,
+/// returns the layout, the mss and the window scale.
+fn options_layout(options: &str) -> (Vec<String>, Option<u16>, Option<u8>) {
+    let mut layout = Vec::new();
+    let mut mss = None;
+    let mut wscale = None;
+    let mut chars = options.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            'M' | 'W' => {
+                let mut value = String::new();
+                while let Some(h) = chars.peek() {
+                    if !h.is_ascii_hexdigit() {
+                        break;
+                    }
+                    value.push(*h);
+                    chars.next();
+                }
+                if c == 'M' {
+                    layout.push(String::from("mss"));
+                    mss = u16::from_str_radix(&value, 16).ok();
+                } else {
+                    layout.push(String::from("ws"));
+                    wscale = u8::from_str_radix(&value, 16).ok();
+                }
+            }
+            'T' => {
+                // the two flags of the timestamp values
+                chars.next();
+                chars.next();
+                layout.push(String::from("ts"));
+            }
+            'S' => layout.push(String::from("sok")),
+            'N' => layout.push(String::from("nop")),
+            'L' => {
+                // the rest is padding
+                layout.push(String::from("eol"));
+                break;
+            }
+            _ => (),
+        }
+    }
+    (layout, mss, wscale)
+}
+
+/// The SYN or SYN+ACK packet seen by passive listening.
+#[derive(Debug, Clone)]
+pub struct PassiveFingerprint {
+    pub src_addr: Ipv4Addr,
+    pub src_port: u16,
+    pub dst_addr: Ipv4Addr,
+    pub dst_port: u16,
+    pub direction: PassiveDirection,
+    pub ttl: u8,
+    /// The initial ttl guess (`tcp_udp_icmp_tg`).
+    pub ittl: u8,
+    pub window: u16,
+    pub mss: Option<u16>,
+    pub wscale: Option<u8>,
+    /// The tcp options in the nmap format (`tcp_o`).
+    pub options: String,
+    /// The tcp options order in the p0f format.
+    pub layout: Vec<String>,
+    pub df: bool,
+    /// When the packet was seen, relative to the start of the listening.
+    pub time: Duration,
+}
+
+impl PassiveFingerprint {
+    /// Returns `None` if the packet is not a tcp SYN or SYN+ACK packet.
+    pub fn new(ipv4_buff: &[u8], time: Duration) -> Result<Option<PassiveFingerprint>> {
+        let ipv4_packet = match Ipv4Packet::new(ipv4_buff) {
+            Some(p) => p,
+            None => return Ok(None),
+        };
+        if ipv4_packet.get_next_level_protocol() != IpNextHeaderProtocols::Tcp {
+            return Ok(None);
+        }
+        let tcp_packet = match TcpPacket::new(ipv4_packet.payload()) {
+            Some(p) => p,
+            None => return Ok(None),
+        };
+        // the ecn setup (ECE and CWR), PSH and URG flags do not change the direction
+        let flags = tcp_packet.get_flags()
+            & !(TcpFlags::ECE | TcpFlags::CWR | TcpFlags::PSH | TcpFlags::URG);
+        let direction = if flags == TcpFlags::SYN {
+            PassiveDirection::Syn
+        } else if flags == TcpFlags::SYN | TcpFlags::ACK {
+            PassiveDirection::SynAck
+        } else {
+            return Ok(None);
+        };
+        let options = tcp_o(ipv4_buff)?;
+        let (layout, mss, wscale) = options_layout(&options);
+        let fingerprint = PassiveFingerprint {
+            src_addr: ipv4_packet.get_source(),
+            src_port: tcp_packet.get_source(),
+            dst_addr: ipv4_packet.get_destination(),
+            dst_port: tcp_packet.get_destination(),
+            direction,
+            ttl: ipv4_packet.get_ttl(),
+            ittl: tcp_udp_icmp_tg(ipv4_buff)?,
+            window: tcp_w(ipv4_buff)?,
+            mss,
+            wscale,
+            options,
+            layout,
+            df: tcp_udp_df(ipv4_buff)? == "Y",
+            time,
+        };
+        Ok(Some(fingerprint))
+    }
+}
+
+impl fmt::Display for PassiveFingerprint {
+    /// The p0f format signature, it can be added to the passive os db directly.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mss = match self.mss {
+            Some(m) => format!("{}", m),
+            None => String::from("*"),
+        };
+        let wscale = match self.wscale {
+            Some(w) => format!("{}", w),
+            None => String::from("*"),
+        };
+        let df = if self.df { "df" } else { "" };
+        write!(
+            f,
+            "4:{}:0:{}:{},{}:{}:{}:0",
+            self.ittl,
+            mss,
+            self.window,
+            wscale,
+            self.layout.join(","),
+            df
+        )
+    }
+}
+
+/// Listen to all the tcp SYN and SYN+ACK packets on the interface, nothing will be sent.
+pub fn recv_tcp_syn_packets(
+    interface: NetworkInterface,
+    duration: Duration,
+) -> Result<Vec<PassiveFingerprint>> {
+    let mut ret = Vec::new();
+    // only the fingerprints are kept, not the packets
+    layer2_capture_with(interface, duration, |buff, time| {
+        let ethernet_packet = match EthernetPacket::new(buff) {
+            Some(e) => e,
+            None => return,
+        };
+        if ethernet_packet.get_ethertype() != EtherTypes::Ipv4 {
+            return;
+        }
+        match PassiveFingerprint::new(ethernet_packet.payload(), time) {
+            Ok(Some(f)) => ret.push(f),
+            // not a SYN or SYN+ACK packet
+            Ok(None) => (),
+            // the malformed packet is skipped
+            Err(_) => (),
+        }
+    })?;
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::os::packet::seq_packet_1_layer3;
+    use crate::os::PassiveOsResults;
+    use pnet::packet::ipv4::Ipv4Flags;
+    use pnet::packet::ipv4::MutableIpv4Packet;
+    use pnet::packet::tcp::MutableTcpPacket;
+    use pnet::packet::MutablePacket;
+    #[test]
+    fn test_passive_os_db_parser() {
+        let passive_os_file = include_str!("../db/passive-os-db");
+        let lines = passive_os_file.lines().map(|l| l.to_string()).collect();
+        let passive_db = passive_os_db_parser(lines).unwrap();
+        assert!(passive_db.len() > 0);
+        let s = &passive_db[0];
+        assert_eq!(s.direction, PassiveDirection::Syn);
+        assert_eq!(s.class, "unix");
+        assert_eq!(s.name, "Linux 4.x and newer");
+        assert_eq!(s.ttl, Some(64));
+        assert_eq!(s.window, PassiveWindow::Mss(44));
+        assert_eq!(s.wscale, Some(7));
+        assert_eq!(s.layout, vec!["mss", "sok", "ts", "nop", "ws"]);
+        assert!(s.df);
+
+        let bad = vec![
+            String::from("[tcp:request]"),
+            String::from("sig = *:64:0:*:mss*44,7:mss:df:0"),
+        ];
+        assert!(passive_os_db_parser(bad).is_err());
+    }
+    #[test]
+    fn test_passive_fingerprint() {
+        let src_ipv4 = Ipv4Addr::new(192, 168, 1, 10);
+        let dst_ipv4 = Ipv4Addr::new(192, 168, 1, 20);
+        let syn = seq_packet_1_layer3(src_ipv4, 45678, dst_ipv4, 22).unwrap();
+        let time = Duration::from_secs(1);
+        let f = PassiveFingerprint::new(&syn, time).unwrap().unwrap();
+        assert_eq!(f.direction, PassiveDirection::Syn);
+        assert_eq!(f.options, "WANM5B4T10S");
+        assert_eq!(f.mss, Some(1460));
+        assert_eq!(f.wscale, Some(10));
+        assert!(!f.df);
+        assert_eq!(format!("{}", f), "4:64:0:1460:1,10:ws,nop,mss,ts,sok::0");
+
+        // the same packet as the server reply, 3 hops away
+        let mut synack = syn.clone();
+        let mut ip_header = MutableIpv4Packet::new(&mut synack).unwrap();
+        ip_header.set_flags(Ipv4Flags::DontFragment);
+        ip_header.set_ttl(125);
+        let mut tcp_header = MutableTcpPacket::new(ip_header.payload_mut()).unwrap();
+        tcp_header.set_flags(TcpFlags::SYN | TcpFlags::ACK);
+        let f2 = PassiveFingerprint::new(&synack, time * 2).unwrap().unwrap();
+        assert_eq!(f2.direction, PassiveDirection::SynAck);
+        assert_eq!(f2.ittl, 128);
+        assert!(f2.df);
+
+        // the ecn SYN is still a SYN
+        let mut ecn_syn = syn.clone();
+        let mut ip_header = MutableIpv4Packet::new(&mut ecn_syn).unwrap();
+        let mut tcp_header = MutableTcpPacket::new(ip_header.payload_mut()).unwrap();
+        tcp_header.set_flags(TcpFlags::SYN | TcpFlags::ECE | TcpFlags::CWR);
+        let f3 = PassiveFingerprint::new(&ecn_syn, time).unwrap().unwrap();
+        assert_eq!(f3.direction, PassiveDirection::Syn);
+
+        let lines = vec![
+            "[tcp:request]",
+            "label = s:!:NMap:OS detection probe",
+            "sig = *:64:0:1460:1,10:ws,nop,mss,ts,sok::0",
+            "[tcp:response]",
+            "label = s:win:Windows:7 or newer",
+            "sig = *:128:0:*:%1,*:ws,nop,mss,ts,sok:df,id+:0",
+            "sig = 6:128:0:*:*,*:ws,nop,mss,ts,sok:df:0",
+        ];
+        let lines = lines.iter().map(|l| l.to_string()).collect();
+        let passive_db = passive_os_db_parser(lines).unwrap();
+        assert_eq!(passive_db.len(), 2);
+        assert!(passive_db[0].do_match(&f));
+        assert!(!passive_db[0].do_match(&f2));
+        assert!(passive_db[1].do_match(&f2));
+
+        let mut results = PassiveOsResults::new();
+        results.update(f, &passive_db);
+        results.update(f2.clone(), &passive_db);
+        results.update(f2, &passive_db);
+        let guesses = results.results[&src_ipv4].guesses();
+        assert_eq!(guesses.len(), 2);
+        assert_eq!(guesses[0].name, "Windows 7 or newer");
+        assert_eq!(guesses[0].count, 2);
+        println!("{}", results);
+    }
+}
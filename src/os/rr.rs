@@ -1,14 +1,18 @@
+use anyhow::Result;
+use serde::Deserialize;
+use serde::Serialize;
+use std::fs;
 use std::time::Duration;
 
 // Each request corresponds to a response, all layer3 packet
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RequestAndResponse {
     // pub name: String,
     pub request: Vec<u8>,  // layer3
     pub response: Vec<u8>, // layer3, if no response: response.len() == 0
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SEQRR {
     pub seq1: RequestAndResponse,
     pub seq2: RequestAndResponse,
@@ -19,18 +23,18 @@ pub struct SEQRR {
     pub elapsed: f64,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct IERR {
     pub ie1: RequestAndResponse,
     pub ie2: RequestAndResponse,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ECNRR {
     pub ecn: RequestAndResponse,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TXRR {
     pub t2: RequestAndResponse,
     pub t3: RequestAndResponse,
@@ -40,21 +44,39 @@ pub struct TXRR {
     pub t7: RequestAndResponse,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct U1RR {
     pub u1: RequestAndResponse,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AllPacketRR {
     pub seq: SEQRR,
     pub ie: IERR,
     pub ecn: ECNRR,
     pub tx: TXRR,
     pub u1: U1RR,
+    /// The SCAN line of the probing, the replay reuses it for the target mac prefix and the time of the probing.
+    /// It is rebuilt at the replay if it is not in the saved file.
+    #[serde(default)]
+    pub scan: Option<String>,
 }
 
-#[derive(Debug, Clone)]
+impl AllPacketRR {
+    /// Save all the probes and responses to the file (json), see `os_detect_replay`.
+    pub fn save(&self, path: &str) -> Result<()> {
+        let data = serde_json::to_string(self)?;
+        fs::write(path, data)?;
+        Ok(())
+    }
+    pub fn load(path: &str) -> Result<AllPacketRR> {
+        let data = fs::read_to_string(path)?;
+        let ap: AllPacketRR = serde_json::from_str(&data)?;
+        Ok(ap)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NXRR6 {
     pub ni: RequestAndResponse,
     pub ns: RequestAndResponse,
@@ -64,14 +86,14 @@ pub struct NXRR6 {
     pub rts: Duration,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TECNRR6 {
     pub tecn: RequestAndResponse,
     pub st: Duration,
     pub rt: Duration,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SEQRR6 {
     pub seq1: RequestAndResponse,
     pub seq2: RequestAndResponse,
@@ -94,7 +116,7 @@ pub struct SEQRR6 {
     pub rt6: Duration,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct IERR6 {
     pub ie1: RequestAndResponse,
     pub ie2: RequestAndResponse,
@@ -104,14 +126,14 @@ pub struct IERR6 {
     pub rt2: Duration,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct U1RR6 {
     pub u1: RequestAndResponse,
     pub st: Duration,
     pub rt: Duration,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TXRR6 {
     pub t2: RequestAndResponse,
     pub t3: RequestAndResponse,
@@ -133,7 +155,7 @@ pub struct TXRR6 {
     pub rt7: Duration,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AllPacketRR6 {
     pub seq: SEQRR6,
     pub ie: IERR6,
@@ -142,3 +164,17 @@ pub struct AllPacketRR6 {
     pub tecn: TECNRR6,
     pub tx: TXRR6,
 }
+
+impl AllPacketRR6 {
+    /// Save all the probes and responses to the file (json), see `os_detect6_replay`.
+    pub fn save(&self, path: &str) -> Result<()> {
+        let data = serde_json::to_string(self)?;
+        fs::write(path, data)?;
+        Ok(())
+    }
+    pub fn load(path: &str) -> Result<AllPacketRR6> {
+        let data = fs::read_to_string(path)?;
+        let ap: AllPacketRR6 = serde_json::from_str(&data)?;
+        Ok(ap)
+    }
+}
@@ -0,0 +1,250 @@
+/* Train the IPv6 OS detection model */
+use anyhow::Result;
+use std::collections::HashMap;
+use std::iter::zip;
+
+use crate::errors::Ipv6ModelError;
+use crate::os::operator6::apply_scale;
+use crate::os::operator6::vectorize;
+use crate::os::rr::AllPacketRR6;
+use crate::os::Linear;
+use crate::os::CPE;
+
+/// One labelled fingerprint, the `name` is the os name of the class (same as the name in `CPE`).
+#[derive(Debug, Clone)]
+pub struct TrainSample6 {
+    pub name: String,
+    /// The features from `operator6::vectorize`, not scaled.
+    pub features: Vec<f64>,
+}
+
+impl TrainSample6 {
+    pub fn new(name: &str, features: Vec<f64>) -> TrainSample6 {
+        TrainSample6 {
+            name: name.to_string(),
+            features,
+        }
+    }
+    /// From the probes and responses of `os_detect6` (`OsStatus6::rr`) or a saved capture.
+    pub fn from_rr(name: &str, ap: &AllPacketRR6) -> Result<TrainSample6> {
+        let features = vectorize(ap)?;
+        Ok(TrainSample6::new(name, features))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TrainParameters6 {
+    /// The iterations of the gradient descent.
+    pub epochs: usize,
+    pub learning_rate: f64,
+    /// The L2 regularization.
+    pub l2: f64,
+}
+
+impl TrainParameters6 {
+    pub fn new() -> TrainParameters6 {
+        TrainParameters6 {
+            epochs: 500,
+            learning_rate: 0.5,
+            l2: 0.001,
+        }
+    }
+}
+
+fn sigmoid(x: f64) -> f64 {
+    1.0 / (1.0 + (-x).exp())
+}
+
+/// Returns the (a, b) of each feature, the feature f is scaled to [0, 1] by (f + a) * b.
+/// The negative feature means the value is missing, it is not scaled (same as `apply_scale`).
+fn fit_scale(samples: &[TrainSample6], features_num: usize) -> Vec<Vec<f64>> {
+    let mut scale = Vec::new();
+    for j in 0..features_num {
+        let mut min = f64::MAX;
+        let mut max = f64::MIN;
+        for s in samples {
+            let f = s.features[j];
+            if f >= 0.0 {
+                min = min.min(f);
+                max = max.max(f);
+            }
+        }
+        let (a, b) = if max > min {
+            (-min, 1.0 / (max - min))
+        } else if max == min {
+            // all the values are the same
+            (-min, 1.0)
+        } else {
+            // all the values are missing
+            (0.0, 1.0)
+        };
+        scale.push(vec![a, b]);
+    }
+    scale
+}
+
+/// One-vs-rest logistic regression without the bias, the same as `predict_value` uses.
+fn fit_weights(
+    x: &[Vec<f64>],
+    y: &[bool],
+    features_num: usize,
+    parameters: TrainParameters6,
+) -> Vec<f64> {
+    let n = x.len() as f64;
+    let mut w = vec![0.0; features_num];
+    for _ in 0..parameters.epochs {
+        let mut grad = vec![0.0; features_num];
+        for (xi, yi) in zip(x, y) {
+            let mut dot = 0.0;
+            for (a, b) in zip(xi, &w) {
+                dot += a * b;
+            }
+            let target = if *yi { 1.0 } else { 0.0 };
+            let err = sigmoid(dot) - target;
+            for (g, a) in zip(&mut grad, xi) {
+                *g += err * a;
+            }
+        }
+        for (wj, g) in zip(&mut w, &grad) {
+            *wj -= parameters.learning_rate * (g / n + parameters.l2 * *wj);
+        }
+    }
+    w
+}
+
+/// Train the model from the labelled samples, the classes and the order of them are the same as `cpe`.
+/// Every class needs at least one sample, the model can be saved by `Linear::save` and used by `os_detect6`.
+pub fn train6(
+    samples: &[TrainSample6],
+    cpe: &[CPE],
+    parameters: TrainParameters6,
+) -> Result<Linear> {
+    let features_num = match samples.first() {
+        Some(s) => s.features.len(),
+        None => return Err(Ipv6ModelError::new("no samples").into()),
+    };
+    let mut class_samples: HashMap<&str, Vec<usize>> = HashMap::new();
+    for c in cpe {
+        class_samples.insert(&c.name, Vec::new());
+    }
+    for (i, s) in samples.iter().enumerate() {
+        if s.features.len() != features_num {
+            return Err(
+                Ipv6ModelError::new("the samples have different numbers of features").into(),
+            );
+        }
+        match class_samples.get_mut(s.name.as_str()) {
+            Some(v) => v.push(i),
+            None => {
+                let reason = format!("the class of sample {} is not in the cpe", s.name);
+                return Err(Ipv6ModelError::new(&reason).into());
+            }
+        }
+    }
+
+    let scale = fit_scale(samples, features_num);
+    let x: Vec<Vec<f64>> = samples
+        .iter()
+        .map(|s| apply_scale(&s.features, &scale))
+        .collect();
+
+    let mut namelist = Vec::new();
+    let mut w = Vec::new();
+    let mut mean = Vec::new();
+    let mut variance = Vec::new();
+    for c in cpe {
+        let index = &class_samples[c.name.as_str()];
+        if index.len() == 0 {
+            let reason = format!("no samples of class {}", c.name);
+            return Err(Ipv6ModelError::new(&reason).into());
+        }
+        let y: Vec<bool> = samples.iter().map(|s| s.name == c.name).collect();
+        w.push(fit_weights(&x, &y, features_num, parameters));
+
+        // the mean and variance of the scaled features for the novelty
+        let num = index.len() as f64;
+        let mut m = vec![0.0; features_num];
+        for i in index {
+            for (mj, f) in zip(&mut m, &x[*i]) {
+                *mj += f / num;
+            }
+        }
+        let mut v = vec![0.0; features_num];
+        for i in index {
+            for ((vj, mj), f) in zip(zip(&mut v, &m), &x[*i]) {
+                *vj += (f - mj) * (f - mj) / num;
+            }
+        }
+        mean.push(m);
+        variance.push(v);
+        namelist.push(c.name.to_string());
+    }
+
+    let linear = Linear {
+        namelist,
+        w,
+        scale,
+        mean,
+        variance,
+        cpe: cpe.to_vec(),
+    };
+    linear.check()?;
+    Ok(linear)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::os::osscan6::predict_value;
+    #[test]
+    fn test_train6() {
+        let cpe: Vec<CPE> = ["Linux 5.X", "Windows 11", "FreeBSD 14"]
+            .iter()
+            .map(|n| CPE {
+                name: n.to_string(),
+                osclass: vec![],
+                cpe: vec![],
+            })
+            .collect();
+        // the -1 means the probe got no response
+        let centers = [
+            vec![64.0, 0.0, 29200.0, 1.0, -1.0],
+            vec![128.0, 1.0, 65535.0, 0.0, 20.0],
+            vec![64.0, 1.0, 65535.0, 1.0, 40.0],
+        ];
+        let mut samples = Vec::new();
+        for (c, center) in zip(&cpe, &centers) {
+            for k in 0..5 {
+                let features: Vec<f64> = center
+                    .iter()
+                    .map(|f| if *f > 1.0 { f + k as f64 } else { *f })
+                    .collect();
+                samples.push(TrainSample6::new(&c.name, features));
+            }
+        }
+        let linear = train6(&samples, &cpe, TrainParameters6::new()).unwrap();
+        assert_eq!(linear.w.len(), 3);
+        assert_eq!(linear.scale.len(), 5);
+        for s in &samples {
+            let features = apply_scale(&s.features, &linear.scale);
+            let predict = predict_value(&features, &linear.w);
+            let mut best = 0;
+            for i in 0..predict.len() {
+                if predict[i] > predict[best] {
+                    best = i;
+                }
+            }
+            assert_eq!(linear.namelist[best], s.name);
+        }
+
+        let path = std::env::temp_dir().join("pistol_test_train6.json");
+        let path = path.to_str().unwrap();
+        linear.save(path).unwrap();
+        let linear_2 = Linear::load(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(linear.w, linear_2.w);
+
+        // every class needs the samples
+        assert!(train6(&samples[0..5], &cpe, TrainParameters6::new()).is_err());
+    }
+}
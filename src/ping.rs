@@ -77,7 +77,7 @@ fn run_ping(
             };
 
             let (ret, rtt) =
-                udp::send_udp_scan_packet(src_ipv4, src_port, dst_ipv4, dst_port, timeout)?;
+                udp::send_udp_scan_packet(src_ipv4, src_port, dst_ipv4, dst_port, &[], timeout)?;
             match ret {
                 TargetScanStatus::Open => (PingStatus::Up, rtt),
                 TargetScanStatus::OpenOrFiltered => (PingStatus::Up, rtt),
@@ -135,7 +135,7 @@ fn run_ping6(
             };
 
             let (ret, rtt) =
-                udp6::send_udp_scan_packet(src_ipv6, src_port, dst_ipv6, dst_port, timeout)?;
+                udp6::send_udp_scan_packet(src_ipv6, src_port, dst_ipv6, dst_port, &[], timeout)?;
             match ret {
                 TargetScanStatus::Open => (PingStatus::Up, rtt),
                 TargetScanStatus::OpenOrFiltered => (PingStatus::Up, rtt),
@@ -2,16 +2,21 @@
 use anyhow::Result;
 use pnet::datalink::MacAddr;
 use pnet::packet::ip::IpNextHeaderProtocol;
+use rand::Rng;
 use std::collections::HashMap;
 use std::fmt;
+use std::hash::Hash;
+use std::net::IpAddr;
 use std::net::Ipv4Addr;
 use std::net::Ipv6Addr;
 use std::sync::mpsc::channel;
+use std::sync::OnceLock;
 use std::time::Duration;
-use std::net::IpAddr;
+use std::time::Instant;
 
 pub mod arp;
 pub mod ip;
+pub mod ndp;
 pub mod tcp;
 pub mod tcp6;
 pub mod udp;
@@ -20,15 +25,24 @@ pub mod udp6;
 use crate::errors::CanNotFoundInterface;
 use crate::errors::CanNotFoundMacAddress;
 use crate::errors::CanNotFoundSourceAddress;
+use crate::errors::IdleScanNoZombie;
+use crate::layers::system_route;
+use crate::layers::system_route6;
+use crate::os::operator::ip_id_class;
 use crate::utils::find_interface_by_ipv4;
+use crate::utils::find_interface_by_ipv6;
 use crate::utils::find_source_ipv4;
 use crate::utils::find_source_ipv6;
 use crate::utils::get_default_timeout;
 use crate::utils::get_threads_pool;
 use crate::utils::random_port;
+use crate::vs::dbparser::nsp_probe_parser;
+use crate::vs::dbparser::udp_payloads;
+use crate::Host6;
 use crate::TargetType;
 
 use super::errors::NotSupportIpTypeForArpScan;
+use super::errors::NotSupportIpTypeForNdpScan;
 use super::Target;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -40,6 +54,8 @@ pub enum TargetScanStatus {
     Unfiltered,
     Unreachable,
     ClosedOrFiltered,
+    /// The port state can not be told, such as every try of the idle scan was disturbed by the other traffic of the zombie.
+    Unknown,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -48,25 +64,253 @@ pub struct IdleScanResults {
     pub zombie_ip_id_2: u16,
 }
 
+#[derive(Debug, Clone, Copy)]
+pub struct IdleScanResults6 {
+    pub zombie_frag_id_1: u32,
+    pub zombie_frag_id_2: u32,
+}
+
+/// How the zombie generates the ip id.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IpIdSequence {
+    Incremental,
+    /// Incremental, but in host byte order (windows).
+    BrokenIncremental,
+    Random,
+    Zero,
+    /// Incremental, but each destination has its own counter, the packets sent to the target can not be seen.
+    PerHost,
+    /// Identical ip id or not enough responses.
+    Unknown,
+}
+
+#[derive(Debug, Clone)]
+pub struct ZombieStatus {
+    pub zombie_ipv4: Ipv4Addr,
+    pub zombie_port: u16,
+    pub ip_ids: Vec<u16>,
+    pub sequence: IpIdSequence,
+    /// Packets per second the zombie sent to other hosts during the probes.
+    pub background_rate: f64,
+    /// The zombie can be used for the idle scan.
+    pub suitable: bool,
+}
+
+impl fmt::Display for ZombieStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let suitable_str = if self.suitable {
+            "suitable"
+        } else {
+            "not suitable"
+        };
+        write!(
+            f,
+            "{}:{}: {:?} {:?} background {:.2} pps ({})",
+            self.zombie_ipv4,
+            self.zombie_port,
+            self.sequence,
+            self.ip_ids,
+            self.background_rate,
+            suitable_str
+        )
+    }
+}
+
+/// How the zombie generates the fragment id after it received a packet too big.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FragIdSequence {
+    /// No fragment header in the replies.
+    NoFragment,
+    /// Every difference between two probes is less than ten.
+    Incremental,
+    Random,
+}
+
+#[derive(Debug, Clone)]
+pub struct ZombieStatus6 {
+    pub zombie_ipv6: Ipv6Addr,
+    pub frag_ids: Vec<u32>,
+    pub sequence: FragIdSequence,
+    /// The zombie can be used for the ipv6 idle scan.
+    pub suitable: bool,
+}
+
+impl fmt::Display for ZombieStatus6 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let suitable_str = if self.suitable {
+            "suitable"
+        } else {
+            "not suitable"
+        };
+        write!(
+            f,
+            "{}: {:?} {:?} ({})",
+            self.zombie_ipv6, self.sequence, self.frag_ids, suitable_str
+        )
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ArpAliveHosts {
     pub mac_addr: MacAddr,
     pub ouis: String,
+    // none when the host is found by passive listening
+    pub rtt: Option<Duration>,
+    // the host has sent gratuitous arp
+    pub gratuitous: bool,
 }
 
 #[derive(Debug, Clone)]
 pub struct ArpScanResults {
     pub alive_hosts: HashMap<Ipv4Addr, ArpAliveHosts>,
+    // the addresses claimed by more than one mac address
+    pub duplicate_hosts: HashMap<Ipv4Addr, Vec<ArpAliveHosts>>,
 }
 
-impl fmt::Display for ArpScanResults {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let mut result_str = String::new();
-        let s = format!("Alive hosts: {}", self.alive_hosts.len());
+// Record the host, if the address has been claimed by another mac address, it will be recorded as a duplicate.
+fn insert_alive_host<T: Hash + Eq + Copy>(
+    alive_hosts: &mut HashMap<T, ArpAliveHosts>,
+    duplicate_hosts: &mut HashMap<T, Vec<ArpAliveHosts>>,
+    ip: T,
+    aah: ArpAliveHosts,
+) {
+    match alive_hosts.get_mut(&ip) {
+        Some(exist) => {
+            if exist.mac_addr == aah.mac_addr {
+                exist.gratuitous |= aah.gratuitous;
+                match (exist.rtt, aah.rtt) {
+                    (Some(r1), Some(r2)) if r2 < r1 => exist.rtt = Some(r2),
+                    (None, Some(r2)) => exist.rtt = Some(r2),
+                    (_, _) => (),
+                }
+            } else {
+                let exist = exist.clone();
+                let dups = duplicate_hosts.entry(ip).or_insert(vec![exist]);
+                match dups.iter_mut().find(|d| d.mac_addr == aah.mac_addr) {
+                    Some(d) => d.gratuitous |= aah.gratuitous,
+                    None => dups.push(aah),
+                }
+            }
+        }
+        None => {
+            alive_hosts.insert(ip, aah);
+        }
+    }
+}
+
+fn alive_hosts_str<T: fmt::Display>(
+    alive_hosts: &HashMap<T, ArpAliveHosts>,
+    duplicate_hosts: &HashMap<T, Vec<ArpAliveHosts>>,
+) -> String {
+    let mut result_str = String::new();
+    let s = format!("Alive hosts: {}", alive_hosts.len());
+    result_str += &s;
+    result_str += "\n";
+    for (ip, aah) in alive_hosts {
+        let mut s = format!("{}: {} ({})", ip, aah.mac_addr, aah.ouis);
+        match aah.rtt {
+            Some(rtt) => s += &format!(" rtt: {:.2}ms", rtt.as_secs_f64() * 1000.0),
+            None => (),
+        }
+        if aah.gratuitous {
+            s += " gratuitous";
+        }
         result_str += &s;
         result_str += "\n";
-        for (ip, aah) in &self.alive_hosts {
-            let s = format!("{}: {} ({})", ip, aah.mac_addr, aah.ouis);
+    }
+    for (ip, dups) in duplicate_hosts {
+        let macs: Vec<String> = dups
+            .iter()
+            .map(|d| format!("{} ({})", d.mac_addr, d.ouis))
+            .collect();
+        let s = format!("Duplicate {}: {}", ip, macs.join(", "));
+        result_str += &s;
+        result_str += "\n";
+    }
+    result_str
+}
+
+impl ArpScanResults {
+    pub fn new() -> ArpScanResults {
+        ArpScanResults {
+            alive_hosts: HashMap::new(),
+            duplicate_hosts: HashMap::new(),
+        }
+    }
+    fn insert(&mut self, ip: Ipv4Addr, aah: ArpAliveHosts) {
+        insert_alive_host(&mut self.alive_hosts, &mut self.duplicate_hosts, ip, aah);
+    }
+}
+
+impl fmt::Display for ArpScanResults {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let result_str = alive_hosts_str(&self.alive_hosts, &self.duplicate_hosts);
+        write!(f, "{}", result_str)
+    }
+}
+
+/// The router found by router advertisement.
+#[derive(Debug, Clone)]
+pub struct RouterInfo {
+    // M flag, addresses are available via dhcpv6
+    pub managed: bool,
+    // O flag, other configuration is available via dhcpv6
+    pub other: bool,
+    // router lifetime in seconds, 0 means it is not a default router
+    pub lifetime: u16,
+    // on-link prefixes and their length
+    pub prefixes: Vec<(Ipv6Addr, u8)>,
+}
+
+/// The results of ndp scan, same as `ArpScanResults` but on ipv6.
+#[derive(Debug, Clone)]
+pub struct NdpScanResults {
+    pub alive_hosts: HashMap<Ipv6Addr, ArpAliveHosts>,
+    // the addresses claimed by more than one mac address
+    pub duplicate_hosts: HashMap<Ipv6Addr, Vec<ArpAliveHosts>>,
+    // the routers which sent router advertisement
+    pub routers: HashMap<Ipv6Addr, RouterInfo>,
+}
+
+impl NdpScanResults {
+    pub fn new() -> NdpScanResults {
+        NdpScanResults {
+            alive_hosts: HashMap::new(),
+            duplicate_hosts: HashMap::new(),
+            routers: HashMap::new(),
+        }
+    }
+    fn insert(&mut self, ip: Ipv6Addr, aah: ArpAliveHosts) {
+        insert_alive_host(&mut self.alive_hosts, &mut self.duplicate_hosts, ip, aah);
+    }
+    /// Convert all the alive hosts to the target of `scan6` or `os_detect6`.
+    pub fn to_target(&self, ports: Option<Vec<u16>>) -> Result<Target> {
+        let mut hosts6 = Vec::new();
+        for &addr in self.alive_hosts.keys() {
+            let h = Host6::new(addr, ports.clone())?;
+            hosts6.push(h);
+        }
+        Ok(Target::new6(hosts6))
+    }
+}
+
+impl fmt::Display for NdpScanResults {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut result_str = alive_hosts_str(&self.alive_hosts, &self.duplicate_hosts);
+        for (ip, ri) in &self.routers {
+            let prefixes: Vec<String> = ri
+                .prefixes
+                .iter()
+                .map(|(p, l)| format!("{}/{}", p, l))
+                .collect();
+            let s = format!(
+                "Router {}: M={} O={} lifetime: {}s prefixes: [{}]",
+                ip,
+                ri.managed,
+                ri.other,
+                ri.lifetime,
+                prefixes.join(", ")
+            );
             result_str += &s;
             result_str += "\n";
         }
@@ -115,6 +359,7 @@ impl fmt::Display for TcpUdpScanResults {
                     TargetScanStatus::Closed => format!("{ip} {port} closed"),
                     TargetScanStatus::Unreachable => format!("{ip} {port} unreachable"),
                     TargetScanStatus::ClosedOrFiltered => format!("{ip} {port} closed|filtered"),
+                    TargetScanStatus::Unknown => format!("{ip} {port} unknown"),
                 };
                 result_str += &status_str;
                 result_str += "\n";
@@ -167,6 +412,7 @@ impl fmt::Display for IpScanResults {
                     TargetScanStatus::ClosedOrFiltered => {
                         format!("{ip} {protocol} closed|filtered")
                     }
+                    TargetScanStatus::Unknown => format!("{ip} {protocol} unknown"),
                 };
                 result_str += &status_str;
                 result_str += "\n";
@@ -207,6 +453,7 @@ pub enum ScanMethod6 {
     Xmas,
     Window,
     Maimon,
+    Idle, // need predictable fragment id
     Udp,
 }
 
@@ -234,6 +481,17 @@ fn get_nmap_mac_prefixes() -> Vec<NmapMacPrefix> {
     ret
 }
 
+fn find_ouis(nmap_mac_prefixes: &[NmapMacPrefix], mac: MacAddr) -> String {
+    let mac_prefix = format!("{:02X}{:02X}{:02X}", mac.0, mac.1, mac.2);
+    let mut ouis = String::new();
+    for p in nmap_mac_prefixes {
+        if mac_prefix == p.prefix {
+            ouis = p.ouis.to_string();
+        }
+    }
+    ouis
+}
+
 pub fn arp_scan(
     target: Target,
     src_ipv4: Option<Ipv4Addr>,
@@ -243,9 +501,7 @@ pub fn arp_scan(
     match target.target_type {
         TargetType::Ipv4 => {
             let nmap_mac_prefixes = get_nmap_mac_prefixes();
-            let mut ret = ArpScanResults {
-                alive_hosts: HashMap::new(),
-            };
+            let mut ret = ArpScanResults::new();
 
             // println!("{:?}", bi_vec);
             let pool = get_threads_pool(threads_num);
@@ -254,16 +510,22 @@ pub fn arp_scan(
                 None => get_default_timeout(),
             };
 
-            let dst_mac = MacAddr::broadcast();
-            let (tx, rx) = channel();
-            let mut recv_size = 0;
+            // the targets behind the same source address share one arp sweep
+            let mut groups: HashMap<Ipv4Addr, Vec<Ipv4Addr>> = HashMap::new();
             for host in target.hosts {
-                recv_size += 1;
                 let dst_ipv4 = host.addr;
                 let src_ipv4 = match find_source_ipv4(src_ipv4, dst_ipv4)? {
                     Some(s) => s,
                     None => return Err(CanNotFoundSourceAddress::new().into()),
                 };
+                groups.entry(src_ipv4).or_insert(Vec::new()).push(dst_ipv4);
+            }
+
+            let dst_mac = MacAddr::broadcast();
+            let (tx, rx) = channel();
+            let mut recv_size = 0;
+            for (src_ipv4, dst_ipv4s) in groups {
+                recv_size += 1;
                 let interface = match find_interface_by_ipv4(src_ipv4) {
                     Some(i) => i,
                     None => return Err(CanNotFoundInterface::new().into()),
@@ -274,60 +536,25 @@ pub fn arp_scan(
                 };
                 let tx = tx.clone();
                 pool.execute(move || {
-                    let scan_ret = arp::send_arp_scan_packet(
-                        dst_ipv4, dst_mac, src_ipv4, src_mac, interface, timeout,
+                    let scan_ret = arp::send_arp_scan_packets(
+                        &dst_ipv4s, dst_mac, src_ipv4, src_mac, interface, timeout,
                     );
-                    match tx.send(Ok((dst_ipv4, scan_ret))) {
+                    match tx.send(scan_ret) {
                         _ => (),
                     }
                 });
             }
             let iter = rx.into_iter().take(recv_size);
             for v in iter {
-                match v {
-                    Ok((target_ipv4, target_mac)) => match target_mac? {
-                        (Some(m), Some(_rtt)) => {
-                            let mut ouis = String::new();
-                            let mut mac_prefix = String::new();
-                            let m0 = format!("{:X}", m.0);
-                            let m1 = format!("{:X}", m.1);
-                            let m2 = format!("{:X}", m.2);
-                            // m0
-                            let i = if m0.len() < 2 { 2 - m0.len() } else { 0 };
-                            if i > 0 {
-                                for _ in 0..i {
-                                    mac_prefix += "0";
-                                }
-                            }
-                            mac_prefix += &m0;
-                            // m1
-                            let i = if m1.len() < 2 { 2 - m1.len() } else { 0 };
-                            if i > 0 {
-                                for _ in 0..i {
-                                    mac_prefix += "0";
-                                }
-                            }
-                            mac_prefix += &m1;
-                            // m2
-                            let i = if m2.len() < 2 { 2 - m2.len() } else { 0 };
-                            if i > 0 {
-                                for _ in 0..i {
-                                    mac_prefix += "0";
-                                }
-                            }
-                            mac_prefix += &m2;
-                            // println!("{}", mac_prefix);
-                            for p in &nmap_mac_prefixes {
-                                if mac_prefix == p.prefix {
-                                    ouis = p.ouis.to_string();
-                                }
-                            }
-                            let aah = ArpAliveHosts { mac_addr: m, ouis };
-                            ret.alive_hosts.insert(target_ipv4, aah);
-                        }
-                        (_, _) => (),
-                    },
-                    Err(e) => return Err(e),
+                for (target_ipv4, m, rtt) in v? {
+                    let ouis = find_ouis(&nmap_mac_prefixes, m);
+                    let aah = ArpAliveHosts {
+                        mac_addr: m,
+                        ouis,
+                        rtt: Some(rtt),
+                        gratuitous: false,
+                    };
+                    ret.insert(target_ipv4, aah);
                 }
             }
             Ok(ret)
@@ -336,6 +563,169 @@ pub fn arp_scan(
     }
 }
 
+pub fn arp_scan_passive(src_ipv4: Option<Ipv4Addr>, duration: Duration) -> Result<ArpScanResults> {
+    let route_ipv4 = system_route()?;
+    let src_ipv4 = match find_source_ipv4(src_ipv4, route_ipv4)? {
+        Some(s) => s,
+        None => return Err(CanNotFoundSourceAddress::new().into()),
+    };
+    let interface = match find_interface_by_ipv4(src_ipv4) {
+        Some(i) => i,
+        None => return Err(CanNotFoundInterface::new().into()),
+    };
+
+    let nmap_mac_prefixes = get_nmap_mac_prefixes();
+    let mut ret = ArpScanResults::new();
+    for o in arp::recv_arp_packets(interface, duration)? {
+        let ouis = find_ouis(&nmap_mac_prefixes, o.sender_mac);
+        let aah = ArpAliveHosts {
+            mac_addr: o.sender_mac,
+            ouis,
+            rtt: None,
+            gratuitous: o.gratuitous,
+        };
+        ret.insert(o.sender_ipv4, aah);
+    }
+    Ok(ret)
+}
+
+pub fn ndp_scan(
+    target: Target,
+    src_ipv6: Option<Ipv6Addr>,
+    threads_num: usize,
+    timeout: Option<Duration>,
+) -> Result<NdpScanResults> {
+    match target.target_type {
+        TargetType::Ipv6 => {
+            let nmap_mac_prefixes = get_nmap_mac_prefixes();
+            let mut ret = NdpScanResults::new();
+
+            let pool = get_threads_pool(threads_num);
+            let timeout = match timeout {
+                Some(t) => t,
+                None => get_default_timeout(),
+            };
+
+            let (tx, rx) = channel();
+            let mut recv_size = 0;
+            for host in target.hosts6 {
+                recv_size += 1;
+                let dst_ipv6 = host.addr;
+                let src_ipv6 = match find_source_ipv6(src_ipv6, dst_ipv6)? {
+                    Some(s) => s,
+                    None => return Err(CanNotFoundSourceAddress::new().into()),
+                };
+                let interface = match find_interface_by_ipv6(src_ipv6) {
+                    Some(i) => i,
+                    None => return Err(CanNotFoundInterface::new().into()),
+                };
+                let src_mac = match interface.mac {
+                    Some(m) => m,
+                    None => return Err(CanNotFoundMacAddress::new().into()),
+                };
+                let tx = tx.clone();
+                pool.execute(move || {
+                    let scan_ret = ndp::send_ndp_ns_scan_packet(
+                        dst_ipv6, src_ipv6, src_mac, interface, timeout,
+                    );
+                    match tx.send((dst_ipv6, scan_ret)) {
+                        _ => (),
+                    }
+                });
+            }
+            let iter = rx.into_iter().take(recv_size);
+            for (target_ipv6, target_macs) in iter {
+                for (m, rtt) in target_macs? {
+                    let ouis = find_ouis(&nmap_mac_prefixes, m);
+                    let aah = ArpAliveHosts {
+                        mac_addr: m,
+                        ouis,
+                        rtt: Some(rtt),
+                        gratuitous: false,
+                    };
+                    ret.insert(target_ipv6, aah);
+                }
+            }
+            Ok(ret)
+        }
+        _ => Err(NotSupportIpTypeForNdpScan::new(target.target_type).into()),
+    }
+}
+
+pub fn ndp_multicast_scan(
+    src_ipv6: Option<Ipv6Addr>,
+    timeout: Option<Duration>,
+) -> Result<NdpScanResults> {
+    let src_ipv6 = match src_ipv6 {
+        Some(s) => s,
+        None => {
+            let route_ipv6 = system_route6()?;
+            match find_source_ipv6(None, route_ipv6)? {
+                Some(s) => s,
+                None => return Err(CanNotFoundSourceAddress::new().into()),
+            }
+        }
+    };
+    let interface = match find_interface_by_ipv6(src_ipv6) {
+        Some(i) => i,
+        None => return Err(CanNotFoundInterface::new().into()),
+    };
+    let src_mac = match interface.mac {
+        Some(m) => m,
+        None => return Err(CanNotFoundMacAddress::new().into()),
+    };
+    let timeout = match timeout {
+        Some(t) => t,
+        None => get_default_timeout(),
+    };
+    // send from both the link-local and global addresses, so that the hosts answer with both of them
+    let mut src_ipv6s = Vec::new();
+    for ipnetwork in &interface.ips {
+        match ipnetwork.ip() {
+            IpAddr::V6(ipv6) => src_ipv6s.push(ipv6),
+            _ => (),
+        }
+    }
+
+    let nmap_mac_prefixes = get_nmap_mac_prefixes();
+    let mut ret = NdpScanResults::new();
+    for o in ndp::send_multicast_probes(src_ipv6s, src_mac, interface, timeout)? {
+        let ouis = find_ouis(&nmap_mac_prefixes, o.mac);
+        let aah = ArpAliveHosts {
+            mac_addr: o.mac,
+            ouis,
+            rtt: Some(o.rtt),
+            gratuitous: false,
+        };
+        ret.insert(o.addr, aah);
+        match o.router {
+            Some(ri) => {
+                ret.routers.insert(o.addr, ri);
+            }
+            None => (),
+        }
+    }
+    Ok(ret)
+}
+
+/// The protocol-specific payloads of the udp scan, the probes come from nmap-service-probes.
+/// The file is only parsed by the first udp scan, the later scans share the same payloads.
+fn get_udp_payloads() -> Result<&'static HashMap<u16, Vec<u8>>> {
+    static UDP_PAYLOADS: OnceLock<HashMap<u16, Vec<u8>>> = OnceLock::new();
+    match UDP_PAYLOADS.get() {
+        Some(p) => return Ok(p),
+        None => (),
+    }
+    let nsp_str = include_str!("./db/nmap-service-probes");
+    let mut nsp_lines = Vec::new();
+    for l in nsp_str.lines() {
+        nsp_lines.push(l.to_string());
+    }
+    let service_probes = nsp_probe_parser(&nsp_lines)?;
+    let payloads = udp_payloads(&service_probes);
+    Ok(UDP_PAYLOADS.get_or_init(|| payloads))
+}
+
 fn run_scan(
     method: ScanMethod,
     src_ipv4: Ipv4Addr,
@@ -345,6 +735,7 @@ fn run_scan(
     zombie_ipv4: Option<Ipv4Addr>,
     zombie_port: Option<u16>,
     protocol: Option<IpNextHeaderProtocol>,
+    udp_payload: &[u8],
     timeout: Duration,
 ) -> Result<(
     Ipv4Addr,
@@ -379,8 +770,10 @@ fn run_scan(
             tcp::send_maimon_scan_packet(src_ipv4, src_port, dst_ipv4, dst_port, timeout)?
         }
         ScanMethod::Idle => {
-            let zombie_ipv4 = zombie_ipv4.unwrap();
-            let zombie_port = zombie_port.unwrap();
+            let (zombie_ipv4, zombie_port) = match (zombie_ipv4, zombie_port) {
+                (Some(z), Some(p)) => (z, p),
+                _ => return Err(IdleScanNoZombie::new().into()),
+            };
             match tcp::send_idle_scan_packet(
                 src_ipv4,
                 src_port,
@@ -395,7 +788,7 @@ fn run_scan(
             }
         }
         ScanMethod::Udp => {
-            udp::send_udp_scan_packet(src_ipv4, src_port, dst_ipv4, dst_port, timeout)?
+            udp::send_udp_scan_packet(src_ipv4, src_port, dst_ipv4, dst_port, udp_payload, timeout)?
         }
         ScanMethod::IpProcotol => {
             ip::send_ip_procotol_scan_packet(src_ipv4, dst_ipv4, protocol.unwrap(), timeout)?
@@ -411,6 +804,8 @@ fn run_scan6(
     src_port: u16,
     dst_ipv6: Ipv6Addr,
     dst_port: u16,
+    zombie_ipv6: Option<Ipv6Addr>,
+    udp_payload: &[u8],
     timeout: Duration,
 ) -> Result<(Ipv6Addr, u16, TargetScanStatus, Option<Duration>)> {
     let (scan_ret, rtt) = match method {
@@ -438,9 +833,31 @@ fn run_scan6(
         ScanMethod6::Maimon => {
             tcp6::send_maimon_scan_packet(src_ipv6, src_port, dst_ipv6, dst_port, timeout)?
         }
-        ScanMethod6::Udp => {
-            udp6::send_udp_scan_packet(src_ipv6, src_port, dst_ipv6, dst_port, timeout)?
+        ScanMethod6::Idle => {
+            let zombie_ipv6 = match zombie_ipv6 {
+                Some(z) => z,
+                None => return Err(IdleScanNoZombie::new().into()),
+            };
+            match tcp6::send_idle_scan_packet(
+                src_ipv6,
+                src_port,
+                dst_ipv6,
+                dst_port,
+                zombie_ipv6,
+                timeout,
+            ) {
+                Ok((status, _idel_rets, rtt)) => (status, rtt),
+                Err(e) => return Err(e.into()),
+            }
         }
+        ScanMethod6::Udp => udp6::send_udp_scan_packet(
+            src_ipv6,
+            src_port,
+            dst_ipv6,
+            dst_port,
+            udp_payload,
+            timeout,
+        )?,
     };
 
     Ok((dst_ipv6, dst_port, scan_ret, rtt))
@@ -464,6 +881,10 @@ pub fn scan(
         Some(t) => t,
         None => get_default_timeout(),
     };
+    let udp_payloads = match method {
+        ScanMethod::Udp => Some(get_udp_payloads()?),
+        _ => None,
+    };
 
     for host in target.hosts {
         let dst_ipv4 = host.addr;
@@ -479,6 +900,11 @@ pub fn scan(
             let tx = tx.clone();
             recv_size += 1;
             pool.execute(move || {
+                // the empty datagram is sent if there is no payload for the port
+                let udp_payload: &[u8] = match udp_payloads.and_then(|p| p.get(&dst_port)) {
+                    Some(p) => p,
+                    None => &[],
+                };
                 let scan_ret = run_scan(
                     method,
                     src_ipv4,
@@ -488,6 +914,7 @@ pub fn scan(
                     zombie_ipv4,
                     zombie_port,
                     protocol,
+                    udp_payload,
                     timeout,
                 );
                 match tx.send(scan_ret) {
@@ -552,6 +979,7 @@ pub fn scan6(
     method: ScanMethod6,
     src_ipv6: Option<Ipv6Addr>,
     src_port: Option<u16>,
+    zombie_ipv6: Option<Ipv6Addr>,
     threads_num: usize,
     timeout: Option<Duration>,
 ) -> Result<TcpUdpScanResults> {
@@ -562,6 +990,10 @@ pub fn scan6(
         Some(t) => t,
         None => get_default_timeout(),
     };
+    let udp_payloads = match method {
+        ScanMethod6::Udp => Some(get_udp_payloads()?),
+        _ => None,
+    };
 
     for host in target.hosts6 {
         let dst_ipv6 = host.addr;
@@ -577,7 +1009,21 @@ pub fn scan6(
             let tx = tx.clone();
             recv_size += 1;
             pool.execute(move || {
-                let scan_ret = run_scan6(method, src_ipv6, src_port, dst_ipv6, dst_port, timeout);
+                // the empty datagram is sent if there is no payload for the port
+                let udp_payload: &[u8] = match udp_payloads.and_then(|p| p.get(&dst_port)) {
+                    Some(p) => p,
+                    None => &[],
+                };
+                let scan_ret = run_scan6(
+                    method,
+                    src_ipv6,
+                    src_port,
+                    dst_ipv6,
+                    dst_port,
+                    zombie_ipv6,
+                    udp_payload,
+                    timeout,
+                );
                 match tx.send(scan_ret) {
                     _ => (),
                 }
@@ -647,6 +1093,7 @@ pub fn tcp_connect_scan6(
         ScanMethod6::Connect,
         src_ipv6,
         src_port,
+        None,
         threads_num,
         timeout,
     )
@@ -685,6 +1132,7 @@ pub fn tcp_syn_scan6(
         ScanMethod6::Syn,
         src_ipv6,
         src_port,
+        None,
         threads_num,
         timeout,
     )
@@ -723,6 +1171,7 @@ pub fn tcp_fin_scan6(
         ScanMethod6::Fin,
         src_ipv6,
         src_port,
+        None,
         threads_num,
         timeout,
     )
@@ -761,6 +1210,7 @@ pub fn tcp_ack_scan6(
         ScanMethod6::Ack,
         src_ipv6,
         src_port,
+        None,
         threads_num,
         timeout,
     )
@@ -799,6 +1249,7 @@ pub fn tcp_null_scan6(
         ScanMethod6::Null,
         src_ipv6,
         src_port,
+        None,
         threads_num,
         timeout,
     )
@@ -837,6 +1288,7 @@ pub fn tcp_xmas_scan6(
         ScanMethod6::Xmas,
         src_ipv6,
         src_port,
+        None,
         threads_num,
         timeout,
     )
@@ -875,6 +1327,7 @@ pub fn tcp_window_scan6(
         ScanMethod6::Window,
         src_ipv6,
         src_port,
+        None,
         threads_num,
         timeout,
     )
@@ -913,6 +1366,7 @@ pub fn tcp_maimon_scan6(
         ScanMethod6::Maimon,
         src_ipv6,
         src_port,
+        None,
         threads_num,
         timeout,
     )
@@ -927,6 +1381,9 @@ pub fn tcp_idle_scan(
     threads_num: usize,
     timeout: Option<Duration>,
 ) -> Result<TcpUdpScanResults> {
+    if zombie_ipv4.is_none() || zombie_port.is_none() {
+        return Err(IdleScanNoZombie::new().into());
+    }
     let (ret, _) = scan(
         target,
         ScanMethod::Idle,
@@ -941,6 +1398,189 @@ pub fn tcp_idle_scan(
     Ok(ret)
 }
 
+pub fn tcp_idle_scan6(
+    target: Target,
+    src_ipv6: Option<Ipv6Addr>,
+    src_port: Option<u16>,
+    zombie_ipv6: Option<Ipv6Addr>,
+    threads_num: usize,
+    timeout: Option<Duration>,
+) -> Result<TcpUdpScanResults> {
+    if zombie_ipv6.is_none() {
+        return Err(IdleScanNoZombie::new().into());
+    }
+    scan6(
+        target,
+        ScanMethod6::Idle,
+        src_ipv6,
+        src_port,
+        zombie_ipv6,
+        threads_num,
+        timeout,
+    )
+}
+
+/// The spoofed source is in the TEST-NET-1 (192.0.2.0/24, RFC 5737),
+/// so the answers of the zombie are not sent to any real host.
+fn random_spoof_ipv4() -> Ipv4Addr {
+    let mut rng = rand::thread_rng();
+    Ipv4Addr::new(192, 0, 2, rng.gen_range(1..255))
+}
+
+/// Check whether the zombie can be used for the idle scan.
+/// A burst of probes is sent to classify the ip id sequence (the same rules as the os detection TI test),
+/// then some packets spoofed from another address are sent to find the per host counter.
+pub fn probe_zombie(
+    zombie_ipv4: Ipv4Addr,
+    zombie_port: u16,
+    src_ipv4: Option<Ipv4Addr>,
+    timeout: Option<Duration>,
+) -> Result<ZombieStatus> {
+    // same as the SEQ probes of the os detection
+    const ZOMBIE_PROBE_NUM: usize = 6;
+    const ZOMBIE_SPOOF_NUM: usize = 4;
+
+    let timeout = match timeout {
+        Some(t) => t,
+        None => get_default_timeout(),
+    };
+    let src_ipv4 = match find_source_ipv4(src_ipv4, zombie_ipv4)? {
+        Some(s) => s,
+        None => return Err(CanNotFoundSourceAddress::new().into()),
+    };
+    let src_port = random_port();
+
+    let mut ip_ids = Vec::new();
+    let mut recv_times = Vec::new();
+    for _ in 0..ZOMBIE_PROBE_NUM {
+        match tcp::send_zombie_probe_packet(src_ipv4, src_port, zombie_ipv4, zombie_port, timeout)?
+        {
+            (Some(id), _) => {
+                ip_ids.push(id);
+                recv_times.push(Instant::now());
+            }
+            (None, _) => (),
+        }
+    }
+
+    let sequence = if ip_ids.len() >= 3 {
+        match ip_id_class(&ip_ids, true)?.as_str() {
+            "Z" => IpIdSequence::Zero,
+            "I" => IpIdSequence::Incremental,
+            "BI" => IpIdSequence::BrokenIncremental,
+            "RI" | "RD" => IpIdSequence::Random,
+            _ => IpIdSequence::Unknown,
+        }
+    } else {
+        IpIdSequence::Unknown
+    };
+    let incremental = |s: IpIdSequence| -> bool {
+        s == IpIdSequence::Incremental || s == IpIdSequence::BrokenIncremental
+    };
+
+    // every probe costs the zombie one ip id, the rest is used by the others
+    let background_rate = if incremental(sequence) {
+        let mut background = 0;
+        for w in ip_ids.windows(2) {
+            background += tcp::ip_id_distance(w[0], w[1]).saturating_sub(1) as u32;
+        }
+        let elapsed = recv_times[recv_times.len() - 1].duration_since(recv_times[0]);
+        if elapsed.as_secs_f64() > 0.0 {
+            background as f64 / elapsed.as_secs_f64()
+        } else {
+            0.0
+        }
+    } else {
+        0.0
+    };
+
+    let sequence = if incremental(sequence) {
+        let spoof_ipv4 = random_spoof_ipv4();
+        tcp::send_zombie_spoof_packets(
+            src_ipv4,
+            spoof_ipv4,
+            zombie_ipv4,
+            zombie_port,
+            ZOMBIE_SPOOF_NUM,
+        )?;
+        match tcp::send_zombie_probe_packet(src_ipv4, src_port, zombie_ipv4, zombie_port, timeout)?
+        {
+            (Some(id), _) => {
+                let last_ip_id = ip_ids[ip_ids.len() - 1];
+                // the answers to the spoofed packets are invisible here if the counter is not shared
+                if (tcp::ip_id_distance(last_ip_id, id) as usize) <= ZOMBIE_SPOOF_NUM / 2 {
+                    IpIdSequence::PerHost
+                } else {
+                    sequence
+                }
+            }
+            (None, _) => sequence,
+        }
+    } else {
+        sequence
+    };
+
+    // one idle scan test takes about one timeout, the noise expected in it should be less than one packet
+    let suitable = incremental(sequence) && background_rate * timeout.as_secs_f64() < 1.0;
+    Ok(ZombieStatus {
+        zombie_ipv4,
+        zombie_port,
+        ip_ids,
+        sequence,
+        background_rate,
+        suitable,
+    })
+}
+
+/// Check whether the zombie can be used for the ipv6 idle scan,
+/// its fragment id must be predictable after we send it a packet too big.
+pub fn probe_zombie6(
+    zombie_ipv6: Ipv6Addr,
+    src_ipv6: Option<Ipv6Addr>,
+    timeout: Option<Duration>,
+) -> Result<ZombieStatus6> {
+    // same as nmap, less than ten means incremental
+    const ZOMBIE_MAX_FRAG_ID_STEP: u32 = 10;
+    const ZOMBIE_PROBE_NUM: usize = 4;
+
+    let timeout = match timeout {
+        Some(t) => t,
+        None => get_default_timeout(),
+    };
+    let src_ipv6 = match find_source_ipv6(src_ipv6, zombie_ipv6)? {
+        Some(s) => s,
+        None => return Err(CanNotFoundSourceAddress::new().into()),
+    };
+
+    tcp6::send_packet_too_big(src_ipv6, zombie_ipv6, &[src_ipv6])?;
+    let mut frag_ids = Vec::new();
+    for _ in 0..ZOMBIE_PROBE_NUM {
+        match tcp6::send_frag_id_probe_packet(src_ipv6, zombie_ipv6, timeout)? {
+            (Some(id), _) => frag_ids.push(id),
+            (None, _) => (),
+        }
+    }
+
+    let sequence = if frag_ids.len() == 0 {
+        FragIdSequence::NoFragment
+    } else if frag_ids.len() >= 2
+        && frag_ids.windows(2).all(|w| {
+            let step = w[1].wrapping_sub(w[0]);
+            step > 0 && step < ZOMBIE_MAX_FRAG_ID_STEP
+        })
+    {
+        FragIdSequence::Incremental
+    } else {
+        FragIdSequence::Random
+    };
+    Ok(ZombieStatus6 {
+        zombie_ipv6,
+        frag_ids,
+        suitable: sequence == FragIdSequence::Incremental,
+        sequence,
+    })
+}
+
 pub fn udp_scan(
     target: Target,
     src_ipv4: Option<Ipv4Addr>,
@@ -974,6 +1614,7 @@ pub fn udp_scan6(
         ScanMethod6::Udp,
         src_ipv6,
         src_port,
+        None,
         threads_num,
         timeout,
     )
@@ -1035,6 +1676,56 @@ mod tests {
         Ok(())
     }
     #[test]
+    fn test_arp_scan_passive() -> Result<()> {
+        let src_ipv4 = None;
+        let duration = Duration::new(10, 0);
+        let ret: ArpScanResults = arp_scan_passive(src_ipv4, duration).unwrap();
+        println!("{}", ret);
+        Ok(())
+    }
+    #[test]
+    fn test_ndp_scan() -> Result<()> {
+        let src_ipv6 = None;
+        let dst_ipv6: Ipv6Addr = "fe80::20c:29ff:fe2a:e252".parse().unwrap();
+        let host = Host6::new(dst_ipv6, None)?;
+        let target: Target = Target::new6(vec![host]);
+        let threads_num = 8;
+        let timeout = Some(Duration::new(1, 5));
+        let ret: NdpScanResults = ndp_scan(target, src_ipv6, threads_num, timeout).unwrap();
+        println!("{}", ret);
+        Ok(())
+    }
+    #[test]
+    fn test_ndp_multicast_scan() -> Result<()> {
+        let src_ipv6 = None;
+        let timeout = Some(Duration::new(3, 0));
+        let ret: NdpScanResults = ndp_multicast_scan(src_ipv6, timeout).unwrap();
+        println!("{}", ret);
+        let target = ret.to_target(Some(vec![22]))?;
+        println!("{}", target);
+        Ok(())
+    }
+    #[test]
+    fn test_arp_results_duplicate() {
+        let ip = Ipv4Addr::new(192, 168, 1, 1);
+        let mut ret = ArpScanResults::new();
+        let aah1 = ArpAliveHosts {
+            mac_addr: MacAddr::new(0, 0x0c, 0x29, 1, 2, 3),
+            ouis: String::from("VMware"),
+            rtt: Some(Duration::from_millis(3)),
+            gratuitous: false,
+        };
+        let mut aah2 = aah1.clone();
+        aah2.mac_addr = MacAddr::new(0, 0x0c, 0x29, 4, 5, 6);
+        ret.insert(ip, aah1.clone());
+        ret.insert(ip, aah1);
+        assert_eq!(ret.duplicate_hosts.len(), 0);
+        ret.insert(ip, aah2);
+        assert_eq!(ret.alive_hosts.len(), 1);
+        assert_eq!(ret.duplicate_hosts[&ip].len(), 2);
+        println!("{}", ret);
+    }
+    #[test]
     fn test_tcp_connect_scan() -> Result<()> {
         let src_ipv4 = None;
         let src_port = None;
@@ -1100,6 +1791,39 @@ mod tests {
         Ok(())
     }
     #[test]
+    fn test_probe_zombie() -> Result<()> {
+        let zombie_ipv4 = Ipv4Addr::new(192, 168, 72, 135);
+        let zombie_port = 80;
+        let timeout = Some(Duration::new(3, 0));
+        let ret = probe_zombie(zombie_ipv4, zombie_port, None, timeout)?;
+        println!("{}", ret);
+        Ok(())
+    }
+    #[test]
+    fn test_probe_zombie6() -> Result<()> {
+        let zombie_ipv6: Ipv6Addr = "fe80::20c:29ff:fe2a:e252".parse().unwrap();
+        let timeout = Some(Duration::new(3, 0));
+        let ret = probe_zombie6(zombie_ipv6, None, timeout)?;
+        println!("{}", ret);
+        Ok(())
+    }
+    #[test]
+    fn test_get_udp_payloads() {
+        let payloads_1 = get_udp_payloads().unwrap();
+        let payloads_2 = get_udp_payloads().unwrap();
+        // parsed once and shared
+        assert!(std::ptr::eq(payloads_1, payloads_2));
+        assert!(payloads_1.contains_key(&53));
+    }
+    #[test]
+    fn test_idle_scan_no_zombie() {
+        let dst_ipv6: Ipv6Addr = "fe80::20c:29ff:fe2a:e252".parse().unwrap();
+        let host = Host6::new(dst_ipv6, Some(vec![22])).unwrap();
+        let target = Target::new6(vec![host]);
+        let ret = tcp_idle_scan6(target, None, None, None, 1, None);
+        assert!(ret.is_err());
+    }
+    #[test]
     fn test_udp_scan() -> Result<()> {
         let src_ipv4: Option<Ipv4Addr> = Some(Ipv4Addr::new(192, 168, 72, 128));
         let src_port: Option<u16> = None;
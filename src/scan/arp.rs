@@ -3,25 +3,37 @@ use pnet::datalink::MacAddr;
 use pnet::datalink::NetworkInterface;
 use pnet::packet::arp::ArpHardwareTypes;
 use pnet::packet::arp::ArpOperations;
+use pnet::packet::arp::ArpPacket;
 use pnet::packet::arp::MutableArpPacket;
 use pnet::packet::ethernet::EtherTypes;
+use pnet::packet::ethernet::EthernetPacket;
+use pnet::packet::Packet;
+use std::collections::HashMap;
+use std::collections::HashSet;
 use std::net::Ipv4Addr;
 use std::time::Duration;
 
 use crate::layers::get_mac_from_arp;
+use crate::layers::layer2_capture;
+use crate::layers::layer2_multi_send_until;
 use crate::layers::layer2_send;
 use crate::layers::Layer2Match;
 use crate::layers::Layer3Match;
 use crate::layers::LayersMatch;
 
-pub fn send_arp_scan_packet(
-    dst_ipv4: Ipv4Addr,
-    dst_mac: MacAddr,
-    src_ipv4: Ipv4Addr,
-    src_mac: MacAddr,
-    interface: NetworkInterface,
-    timeout: Duration,
-) -> Result<(Option<MacAddr>, Option<Duration>)> {
+/// After every target has replied, keep listening this long for the duplicate replies.
+const ARP_DUPLICATE_WAIT_MS: u64 = 200;
+
+/// The arp packet seen by passive listening.
+#[derive(Debug, Clone, Copy)]
+pub struct ArpObserved {
+    pub sender_ipv4: Ipv4Addr,
+    pub sender_mac: MacAddr,
+    /// The sender announced its own address (sender ip == target ip).
+    pub gratuitous: bool,
+}
+
+fn arp_request_packet(dst_ipv4: Ipv4Addr, src_ipv4: Ipv4Addr, src_mac: MacAddr) -> [u8; 28] {
     let mut arp_buffer = [0u8; 28];
     let mut arp_packet = MutableArpPacket::new(&mut arp_buffer).unwrap();
 
@@ -34,24 +46,39 @@ pub fn send_arp_scan_packet(
     arp_packet.set_sender_proto_addr(src_ipv4);
     arp_packet.set_target_hw_addr(MacAddr::zero());
     arp_packet.set_target_proto_addr(dst_ipv4);
+    arp_buffer
+}
 
-    let ethernet_type = EtherTypes::Arp;
+fn arp_reply_match(dst_ipv4: Ipv4Addr, src_ipv4: Ipv4Addr, src_mac: MacAddr) -> LayersMatch {
     let layer2 = Layer2Match {
         src_mac: None,
         dst_mac: Some(src_mac),
-        ethernet_type: Some(ethernet_type),
+        ethernet_type: Some(EtherTypes::Arp),
     };
     let layer3 = Layer3Match {
         layer2: Some(layer2),
         src_addr: Some(dst_ipv4.into()),
         dst_addr: Some(src_ipv4.into()),
     };
-    let layers_match = LayersMatch::Layer3Match(layer3);
+    LayersMatch::Layer3Match(layer3)
+}
+
+/// Returns the first reply, it does not wait for the timeout after the reply arrives.
+pub fn send_arp_scan_packet(
+    dst_ipv4: Ipv4Addr,
+    dst_mac: MacAddr,
+    src_ipv4: Ipv4Addr,
+    src_mac: MacAddr,
+    interface: NetworkInterface,
+    timeout: Duration,
+) -> Result<(Option<MacAddr>, Option<Duration>)> {
+    let arp_buffer = arp_request_packet(dst_ipv4, src_ipv4, src_mac);
+    let layers_match = arp_reply_match(dst_ipv4, src_ipv4, src_mac);
     match layer2_send(
         dst_mac,
         interface,
         &arp_buffer,
-        ethernet_type,
+        EtherTypes::Arp,
         vec![layers_match],
         timeout,
     )? {
@@ -59,3 +86,106 @@ pub fn send_arp_scan_packet(
         (_, _) => Ok((None, None)),
     }
 }
+
+/// Send the arp requests of all the `dst_ipv4s` on one channel and collect the replies on it,
+/// it stops a short while after every target has replied, or at the timeout.
+/// Returns all the replies with the sender address and the rtt of the request to it,
+/// more than one reply of an address means that more than one host is using it.
+pub fn send_arp_scan_packets(
+    dst_ipv4s: &[Ipv4Addr],
+    dst_mac: MacAddr,
+    src_ipv4: Ipv4Addr,
+    src_mac: MacAddr,
+    interface: NetworkInterface,
+    timeout: Duration,
+) -> Result<Vec<(Ipv4Addr, MacAddr, Duration)>> {
+    let mut send_buffs = Vec::new();
+    let mut layers_match = Vec::new();
+    for &dst_ipv4 in dst_ipv4s {
+        let arp_buffer = arp_request_packet(dst_ipv4, src_ipv4, src_mac);
+        send_buffs.push((dst_mac, arp_buffer.to_vec()));
+        layers_match.push(arp_reply_match(dst_ipv4, src_ipv4, src_mac));
+    }
+
+    let mut waiting: HashSet<Ipv4Addr> = dst_ipv4s.iter().copied().collect();
+    let (send_times, rets) = layer2_multi_send_until(
+        send_buffs,
+        interface,
+        EtherTypes::Arp,
+        layers_match,
+        timeout,
+        Duration::from_millis(ARP_DUPLICATE_WAIT_MS),
+        |r| {
+            match arp_sender(r) {
+                Some((ipv4, _)) => {
+                    waiting.remove(&ipv4);
+                }
+                None => (),
+            }
+            waiting.is_empty()
+        },
+    )?;
+    // the requests are sent in the order of dst_ipv4s
+    let send_times: HashMap<Ipv4Addr, Duration> =
+        dst_ipv4s.iter().copied().zip(send_times).collect();
+    let mut ret = Vec::new();
+    for (r, recv_time) in rets {
+        match arp_sender(&r) {
+            Some((ipv4, mac)) => {
+                let rtt = match send_times.get(&ipv4) {
+                    Some(t) => recv_time.saturating_sub(*t),
+                    None => recv_time,
+                };
+                ret.push((ipv4, mac, rtt))
+            }
+            None => (),
+        }
+    }
+    Ok(ret)
+}
+
+fn arp_sender(ethernet_buff: &[u8]) -> Option<(Ipv4Addr, MacAddr)> {
+    let ethernet_packet = EthernetPacket::new(ethernet_buff)?;
+    let arp_packet = ArpPacket::new(ethernet_packet.payload())?;
+    Some((
+        arp_packet.get_sender_proto_addr(),
+        arp_packet.get_sender_hw_addr(),
+    ))
+}
+
+/// Listen to all the arp traffic on the interface, nothing will be sent.
+pub fn recv_arp_packets(
+    interface: NetworkInterface,
+    duration: Duration,
+) -> Result<Vec<ArpObserved>> {
+    let layer2 = Layer2Match {
+        src_mac: None,
+        dst_mac: None,
+        ethernet_type: Some(EtherTypes::Arp),
+    };
+    let layers_match = LayersMatch::Layer2Match(layer2);
+    let rets = layer2_capture(interface, vec![layers_match], duration)?;
+    let mut ret = Vec::new();
+    for (r, _) in rets {
+        let ethernet_packet = match EthernetPacket::new(&r) {
+            Some(e) => e,
+            None => continue,
+        };
+        let arp_packet = match ArpPacket::new(ethernet_packet.payload()) {
+            Some(a) => a,
+            None => continue,
+        };
+        let sender_ipv4 = arp_packet.get_sender_proto_addr();
+        // arp probe (RFC 5227) does not tell us the sender address
+        if sender_ipv4.is_unspecified() {
+            continue;
+        }
+        let o = ArpObserved {
+            sender_ipv4,
+            sender_mac: arp_packet.get_sender_hw_addr(),
+            gratuitous: sender_ipv4 == arp_packet.get_target_proto_addr(),
+        };
+        ret.push(o);
+    }
+    Ok(ret)
+}
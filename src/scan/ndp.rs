@@ -0,0 +1,303 @@
+use anyhow::Result;
+use pnet::datalink::MacAddr;
+use pnet::datalink::NetworkInterface;
+use pnet::packet::ethernet::EtherTypes;
+use pnet::packet::ethernet::EthernetPacket;
+use pnet::packet::icmpv6;
+use pnet::packet::icmpv6::echo_request::MutableEchoRequestPacket;
+use pnet::packet::icmpv6::ndp::NdpOptionTypes;
+use pnet::packet::icmpv6::ndp::NeighborAdvertPacket;
+use pnet::packet::icmpv6::ndp::RouterAdvertFlags;
+use pnet::packet::icmpv6::ndp::RouterAdvertPacket;
+use pnet::packet::icmpv6::Icmpv6Code;
+use pnet::packet::icmpv6::Icmpv6Packet;
+use pnet::packet::icmpv6::Icmpv6Type;
+use pnet::packet::icmpv6::Icmpv6Types;
+use pnet::packet::icmpv6::MutableIcmpv6Packet;
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv6::Ipv6Packet;
+use pnet::packet::ipv6::MutableIpv6Packet;
+use pnet::packet::Packet;
+use rand::Rng;
+use std::net::Ipv6Addr;
+use std::time::Duration;
+
+use crate::layers::ipv6_multicast_mac;
+use crate::layers::layer2_multi_send_all;
+use crate::layers::layer2_multi_send_until;
+use crate::layers::multicast_mac;
+use crate::layers::ndp_ns_packet;
+use crate::layers::ndp_rs_packet;
+use crate::layers::Layer2Match;
+use crate::layers::Layer3Match;
+use crate::layers::Layer4MatchIcmpv6;
+use crate::layers::LayersMatch;
+use crate::layers::ICMPV6_ER_HEADER_SIZE;
+use crate::layers::IPV6_HEADER_SIZE;
+use crate::scan::RouterInfo;
+
+const ALL_NODES: Ipv6Addr = Ipv6Addr::new(0xFF02, 0, 0, 0, 0, 0, 0, 1);
+const ALL_ROUTERS: Ipv6Addr = Ipv6Addr::new(0xFF02, 0, 0, 0, 0, 0, 0, 2);
+// hop-by-hop header with the router alert option (RFC 2711)
+const HOP_BY_HOP_SIZE: usize = 8;
+const MLDV2_QUERY_SIZE: usize = 28;
+const MLD_QUERY: Icmpv6Type = Icmpv6Type(130);
+const MLDV1_REPORT: Icmpv6Type = Icmpv6Type(131);
+const MLDV2_REPORT: Icmpv6Type = Icmpv6Type(143);
+// max response delay of the mld query in milliseconds
+const MLD_MAX_RESPONSE_DELAY: u16 = 1000;
+/// After the first neighbor advertisement, keep listening this long for the duplicate ones.
+const NDP_DUPLICATE_WAIT_MS: u64 = 200;
+
+/// The host seen by the multicast probes.
+#[derive(Debug, Clone)]
+pub struct MulticastObserved {
+    pub addr: Ipv6Addr,
+    pub mac: MacAddr,
+    pub rtt: Duration,
+    // some if the packet is a router advertisement
+    pub router: Option<RouterInfo>,
+}
+
+/// Returns the target link-layer address in the neighbor advertisement,
+/// or the ethernet source address if the option is missing.
+pub fn get_mac_from_ndp_na(ethernet_buff: &[u8]) -> Option<MacAddr> {
+    let ethernet_packet = EthernetPacket::new(ethernet_buff)?;
+    let ipv6_packet = Ipv6Packet::new(ethernet_packet.payload())?;
+    let na_packet = NeighborAdvertPacket::new(ipv6_packet.payload())?;
+    for o in na_packet.get_options() {
+        if o.option_type == NdpOptionTypes::TargetLLAddr && o.data.len() >= 6 {
+            let mac = MacAddr::new(
+                o.data[0], o.data[1], o.data[2], o.data[3], o.data[4], o.data[5],
+            );
+            return Some(mac);
+        }
+    }
+    Some(ethernet_packet.get_source())
+}
+
+/// Returns the neighbor advertisements received in a short while after the first one (or before the timeout),
+/// more than one reply means that more than one host is using the same address.
+pub fn send_ndp_ns_scan_packet(
+    dst_ipv6: Ipv6Addr,
+    src_ipv6: Ipv6Addr,
+    src_mac: MacAddr,
+    interface: NetworkInterface,
+    timeout: Duration,
+) -> Result<Vec<(MacAddr, Duration)>> {
+    let ipv6_buff = ndp_ns_packet(src_ipv6, src_mac, dst_ipv6);
+
+    let layer3 = Layer3Match {
+        layer2: None,
+        src_addr: Some(dst_ipv6.into()),
+        dst_addr: Some(src_ipv6.into()),
+    };
+    let layer4_icmpv6 = Layer4MatchIcmpv6 {
+        layer3: Some(layer3),
+        types: Some(Icmpv6Types::NeighborAdvert),
+        codes: None,
+    };
+    let layers_match = LayersMatch::Layer4MatchIcmpv6(layer4_icmpv6);
+
+    let (_, rets) = layer2_multi_send_until(
+        vec![(multicast_mac(dst_ipv6), ipv6_buff.to_vec())],
+        interface,
+        EtherTypes::Ipv6,
+        vec![layers_match],
+        timeout,
+        Duration::from_millis(NDP_DUPLICATE_WAIT_MS),
+        |r| get_mac_from_ndp_na(r).is_some(),
+    )?;
+    let mut ret = Vec::new();
+    for (r, rtt) in rets {
+        match get_mac_from_ndp_na(&r) {
+            Some(m) => ret.push((m, rtt)),
+            None => (),
+        }
+    }
+    Ok(ret)
+}
+
+fn echo_request_packet(
+    src_ipv6: Ipv6Addr,
+    dst_ipv6: Ipv6Addr,
+) -> [u8; IPV6_HEADER_SIZE + ICMPV6_ER_HEADER_SIZE] {
+    let mut rng = rand::thread_rng();
+    let mut ipv6_buff = [0u8; IPV6_HEADER_SIZE + ICMPV6_ER_HEADER_SIZE];
+    let mut ipv6_header = MutableIpv6Packet::new(&mut ipv6_buff).unwrap();
+    ipv6_header.set_version(6);
+    ipv6_header.set_payload_length(ICMPV6_ER_HEADER_SIZE as u16);
+    ipv6_header.set_next_header(IpNextHeaderProtocols::Icmpv6);
+    ipv6_header.set_hop_limit(1);
+    ipv6_header.set_source(src_ipv6);
+    ipv6_header.set_destination(dst_ipv6);
+
+    let mut icmpv6_header =
+        MutableEchoRequestPacket::new(&mut ipv6_buff[IPV6_HEADER_SIZE..]).unwrap();
+    icmpv6_header.set_icmpv6_type(Icmpv6Types::EchoRequest);
+    icmpv6_header.set_icmpv6_code(Icmpv6Code(0));
+    icmpv6_header.set_sequence_number(1);
+    icmpv6_header.set_identifier(rng.gen());
+
+    let mut icmpv6_header = MutableIcmpv6Packet::new(&mut ipv6_buff[IPV6_HEADER_SIZE..]).unwrap();
+    let checksum = icmpv6::checksum(&icmpv6_header.to_immutable(), &src_ipv6, &dst_ipv6);
+    icmpv6_header.set_checksum(checksum);
+    ipv6_buff
+}
+
+/// MLDv2 general query (RFC 3810), every listener on the link will answer it with a report.
+fn mld_query_packet(
+    src_ipv6: Ipv6Addr,
+) -> [u8; IPV6_HEADER_SIZE + HOP_BY_HOP_SIZE + MLDV2_QUERY_SIZE] {
+    let mut ipv6_buff = [0u8; IPV6_HEADER_SIZE + HOP_BY_HOP_SIZE + MLDV2_QUERY_SIZE];
+    let mut ipv6_header = MutableIpv6Packet::new(&mut ipv6_buff).unwrap();
+    ipv6_header.set_version(6);
+    ipv6_header.set_payload_length((HOP_BY_HOP_SIZE + MLDV2_QUERY_SIZE) as u16);
+    ipv6_header.set_next_header(IpNextHeaderProtocols::Hopopt);
+    ipv6_header.set_hop_limit(1);
+    ipv6_header.set_source(src_ipv6);
+    ipv6_header.set_destination(ALL_NODES);
+
+    // next header, header length, router alert (type 5, length 2, value 0 means mld), padn (type 1, length 0)
+    let hop_by_hop = [
+        IpNextHeaderProtocols::Icmpv6.0,
+        0,
+        0x05,
+        0x02,
+        0x00,
+        0x00,
+        0x01,
+        0x00,
+    ];
+    let hop_by_hop_end = IPV6_HEADER_SIZE + HOP_BY_HOP_SIZE;
+    ipv6_buff[IPV6_HEADER_SIZE..hop_by_hop_end].copy_from_slice(&hop_by_hop);
+
+    let mut mld_buff = [0u8; MLDV2_QUERY_SIZE];
+    mld_buff[0] = MLD_QUERY.0;
+    // mld_buff[1] is code and mld_buff[2..4] is checksum
+    mld_buff[4..6].copy_from_slice(&MLD_MAX_RESPONSE_DELAY.to_be_bytes());
+    // mld_buff[8..24] is the multicast address, unspecified means general query
+    // S flag is 0 and QRV is 2
+    mld_buff[24] = 0x02;
+    // QQIC, 125 seconds by default
+    mld_buff[25] = 125;
+    // mld_buff[26..28] is the number of sources
+    let mld_packet = Icmpv6Packet::new(&mld_buff).unwrap();
+    let checksum = icmpv6::checksum(&mld_packet, &src_ipv6, &ALL_NODES);
+    mld_buff[2..4].copy_from_slice(&checksum.to_be_bytes());
+
+    ipv6_buff[hop_by_hop_end..].copy_from_slice(&mld_buff);
+    ipv6_buff
+}
+
+fn parse_router_advert(icmpv6_buff: &[u8]) -> Option<RouterInfo> {
+    let ra_packet = RouterAdvertPacket::new(icmpv6_buff)?;
+    let flags = ra_packet.get_flags();
+    let mut prefixes = Vec::new();
+    for o in ra_packet.get_options() {
+        // prefix length (1), flags (1), valid lifetime (4), preferred lifetime (4), reserved (4), prefix (16)
+        if o.option_type == NdpOptionTypes::PrefixInformation && o.data.len() >= 30 {
+            let mut prefix = [0u8; 16];
+            prefix.copy_from_slice(&o.data[14..30]);
+            prefixes.push((Ipv6Addr::from(prefix), o.data[0]));
+        }
+    }
+    let ri = RouterInfo {
+        managed: flags & RouterAdvertFlags::ManagedAddressConf != 0,
+        other: flags & RouterAdvertFlags::OtherConf != 0,
+        lifetime: ra_packet.get_lifetime(),
+        prefixes,
+    };
+    Some(ri)
+}
+
+fn parse_multicast_response(ethernet_buff: &[u8], rtt: Duration) -> Option<MulticastObserved> {
+    let ethernet_packet = EthernetPacket::new(ethernet_buff)?;
+    let ipv6_packet = Ipv6Packet::new(ethernet_packet.payload())?;
+    let addr = ipv6_packet.get_source();
+    // the report sent by host which is doing duplicate address detection
+    if addr.is_unspecified() {
+        return None;
+    }
+    let payload = ipv6_packet.payload();
+    // mld reports carry the hop-by-hop header
+    let icmpv6_buff = match ipv6_packet.get_next_header() {
+        IpNextHeaderProtocols::Icmpv6 => payload,
+        IpNextHeaderProtocols::Hopopt => {
+            if payload.len() < 2 || payload[0] != IpNextHeaderProtocols::Icmpv6.0 {
+                return None;
+            }
+            let hop_by_hop_len = (payload[1] as usize + 1) * 8;
+            if payload.len() <= hop_by_hop_len {
+                return None;
+            }
+            &payload[hop_by_hop_len..]
+        }
+        _ => return None,
+    };
+    let icmpv6_packet = Icmpv6Packet::new(icmpv6_buff)?;
+    let router = match icmpv6_packet.get_icmpv6_type() {
+        Icmpv6Types::EchoReply | Icmpv6Types::NeighborAdvert | MLDV1_REPORT | MLDV2_REPORT => None,
+        Icmpv6Types::RouterAdvert => Some(parse_router_advert(icmpv6_buff)?),
+        _ => return None,
+    };
+    let o = MulticastObserved {
+        addr,
+        mac: ethernet_packet.get_source(),
+        rtt,
+        router,
+    };
+    Some(o)
+}
+
+/// Sends icmpv6 echo requests to all nodes (ff02::1) and all routers (ff02::2) from every source address,
+/// a mld general query and a router solicitation from the link-local source address,
+/// then returns all the hosts which answered before the timeout.
+pub fn send_multicast_probes(
+    src_ipv6s: Vec<Ipv6Addr>,
+    src_mac: MacAddr,
+    interface: NetworkInterface,
+    timeout: Duration,
+) -> Result<Vec<MulticastObserved>> {
+    let mut send_buffs = Vec::new();
+    for &src_ipv6 in &src_ipv6s {
+        for dst_ipv6 in [ALL_NODES, ALL_ROUTERS] {
+            let buff = echo_request_packet(src_ipv6, dst_ipv6);
+            send_buffs.push((ipv6_multicast_mac(dst_ipv6), buff.to_vec()));
+        }
+        // mld and ndp must be sent with the link-local address
+        if src_ipv6.segments()[0] & 0xFFC0 == 0xFE80 {
+            let buff = mld_query_packet(src_ipv6);
+            send_buffs.push((ipv6_multicast_mac(ALL_NODES), buff.to_vec()));
+            let buff = ndp_rs_packet(src_ipv6, src_mac);
+            send_buffs.push((ipv6_multicast_mac(ALL_ROUTERS), buff.to_vec()));
+        }
+    }
+
+    let layer2 = Layer2Match {
+        src_mac: None,
+        dst_mac: None,
+        ethernet_type: Some(EtherTypes::Ipv6),
+    };
+    let layers_match = LayersMatch::Layer2Match(layer2);
+    let rets = layer2_multi_send_all(
+        send_buffs,
+        interface,
+        EtherTypes::Ipv6,
+        vec![layers_match],
+        timeout,
+    )?;
+    let mut ret = Vec::new();
+    for (r, rtt) in rets {
+        match parse_multicast_response(&r, rtt) {
+            Some(o) => {
+                // skip the packets sent by ourselves
+                if o.mac != src_mac && !src_ipv6s.contains(&o.addr) {
+                    ret.push(o);
+                }
+            }
+            None => (),
+        }
+    }
+    Ok(ret)
+}
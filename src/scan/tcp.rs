@@ -30,12 +30,15 @@ use crate::layers::Layer4MatchTcpUdp;
 use crate::layers::LayersMatch;
 use crate::layers::IPV4_HEADER_SIZE;
 use crate::layers::TCP_HEADER_SIZE;
+use crate::utils::random_port;
 
 use super::IdleScanResults;
 use super::TargetScanStatus;
 
 const TCP_DATA_SIZE: usize = 0;
 const TTL: u8 = 64;
+// the idle scan result is ambiguous when the zombie sends other packets between our two probes
+const IDLE_SCAN_MAX_TRY: usize = 3;
 
 /* IdleScanAllZeroError */
 #[derive(Debug, Clone)]
@@ -887,244 +890,173 @@ pub fn send_maimon_scan_packet(
     Ok((TargetScanStatus::OpenOrFiltered, rtt))
 }
 
-pub fn send_idle_scan_packet(
+fn forge_syn_packet(
     src_ipv4: Ipv4Addr,
-    src_port: u16,
     dst_ipv4: Ipv4Addr,
+    src_port: u16,
     dst_port: u16,
-    zombie_ipv4: Ipv4Addr,
-    zombie_port: u16,
-    timeout: Duration,
-) -> Result<(TargetScanStatus, Option<IdleScanResults>, Option<Duration>)> {
-    fn _forge_syn_packet(
-        src_ipv4: Ipv4Addr,
-        dst_ipv4: Ipv4Addr,
-        src_port: u16,
-        dst_port: u16,
-    ) -> Result<Vec<u8>> {
-        let mut rng = rand::thread_rng();
-        // ip header
-        let mut ip_buff = [0u8; IPV4_HEADER_SIZE + TCP_HEADER_SIZE + TCP_DATA_SIZE];
-        let mut ip_header = MutableIpv4Packet::new(&mut ip_buff).unwrap();
-        ip_header.set_version(4);
-        ip_header.set_header_length(5);
-        ip_header.set_source(src_ipv4);
-        ip_header.set_destination(dst_ipv4);
-        ip_header.set_total_length((IPV4_HEADER_SIZE + TCP_HEADER_SIZE + TCP_DATA_SIZE) as u16);
-        let id = rng.gen();
-        ip_header.set_identification(id);
-        ip_header.set_flags(Ipv4Flags::DontFragment);
-        ip_header.set_ttl(TTL);
-        ip_header.set_next_level_protocol(IpNextHeaderProtocols::Tcp);
-        let c = checksum(&ip_header.to_immutable());
-        ip_header.set_checksum(c);
+) -> Result<Vec<u8>> {
+    let mut rng = rand::thread_rng();
+    // ip header
+    let mut ip_buff = [0u8; IPV4_HEADER_SIZE + TCP_HEADER_SIZE + TCP_DATA_SIZE];
+    let mut ip_header = MutableIpv4Packet::new(&mut ip_buff).unwrap();
+    ip_header.set_version(4);
+    ip_header.set_header_length(5);
+    ip_header.set_source(src_ipv4);
+    ip_header.set_destination(dst_ipv4);
+    ip_header.set_total_length((IPV4_HEADER_SIZE + TCP_HEADER_SIZE + TCP_DATA_SIZE) as u16);
+    let id = rng.gen();
+    ip_header.set_identification(id);
+    ip_header.set_flags(Ipv4Flags::DontFragment);
+    ip_header.set_ttl(TTL);
+    ip_header.set_next_level_protocol(IpNextHeaderProtocols::Tcp);
+    let c = checksum(&ip_header.to_immutable());
+    ip_header.set_checksum(c);
 
-        // tcp header
-        let mut tcp_header = MutableTcpPacket::new(&mut ip_buff[IPV4_HEADER_SIZE..]).unwrap();
-        tcp_header.set_source(src_port);
-        tcp_header.set_destination(dst_port);
-        tcp_header.set_sequence(rng.gen());
-        tcp_header.set_acknowledgement(rng.gen());
-        tcp_header.set_reserved(0);
-        tcp_header.set_flags(TcpFlags::SYN);
-        tcp_header.set_urgent_ptr(0);
-        tcp_header.set_window(1024);
-        tcp_header.set_data_offset(5);
-        let checksum = ipv4_checksum(&tcp_header.to_immutable(), &src_ipv4, &dst_ipv4);
-        tcp_header.set_checksum(checksum);
+    // tcp header
+    let mut tcp_header = MutableTcpPacket::new(&mut ip_buff[IPV4_HEADER_SIZE..]).unwrap();
+    tcp_header.set_source(src_port);
+    tcp_header.set_destination(dst_port);
+    tcp_header.set_sequence(rng.gen());
+    tcp_header.set_acknowledgement(rng.gen());
+    tcp_header.set_reserved(0);
+    tcp_header.set_flags(TcpFlags::SYN);
+    tcp_header.set_urgent_ptr(0);
+    tcp_header.set_window(1024);
+    tcp_header.set_data_offset(5);
+    let checksum = ipv4_checksum(&tcp_header.to_immutable(), &src_ipv4, &dst_ipv4);
+    tcp_header.set_checksum(checksum);
 
-        Ok(ip_buff.to_vec())
-    }
+    Ok(ip_buff.to_vec())
+}
 
-    // 1. probe the zombie's ip id
-    let layer3_zombie = Layer3Match {
+/// Send a syn packet to the zombie and returns the ip id of its response,
+/// `None` means that the zombie did not answer with a tcp packet.
+pub fn send_zombie_probe_packet(
+    src_ipv4: Ipv4Addr,
+    src_port: u16,
+    zombie_ipv4: Ipv4Addr,
+    zombie_port: u16,
+    timeout: Duration,
+) -> Result<(Option<u16>, Option<Duration>)> {
+    let layer3 = Layer3Match {
         layer2: None,
         src_addr: Some(zombie_ipv4.into()),
         dst_addr: Some(src_ipv4.into()),
     };
-    let layer4_tcp_udp_zombie = Layer4MatchTcpUdp {
-        layer3: Some(layer3_zombie),
+    let layer4_tcp_udp = Layer4MatchTcpUdp {
+        layer3: Some(layer3),
         src_port: Some(zombie_port),
         dst_port: Some(src_port),
     };
-    let layer4_icmp_zombie = Layer4MatchIcmp {
-        layer3: Some(layer3_zombie),
+    let layer4_icmp = Layer4MatchIcmp {
+        layer3: Some(layer3),
         types: None,
         codes: None,
     };
-    let layers_match_zombie_1 = LayersMatch::Layer4MatchTcpUdp(layer4_tcp_udp_zombie);
-    let layers_match_zombie_2 = LayersMatch::Layer4MatchIcmp(layer4_icmp_zombie);
+    let layers_match_1 = LayersMatch::Layer4MatchTcpUdp(layer4_tcp_udp);
+    let layers_match_2 = LayersMatch::Layer4MatchIcmp(layer4_icmp);
 
-    let ip_buff = _forge_syn_packet(src_ipv4, zombie_ipv4, src_port, zombie_port)?;
-    let (ret, rtt_1) = layer3_ipv4_send(
+    let ip_buff = forge_syn_packet(src_ipv4, zombie_ipv4, src_port, zombie_port)?;
+    let (ret, rtt) = layer3_ipv4_send(
         src_ipv4,
         zombie_ipv4,
         &ip_buff,
-        vec![layers_match_zombie_1, layers_match_zombie_2],
+        vec![layers_match_1, layers_match_2],
         timeout,
     )?;
-
-    let mut zombie_ip_id_1 = 0;
     match ret {
-        Some(r) => {
-            match Ipv4Packet::new(&r) {
-                Some(ipv4_packet) => {
-                    match ipv4_packet.get_next_level_protocol() {
-                        IpNextHeaderProtocols::Tcp => {
-                            match TcpPacket::new(ipv4_packet.payload()) {
-                                Some(tcp_packet) => {
-                                    // println!(">>> {}", tcp_packet.get_flags());
-                                    let tcp_flags = tcp_packet.get_flags();
-                                    if tcp_flags & TCP_FLAGS_RST_MASK == TcpFlags::RST {
-                                        // 2. zombie return rst packet, get this packet ip id
-                                        zombie_ip_id_1 = ipv4_packet.get_identification();
-                                    }
-                                }
-                                None => (),
-                            }
-                        }
-                        IpNextHeaderProtocols::Icmp => {
-                            match IcmpPacket::new(ipv4_packet.payload()) {
-                                Some(icmp_packet) => {
-                                    let icmp_type = icmp_packet.get_icmp_type();
-                                    let icmp_code = icmp_packet.get_icmp_code();
-                                    let codes = vec![
-                                        destination_unreachable::IcmpCodes::DestinationHostUnreachable, // 1
-                                        destination_unreachable::IcmpCodes::DestinationProtocolUnreachable, // 2
-                                        destination_unreachable::IcmpCodes::DestinationPortUnreachable, // 3
-                                        destination_unreachable::IcmpCodes::NetworkAdministrativelyProhibited, // 9
-                                        destination_unreachable::IcmpCodes::HostAdministrativelyProhibited, // 10
-                                        destination_unreachable::IcmpCodes::CommunicationAdministrativelyProhibited, // 13
-                                    ];
-                                    if icmp_type == IcmpTypes::DestinationUnreachable
-                                        && codes.contains(&icmp_code)
-                                    {
-                                        // icmp unreachable error (type 3, code 1, 2, 3, 9, 10, or 13)
-                                        // dst is unreachable ignore this port
-                                        return Ok((TargetScanStatus::Unreachable, None, rtt_1));
-                                    }
-                                }
-                                None => (),
-                            }
-                        }
-                        _ => (),
-                    }
-                }
-                None => (),
-            }
-        }
-        None => (),
+        Some(r) => match Ipv4Packet::new(&r) {
+            Some(ipv4_packet) => match ipv4_packet.get_next_level_protocol() {
+                // both rst and syn/ack use the ip id counter
+                IpNextHeaderProtocols::Tcp => Ok((Some(ipv4_packet.get_identification()), rtt)),
+                // icmp unreachable error, the zombie can not be used
+                _ => Ok((None, rtt)),
+            },
+            None => Ok((None, rtt)),
+        },
+        None => Ok((None, rtt)),
     }
+}
 
-    // 3. forge a syn packet from the zombie to the target
-    let ip_buff_2 = _forge_syn_packet(zombie_ipv4, dst_ipv4, zombie_port, dst_port)?;
-    // ignore the response
-    let _ret = layer3_ipv4_send(src_ipv4, dst_ipv4, &ip_buff_2, vec![], timeout)?;
+/// Send syn packets from `spoof_ipv4` to the zombie, the zombie answers them to `spoof_ipv4`, not to us.
+pub fn send_zombie_spoof_packets(
+    src_ipv4: Ipv4Addr,
+    spoof_ipv4: Ipv4Addr,
+    zombie_ipv4: Ipv4Addr,
+    zombie_port: u16,
+    num: usize,
+) -> Result<()> {
+    for _ in 0..num {
+        let ip_buff = forge_syn_packet(spoof_ipv4, zombie_ipv4, random_port(), zombie_port)?;
+        let _ = layer3_ipv4_send(src_ipv4, zombie_ipv4, &ip_buff, vec![], Duration::new(0, 0))?;
+    }
+    Ok(())
+}
 
-    // 4. probe the zombie's ip id again
-    let ip_buff_3 = _forge_syn_packet(src_ipv4, zombie_ipv4, src_port, zombie_port)?;
-    let layer3 = Layer3Match {
-        layer2: None,
-        src_addr: Some(dst_ipv4.into()),
-        dst_addr: Some(src_ipv4.into()),
-    };
-    let layer4_tcp_udp = Layer4MatchTcpUdp {
-        layer3: Some(layer3),
-        src_port: Some(dst_port),
-        dst_port: Some(src_port),
-    };
-    let layer4_icmp = Layer4MatchIcmp {
-        layer3: Some(layer3),
-        types: None,
-        codes: None,
-    };
-    let layers_match_1 = LayersMatch::Layer4MatchTcpUdp(layer4_tcp_udp);
-    let layers_match_2 = LayersMatch::Layer4MatchIcmp(layer4_icmp);
+/// How many ip id the zombie used from `ip_id_1` to `ip_id_2`,
+/// the broken increment zombie (windows) sends the ip id in host byte order, so the smaller one of the two byte orders is returned.
+pub fn ip_id_distance(ip_id_1: u16, ip_id_2: u16) -> u16 {
+    let distance = ip_id_2.wrapping_sub(ip_id_1);
+    let distance_swapped = ip_id_2.swap_bytes().wrapping_sub(ip_id_1.swap_bytes());
+    distance.min(distance_swapped)
+}
 
-    let (ret, rtt_2) = layer3_ipv4_send(
-        src_ipv4,
-        dst_ipv4,
-        &ip_buff_3,
-        vec![layers_match_1, layers_match_2],
-        timeout,
-    )?;
+pub fn send_idle_scan_packet(
+    src_ipv4: Ipv4Addr,
+    src_port: u16,
+    dst_ipv4: Ipv4Addr,
+    dst_port: u16,
+    zombie_ipv4: Ipv4Addr,
+    zombie_port: u16,
+    timeout: Duration,
+) -> Result<(TargetScanStatus, Option<IdleScanResults>, Option<Duration>)> {
+    let mut idle_rets = None;
+    let mut rtt = None;
+    for _ in 0..IDLE_SCAN_MAX_TRY {
+        // 1. probe the zombie's ip id
+        let (zombie_ip_id_1, rtt_1) =
+            send_zombie_probe_packet(src_ipv4, src_port, zombie_ipv4, zombie_port, timeout)?;
 
-    let mut zombie_ip_id_2 = 0;
-    let rtt = (rtt_1.unwrap() + rtt_2.unwrap()) / 2;
-    match ret {
-        Some(r) => {
-            match Ipv4Packet::new(&r) {
-                Some(ipv4_packet) => {
-                    match ipv4_packet.get_next_level_protocol() {
-                        IpNextHeaderProtocols::Tcp => {
-                            match TcpPacket::new(ipv4_packet.payload()) {
-                                Some(tcp_packet) => {
-                                    // println!(">>> {}", tcp_packet.get_flags());
-                                    let tcp_flags = tcp_packet.get_flags();
-                                    if tcp_flags & TCP_FLAGS_RST_MASK == TcpFlags::RST {
-                                        // 5. zombie return rst packet again, get this packet ip id
-                                        zombie_ip_id_2 = ipv4_packet.get_identification();
-                                    }
-                                }
-                                None => (),
-                            }
-                        }
-                        IpNextHeaderProtocols::Icmp => {
-                            match IcmpPacket::new(ipv4_packet.payload()) {
-                                Some(icmp_packet) => {
-                                    let icmp_type = icmp_packet.get_icmp_type();
-                                    let icmp_code = icmp_packet.get_icmp_code();
-                                    let codes = vec![
-                                        destination_unreachable::IcmpCodes::DestinationHostUnreachable, // 1
-                                        destination_unreachable::IcmpCodes::DestinationProtocolUnreachable, // 2
-                                        destination_unreachable::IcmpCodes::DestinationPortUnreachable, // 3
-                                        destination_unreachable::IcmpCodes::NetworkAdministrativelyProhibited, // 9
-                                        destination_unreachable::IcmpCodes::HostAdministrativelyProhibited, // 10
-                                        destination_unreachable::IcmpCodes::CommunicationAdministrativelyProhibited, // 13
-                                    ];
-                                    if icmp_type == IcmpTypes::DestinationUnreachable
-                                        && codes.contains(&icmp_code)
-                                    {
-                                        // icmp unreachable error (type 3, code 1, 2, 3, 9, 10, or 13)
-                                        // dst is unreachable ignore this port
-                                        return Ok((
-                                            TargetScanStatus::Unreachable,
-                                            None,
-                                            Some(rtt),
-                                        ));
-                                    }
-                                }
-                                None => (),
-                            }
-                        }
-                        _ => (),
-                    }
-                }
-                None => (),
-            }
+        // 2. forge a syn packet from the zombie to the target
+        let ip_buff = forge_syn_packet(zombie_ipv4, dst_ipv4, zombie_port, dst_port)?;
+        // ignore the response
+        let _ret = layer3_ipv4_send(src_ipv4, dst_ipv4, &ip_buff, vec![], timeout)?;
+
+        // 3. probe the zombie's ip id again
+        let (zombie_ip_id_2, rtt_2) =
+            send_zombie_probe_packet(src_ipv4, src_port, zombie_ipv4, zombie_port, timeout)?;
+
+        let (zombie_ip_id_1, zombie_ip_id_2) = match (zombie_ip_id_1, zombie_ip_id_2) {
+            (Some(id_1), Some(id_2)) => (id_1, id_2),
+            // the probe or its response was lost
+            _ => continue,
+        };
+        if zombie_ip_id_1 == 0 && zombie_ip_id_2 == 0 {
+            return Err(IdleScanAllZeroError::new(zombie_ipv4, zombie_port).into());
+        }
+        rtt = match (rtt_1, rtt_2) {
+            (Some(r1), Some(r2)) => Some((r1 + r2) / 2),
+            (r1, r2) => r1.or(r2),
+        };
+        idle_rets = Some(IdleScanResults {
+            zombie_ip_id_1,
+            zombie_ip_id_2,
+        });
+        match ip_id_distance(zombie_ip_id_1, zombie_ip_id_2) {
+            // only our second probe
+            1 => return Ok((TargetScanStatus::ClosedOrFiltered, idle_rets, rtt)),
+            // the zombie sent a rst to the target before our second probe
+            2 => return Ok((TargetScanStatus::Open, idle_rets, rtt)),
+            // the zombie talked to someone else meanwhile, try again
+            _ => (),
         }
-        None => (),
     }
-    if zombie_ip_id_1 == 0 && zombie_ip_id_2 == 0 {
-        return Err(IdleScanAllZeroError::new(zombie_ipv4, zombie_port).into());
-    } else if zombie_ip_id_2 - zombie_ip_id_1 >= 2 {
-        Ok((
-            TargetScanStatus::Open,
-            Some(IdleScanResults {
-                zombie_ip_id_1,
-                zombie_ip_id_2,
-            }),
-            Some(rtt),
-        ))
-    } else {
-        Ok((
-            TargetScanStatus::ClosedOrFiltered,
-            Some(IdleScanResults {
-                zombie_ip_id_1,
-                zombie_ip_id_2,
-            }),
-            Some(rtt),
-        ))
+    match idle_rets {
+        // every try was disturbed by the other traffic of the zombie, the port state can not be told
+        Some(_) => Ok((TargetScanStatus::Unknown, idle_rets, rtt)),
+        // the zombie never answered
+        None => Ok((TargetScanStatus::Unreachable, None, None)),
     }
 }
 
@@ -1231,6 +1163,13 @@ mod tests {
         println!("{:?}", i.unwrap());
     }
     #[test]
+    fn test_ip_id_distance() {
+        assert_eq!(ip_id_distance(100, 102), 2);
+        assert_eq!(ip_id_distance(65535, 1), 2);
+        // broken increment
+        assert_eq!(ip_id_distance(0x0100, 0x0300), 2);
+    }
+    #[test]
     fn test_send_tcp_connect_scan_packet() {
         let src_ipv4 = Ipv4Addr::new(192, 168, 72, 128);
         let dst_ipv4 = Ipv4Addr::new(192, 168, 72, 134);
@@ -1,8 +1,13 @@
 use anyhow::Result;
+use pnet::packet::icmpv6;
+use pnet::packet::icmpv6::echo_reply::MutableEchoReplyPacket;
+use pnet::packet::icmpv6::echo_request::MutableEchoRequestPacket;
 use pnet::packet::icmpv6::Icmpv6Code;
 use pnet::packet::icmpv6::Icmpv6Packet;
 use pnet::packet::icmpv6::Icmpv6Types;
+use pnet::packet::icmpv6::MutableIcmpv6Packet;
 use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv6::FragmentPacket;
 use pnet::packet::ipv6::Ipv6Packet;
 use pnet::packet::ipv6::MutableIpv6Packet;
 use pnet::packet::tcp::ipv6_checksum;
@@ -11,6 +16,8 @@ use pnet::packet::tcp::TcpFlags;
 use pnet::packet::tcp::TcpPacket;
 use pnet::packet::Packet;
 use rand::Rng;
+use std::error::Error;
+use std::fmt;
 use std::net::Ipv6Addr;
 use std::net::SocketAddr;
 use std::net::SocketAddrV6;
@@ -23,9 +30,11 @@ use crate::layers::Layer3Match;
 use crate::layers::Layer4MatchIcmpv6;
 use crate::layers::Layer4MatchTcpUdp;
 use crate::layers::LayersMatch;
+use crate::layers::ICMPV6_ER_HEADER_SIZE;
 use crate::layers::IPV6_HEADER_SIZE;
 use crate::layers::TCP_HEADER_SIZE;
 
+use super::IdleScanResults6;
 use super::TargetScanStatus;
 
 // const TCP_FLAGS_CWR_MASK: u8 = 0b10000000;
@@ -40,6 +49,31 @@ const TCP_FLAGS_RST_MASK: u8 = 0b00000100;
 const TCP_DATA_SIZE: usize = 0;
 const TTL: u8 = 255;
 
+const ICMPV6_PTB_HEADER_SIZE: usize = 8;
+const IDLE_ECHO_DATA_SIZE: usize = 8;
+// less than the IPv6 minimum link mtu (1280), so the zombie only adds a fragment header and keeps the packet size
+const IDLE_PTB_MTU: u32 = 1000;
+
+/* IdleScanNoFragmentError */
+#[derive(Debug, Clone)]
+struct IdleScanNoFragmentError {
+    zombie_ipv6: Ipv6Addr,
+}
+
+impl fmt::Display for IdleScanNoFragmentError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "idle scan zombie {} cannot be used because it does not add fragment id to its packets after packet too big, try another proxy", self.zombie_ipv6)
+    }
+}
+
+impl IdleScanNoFragmentError {
+    pub fn new(zombie_ipv6: Ipv6Addr) -> IdleScanNoFragmentError {
+        IdleScanNoFragmentError { zombie_ipv6 }
+    }
+}
+
+impl Error for IdleScanNoFragmentError {}
+
 pub fn send_syn_scan_packet(
     src_ipv6: Ipv6Addr,
     src_port: u16,
@@ -828,6 +862,223 @@ pub fn send_maimon_scan_packet(
     Ok((TargetScanStatus::OpenOrFiltered, rtt))
 }
 
+/// ICMPv6 packet too big message (RFC 4443 section 3.2) sent to the zombie,
+/// it quotes an echo reply from the zombie to `path_dst_ipv6` and reports an mtu less than 1280,
+/// a node accepting it will add a fragment header to every packet it sends to `path_dst_ipv6` (RFC 2460 section 5).
+fn packet_too_big_packet(
+    src_ipv6: Ipv6Addr,
+    zombie_ipv6: Ipv6Addr,
+    path_dst_ipv6: Ipv6Addr,
+) -> [u8; IPV6_HEADER_SIZE
+       + ICMPV6_PTB_HEADER_SIZE
+       + IPV6_HEADER_SIZE
+       + ICMPV6_ER_HEADER_SIZE
+       + IDLE_ECHO_DATA_SIZE] {
+    const QUOTE_SIZE: usize = IPV6_HEADER_SIZE + ICMPV6_ER_HEADER_SIZE + IDLE_ECHO_DATA_SIZE;
+    let mut rng = rand::thread_rng();
+    let mut ipv6_buff = [0u8; IPV6_HEADER_SIZE + ICMPV6_PTB_HEADER_SIZE + QUOTE_SIZE];
+    let mut ipv6_header = MutableIpv6Packet::new(&mut ipv6_buff).unwrap();
+    ipv6_header.set_version(6);
+    ipv6_header.set_flow_label(0x12345);
+    ipv6_header.set_payload_length((ICMPV6_PTB_HEADER_SIZE + QUOTE_SIZE) as u16);
+    ipv6_header.set_next_header(IpNextHeaderProtocols::Icmpv6);
+    ipv6_header.set_hop_limit(TTL);
+    ipv6_header.set_source(src_ipv6);
+    ipv6_header.set_destination(zombie_ipv6);
+
+    // the quoted packet, which looks like an echo reply sent by the zombie
+    let quote_start = IPV6_HEADER_SIZE + ICMPV6_PTB_HEADER_SIZE;
+    let mut quote_header = MutableIpv6Packet::new(&mut ipv6_buff[quote_start..]).unwrap();
+    quote_header.set_version(6);
+    quote_header.set_payload_length((ICMPV6_ER_HEADER_SIZE + IDLE_ECHO_DATA_SIZE) as u16);
+    quote_header.set_next_header(IpNextHeaderProtocols::Icmpv6);
+    quote_header.set_hop_limit(64);
+    quote_header.set_source(zombie_ipv6);
+    quote_header.set_destination(path_dst_ipv6);
+    let mut quote_icmpv6 =
+        MutableEchoReplyPacket::new(&mut ipv6_buff[quote_start + IPV6_HEADER_SIZE..]).unwrap();
+    quote_icmpv6.set_icmpv6_type(Icmpv6Types::EchoReply);
+    quote_icmpv6.set_icmpv6_code(Icmpv6Code(0));
+    quote_icmpv6.set_identifier(rng.gen());
+    quote_icmpv6.set_sequence_number(1);
+
+    let mut icmpv6_header = MutableIcmpv6Packet::new(&mut ipv6_buff[IPV6_HEADER_SIZE..]).unwrap();
+    icmpv6_header.set_icmpv6_type(Icmpv6Types::PacketTooBig);
+    icmpv6_header.set_icmpv6_code(Icmpv6Code(0));
+    ipv6_buff[IPV6_HEADER_SIZE + 4..IPV6_HEADER_SIZE + 8]
+        .copy_from_slice(&IDLE_PTB_MTU.to_be_bytes());
+    let mut icmpv6_header = MutableIcmpv6Packet::new(&mut ipv6_buff[IPV6_HEADER_SIZE..]).unwrap();
+    let checksum = icmpv6::checksum(&icmpv6_header.to_immutable(), &src_ipv6, &zombie_ipv6);
+    icmpv6_header.set_checksum(checksum);
+    ipv6_buff
+}
+
+fn echo_request_packet(
+    src_ipv6: Ipv6Addr,
+    dst_ipv6: Ipv6Addr,
+) -> [u8; IPV6_HEADER_SIZE + ICMPV6_ER_HEADER_SIZE + IDLE_ECHO_DATA_SIZE] {
+    let mut rng = rand::thread_rng();
+    let mut ipv6_buff = [0u8; IPV6_HEADER_SIZE + ICMPV6_ER_HEADER_SIZE + IDLE_ECHO_DATA_SIZE];
+    let mut ipv6_header = MutableIpv6Packet::new(&mut ipv6_buff).unwrap();
+    ipv6_header.set_version(6);
+    ipv6_header.set_flow_label(0x12345);
+    ipv6_header.set_payload_length((ICMPV6_ER_HEADER_SIZE + IDLE_ECHO_DATA_SIZE) as u16);
+    ipv6_header.set_next_header(IpNextHeaderProtocols::Icmpv6);
+    ipv6_header.set_hop_limit(TTL);
+    ipv6_header.set_source(src_ipv6);
+    ipv6_header.set_destination(dst_ipv6);
+
+    let mut icmpv6_header =
+        MutableEchoRequestPacket::new(&mut ipv6_buff[IPV6_HEADER_SIZE..]).unwrap();
+    icmpv6_header.set_icmpv6_type(Icmpv6Types::EchoRequest);
+    icmpv6_header.set_icmpv6_code(Icmpv6Code(0));
+    icmpv6_header.set_identifier(rng.gen());
+    icmpv6_header.set_sequence_number(1);
+
+    let mut icmpv6_header = MutableIcmpv6Packet::new(&mut ipv6_buff[IPV6_HEADER_SIZE..]).unwrap();
+    let checksum = icmpv6::checksum(&icmpv6_header.to_immutable(), &src_ipv6, &dst_ipv6);
+    icmpv6_header.set_checksum(checksum);
+    ipv6_buff
+}
+
+/// Send the packet too big messages, after this the zombie packets to `path_dsts` carry a fragment id.
+pub fn send_packet_too_big(
+    src_ipv6: Ipv6Addr,
+    zombie_ipv6: Ipv6Addr,
+    path_dsts: &[Ipv6Addr],
+) -> Result<()> {
+    for path_dst_ipv6 in path_dsts {
+        let ipv6_buff = packet_too_big_packet(src_ipv6, zombie_ipv6, *path_dst_ipv6);
+        let _ = layer3_ipv6_send(
+            src_ipv6,
+            zombie_ipv6,
+            &ipv6_buff,
+            vec![],
+            Duration::new(0, 0),
+        )?;
+    }
+    Ok(())
+}
+
+/// Ping the zombie and returns the fragment id of its echo reply,
+/// `None` means that the reply was not fragmented (or no reply).
+pub fn send_frag_id_probe_packet(
+    src_ipv6: Ipv6Addr,
+    zombie_ipv6: Ipv6Addr,
+    timeout: Duration,
+) -> Result<(Option<u32>, Option<Duration>)> {
+    let ipv6_buff = echo_request_packet(src_ipv6, zombie_ipv6);
+    // the echo reply is behind the fragment header, so the icmpv6 matcher can not be used here
+    let layer3 = Layer3Match {
+        layer2: None,
+        src_addr: Some(zombie_ipv6.into()),
+        dst_addr: Some(src_ipv6.into()),
+    };
+    let layers_match = LayersMatch::Layer3Match(layer3);
+    let (ret, rtt) = layer3_ipv6_send(
+        src_ipv6,
+        zombie_ipv6,
+        &ipv6_buff,
+        vec![layers_match],
+        timeout,
+    )?;
+    match ret {
+        Some(r) => Ok((get_frag_id(&r), rtt)),
+        None => Ok((None, rtt)),
+    }
+}
+
+fn get_frag_id(ipv6_buff: &[u8]) -> Option<u32> {
+    match Ipv6Packet::new(ipv6_buff) {
+        Some(ipv6_packet) => {
+            if ipv6_packet.get_next_header() == IpNextHeaderProtocols::Ipv6Frag {
+                match FragmentPacket::new(ipv6_packet.payload()) {
+                    Some(fragment_packet) => Some(fragment_packet.get_id()),
+                    None => None,
+                }
+            } else {
+                None
+            }
+        }
+        None => None,
+    }
+}
+
+pub fn send_idle_scan_packet(
+    src_ipv6: Ipv6Addr,
+    src_port: u16,
+    dst_ipv6: Ipv6Addr,
+    dst_port: u16,
+    zombie_ipv6: Ipv6Addr,
+    timeout: Duration,
+) -> Result<(TargetScanStatus, Option<IdleScanResults6>, Option<Duration>)> {
+    fn _forge_syn_packet(
+        src_ipv6: Ipv6Addr,
+        dst_ipv6: Ipv6Addr,
+        src_port: u16,
+        dst_port: u16,
+    ) -> Result<[u8; IPV6_HEADER_SIZE + TCP_HEADER_SIZE + TCP_DATA_SIZE]> {
+        let mut rng = rand::thread_rng();
+        // ipv6 header
+        let mut ipv6_buff = [0u8; IPV6_HEADER_SIZE + TCP_HEADER_SIZE + TCP_DATA_SIZE];
+        let mut ipv6_header = MutableIpv6Packet::new(&mut ipv6_buff).unwrap();
+        ipv6_header.set_version(6);
+        ipv6_header.set_flow_label(0x12345);
+        let payload_length = TCP_HEADER_SIZE + TCP_DATA_SIZE;
+        ipv6_header.set_payload_length(payload_length as u16);
+        ipv6_header.set_next_header(IpNextHeaderProtocols::Tcp);
+        ipv6_header.set_hop_limit(TTL);
+        ipv6_header.set_source(src_ipv6);
+        ipv6_header.set_destination(dst_ipv6);
+
+        // tcp header
+        let mut tcp_header = MutableTcpPacket::new(&mut ipv6_buff[IPV6_HEADER_SIZE..]).unwrap();
+        tcp_header.set_source(src_port);
+        tcp_header.set_destination(dst_port);
+        tcp_header.set_sequence(rng.gen());
+        tcp_header.set_acknowledgement(rng.gen());
+        tcp_header.set_reserved(0);
+        tcp_header.set_flags(TcpFlags::SYN);
+        tcp_header.set_urgent_ptr(0);
+        tcp_header.set_window(1024);
+        tcp_header.set_data_offset(5);
+        let checksum = ipv6_checksum(&tcp_header.to_immutable(), &src_ipv6, &dst_ipv6);
+        tcp_header.set_checksum(checksum);
+        Ok(ipv6_buff)
+    }
+
+    // 1. make the zombie add a fragment header to the packets it sends to us and to the target
+    send_packet_too_big(src_ipv6, zombie_ipv6, &[src_ipv6, dst_ipv6])?;
+
+    // 2. ping the zombie and get the fragment id of the reply
+    let (zombie_frag_id_1, rtt) = match send_frag_id_probe_packet(src_ipv6, zombie_ipv6, timeout)? {
+        (Some(id), rtt) => (id, rtt),
+        (None, _) => return Err(IdleScanNoFragmentError::new(zombie_ipv6).into()),
+    };
+
+    // 3. forge a syn packet from the zombie to the target,
+    // if the port is open, the target will send syn/ack to the zombie and the zombie will answer it with a rst
+    let ipv6_buff = _forge_syn_packet(zombie_ipv6, dst_ipv6, src_port, dst_port)?;
+    let _ = layer3_ipv6_send(src_ipv6, dst_ipv6, &ipv6_buff, vec![], timeout)?;
+
+    // 4. ping the zombie again
+    let zombie_frag_id_2 = match send_frag_id_probe_packet(src_ipv6, zombie_ipv6, timeout)? {
+        (Some(id), _) => id,
+        (None, _) => return Err(IdleScanNoFragmentError::new(zombie_ipv6).into()),
+    };
+
+    let idle_rets = IdleScanResults6 {
+        zombie_frag_id_1,
+        zombie_frag_id_2,
+    };
+    // the rst sent to the target used one fragment id
+    if zombie_frag_id_2.wrapping_sub(zombie_frag_id_1) >= 2 {
+        Ok((TargetScanStatus::Open, Some(idle_rets), rtt))
+    } else {
+        Ok((TargetScanStatus::ClosedOrFiltered, Some(idle_rets), rtt))
+    }
+}
+
 pub fn send_connect_scan_packet(
     _: Ipv6Addr,
     _: u16,
@@ -907,4 +1158,16 @@ mod tests {
             send_connect_scan_packet(src_ipv6, src_port, dst_ipv6, dst_port, timeout).unwrap();
         println!("{:?}", ret);
     }
+    #[test]
+    fn test_send_idle_scan_packet() {
+        let src_ipv6: Ipv6Addr = "fe80::20c:29ff:fe43:9c82".parse().unwrap();
+        let dst_ipv6: Ipv6Addr = "fe80::20c:29ff:fe2a:e252".parse().unwrap();
+        let zombie_ipv6: Ipv6Addr = "fe80::20c:29ff:fe2a:e253".parse().unwrap();
+        let src_port = 32109;
+        let dst_port = 22;
+        let timeout = Duration::new(3, 0);
+        let ret =
+            send_idle_scan_packet(src_ipv6, src_port, dst_ipv6, dst_port, zombie_ipv6, timeout);
+        println!("{:?}", ret);
+    }
 }
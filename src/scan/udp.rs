@@ -23,7 +23,6 @@ use crate::layers::UDP_HEADER_SIZE;
 
 use super::TargetScanStatus;
 
-const UDP_DATA_SIZE: usize = 0;
 const TTL: u8 = 64;
 
 pub fn send_udp_scan_packet(
@@ -31,15 +30,16 @@ pub fn send_udp_scan_packet(
     src_port: u16,
     dst_ipv4: Ipv4Addr,
     dst_port: u16,
+    payload: &[u8],
     timeout: Duration,
 ) -> Result<(TargetScanStatus, Option<Duration>)> {
     let mut rng = rand::thread_rng();
     // ip header
-    let mut ip_buff = [0u8; IPV4_HEADER_SIZE + UDP_HEADER_SIZE + UDP_DATA_SIZE];
+    let mut ip_buff = vec![0u8; IPV4_HEADER_SIZE + UDP_HEADER_SIZE + payload.len()];
     let mut ip_header = MutableIpv4Packet::new(&mut ip_buff).unwrap();
     ip_header.set_version(4);
     ip_header.set_header_length(5);
-    ip_header.set_total_length((IPV4_HEADER_SIZE + UDP_HEADER_SIZE + UDP_DATA_SIZE) as u16);
+    ip_header.set_total_length((IPV4_HEADER_SIZE + UDP_HEADER_SIZE + payload.len()) as u16);
     let id = rng.gen();
     ip_header.set_identification(id);
     ip_header.set_flags(Ipv4Flags::DontFragment);
@@ -54,8 +54,8 @@ pub fn send_udp_scan_packet(
     let mut udp_header = MutableUdpPacket::new(&mut ip_buff[IPV4_HEADER_SIZE..]).unwrap();
     udp_header.set_source(src_port);
     udp_header.set_destination(dst_port);
-    udp_header.set_length((UDP_HEADER_SIZE + UDP_DATA_SIZE) as u16);
-    // udp_header.set_payload(&vec![b'a'; 10]); // test
+    udp_header.set_length((UDP_HEADER_SIZE + payload.len()) as u16);
+    udp_header.set_payload(payload);
     let checksum = ipv4_checksum(&udp_header.to_immutable(), &src_ipv4, &dst_ipv4);
     udp_header.set_checksum(checksum);
 
@@ -143,7 +143,8 @@ mod tests {
         // let dst_port = 53;
         let dst_port = 2233;
         let timeout = Duration::new(3, 0);
-        let ret = send_udp_scan_packet(src_ipv4, src_port, dst_ipv4, dst_port, timeout).unwrap();
+        let ret =
+            send_udp_scan_packet(src_ipv4, src_port, dst_ipv4, dst_port, &[], timeout).unwrap();
         println!("{:?}", ret);
     }
 }
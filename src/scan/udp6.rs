@@ -20,7 +20,6 @@ use crate::layers::UDP_HEADER_SIZE;
 
 use super::TargetScanStatus;
 
-const UDP_DATA_SIZE: usize = 0;
 const TTL: u8 = 255;
 
 pub fn send_udp_scan_packet(
@@ -28,16 +27,17 @@ pub fn send_udp_scan_packet(
     src_port: u16,
     dst_ipv6: Ipv6Addr,
     dst_port: u16,
+    payload: &[u8],
     timeout: Duration,
 ) -> Result<(TargetScanStatus, Option<Duration>)> {
     // ipv6 header
-    let mut ipv6_buff = [0u8; IPV6_HEADER_SIZE + UDP_HEADER_SIZE + UDP_DATA_SIZE];
+    let mut ipv6_buff = vec![0u8; IPV6_HEADER_SIZE + UDP_HEADER_SIZE + payload.len()];
     let mut ipv6_header = MutableIpv6Packet::new(&mut ipv6_buff).unwrap();
     ipv6_header.set_version(6);
     // In all cases, the IPv6 flow label is 0x12345, on platforms that allow us to set it.
     // On platforms that do not (which includes non-Linux Unix platforms when not using Ethernet to send), the flow label will be 0.
     ipv6_header.set_flow_label(0x12345);
-    let payload_length = UDP_HEADER_SIZE + UDP_DATA_SIZE;
+    let payload_length = UDP_HEADER_SIZE + payload.len();
     ipv6_header.set_payload_length(payload_length as u16);
     ipv6_header.set_next_header(IpNextHeaderProtocols::Udp);
     ipv6_header.set_hop_limit(TTL);
@@ -48,7 +48,8 @@ pub fn send_udp_scan_packet(
     let mut udp_header = MutableUdpPacket::new(&mut ipv6_buff[IPV6_HEADER_SIZE..]).unwrap();
     udp_header.set_source(src_port);
     udp_header.set_destination(dst_port);
-    udp_header.set_length((UDP_HEADER_SIZE + UDP_DATA_SIZE) as u16);
+    udp_header.set_length((UDP_HEADER_SIZE + payload.len()) as u16);
+    udp_header.set_payload(payload);
     let checksum = ipv6_checksum(&udp_header.to_immutable(), &src_ipv6, &dst_ipv6);
     udp_header.set_checksum(checksum);
 
@@ -132,7 +133,8 @@ mod tests {
         let src_port = 32109;
         let dst_port = 80;
         let timeout = Duration::new(3, 0);
-        let ret = send_udp_scan_packet(src_ipv6, src_port, dst_ipv6, dst_port, timeout).unwrap();
+        let ret =
+            send_udp_scan_packet(src_ipv6, src_port, dst_ipv6, dst_port, &[], timeout).unwrap();
         println!("{:?}", ret);
     }
 }
@@ -2,16 +2,25 @@ use std::collections::HashMap;
 use std::fmt;
 use std::net::IpAddr;
 use std::sync::mpsc::channel;
+use std::sync::Arc;
+use std::sync::OnceLock;
 use std::time::Duration;
 
 use anyhow::Result;
 
 use self::dbparser::ExcludePorts;
+use crate::scan::TargetScanStatus;
+use crate::scan::TcpUdpScanResults;
 use crate::utils::get_default_timeout;
 use crate::utils::get_threads_pool;
+use crate::vs::dbparser::merge_service_probes;
 use crate::vs::dbparser::nsp_exclued_parser;
 use crate::vs::dbparser::nsp_parser;
 use crate::vs::dbparser::Match;
+use crate::vs::dbparser::ProbesProtocol;
+use crate::vs::dbparser::ServiceProbe;
+use crate::vs::dbparser::ServiceVersion;
+use crate::vs::vscan::unmatched_service_result;
 use crate::vs::vscan::vs_probe;
 use crate::Target;
 use crate::TargetType;
@@ -19,79 +28,217 @@ use crate::TargetType;
 pub mod dbparser;
 pub mod vscan;
 
-pub struct NmapVsDetectRet {
-    pub port: u16,
-    pub services: Vec<Match>,
+/// How the service was identified.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ServiceMatchKind {
+    /// The match line, the service is identified.
+    Hard,
+    /// The softmatch line, only the service is identified and the version is unknown.
+    Soft,
+    /// Nothing matched, the service name is guessed from the well-known port number.
+    PortGuess,
 }
 
-impl fmt::Display for NmapVsDetectRet {
+#[derive(Debug, Clone)]
+pub struct ServiceResult {
+    /// `None` if the service is unknown.
+    pub service: Option<String>,
+    pub version: ServiceVersion,
+    pub kind: Option<ServiceMatchKind>,
+    /// The probe which elicited the matched response.
+    pub probename: Option<String>,
+    /// The time of the tcp connect, or the time until the udp response.
+    pub rtt: Option<Duration>,
+    /// The match or softmatch line of nmap-service-probes.
+    pub matched: Option<Match>,
+    /// The service fingerprint if the service is not recognized but it returned some data.
+    pub fingerprint: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct VsScanResults {
+    pub results: HashMap<IpAddr, HashMap<(u16, ProbesProtocol), ServiceResult>>,
+}
+
+impl VsScanResults {
+    pub fn new() -> VsScanResults {
+        VsScanResults {
+            results: HashMap::new(),
+        }
+    }
+}
+
+impl fmt::Display for VsScanResults {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let mut output = format!(">>> port:\n{}", self.port);
-        for m in &self.services {
-            output += &format!(
-                "\n>>> services:\n{}\n>>> versioninfo:\n{}",
-                m.service, m.versioninfo
-            );
+        let mut result_str = String::new();
+        let mut ips: Vec<&IpAddr> = self.results.keys().collect();
+        ips.sort();
+        for ip in ips {
+            let services = &self.results[ip];
+            let mut keys: Vec<&(u16, ProbesProtocol)> = services.keys().collect();
+            keys.sort_by_key(|(port, protocol)| (*port, *protocol == ProbesProtocol::Udp));
+            for key in keys {
+                let (port, protocol) = key;
+                let sr = &services[key];
+                let protocol = match protocol {
+                    ProbesProtocol::Tcp => "tcp",
+                    ProbesProtocol::Udp => "udp",
+                };
+                let service = match &sr.service {
+                    Some(s) => s.to_string(),
+                    None => String::from("unknown"),
+                };
+                let service = match sr.kind {
+                    Some(ServiceMatchKind::Soft) => format!("{service}?"),
+                    Some(ServiceMatchKind::PortGuess) => format!("{service}?"),
+                    _ => service,
+                };
+                result_str += &format!("{ip} {port}/{protocol} {service} {}", sr.version);
+                result_str = result_str.trim_end().to_string();
+                result_str += "\n";
+                match &sr.fingerprint {
+                    Some(fp) => {
+                        result_str += fp;
+                        result_str += "\n";
+                    }
+                    None => (),
+                }
+            }
         }
-        write!(f, "{}", output)
+        write!(f, "{}", result_str)
     }
 }
 
+/// The `ports` and `udp_ports` of the hosts in `target` are probed as tcp and udp ports,
+/// the open ports of `tcp_results` (from a tcp scan) and the open or open|filtered ports of `udp_results` (from `udp_scan`)
+/// are probed as tcp and udp ports too.
+/// The nmap-service-probes database with the patterns compiled and the ports excluded by it.
+/// The file is only parsed and compiled by the first scan, the later scans share the same database.
+fn get_service_probes() -> Result<&'static (Arc<Vec<ServiceProbe>>, ExcludePorts)> {
+    static SERVICE_PROBES: OnceLock<(Arc<Vec<ServiceProbe>>, ExcludePorts)> = OnceLock::new();
+    match SERVICE_PROBES.get() {
+        Some(s) => return Ok(s),
+        None => (),
+    }
+    let nsp_str = include_str!("./db/nmap-service-probes");
+    let mut nsp_lines = Vec::new();
+    for l in nsp_str.lines() {
+        nsp_lines.push(l.to_string());
+    }
+    let exclude_ports = nsp_exclued_parser(&nsp_lines)?;
+    let service_probes = nsp_parser(&nsp_lines)?;
+    Ok(SERVICE_PROBES.get_or_init(|| (Arc::new(service_probes), exclude_ports)))
+}
+
 pub fn vs_scan(
     target: Target,
+    tcp_results: Option<TcpUdpScanResults>,
+    udp_results: Option<TcpUdpScanResults>,
     only_null_probe: bool,
     only_tcp_recommended: bool,
     only_udp_recommended: bool,
     exclude_ports: Option<ExcludePorts>,
+    user_probes: Option<Vec<ServiceProbe>>,
     intensity: usize,
     threads_num: usize,
     timeout: Option<Duration>,
-) -> Result<Vec<NmapVsDetectRet>> {
+) -> Result<VsScanResults> {
     let timeout = match timeout {
         Some(t) => t,
         None => get_default_timeout(),
     };
-    let nsp_str = include_str!("./db/nmap-service-probes");
-    let mut nsp_lines = Vec::new();
-    for l in nsp_str.lines() {
-        nsp_lines.push(l.to_string());
-    }
-
     let pool = get_threads_pool(threads_num);
     let (tx, rx) = channel();
+    // the `ports` of the host are tcp ports and the `udp_ports` are udp ports
     let mut vs_target = HashMap::new();
+    let tag_ports = |ports: Vec<u16>, udp_ports: Vec<u16>| -> Vec<(u16, ProbesProtocol)> {
+        let mut ret: Vec<(u16, ProbesProtocol)> = ports
+            .into_iter()
+            .map(|p| (p, ProbesProtocol::Tcp))
+            .collect();
+        ret.extend(udp_ports.into_iter().map(|p| (p, ProbesProtocol::Udp)));
+        ret
+    };
+    // the same host can be in the target more than once
+    let mut add_target = |addr: IpAddr, ports: Vec<(u16, ProbesProtocol)>| {
+        let target_ports: &mut Vec<(u16, ProbesProtocol)> = vs_target.entry(addr).or_default();
+        for p in ports {
+            if !target_ports.contains(&p) {
+                target_ports.push(p);
+            }
+        }
+    };
     match target.target_type {
         TargetType::Ipv4 => {
             for h in target.hosts {
                 let addr = IpAddr::V4(h.addr);
-                vs_target.insert(addr, h.ports);
+                add_target(addr, tag_ports(h.ports, h.udp_ports));
             }
         }
         TargetType::Ipv6 => {
             for h in target.hosts6 {
                 let addr = IpAddr::V6(h.addr);
-                vs_target.insert(addr, h.ports);
+                add_target(addr, tag_ports(h.ports, h.udp_ports));
             }
         }
     }
+    // the udp port that gives no response is open|filtered, it may be open
+    let scan_results = [
+        (tcp_results, ProbesProtocol::Tcp),
+        (udp_results, ProbesProtocol::Udp),
+    ];
+    for (results, protocol) in scan_results {
+        match results {
+            Some(results) => {
+                for (addr, port_status) in results.results {
+                    let ports: Vec<(u16, ProbesProtocol)> = port_status
+                        .status
+                        .into_iter()
+                        .filter(|(_, status)| match status {
+                            TargetScanStatus::Open => true,
+                            TargetScanStatus::OpenOrFiltered => protocol == ProbesProtocol::Udp,
+                            _ => false,
+                        })
+                        .map(|(port, _)| (port, protocol))
+                        .collect();
+                    add_target(addr, ports);
+                }
+            }
+            None => (),
+        }
+    }
 
+    // the patterns are compiled once and shared by all the threads and the later scans
+    let (db_probes, db_exclude_ports) = get_service_probes()?;
     let exclude_ports = match exclude_ports {
         Some(e) => e,
-        None => nsp_exclued_parser(&nsp_lines)?,
+        None => db_exclude_ports.clone(),
+    };
+    let service_probes = match user_probes {
+        Some(user_probes) => {
+            let mut service_probes = db_probes.as_ref().clone();
+            merge_service_probes(&mut service_probes, user_probes)?;
+            Arc::new(service_probes)
+        }
+        None => db_probes.clone(),
     };
-    let service_probes = nsp_parser(&nsp_lines)?;
 
     let mut recv_size = 0;
     for (addr, ports) in vs_target {
-        for port in ports {
+        for (port, protocol) in ports {
+            let exclude_protocol_ports = match protocol {
+                ProbesProtocol::Tcp => &exclude_ports.tcp_ports,
+                ProbesProtocol::Udp => &exclude_ports.udp_ports,
+            };
             // Nmap checks to see if the port is one of the ports to be excluded.
-            if !exclude_ports.ports.contains(&port) {
+            if !exclude_ports.ports.contains(&port) && !exclude_protocol_ports.contains(&port) {
                 let tx = tx.clone();
                 let service_probes = service_probes.clone();
                 pool.execute(move || {
                     let r = vs_probe(
                         addr,
                         port,
+                        protocol,
                         only_null_probe,
                         only_tcp_recommended,
                         only_udp_recommended,
@@ -99,7 +246,7 @@ pub fn vs_scan(
                         &service_probes,
                         timeout,
                     );
-                    match tx.send((port, r)) {
+                    match tx.send((addr, port, protocol, r)) {
                         _ => (),
                     }
                 });
@@ -108,15 +255,20 @@ pub fn vs_scan(
         }
     }
 
-    let mut ret = Vec::new();
+    let mut ret = VsScanResults::new();
     let rx = rx.into_iter().take(recv_size);
-    for (port, r) in rx {
+    for (addr, port, protocol, r) in rx {
         match r {
-            Ok(r) => {
-                let nvdr = NmapVsDetectRet { port, services: r };
-                ret.push(nvdr);
+            Ok(Some(sr)) => {
+                let services = ret.results.entry(addr).or_insert(HashMap::new());
+                services.insert((port, protocol), sr);
+            }
+            Ok(None) => (), // closed port
+            // the error of one port does not stop the others, it is recorded as nothing matched
+            Err(_) => {
+                let services = ret.results.entry(addr).or_insert(HashMap::new());
+                services.insert((port, protocol), unmatched_service_result(port, protocol));
             }
-            Err(e) => return Err(e),
         }
     }
     Ok(ret)
@@ -133,29 +285,40 @@ mod tests {
     #[test]
     fn test_vs_detect() -> Result<()> {
         let dst_addr = Ipv4Addr::new(192, 168, 72, 134);
-        let host = Host::new(dst_addr, Some(vec![22, 443]))?;
+        let host = Host::new_with_udp_ports(dst_addr, Some(vec![22, 443]), Some(vec![53]))?;
         let target = Target::new(vec![host]);
         let threads_num = 8;
         let timeout = Some(Duration::new(1, 0));
         let (only_null_probe, only_tcp_recommended, only_udp_recommended) = (false, true, true);
         let exclude_ports = Some(ExcludePorts::new(vec![51, 52]));
+        // the user-defined probes in the nmap-service-probes syntax, see `nsp_user_parser`
+        let user_probes = None;
         let intensity = 7; // nmap default
         let ret = vs_scan(
             target,
+            None,
+            None,
             only_null_probe,
             only_tcp_recommended,
             only_udp_recommended,
             exclude_ports,
+            user_probes,
             intensity,
             threads_num,
             timeout,
         )?;
-        for r in ret {
-            println!("{}", r);
-        }
+        println!("{}", ret);
         Ok(())
     }
     #[test]
+    fn test_get_service_probes() {
+        let (probes_1, _) = get_service_probes().unwrap();
+        let (probes_2, _) = get_service_probes().unwrap();
+        // compiled once and shared
+        assert!(Arc::ptr_eq(probes_1, probes_2));
+        assert!(probes_1.len() > 0);
+    }
+    #[test]
     fn test_regex() {
         let mut buff = [0u8; 102400];
         let mut file = File::open("./response.bin").unwrap();
@@ -179,7 +342,7 @@ mod tests {
 
         for s in service_probes {
             if s.probe.probename == "GetRequest" {
-                let _ret = s.check(&buff_str);
+                let _ret = s.check(&buff);
                 // for m in s.matchs {
                 //     if m.pattern == r"^HTTP/1\.[01] \d\d\d (?:[^\r\n]*\r\n(?!\r\n))*?Server: Apache[/ ](\d[-.\w]+) ([^\r\n]+)\s" {
                 //         println!("FIND!");
@@ -2,8 +2,77 @@ use anyhow::Result;
 use fancy_regex::Regex;
 use serde::Deserialize;
 use serde::Serialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+/* MatchLineError */
+#[derive(Debug, Clone)]
+struct MatchLineError {
+    line: String,
+}
+
+impl fmt::Display for MatchLineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "can not parse the match line: {}", self.line)
+    }
+}
+
+impl MatchLineError {
+    pub fn new(line: &str) -> MatchLineError {
+        MatchLineError {
+            line: line.to_string(),
+        }
+    }
+}
+
+impl Error for MatchLineError {}
+
+/* ProbeLineError */
+#[derive(Debug, Clone)]
+struct ProbeLineError {
+    line: String,
+}
+
+impl fmt::Display for ProbeLineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "can not parse the probe line: {}", self.line)
+    }
+}
+
+impl ProbeLineError {
+    pub fn new(line: &str) -> ProbeLineError {
+        ProbeLineError {
+            line: line.to_string(),
+        }
+    }
+}
+
+impl Error for ProbeLineError {}
+
+/* ServicesLineError */
+#[derive(Debug, Clone)]
+struct ServicesLineError {
+    line: String,
+}
+
+impl fmt::Display for ServicesLineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "can not parse the nmap-services line: {}", self.line)
+    }
+}
+
+impl ServicesLineError {
+    pub fn new(line: &str) -> ServicesLineError {
+        ServicesLineError {
+            line: line.to_string(),
+        }
+    }
+}
+
+impl Error for ServicesLineError {}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub enum ProbesProtocol {
     Tcp,
     Udp,
@@ -17,8 +86,282 @@ pub struct Match {
     pub service: String,
     // This pattern is used to determine whether the response received matches the service given in the previous parameter.
     pub pattern: String,
+    // The options after the closing delimiter, `i` (case-insensitive) and `s` (dot matches newline).
+    pub flags: String,
     // The <versioninfo> section actually contains several optional fields.
     pub versioninfo: String,
+    // The <versioninfo> fields with the `$` templates substituted, only filled in the result of the check.
+    #[serde(default)]
+    pub version: ServiceVersion,
+    // The compiled pattern, `None` if the pattern is not supported by fancy-regex.
+    #[serde(skip)]
+    pub regex: Option<Regex>,
+}
+
+impl Match {
+    /// Parse the `match` or `softmatch` line, the pattern is not compiled here.
+    /// The pattern is `m<delimiter>...<delimiter>[flags]` and the delimiter can be any char.
+    pub fn parse(line: &str) -> Result<Match> {
+        let (class, other) = match line.split_once(' ') {
+            Some(s) => s,
+            None => return Err(MatchLineError::new(line).into()),
+        };
+        let (service, other) = match other.trim_start().split_once(' ') {
+            Some(s) => s,
+            None => return Err(MatchLineError::new(line).into()),
+        };
+        let other = other.trim_start();
+        let delimiter = match other.strip_prefix('m').and_then(|o| o.chars().next()) {
+            Some(d) => d,
+            None => return Err(MatchLineError::new(line).into()),
+        };
+        let body = &other[1 + delimiter.len_utf8()..];
+        let (pattern, other) = match body.find(delimiter) {
+            Some(end) => (&body[..end], &body[end + delimiter.len_utf8()..]),
+            None => return Err(MatchLineError::new(line).into()),
+        };
+        let flags_len = other
+            .find(|c: char| c != 'i' && c != 's')
+            .unwrap_or(other.len());
+        let m = Match {
+            class: class.to_string(),
+            service: service.to_string(),
+            pattern: pattern.to_string(),
+            flags: other[..flags_len].to_string(),
+            versioninfo: other[flags_len..].trim().to_string(),
+            version: ServiceVersion::default(),
+            regex: None,
+        };
+        Ok(m)
+    }
+    /// Returns the match with the <versioninfo> substituted if the response matches the pattern.
+    fn check(&self, recv_str: &str) -> Option<Match> {
+        let re = match &self.regex {
+            Some(re) => re,
+            None => return None,
+        };
+        match re.captures(recv_str) {
+            Ok(Some(v)) => {
+                let captures: Vec<Option<String>> = v
+                    .iter()
+                    .map(|c| c.map(|c| c.as_str().to_string()))
+                    .collect();
+                let mut new_match = self.clone();
+                new_match.versioninfo = versioninfo_subst(&self.versioninfo, &captures);
+                new_match.version = ServiceVersion::parse(&self.versioninfo, &captures);
+                Some(new_match)
+            }
+            // no match or the backtrack limit is reached
+            _ => None,
+        }
+    }
+    /// Compile the pattern, the pcre syntax is translated for fancy-regex first (see `pcre_translate`).
+    pub fn compile(&mut self) -> Result<()> {
+        let pattern = pcre_translate(&self.pattern, &self.flags);
+        let re = Regex::new(&pattern)?;
+        self.regex = Some(re);
+        Ok(())
+    }
+}
+
+/// The <versioninfo> of the matched service.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct ServiceVersion {
+    /// p/vendorproductname/
+    pub product: Option<String>,
+    /// v/version/
+    pub version: Option<String>,
+    /// i/info/
+    pub info: Option<String>,
+    /// h/hostname/
+    pub hostname: Option<String>,
+    /// o/operatingsystem/
+    pub ostype: Option<String>,
+    /// d/devicetype/
+    pub devicetype: Option<String>,
+    /// cpe:/cpename/[a]
+    pub cpe: Vec<String>,
+}
+
+impl fmt::Display for ServiceVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut output = Vec::new();
+        match &self.product {
+            Some(p) => output.push(p.to_string()),
+            None => (),
+        }
+        match &self.version {
+            Some(v) => output.push(v.to_string()),
+            None => (),
+        }
+        match &self.info {
+            Some(i) => output.push(format!("({})", i)),
+            None => (),
+        }
+        let mut service_info = Vec::new();
+        match &self.hostname {
+            Some(h) => service_info.push(format!("Host: {}", h)),
+            None => (),
+        }
+        match &self.ostype {
+            Some(o) => service_info.push(format!("OS: {}", o)),
+            None => (),
+        }
+        match &self.devicetype {
+            Some(d) => service_info.push(format!("Device: {}", d)),
+            None => (),
+        }
+        if self.cpe.len() > 0 {
+            service_info.push(format!("CPE: {}", self.cpe.join(", ")));
+        }
+        let mut output = output.join(" ");
+        if service_info.len() > 0 {
+            output += &format!("\nService Info: {}", service_info.join("; "));
+        }
+        write!(f, "{}", output)
+    }
+}
+
+impl ServiceVersion {
+    /// Parse the <versioninfo> and substitute the templates in the fields with the captures of the pattern,
+    /// the capture 0 is the whole match, see `versioninfo_subst`.
+    pub fn parse(versioninfo: &str, captures: &[Option<String>]) -> ServiceVersion {
+        let mut sv = ServiceVersion::default();
+        for (name, value) in versioninfo_fields(versioninfo) {
+            let value = versioninfo_subst(&value, captures);
+            // the empty field is useless, nmap does not show it either
+            if value.len() == 0 {
+                continue;
+            }
+            match name.as_str() {
+                "p" => sv.product = Some(value),
+                "v" => sv.version = Some(value),
+                "i" => sv.info = Some(value),
+                "h" => sv.hostname = Some(value),
+                "o" => sv.ostype = Some(value),
+                "d" => sv.devicetype = Some(value),
+                "cpe" => sv.cpe.push(format!("cpe:/{}", value)),
+                _ => (),
+            }
+        }
+        sv
+    }
+}
+
+/// Split the <versioninfo> to the `(name, value)` fields, the value is still the template.
+/// The field is like `p/value/` and the delimiter can be any char, the `a` after the cpe is dropped.
+fn versioninfo_fields(versioninfo: &str) -> Vec<(String, String)> {
+    let mut ret = Vec::new();
+    let mut rest = versioninfo.trim_start();
+    while rest.len() > 0 {
+        let (name, other) = if rest.starts_with("cpe:") {
+            ("cpe", &rest[4..])
+        } else {
+            let c = rest.chars().next().unwrap();
+            (&rest[..c.len_utf8()], &rest[c.len_utf8()..])
+        };
+        let delimiter = match other.chars().next() {
+            Some(d) => d,
+            None => break,
+        };
+        let body = &other[delimiter.len_utf8()..];
+        let (value, other) = match body.find(delimiter) {
+            Some(end) => (&body[..end], &body[end + delimiter.len_utf8()..]),
+            None => break,
+        };
+        ret.push((name.to_string(), value.to_string()));
+        let other = if name == "cpe" {
+            other.strip_prefix('a').unwrap_or(other)
+        } else {
+            other
+        };
+        rest = other.trim_start();
+    }
+    ret
+}
+
+/// Parse the arguments of the helper function like `(1,".","_")`, the `buff` starts after the `(`.
+/// Returns the arguments and the length until the `)`.
+fn helper_args(buff: &str) -> Option<(Vec<String>, usize)> {
+    let mut args = Vec::new();
+    let mut arg = String::new();
+    let mut in_quote = false;
+    for (i, c) in buff.char_indices() {
+        match c {
+            '"' => in_quote = !in_quote,
+            ',' if !in_quote => args.push(std::mem::take(&mut arg)),
+            ')' if !in_quote => {
+                args.push(arg);
+                return Some((args, i + 1));
+            }
+            _ => arg.push(c),
+        }
+    }
+    None
+}
+
+/// Substitute the templates in the <versioninfo> field like nmap:
+/// `$1` is the capture, `$P(1)` only keeps the printable chars of the capture (useful for the UTF-16 strings),
+/// `$SUBST(1,".","_")` replaces the `.` of the capture with `_`,
+/// `$I(1,">")` unpacks the capture as a big endian (`<` for little endian) unsigned integer.
+pub fn versioninfo_subst(template: &str, captures: &[Option<String>]) -> String {
+    let capture = |num: &str| -> String {
+        match num.trim().parse::<usize>() {
+            Ok(n) => match captures.get(n) {
+                Some(Some(c)) => c.to_string(),
+                _ => String::new(),
+            },
+            Err(_) => String::new(),
+        }
+    };
+    let mut ret = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('$') {
+        ret += &rest[..start];
+        rest = &rest[start..];
+        // only $1 to $9
+        if rest[1..].starts_with(|c: char| c.is_ascii_digit()) {
+            ret += &capture(&rest[1..2]);
+            rest = &rest[2..];
+            continue;
+        }
+        let helper = ["P(", "SUBST(", "I("]
+            .into_iter()
+            .find(|h| rest[1..].starts_with(h));
+        let parsed = match helper {
+            Some(h) => helper_args(&rest[1 + h.len()..]).map(|(args, len)| (h, args, len)),
+            None => None,
+        };
+        match parsed {
+            Some((h, args, len)) => {
+                let c = capture(&args[0]);
+                let value = match h {
+                    "P(" => c.chars().filter(|c| (' '..='~').contains(c)).collect(),
+                    "SUBST(" if args.len() == 3 => c.replace(&args[1], &args[2]),
+                    "I(" if args.len() == 2 && c.chars().count() <= 8 => {
+                        let mut bytes: Vec<u8> = c.chars().map(|c| c as u8).collect();
+                        if args[1] == "<" {
+                            bytes.reverse();
+                        }
+                        let mut value: u64 = 0;
+                        for b in bytes {
+                            value = (value << 8) | b as u64;
+                        }
+                        value.to_string()
+                    }
+                    _ => String::new(),
+                };
+                ret += &value;
+                rest = &rest[1 + h.len() + len..];
+            }
+            None => {
+                ret.push('$');
+                rest = &rest[1..];
+            }
+        }
+    }
+    ret += rest;
+    ret
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -27,8 +370,8 @@ pub struct Probe {
     pub protocol: ProbesProtocol,
     /// This is a plain English name for the probe. It is used in service fingerprints to describe which probes elicited responses.
     pub probename: String,
-    /// Tells Nmap what to send.
-    pub probestring: String,
+    /// Tells Nmap what to send, the escapes are decoded to the exact bytes.
+    pub probestring: Vec<u8>,
     /// This keyword is used to instruct Nmap not to use the given probe as a protocol-specific payload during UDP port scanning.
     pub no_payload: bool,
 }
@@ -50,62 +393,20 @@ pub struct ServiceProbe {
     pub rarity: Option<u64>,
     /// This optional directive specifies which probes should be used as fallbacks for if there are no matches in the current Probe section.
     pub fallback: Option<Vec<String>>,
+    /// The probes with the higher priority are tried first, the probes of the nmap database have the priority 0.
+    #[serde(default)]
+    pub priority: i32,
 }
 
 impl ServiceProbe {
-    pub fn check(&self, recv_str: &str) -> Vec<Match> {
-        let match_function = |m: &Match, re: &Regex, recv_str: &str| -> Option<Match> {
-            match re.is_match(&recv_str) {
-                Ok(b) => {
-                    if b {
-                        let captures_group = match re.captures(&recv_str) {
-                            Ok(v) => v,
-                            Err(_) => None,
-                        };
-                        match captures_group {
-                            Some(v) => {
-                                let mut versioninfo = m.versioninfo.to_string();
-                                for i in 0..v.len() {
-                                    let value = v.get(i).unwrap();
-                                    versioninfo =
-                                        versioninfo.replace(&format!("${}", i), value.as_str());
-                                }
-                                let new_match = Match {
-                                    class: m.class.clone(),
-                                    service: m.service.clone(),
-                                    pattern: m.pattern.clone(),
-                                    versioninfo,
-                                };
-                                return Some(new_match);
-                            }
-                            None => {
-                                let new_match = Match {
-                                    class: m.class.clone(),
-                                    service: m.service.clone(),
-                                    pattern: m.pattern.clone(),
-                                    versioninfo: String::from(""),
-                                };
-                                return Some(new_match);
-                            }
-                        }
-                    }
-                }
-                Err(_) => (),
-            }
-            None
-        };
-
+    /// The response is matched byte by byte like nmap, see `latin1_decode`.
+    /// The patterns that failed to compile are skipped, `nsp_parser_diagnostic` tells which ones.
+    pub fn check(&self, recv_buff: &[u8]) -> Vec<Match> {
+        let recv_str = latin1_decode(recv_buff);
         let mut ret = Vec::new();
         // match
         for m in &self.matchs {
-            // println!("{}", m.pattern);
-            let recv_str = recv_str.to_string();
-            let re = match Regex::new(&m.pattern) {
-                Ok(r) => r,
-                Err(_) => continue, // rust regex is not support some format, and it will return error here
-            };
-            let r = match_function(m, &re, &recv_str);
-            match r {
+            match m.check(&recv_str) {
                 Some(r) => ret.push(r),
                 None => (),
             }
@@ -113,18 +414,225 @@ impl ServiceProbe {
 
         // softmatch
         for m in &self.softmatchs {
-            let re = match Regex::new(&m.pattern) {
-                Ok(r) => r,
-                Err(_) => continue, // rust regex is not support some format, and it will return error here
-            };
-            let r = match_function(m, &re, &recv_str);
-            match r {
+            match m.check(&recv_str) {
                 Some(r) => ret.push(r),
                 None => (),
             }
         }
         ret
     }
+    /// Same as `check` but stops at the first match like nmap,
+    /// the softmatch is only returned when no match is found.
+    pub fn check_first(&self, recv_buff: &[u8]) -> Option<Match> {
+        let recv_str = latin1_decode(recv_buff);
+        for m in self.matchs.iter().chain(&self.softmatchs) {
+            match m.check(&recv_str) {
+                Some(r) => return Some(r),
+                None => (),
+            }
+        }
+        None
+    }
+    /// Compile the patterns which are not compiled yet, it is needed by the probe built by hand.
+    pub fn compile(&mut self) -> Result<()> {
+        for m in self.matchs.iter_mut().chain(self.softmatchs.iter_mut()) {
+            if m.regex.is_none() {
+                m.compile()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Translate the pcre pattern of nmap to the fancy-regex syntax.
+/// The flags `i` and `s` become the inline flags, and the constructs with a different meaning are rewritten:
+/// `\0` octal, one digit `\xH`, `\Z`, `$`, `\h` and `\e`, `\w` and `\s` only match ascii in pcre,
+/// `{` without a valid repetition and `[`, `&`, `~` inside the class are literal.
+fn pcre_translate(pattern: &str, flags: &str) -> String {
+    let mut ret = String::new();
+    let mut opts = String::new();
+    if flags.contains('i') {
+        opts.push('i');
+    }
+    if flags.contains('s') {
+        opts.push('s');
+    }
+    if opts.len() > 0 {
+        ret += &format!("(?{})", opts);
+    }
+
+    let chars: Vec<char> = pattern.chars().collect();
+    let is_repetition = |start: usize| -> bool {
+        // {n}, {n,} or {n,m}
+        let mut i = start + 1;
+        let mut digits = 0;
+        while i < chars.len() && chars[i].is_ascii_digit() {
+            i += 1;
+            digits += 1;
+        }
+        if digits == 0 {
+            return false;
+        }
+        if i < chars.len() && chars[i] == ',' {
+            i += 1;
+            while i < chars.len() && chars[i].is_ascii_digit() {
+                i += 1;
+            }
+        }
+        i < chars.len() && chars[i] == '}'
+    };
+
+    let mut in_class = false;
+    // the first char of the class is always literal, even the ']'
+    let mut class_start = false;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let first = class_start;
+        class_start = false;
+        if c == '\\' && i + 1 < chars.len() {
+            let e = chars[i + 1];
+            i += 2;
+            match e {
+                '0' => {
+                    let mut value = 0;
+                    let mut num = 0;
+                    while num < 2 && i < chars.len() && chars[i].is_digit(8) {
+                        value = value * 8 + chars[i].to_digit(8).unwrap();
+                        i += 1;
+                        num += 1;
+                    }
+                    ret += &format!("\\x{:02x}", value);
+                }
+                'x' => {
+                    if i < chars.len() && chars[i] == '{' {
+                        ret += "\\x";
+                    } else {
+                        let mut hex = String::new();
+                        while hex.len() < 2 && i < chars.len() && chars[i].is_ascii_hexdigit() {
+                            hex.push(chars[i]);
+                            i += 1;
+                        }
+                        ret += &format!("\\x{:0>2}", hex);
+                    }
+                }
+                'e' => ret += "\\x1b",
+                'Z' if !in_class => ret += "(?=\\n?\\z)",
+                'h' if in_class => ret += "\\t\\x20",
+                'h' => ret += "[\\t\\x20]",
+                'w' if in_class => ret += "0-9A-Za-z_",
+                'w' => ret += "[0-9A-Za-z_]",
+                'W' if !in_class => ret += "[^0-9A-Za-z_]",
+                's' if in_class => ret += "\\t\\n\\x0b\\x0c\\r\\x20",
+                's' => ret += "[\\t\\n\\x0b\\x0c\\r\\x20]",
+                'S' if !in_class => ret += "[^\\t\\n\\x0b\\x0c\\r\\x20]",
+                _ => {
+                    ret.push('\\');
+                    ret.push(e);
+                }
+            }
+            continue;
+        }
+        if in_class {
+            match c {
+                ']' if !first => {
+                    in_class = false;
+                    ret.push(']');
+                }
+                ']' => ret += "\\]",
+                '[' => {
+                    // posix class like [:alpha:]
+                    let posix = if i + 1 < chars.len() && chars[i + 1] == ':' {
+                        let rest: String = chars[i + 2..].iter().collect();
+                        rest.find(":]").map(|end| rest[..end].chars().count())
+                    } else {
+                        None
+                    };
+                    match posix {
+                        Some(len) => {
+                            let class: String = chars[i..i + len + 4].iter().collect();
+                            ret += &class;
+                            i += len + 3;
+                        }
+                        None => ret += "\\[",
+                    }
+                }
+                '&' | '~' => {
+                    ret.push('\\');
+                    ret.push(c);
+                }
+                '^' if first => {
+                    ret.push('^');
+                    class_start = true;
+                }
+                _ => ret.push(c),
+            }
+        } else {
+            match c {
+                '[' => {
+                    in_class = true;
+                    class_start = true;
+                    ret.push('[');
+                }
+                '{' if !is_repetition(i) => ret += "\\{",
+                '$' => ret += "(?=\\n?\\z)",
+                _ => ret.push(c),
+            }
+        }
+        i += 1;
+    }
+    ret
+}
+
+/// Map every byte to the char with the same code point (Latin-1),
+/// so one char of the regex is always one byte of the response (`\x00`-`\xff`, `.` and the classes work on the raw bytes),
+/// this is how nmap runs pcre on the binary responses.
+pub fn latin1_decode(buff: &[u8]) -> String {
+    buff.iter().map(|&b| b as char).collect()
+}
+
+/// Decode the escapes of the nmap probe string (`\\`, `\0`, `\a`, `\b`, `\f`, `\n`, `\r`, `\t`, `\v` and `\xHH`).
+pub fn probestring_decode(probestring: &str) -> Vec<u8> {
+    let mut ret = Vec::new();
+    let bytes = probestring.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 1 < bytes.len() {
+            let c = bytes[i + 1];
+            i += 2;
+            let b = match c {
+                b'0' => 0x00,
+                b'a' => 0x07,
+                b'b' => 0x08,
+                b'f' => 0x0c,
+                b'n' => b'\n',
+                b'r' => b'\r',
+                b't' => b'\t',
+                b'v' => 0x0b,
+                b'x' => {
+                    let hex = if i + 2 <= bytes.len() {
+                        std::str::from_utf8(&bytes[i..i + 2]).unwrap_or("")
+                    } else {
+                        ""
+                    };
+                    match u8::from_str_radix(hex, 16) {
+                        Ok(h) => {
+                            i += 2;
+                            h
+                        }
+                        Err(_) => b'x',
+                    }
+                }
+                // \\ and the others
+                _ => c,
+            };
+            ret.push(b);
+        } else {
+            ret.push(bytes[i]);
+            i += 1;
+        }
+    }
+    ret
 }
 
 fn ports_parser(ports: &str) -> Result<Vec<u16>> {
@@ -146,9 +654,56 @@ fn ports_parser(ports: &str) -> Result<Vec<u16>> {
     Ok(ret)
 }
 
+/// The line of `nmap-service-probes` which can not be used as it is.
+#[derive(Debug, Clone)]
+pub struct NspDiagnostic {
+    /// Starts from 1.
+    pub line_num: usize,
+    pub line: String,
+    pub reason: String,
+}
+
+impl fmt::Display for NspDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {} ({})", self.line_num, self.reason, self.line)
+    }
+}
+
+impl NspDiagnostic {
+    pub fn new(line_num: usize, line: &str, reason: &str) -> NspDiagnostic {
+        NspDiagnostic {
+            line_num,
+            line: line.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+}
+
 /// Instead of getting the `Exclude` port based on the `nmap-service-probes` file,
 /// we expect the user to provide a parameter to specify this value themselves.
 pub fn nsp_parser(lines: &[String]) -> Result<Vec<ServiceProbe>> {
+    let (ret, _) = nsp_parser_diagnostic(lines)?;
+    Ok(ret)
+}
+
+/// Same as `nsp_parser`, but also returns the match lines that are not parsed or compiled,
+/// the patterns that fancy-regex does not support are kept without the compiled regex.
+pub fn nsp_parser_diagnostic(lines: &[String]) -> Result<(Vec<ServiceProbe>, Vec<NspDiagnostic>)> {
+    nsp_parser_inner(lines, true)
+}
+
+/// Same as `nsp_parser`, but the patterns are not compiled, so `check` matches nothing.
+/// Compiling all the patterns is slow, use it when only the probes are needed.
+pub fn nsp_probe_parser(lines: &[String]) -> Result<Vec<ServiceProbe>> {
+    let (ret, _) = nsp_parser_inner(lines, false)?;
+    Ok(ret)
+}
+
+fn nsp_parser_inner(
+    lines: &[String],
+    compile: bool,
+) -> Result<(Vec<ServiceProbe>, Vec<NspDiagnostic>)> {
+    let mut diagnostics: Vec<NspDiagnostic> = Vec::new();
     let mut ret: Vec<ServiceProbe> = Vec::new();
     let mut probe_global: Option<Probe> = None;
     let mut matchs_global: Vec<Match> = Vec::new();
@@ -159,10 +714,12 @@ pub fn nsp_parser(lines: &[String]) -> Result<Vec<ServiceProbe>> {
     let mut tcpwrappedms_global: Option<u64> = None;
     let mut rarity_global: Option<u64> = None;
     let mut fallback_gloabl: Option<Vec<String>> = None;
-    for line in lines {
-        if line.contains("#") {
+    for (i, line) in lines.iter().enumerate() {
+        let line_num = i + 1;
+        // the '#' can be found inside the pattern
+        if line.starts_with("#") {
             continue;
-        } else if line.contains("Exclude") {
+        } else if line.starts_with("Exclude") {
             continue;
         }
 
@@ -179,6 +736,7 @@ pub fn nsp_parser(lines: &[String]) -> Result<Vec<ServiceProbe>> {
                         tcpwrappedms: tcpwrappedms_global,
                         rarity: rarity_global,
                         fallback: fallback_gloabl.clone(),
+                        priority: 0,
                     };
                     ret.push(sp);
                     matchs_global = Vec::new();
@@ -194,17 +752,27 @@ pub fn nsp_parser(lines: &[String]) -> Result<Vec<ServiceProbe>> {
             }
 
             // println!("{}", line);
+            // Probe <protocol> <probename> <probestring>
             let line_split: Vec<&str> = line.split(" ").collect();
+            if line_split.len() < 4 || !line_split[3].starts_with("q") {
+                return Err(ProbeLineError::new(line).into());
+            }
             let protocol = match line_split[1] {
                 "TCP" => ProbesProtocol::Tcp,
                 "UDP" => ProbesProtocol::Udp,
-                _ => panic!("new protocol: {}", line_split[1]),
+                _ => return Err(ProbeLineError::new(line).into()),
             };
             let probename = line_split[2].to_string();
+            // q|...| and the delimiter can be any char
             let probelast = line_split[3..].to_vec().join(" ");
-            let probelast_split: Vec<&str> = probelast.split("|").map(|s| s.trim()).collect();
-            let probestring = probelast_split[1].to_string();
-            let no_payload = if probelast.contains("no-payload") {
+            let delimiter = probelast.chars().nth(1).unwrap_or('|');
+            let probebody = probelast.get(2..).unwrap_or("");
+            let (probestring, probeflags) = match probebody.find(delimiter) {
+                Some(end) => (&probebody[..end], &probebody[end + 1..]),
+                None => (probebody, ""),
+            };
+            let probestring = probestring_decode(probestring);
+            let no_payload = if probeflags.contains("no-payload") {
                 true
             } else {
                 false
@@ -216,58 +784,26 @@ pub fn nsp_parser(lines: &[String]) -> Result<Vec<ServiceProbe>> {
                 no_payload,
             };
             probe_global = Some(sp);
-        } else if line.starts_with("match") {
-            let line_split: Vec<&str> = line.split(" ").collect();
-            let class = line_split[0].to_string();
-            let service = line_split[1].to_string();
-            let line_other = line_split[2..].to_vec().join(" ");
-
-            let line_other_replace = line_other.replace("|s", "|");
-            let line_other_split: Vec<&str> = line_other_replace.split("|").collect();
-            let mut pattern = line_other_split[1].to_string();
-            if line_other.contains("|s") {
-                pattern += r"\s"
-            } else if line_other.contains("|i") {
-                pattern += r"\i";
-            }
-
-            let versioninfo = line_other_split[line_other_split.len() - 1]
-                .trim()
-                .to_string();
-
-            let m = Match {
-                class,
-                service,
-                pattern,
-                versioninfo,
+        } else if line.starts_with("match") || line.starts_with("softmatch") {
+            let mut m = match Match::parse(line) {
+                Ok(m) => m,
+                Err(e) => {
+                    diagnostics.push(NspDiagnostic::new(line_num, line, &e.to_string()));
+                    continue;
+                }
             };
-            matchs_global.push(m);
-        } else if line.starts_with("softmatch") {
-            let line_split: Vec<&str> = line.split(" ").collect();
-            let class = line_split[0].to_string();
-            let service = line_split[1].to_string();
-            let line_other = line_split[2..].to_vec().join(" ");
-
-            let line_other_replace = line_other.replace("|s", "|");
-            let line_other_split: Vec<&str> = line_other_replace.split("|").collect();
-            let mut pattern = line_other_split[1].to_string();
-            if line_other.contains("|s") {
-                pattern += r"\s"
-            } else if line_other.contains("|i") {
-                pattern += r"\i";
+            if compile {
+                match m.compile() {
+                    Ok(_) => (),
+                    // keep the line, it will not be used by the check
+                    Err(e) => diagnostics.push(NspDiagnostic::new(line_num, line, &e.to_string())),
+                }
+            }
+            if m.class == "match" {
+                matchs_global.push(m);
+            } else {
+                softmatchs_global.push(m);
             }
-
-            let versioninfo = line_other_split[line_other_split.len() - 1]
-                .trim()
-                .to_string();
-
-            let m = Match {
-                class,
-                service,
-                pattern,
-                versioninfo,
-            };
-            softmatchs_global.push(m);
         } else if line.starts_with("ports") {
             let line_split: Vec<&str> = line.split(" ").collect();
             let ports_line = line_split[1..].to_vec().join(" ");
@@ -291,8 +827,13 @@ pub fn nsp_parser(lines: &[String]) -> Result<Vec<ServiceProbe>> {
             let rarity: u64 = line_split[1].parse()?;
             rarity_global = Some(rarity);
         } else if line.starts_with("fallback") {
-            let line_split: Vec<String> = line.split(" ").map(|s| s.to_string()).collect();
-            let fallback = line_split[1..].to_vec();
+            // fallback GetRequest,HTTPOptions
+            let line_split: Vec<&str> = line.split(" ").collect();
+            let fallback_line = line_split[1..].to_vec().join(" ");
+            let fallback = fallback_line
+                .split(",")
+                .map(|s| s.trim().to_string())
+                .collect();
             fallback_gloabl = Some(fallback);
         }
     }
@@ -308,14 +849,101 @@ pub fn nsp_parser(lines: &[String]) -> Result<Vec<ServiceProbe>> {
                 tcpwrappedms: tcpwrappedms_global,
                 rarity: rarity_global,
                 fallback: fallback_gloabl,
+                priority: 0,
             };
             ret.push(sp);
         }
         None => (),
     }
+    Ok((ret, diagnostics))
+}
+
+/// Parse the user-defined probes in the nmap-service-probes syntax and set the `priority` of them,
+/// use a positive value to try them before the probes of the nmap database.
+/// Unlike `nsp_parser`, the match line which can not be parsed or compiled is an error.
+pub fn nsp_user_parser(lines: &[String], priority: i32) -> Result<Vec<ServiceProbe>> {
+    let (mut ret, diagnostics) = nsp_parser_diagnostic(lines)?;
+    match diagnostics.first() {
+        Some(d) => return Err(MatchLineError::new(&d.line).into()),
+        None => (),
+    }
+    for sp in &mut ret {
+        sp.priority = priority;
+    }
     Ok(ret)
 }
 
+/// Merge the user-defined probes into the probes of the nmap database.
+/// If a probe with the same name and protocol exists, the match lines and ports are added to it,
+/// the match lines go before the existing ones if the user-defined probe has the higher priority.
+/// Otherwise the probe is added with its own ports and rarity.
+pub fn merge_service_probes(
+    service_probes: &mut Vec<ServiceProbe>,
+    user_probes: Vec<ServiceProbe>,
+) -> Result<()> {
+    let merge_ports = |ports: &mut Option<Vec<u16>>, user_ports: Option<Vec<u16>>| match user_ports
+    {
+        Some(up) => {
+            let ports = ports.get_or_insert(Vec::new());
+            for p in up {
+                if !ports.contains(&p) {
+                    ports.push(p);
+                }
+            }
+        }
+        None => (),
+    };
+    for mut up in user_probes {
+        up.compile()?;
+        let exist = service_probes.iter_mut().find(|sp| {
+            sp.probe.probename == up.probe.probename && sp.probe.protocol == up.probe.protocol
+        });
+        match exist {
+            Some(sp) => {
+                if up.priority > sp.priority {
+                    up.matchs.append(&mut sp.matchs);
+                    up.softmatchs.append(&mut sp.softmatchs);
+                    sp.matchs = up.matchs;
+                    sp.softmatchs = up.softmatchs;
+                    sp.priority = up.priority;
+                } else {
+                    sp.matchs.append(&mut up.matchs);
+                    sp.softmatchs.append(&mut up.softmatchs);
+                }
+                merge_ports(&mut sp.ports, up.ports);
+                merge_ports(&mut sp.sslports, up.sslports);
+            }
+            None => service_probes.push(up),
+        }
+    }
+    Ok(())
+}
+
+/// The payloads used by the udp port scan, the key is the destination port.
+/// Like nmap, the probes with `no-payload` are skipped and the first probe of a port wins.
+pub fn udp_payloads(service_probes: &[ServiceProbe]) -> HashMap<u16, Vec<u8>> {
+    let mut ret = HashMap::new();
+    for sp in service_probes {
+        if sp.probe.protocol != ProbesProtocol::Udp || sp.probe.no_payload {
+            continue;
+        }
+        if sp.probe.probestring.len() == 0 {
+            continue;
+        }
+        match &sp.ports {
+            Some(ports) => {
+                for p in ports {
+                    if !ret.contains_key(p) {
+                        ret.insert(*p, sp.probe.probestring.clone());
+                    }
+                }
+            }
+            None => (),
+        }
+    }
+    ret
+}
+
 #[derive(Debug, Clone)]
 pub struct ExcludePorts {
     pub ports: Vec<u16>,
@@ -333,6 +961,41 @@ impl ExcludePorts {
     }
 }
 
+/// Parse the nmap-services file, returns the service name of each tcp and udp port.
+/// The `unknown` services and the other protocols (sctp) are ignored.
+pub fn nmap_services_parser(lines: &[String]) -> Result<HashMap<(u16, ProbesProtocol), String>> {
+    let mut ret = HashMap::new();
+    for line in lines {
+        let line = line.trim();
+        if line.len() == 0 || line.starts_with("#") {
+            continue;
+        }
+        // service port/protocol open-frequency # comment
+        let mut line_split = line.split_whitespace();
+        let (service, port_protocol) = match (line_split.next(), line_split.next()) {
+            (Some(s), Some(p)) => (s, p),
+            _ => return Err(ServicesLineError::new(line).into()),
+        };
+        let (port, protocol) = match port_protocol.split_once("/") {
+            Some(p) => p,
+            None => return Err(ServicesLineError::new(line).into()),
+        };
+        let port: u16 = match port.parse() {
+            Ok(p) => p,
+            Err(_) => return Err(ServicesLineError::new(line).into()),
+        };
+        let protocol = match protocol {
+            "tcp" => ProbesProtocol::Tcp,
+            "udp" => ProbesProtocol::Udp,
+            _ => continue,
+        };
+        if service != "unknown" {
+            ret.entry((port, protocol)).or_insert(service.to_string());
+        }
+    }
+    Ok(ret)
+}
+
 pub fn nsp_exclued_parser(lines: &[String]) -> Result<ExcludePorts> {
     let mut ports: Vec<u16> = Vec::new();
     let mut tcp_ports: Vec<u16> = Vec::new();
@@ -362,8 +1025,7 @@ pub fn nsp_exclued_parser(lines: &[String]) -> Result<ExcludePorts> {
                         }
                         "U" => {
                             if ex_split[1].contains("-") {
-                                let ex_split_split: Vec<&str> =
-                                    exclued_split[1].split("-").collect();
+                                let ex_split_split: Vec<&str> = ex_split[1].split("-").collect();
                                 let start: u16 = ex_split_split[0].parse()?;
                                 let end: u16 = ex_split_split[1].parse()?;
                                 for p in start..=end {
@@ -418,71 +1080,162 @@ mod tests {
         // println!("{:?}", ret);
     }
     #[test]
+    fn test_probestring_decode() {
+        let probestring = r"\x16\x03\0\0S\x01|GET / HTTP/1.0\r\n\r\n\\";
+        let ret = probestring_decode(probestring);
+        let mut target = vec![0x16, 0x03, 0x00, 0x00, b'S', 0x01, b'|'];
+        target.extend(b"GET / HTTP/1.0\r\n\r\n\\");
+        assert_eq!(ret, target);
+        assert_eq!(latin1_decode(&[0x41, 0xff]), "A\u{ff}");
+    }
+    #[test]
+    fn test_check_binary() {
+        let line = r"match ssl m|^\x16\x03[\0-\x03][\x80-\xff]\x02| p/TLS/";
+        let mut m = Match::parse(line).unwrap();
+        m.compile().unwrap();
+        let sp = ServiceProbe {
+            probe: Probe {
+                protocol: ProbesProtocol::Tcp,
+                probename: String::from("SSLSessionReq"),
+                probestring: vec![],
+                no_payload: false,
+            },
+            matchs: vec![m],
+            softmatchs: vec![],
+            ports: None,
+            sslports: None,
+            totalwaitms: None,
+            tcpwrappedms: None,
+            rarity: None,
+            fallback: None,
+            priority: 0,
+        };
+        let ret = sp.check(&[0x16, 0x03, 0x01, 0xff, 0x02]);
+        assert_eq!(ret.len(), 1);
+    }
+    #[test]
+    fn test_pcre_translate() {
+        let m = Match::parse(r"match ftp m=^220 [\w-]+ FTP\r\n$=si p/$1/").unwrap();
+        assert_eq!(m.pattern, r"^220 [\w-]+ FTP\r\n$");
+        assert_eq!(m.flags, "si");
+        assert_eq!(m.versioninfo, "p/$1/");
+        let pattern = pcre_translate(&m.pattern, &m.flags);
+        assert_eq!(pattern, r"(?is)^220 [0-9A-Za-z_-]+ FTP\r\n(?=\n?\z)");
+        let pattern = pcre_translate(r"^\0\x1{a}[[:digit:]&\s]\Z", "");
+        assert_eq!(
+            pattern,
+            r"^\x00\x01\{a}[[:digit:]\&\t\n\x0b\x0c\r\x20](?=\n?\z)"
+        );
+    }
+    #[test]
+    fn test_versioninfo() {
+        let captures = vec![
+            None,
+            Some(String::from("8.9p1")),
+            Some(String::from("W\0O\0R\0K\0")),
+            Some(String::from("\u{1}\u{2}")),
+        ];
+        let versioninfo = r#"p/OpenSSH/ v/$1/ i|$P(2) $SUBST(1,".","_")| h/$I(3,">")/ cpe:/a:openbsd:openssh:$1/a"#;
+        let sv = ServiceVersion::parse(versioninfo, &captures);
+        assert_eq!(sv.product, Some(String::from("OpenSSH")));
+        assert_eq!(sv.version, Some(String::from("8.9p1")));
+        assert_eq!(sv.info, Some(String::from("WORK 8_9p1")));
+        assert_eq!(sv.hostname, Some(String::from("258")));
+        assert_eq!(sv.ostype, None);
+        assert_eq!(sv.cpe, vec![String::from("cpe:/a:openbsd:openssh:8.9p1")]);
+        assert_eq!(versioninfo_subst(r#"$I(3,"<")"#, &captures), "513");
+    }
+    #[test]
     fn test_build_regex() {
-        /*
-        Note:
-            { => \{
-            [] => [.*?]
-            \1 => .*?
-            [ => \[
-            ?= => .*?
-            ?! => .*?
-            ?<= => .*?
-         */
         let nsp_str = include_str!("../db/nmap-service-probes");
         let mut nsp_lines = Vec::new();
         for l in nsp_str.lines() {
             nsp_lines.push(l.to_string());
         }
-        let service_probes = nsp_parser(&nsp_lines).unwrap();
-        for sp in service_probes {
-            // println!("{}", sp.probe.probename);
-            if sp.probe.probename == "NULL" {
-                for m in sp.matchs {
-                    // println!(">>> {} <<<", m.pattern);
-                    // let pattern = format!(r"{}", m.pattern);
-                    // match Regex::new(&m.pattern) {
-                    //     Ok(_) => (),
-                    //     Err(e) => {
-                    //         println!("{}", e);
-                    //         println!("{}", m.pattern);
-                    //     }
-                    // };
-                    if m.pattern.contains("Ubuntu") {
-                        println!(">>>>>>>>>>>>>>>> found: {}", m.pattern);
-                    }
-                }
-            }
+        let (service_probes, diagnostics) = nsp_parser_diagnostic(&nsp_lines).unwrap();
+        for d in &diagnostics {
+            println!("{}", d);
+        }
+        let mut total = 0;
+        for sp in &service_probes {
+            total += sp.matchs.len() + sp.softmatchs.len();
+        }
+        println!("{}/{} failed", diagnostics.len(), total);
+        let null_probe = service_probes
+            .iter()
+            .find(|sp| sp.probe.probename == "NULL")
+            .unwrap();
+        let ret = null_probe.check(b"SSH-2.0-OpenSSH_8.9p1 Ubuntu-3ubuntu0.7\r\n");
+        let ssh = ret.iter().find(|m| m.service == "ssh").unwrap();
+        assert_eq!(ssh.version.product, Some(String::from("OpenSSH")));
+        assert_eq!(
+            ssh.version.version,
+            Some(String::from("8.9p1 Ubuntu 3ubuntu0.7"))
+        );
+    }
+    #[test]
+    fn test_udp_payloads() {
+        let nsp_str = include_str!("../db/nmap-service-probes");
+        let mut nsp_lines = Vec::new();
+        for l in nsp_str.lines() {
+            nsp_lines.push(l.to_string());
         }
+        let service_probes = nsp_probe_parser(&nsp_lines).unwrap();
+        let payloads = udp_payloads(&service_probes);
+        // dns
+        assert!(payloads.contains_key(&53));
+        // snmp
+        assert!(payloads.contains_key(&161));
+        // the Sqlping probe is marked as no-payload
+        assert!(!payloads.contains_key(&1434));
+    }
+    #[test]
+    fn test_merge_service_probes() {
+        let to_lines = |s: &str| -> Vec<String> { s.lines().map(|l| l.to_string()).collect() };
+        let nsp = "Probe TCP NULL q||\n\
+                   match ftp m|^220 | p/FTP/\n\
+                   Probe TCP GetRequest q|GET / HTTP/1.0\\r\\n\\r\\n|\n\
+                   rarity 1\n\
+                   ports 80";
+        let user = "Probe TCP NULL q||\n\
+                    match myrpc m|^220 MYRPC| p/MyRPC/\n\
+                    Probe TCP MyRPCReq q|\\x01\\x02|\n\
+                    rarity 1\n\
+                    ports 9999\n\
+                    match myrpc m|^\\x03| p/MyRPC/";
+        let mut service_probes = nsp_parser(&to_lines(nsp)).unwrap();
+        let user_probes = nsp_user_parser(&to_lines(user), 1).unwrap();
+        merge_service_probes(&mut service_probes, user_probes).unwrap();
+        assert_eq!(service_probes.len(), 3);
+        let m = service_probes[0].check_first(b"220 MYRPC ready").unwrap();
+        assert_eq!(m.service, "myrpc");
+        assert_eq!(service_probes[0].priority, 1);
+        assert_eq!(service_probes[2].probe.probestring, vec![0x01, 0x02]);
+        assert_eq!(service_probes[2].ports, Some(vec![9999]));
+        let bad = "Probe TCP Bad q||\nmatch bad m|^(| p/Bad/";
+        assert!(nsp_user_parser(&to_lines(bad), 1).is_err());
+        // the malformed probe lines are errors, not panics
+        assert!(nsp_user_parser(&to_lines("Probe SCTP Bad q||"), 1).is_err());
+        assert!(nsp_user_parser(&to_lines("Probe TCP"), 1).is_err());
+        assert!(nsp_user_parser(&to_lines("Probe TCP Bad"), 1).is_err());
     }
     #[test]
-    fn test_db_parser() {
-        let line = r"match http m|^HTTP/1\.[01] \d\d\d (?:[^\r\n]*\r\n(?!\r\n))*?Server: Apache +\(([^\r\n\)]+)\)\r\n|s p/Apache httpd/ i/$1/ cpe:/a:apache:http_server/";
-        let line_split: Vec<&str> = line.split(" ").collect();
-        let class = line_split[0].to_string();
-        let service = line_split[1].to_string();
-        let line_other = line_split[2..].to_vec().join(" ");
-        println!("{}", line_other);
-
-        let line_other_replace = line_other.replace("|s", "|");
-        let line_other_split: Vec<&str> = line_other_replace.split("|").collect();
-        let mut pattern = line_other_split[1].to_string();
-        if line_other.contains("|s") {
-            pattern += r"\s"
-        } else if line_other.contains("|i") {
-            pattern += r"\i";
-        }
-
-        let versioninfo = line_other_split[line_other_split.len() - 1]
-            .trim()
-            .to_string();
+    fn test_nmap_services_parser() {
+        let services_str = include_str!("../db/nmap-services-subset");
+        let lines: Vec<String> = services_str.lines().map(|l| l.to_string()).collect();
+        let services = nmap_services_parser(&lines).unwrap();
+        assert_eq!(services[&(22, ProbesProtocol::Tcp)], "ssh");
+        assert_eq!(services[&(514, ProbesProtocol::Udp)], "syslog");
 
-        let m = Match {
-            class,
-            service,
-            pattern,
-            versioninfo,
-        };
-        println!("{:?}", m);
+        // the upstream format with the frequency and the comment
+        let lines = vec![
+            String::from("http\t80/tcp\t0.484143\t# World Wide Web HTTP"),
+            String::from("unknown\t1/udp\t0.001236"),
+            String::from("sctp-svc\t80/sctp\t0.000000"),
+        ];
+        let services = nmap_services_parser(&lines).unwrap();
+        assert_eq!(services.len(), 1);
+        assert_eq!(services[&(80, ProbesProtocol::Tcp)], "http");
+        assert!(nmap_services_parser(&[String::from("http 80")]).is_err());
     }
 }
@@ -1,4 +1,25 @@
 use anyhow::Result;
+use chrono::DateTime;
+use chrono::Local;
+use chrono::Utc;
+use rustls::client::danger::HandshakeSignatureValid;
+use rustls::client::danger::ServerCertVerified;
+use rustls::client::danger::ServerCertVerifier;
+use rustls::crypto::ring;
+use rustls::crypto::verify_tls12_signature;
+use rustls::crypto::verify_tls13_signature;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::CertificateDer;
+use rustls::pki_types::ServerName;
+use rustls::pki_types::UnixTime;
+use rustls::ClientConfig;
+use rustls::ClientConnection;
+use rustls::DigitallySignedStruct;
+use rustls::SignatureScheme;
+use rustls::StreamOwned;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::io::ErrorKind;
 use std::io::Read;
 use std::io::Write;
 use std::net::IpAddr;
@@ -7,268 +28,811 @@ use std::net::Ipv6Addr;
 use std::net::SocketAddr;
 use std::net::TcpStream;
 use std::net::UdpSocket;
+use std::sync::Arc;
+use std::sync::OnceLock;
 use std::time::Duration;
+use std::time::Instant;
 // use std::fs::File;
 
+use super::dbparser::nmap_services_parser;
 use super::dbparser::Match;
 use super::dbparser::ProbesProtocol;
 use super::dbparser::ServiceProbe;
-use crate::utils::random_port;
+use super::dbparser::ServiceVersion;
+use super::ServiceMatchKind;
+use super::ServiceResult;
 
 const TCP_BUFF_SIZE: usize = 4096;
 const UDP_BUFF_SIZE: usize = 4096;
+const NULL_PROBE_NAME: &str = "NULL";
+// nmap waits 5 seconds for the probe without the totalwaitms directive
+const DEFAULT_TOTALWAITMS: u64 = 5000;
+// the response in the service fingerprint is truncated
+const SF_RESPONSE_MAX_LEN: usize = 1024;
+const SF_LINE_WIDTH: usize = 80;
+// the probe that finds the ssl/tls services, it is useless inside the tunnel
+const SSL_PROBE_NAME: &str = "SSLSessionReq";
 
-fn format_send(data: &str) -> String {
-    let new_data = data.replace("\\n", "\n");
-    let new_data = new_data.replace("\\r", "\r");
-    let new_data = new_data.replace("\\t", "\t");
-    new_data
+/// We only want to see what is inside the tunnel, so any certificate is accepted.
+#[derive(Debug)]
+struct NoCertificateVerification(CryptoProvider);
+
+impl ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer,
+        _intermediates: &[CertificateDer],
+        _server_name: &ServerName,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Connect to the target and finish the tls handshake (TLS 1.2 and 1.3 only).
+fn tls_connect(
+    dst_addr: IpAddr,
+    dst_port: u16,
+    timeout: Duration,
+) -> Result<StreamOwned<ClientConnection, TcpStream>> {
+    let provider = Arc::new(ring::default_provider());
+    let verifier = Arc::new(NoCertificateVerification(ring::default_provider()));
+    let config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(verifier)
+        .with_no_client_auth();
+    // no sni will be sent for the ip address
+    let server_name = ServerName::IpAddress(dst_addr.into());
+    let mut conn = ClientConnection::new(Arc::new(config), server_name)?;
+
+    let tcp_dst_addr = SocketAddr::new(dst_addr, dst_port);
+    let mut stream = TcpStream::connect_timeout(&tcp_dst_addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    while conn.is_handshaking() {
+        conn.complete_io(&mut stream)?;
+    }
+    Ok(StreamOwned::new(conn, stream))
 }
 
-fn tcp_null_probe(stream: &mut TcpStream, service_probes: &[ServiceProbe]) -> Result<Vec<Match>> {
+/// The plain tcp stream or the ssl/tls tunnel.
+trait ProbeStream: Read + Write {
+    fn set_read_timeout(&self, dur: Option<Duration>) -> std::io::Result<()>;
+}
+
+impl ProbeStream for TcpStream {
+    fn set_read_timeout(&self, dur: Option<Duration>) -> std::io::Result<()> {
+        TcpStream::set_read_timeout(self, dur)
+    }
+}
+
+impl ProbeStream for StreamOwned<ClientConnection, TcpStream> {
+    fn set_read_timeout(&self, dur: Option<Duration>) -> std::io::Result<()> {
+        self.sock.set_read_timeout(dur)
+    }
+}
+
+/// Nmap opens a new connection for every probe.
+fn tcp_connect(
+    dst_addr: IpAddr,
+    dst_port: u16,
+    in_tunnel: bool,
+    timeout: Duration,
+) -> Result<Box<dyn ProbeStream>> {
+    if in_tunnel {
+        let stream = tls_connect(dst_addr, dst_port, timeout)?;
+        Ok(Box::new(stream))
+    } else {
+        let tcp_dst_addr = SocketAddr::new(dst_addr, dst_port);
+        let stream = TcpStream::connect_timeout(&tcp_dst_addr, timeout)?;
+        stream.set_write_timeout(Some(timeout))?;
+        Ok(Box::new(stream))
+    }
+}
+
+/// Read until the server closes the connection or the `wait` is up,
+/// `done` is called with all the data received so far and the reading stops when it returns true.
+/// Returns the data and whether the connection was closed by the server.
+fn read_response<S: ProbeStream + ?Sized, F: FnMut(&[u8]) -> bool>(
+    stream: &mut S,
+    wait: Duration,
+    mut done: F,
+) -> (Vec<u8>, bool) {
+    let start = Instant::now();
     let mut recv_buff = [0u8; TCP_BUFF_SIZE];
     let mut recv_all_buff = Vec::new();
     loop {
-        let n = match stream.read(&mut recv_buff) {
-            Ok(n) => n,
-            Err(_) => 0,
-        };
-        if n == 0 {
-            break;
-        } else {
-            recv_all_buff.extend(recv_buff);
+        let elapsed = start.elapsed();
+        if elapsed >= wait {
+            return (recv_all_buff, false);
+        }
+        match stream.set_read_timeout(Some(wait - elapsed)) {
+            Ok(_) => (),
+            Err(_) => return (recv_all_buff, true),
+        }
+        match stream.read(&mut recv_buff) {
+            Ok(0) => return (recv_all_buff, true),
+            Ok(n) => {
+                recv_all_buff.extend(&recv_buff[..n]);
+                if done(&recv_all_buff) {
+                    return (recv_all_buff, false);
+                }
+            }
+            Err(e) => match e.kind() {
+                ErrorKind::WouldBlock | ErrorKind::TimedOut => return (recv_all_buff, false),
+                ErrorKind::Interrupted => (),
+                // reset by the server
+                _ => return (recv_all_buff, true),
+            },
         }
     }
+}
 
-    let mut ret = Vec::new();
-    if recv_all_buff.len() > 0 {
-        let recv_str = String::from_utf8_lossy(&recv_buff);
-        // println!("{}", recv_str);
-        for s in service_probes {
-            if s.probe.probename == "NULL" {
-                let r = s.check(&recv_str);
-                ret.extend(r);
+/// The probe itself first, then the probes in the `fallback` directive,
+/// the NULL probe is always the last fallback of the tcp probes.
+fn fallback_probes<'a>(
+    sp: &'a ServiceProbe,
+    service_probes: &'a [ServiceProbe],
+) -> Vec<&'a ServiceProbe> {
+    let mut ret = vec![sp];
+    match &sp.fallback {
+        Some(fallback) => {
+            for name in fallback {
+                for f in service_probes {
+                    if &f.probe.probename == name && f.probe.protocol == sp.probe.protocol {
+                        ret.push(f);
+                    }
+                }
             }
         }
+        None => (),
     }
-    Ok(ret)
+    if sp.probe.protocol == ProbesProtocol::Tcp && sp.probe.probename != NULL_PROBE_NAME {
+        for f in service_probes {
+            if f.probe.probename == NULL_PROBE_NAME && f.probe.protocol == ProbesProtocol::Tcp {
+                ret.push(f);
+            }
+        }
+    }
+    ret
 }
 
-fn tcp_continue_probe(
-    stream: &mut TcpStream,
-    dst_port: u16,
-    only_tcp_recommended: bool,
-    intensity: usize,
-    service_probes: &[ServiceProbe],
-) -> Result<Vec<Match>> {
-    let mut run_probe = |sp: &ServiceProbe| -> Result<Vec<Match>> {
-        let probestring = format_send(&sp.probe.probestring);
-        stream.write(probestring.as_bytes())?;
-        let mut recv_buff = [0u8; TCP_BUFF_SIZE];
-        let mut recv_all_buff = Vec::new();
-        loop {
-            let n = match stream.read(&mut recv_buff) {
-                Ok(n) => n,
-                Err(_) => 0,
-            };
-            if n == 0 {
-                break;
-            } else {
-                recv_all_buff.extend(recv_buff);
+/// Returns the first match of the probes, or the first softmatch if no match is found.
+fn fallback_check(probes: &[&ServiceProbe], recv_buff: &[u8]) -> Option<Match> {
+    let mut soft = None;
+    for sp in probes {
+        match sp.check_first(recv_buff) {
+            Some(m) => {
+                if m.class == "match" {
+                    return Some(m);
+                } else if soft.is_none() {
+                    soft = Some(m);
+                }
             }
+            None => (),
         }
-        if recv_all_buff.len() > 0 {
-            let recv_str = String::from_utf8_lossy(&recv_all_buff);
-            println!("{}", recv_str);
-            let r = sp.check(&recv_str);
-            Ok(r)
-        } else {
-            Ok(vec![])
+    }
+    soft
+}
+
+fn tcpwrapped_match() -> Match {
+    Match {
+        class: String::from("match"),
+        service: String::from("tcpwrapped"),
+        pattern: String::new(),
+        flags: String::new(),
+        versioninfo: String::new(),
+        version: ServiceVersion::default(),
+        regex: None,
+    }
+}
+
+/// Send the probe string and wait for the `totalwaitms` of the probe.
+/// Returns the match and whether the service is tcpwrapped,
+/// that is the server closed the connection without any data inside the `tcpwrappedms`,
+/// the response is recorded in `responses` for the service fingerprint.
+fn tcp_run_probe(
+    stream: &mut dyn ProbeStream,
+    sp: &ServiceProbe,
+    service_probes: &[ServiceProbe],
+    responses: &mut Vec<ProbeResponse>,
+) -> (Option<Match>, bool) {
+    if sp.probe.probestring.len() > 0 {
+        match stream.write_all(&sp.probe.probestring) {
+            Ok(_) => (),
+            // the connection was closed by the server
+            Err(_) => return (None, false),
+        }
+    }
+    let probes = fallback_probes(sp, service_probes);
+    let wait = Duration::from_millis(sp.totalwaitms.unwrap_or(DEFAULT_TOTALWAITMS));
+    let start = Instant::now();
+    let mut ret = None;
+    let (recv_buff, closed) = read_response(stream, wait, |buff| {
+        ret = fallback_check(&probes, buff);
+        match &ret {
+            Some(m) => m.class == "match",
+            None => false,
         }
+    });
+    if recv_buff.len() > 0 {
+        responses.push(ProbeResponse::new(&sp.probe.probename, recv_buff.clone()));
+    }
+    let tcpwrapped = match sp.tcpwrappedms {
+        Some(ms) => closed && recv_buff.len() == 0 && start.elapsed() < Duration::from_millis(ms),
+        None => false,
     };
+    (ret, tcpwrapped)
+}
 
-    let mut ret = Vec::new();
-    // TCP connections continue here if the NULL probe described above fails or soft-matches.
+/// The probes to try after the NULL probe in order, like nmap.
+/// The probes registered for the port (`sslports` inside the ssl/tls tunnel) are tried first regardless of the intensity,
+/// then the other probes whose rarity is not larger than the intensity, from the common ones to the rare ones.
+/// Inside the two groups, the probes with the higher priority come first (see `merge_service_probes`).
+fn probe_schedule<'a>(
+    dst_port: u16,
+    protocol: ProbesProtocol,
+    only_recommended: bool,
+    in_tunnel: bool,
+    intensity: usize,
+    service_probes: &'a [ServiceProbe],
+) -> Vec<&'a ServiceProbe> {
+    let mut recommended = Vec::new();
+    let mut others = Vec::new();
     for sp in service_probes {
+        if sp.probe.probename == NULL_PROBE_NAME
+            || sp.probe.protocol != protocol
+            || (in_tunnel && sp.probe.probename == SSL_PROBE_NAME)
+        {
+            continue;
+        }
+        let ports = if in_tunnel { &sp.sslports } else { &sp.ports };
+        let registered = match ports {
+            Some(p) => p.contains(&dst_port),
+            None => false,
+        };
         let rarity = match sp.rarity {
             Some(r) => r as usize,
             None => 0,
         };
-        let mut ports: Vec<u16> = Vec::new();
-        match &sp.ports {
-            Some(p) => ports.extend(p),
-            None => (),
+        // Since the reality is that most ports are used by the service they are registered to in nmap-services,
+        // every probe has a list of port numbers that are considered to be most effective.
+        if registered {
+            recommended.push(sp);
+        } else if !only_recommended && intensity >= rarity {
+            others.push(sp);
         }
-        match &sp.sslports {
-            Some(s) => ports.extend(s),
-            None => (),
+    }
+    // the sort is stable, so the probes with the same rarity keep the order of the file
+    let order = |sp: &&ServiceProbe| (Reverse(sp.priority), sp.rarity.unwrap_or(0));
+    recommended.sort_by_key(order);
+    others.sort_by_key(order);
+    recommended.extend(others);
+    recommended
+}
+
+/// The match and the probe which elicited the response.
+struct ProbeMatch {
+    m: Match,
+    probename: String,
+    rtt: Duration,
+}
+
+impl ProbeMatch {
+    fn new(m: Match, sp: &ServiceProbe, rtt: Duration) -> ProbeMatch {
+        ProbeMatch {
+            m,
+            probename: sp.probe.probename.clone(),
+            rtt,
         }
-        if sp.probe.probename != "NULL"
-            && sp.probe.protocol == ProbesProtocol::Tcp
-            && intensity >= rarity
-        {
-            // Since the reality is that most ports are used by the service they are registered to in nmap-services,
-            // every probe has a list of port numbers that are considered to be most effective.
-            if only_tcp_recommended {
-                if ports.contains(&dst_port) {
-                    let r = run_probe(sp);
-                    match r {
-                        Ok(r) => ret.extend(r),
-                        Err(e) => return Err(e.into()),
+    }
+    fn is_hard(&self) -> bool {
+        self.m.class == "match"
+    }
+}
+
+/// The tcp part of the nmap algorithm, the NULL probe first and then the other probes,
+/// every probe runs in a new connection and it stops at the first match.
+/// If only a softmatch is found, only the probes that can match the same service are tried next.
+/// The rtt is the time of the connect.
+fn tcp_probe(
+    dst_addr: IpAddr,
+    dst_port: u16,
+    only_null_probe: bool,
+    only_tcp_recommended: bool,
+    in_tunnel: bool,
+    intensity: usize,
+    service_probes: &[ServiceProbe],
+    timeout: Duration,
+    responses: &mut Vec<ProbeResponse>,
+) -> Result<Option<ProbeMatch>> {
+    // If the port is TCP, Nmap starts by connecting to it.
+    let start = Instant::now();
+    let mut stream = tcp_connect(dst_addr, dst_port, in_tunnel, timeout)?;
+    let rtt = start.elapsed();
+    let mut soft: Option<ProbeMatch> = None;
+    for sp in service_probes {
+        if sp.probe.probename == NULL_PROBE_NAME && sp.probe.protocol == ProbesProtocol::Tcp {
+            let (r, tcpwrapped) = tcp_run_probe(&mut *stream, sp, service_probes, responses);
+            if tcpwrapped {
+                return Ok(Some(ProbeMatch::new(tcpwrapped_match(), sp, rtt)));
+            }
+            match r {
+                Some(m) => {
+                    let pm = ProbeMatch::new(m, sp, rtt);
+                    if pm.is_hard() {
+                        return Ok(Some(pm));
                     }
+                    soft = Some(pm);
                 }
-            } else {
-                let r = run_probe(sp);
-                match r {
-                    Ok(r) => ret.extend(r),
-                    Err(e) => return Err(e.into()),
+                None => (),
+            }
+            break;
+        }
+    }
+    drop(stream);
+
+    if !only_null_probe {
+        // TCP connections continue here if the NULL probe described above fails or soft-matches.
+        let schedule = probe_schedule(
+            dst_port,
+            ProbesProtocol::Tcp,
+            only_tcp_recommended,
+            in_tunnel,
+            intensity,
+            service_probes,
+        );
+        for sp in schedule {
+            match &soft {
+                Some(s) => {
+                    if !sp.matchs.iter().any(|m| m.service == s.m.service) {
+                        continue;
+                    }
                 }
+                None => (),
+            }
+            let start = Instant::now();
+            let mut stream = match tcp_connect(dst_addr, dst_port, in_tunnel, timeout) {
+                Ok(s) => s,
+                Err(_) => break, // the port is not open anymore
+            };
+            let rtt = start.elapsed();
+            let (r, _) = tcp_run_probe(&mut *stream, sp, service_probes, responses);
+            match r {
+                Some(m) => {
+                    let pm = ProbeMatch::new(m, sp, rtt);
+                    if pm.is_hard() {
+                        return Ok(Some(pm));
+                    } else if soft.is_none() {
+                        soft = Some(pm);
+                    }
+                }
+                None => (),
             }
         }
     }
-    Ok(ret)
+    Ok(soft)
 }
 
-fn udp_probe(
+/// Run the probes again inside the ssl/tls tunnel, the service name of the results are prefixed with `ssl/`.
+fn tls_probe(
     dst_addr: IpAddr,
     dst_port: u16,
-    only_udp_recommended: bool,
+    only_null_probe: bool,
+    only_tcp_recommended: bool,
     intensity: usize,
     service_probes: &[ServiceProbe],
     timeout: Duration,
-) -> Result<Vec<Match>> {
-    let run_probe = |socket: &UdpSocket, sp: &ServiceProbe| -> Result<Vec<Match>> {
-        let mut ret = Vec::new();
-        let probestring = sp.probe.probestring.as_bytes();
-        socket.send(probestring)?;
-        let mut recv_buff = [0u8; UDP_BUFF_SIZE];
-        let n = match socket.recv(&mut recv_buff) {
-            Ok(n) => n,
-            Err(_) => 0,
-        };
-        if n > 0 {
-            let recv_str = String::from_utf8_lossy(&recv_buff);
-            let r = sp.check(&recv_str);
-            ret.extend(r);
-        }
-        Ok(ret)
-    };
+    responses: &mut Vec<ProbeResponse>,
+) -> Result<Option<ProbeMatch>> {
+    let mut ret = tcp_probe(
+        dst_addr,
+        dst_port,
+        only_null_probe,
+        only_tcp_recommended,
+        true,
+        intensity,
+        service_probes,
+        timeout,
+        responses,
+    )?;
+    match &mut ret {
+        Some(pm) => pm.m.service = format!("ssl/{}", pm.m.service),
+        None => (),
+    }
+    Ok(ret)
+}
 
-    let random_port = random_port();
+/// Send the probe string from a new socket and wait for the `totalwaitms` of the probe (or the `timeout`).
+/// Returns the `ConnectionRefused` error if the port is closed (icmp port unreachable), the rtt is the time until the response.
+fn udp_run_probe(
+    dst_addr: IpAddr,
+    dst_port: u16,
+    sp: &ServiceProbe,
+    service_probes: &[ServiceProbe],
+    timeout: Duration,
+    responses: &mut Vec<ProbeResponse>,
+) -> Result<Option<ProbeMatch>> {
+    // port 0, the system picks a free source port
     let src_addr = match dst_addr {
         IpAddr::V4(_) => {
             let addr = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0));
-            SocketAddr::new(addr, random_port)
+            SocketAddr::new(addr, 0)
         }
         IpAddr::V6(_) => {
             let addr = IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0));
-            SocketAddr::new(addr, random_port)
+            SocketAddr::new(addr, 0)
         }
     };
+    let wait = match sp.totalwaitms {
+        Some(ms) => Duration::from_millis(ms),
+        None => timeout,
+    };
 
     let dst_addr = SocketAddr::new(dst_addr, dst_port);
     let socket = UdpSocket::bind(src_addr)?;
-    // let timeout = Duration::from_secs(1); // both 1 sec
-    socket.set_read_timeout(Some(timeout))?;
+    socket.set_read_timeout(Some(wait))?;
     socket.set_write_timeout(Some(timeout))?;
     socket.connect(dst_addr)?;
+    let start = Instant::now();
+    socket.send(&sp.probe.probestring)?;
 
-    let mut ret = Vec::new();
-    for sp in service_probes {
-        let rarity = match sp.rarity {
-            Some(r) => r as usize,
-            None => 0,
+    let mut recv_buff = [0u8; UDP_BUFF_SIZE];
+    let n = match socket.recv(&mut recv_buff) {
+        Ok(n) => n,
+        Err(e) => match e.kind() {
+            // no response in the wait time
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => 0,
+            _ => return Err(e.into()),
+        },
+    };
+    if n > 0 {
+        let rtt = start.elapsed();
+        responses.push(ProbeResponse::new(
+            &sp.probe.probename,
+            recv_buff[..n].to_vec(),
+        ));
+        let probes = fallback_probes(sp, service_probes);
+        let ret = match fallback_check(&probes, &recv_buff[..n]) {
+            Some(m) => Some(ProbeMatch::new(m, sp, rtt)),
+            None => None,
         };
-        let mut ports: Vec<u16> = Vec::new();
-        match &sp.ports {
-            Some(p) => ports.extend(p),
+        Ok(ret)
+    } else {
+        Ok(None)
+    }
+}
+
+/// The udp part of the nmap algorithm, there is no NULL probe for udp,
+/// the probes are sent from the different source port so the late reply is not mixed up with the next probe.
+/// Returns the `ConnectionRefused` error if the port is closed.
+fn udp_probe(
+    dst_addr: IpAddr,
+    dst_port: u16,
+    only_udp_recommended: bool,
+    intensity: usize,
+    service_probes: &[ServiceProbe],
+    timeout: Duration,
+    responses: &mut Vec<ProbeResponse>,
+) -> Result<Option<ProbeMatch>> {
+    let schedule = probe_schedule(
+        dst_port,
+        ProbesProtocol::Udp,
+        only_udp_recommended,
+        false,
+        intensity,
+        service_probes,
+    );
+    let mut soft: Option<ProbeMatch> = None;
+    for sp in schedule {
+        match udp_run_probe(dst_addr, dst_port, sp, service_probes, timeout, responses)? {
+            Some(pm) => {
+                if pm.is_hard() {
+                    return Ok(Some(pm));
+                } else if soft.is_none() {
+                    soft = Some(pm);
+                }
+            }
             None => (),
         }
-        if sp.probe.probename != "NULL"
-            && sp.probe.protocol == ProbesProtocol::Udp
-            && intensity >= rarity
-        {
-            // Since the reality is that most ports are used by the service they are registered to in nmap-services,
-            // every probe has a list of port numbers that are considered to be most effective.
-            if only_udp_recommended {
-                if ports.contains(&dst_port) {
-                    let r = run_probe(&socket, sp);
-                    match r {
-                        Ok(r) => ret.extend(r),
-                        Err(e) => return Err(e.into()),
-                    }
-                }
+    }
+    Ok(soft)
+}
+
+/// The service of the port in the bundled subset of nmap-services, used when nothing matched.
+/// The uncommon ports are not in the subset, so `None` is returned for them.
+pub fn guess_service(dst_port: u16, protocol: ProbesProtocol) -> Option<&'static str> {
+    static NMAP_SERVICES: OnceLock<HashMap<(u16, ProbesProtocol), String>> = OnceLock::new();
+    let services = NMAP_SERVICES.get_or_init(|| {
+        let services_str = include_str!("../db/nmap-services-subset");
+        let lines: Vec<String> = services_str.lines().map(|l| l.to_string()).collect();
+        match nmap_services_parser(&lines) {
+            Ok(s) => s,
+            // the bundled file is checked by the test of the parser
+            Err(_) => HashMap::new(),
+        }
+    });
+    match services.get(&(dst_port, protocol)) {
+        Some(s) => Some(s.as_str()),
+        None => None,
+    }
+}
+
+/// The result of the port which nothing matched, the service is guessed from the port.
+pub fn unmatched_service_result(dst_port: u16, protocol: ProbesProtocol) -> ServiceResult {
+    service_result(dst_port, protocol, None, None)
+}
+
+fn service_result(
+    dst_port: u16,
+    protocol: ProbesProtocol,
+    pm: Option<ProbeMatch>,
+    fingerprint: Option<String>,
+) -> ServiceResult {
+    match pm {
+        Some(pm) => {
+            let kind = if pm.is_hard() {
+                ServiceMatchKind::Hard
             } else {
-                let r = run_probe(&socket, sp);
-                match r {
-                    Ok(r) => ret.extend(r),
-                    Err(e) => return Err(e.into()),
-                }
+                ServiceMatchKind::Soft
+            };
+            ServiceResult {
+                service: Some(pm.m.service.clone()),
+                version: pm.m.version.clone(),
+                kind: Some(kind),
+                probename: Some(pm.probename),
+                rtt: Some(pm.rtt),
+                matched: Some(pm.m),
+                fingerprint,
+            }
+        }
+        None => {
+            let (service, kind) = match guess_service(dst_port, protocol) {
+                Some(s) => (Some(s.to_string()), Some(ServiceMatchKind::PortGuess)),
+                None => (None, None),
+            };
+            ServiceResult {
+                service,
+                version: ServiceVersion::default(),
+                kind,
+                probename: None,
+                rtt: None,
+                matched: None,
+                fingerprint,
             }
         }
     }
-    Ok(ret)
 }
 
+/// The response elicited by the probe.
+#[derive(Debug, Clone)]
+pub struct ProbeResponse {
+    pub probename: String,
+    pub response: Vec<u8>,
+}
+
+impl ProbeResponse {
+    pub fn new(probename: &str, response: Vec<u8>) -> ProbeResponse {
+        ProbeResponse {
+            probename: probename.to_string(),
+            response,
+        }
+    }
+}
+
+/// Escape the response like nmap, so the string can be used as the pattern directly:
+/// the regex metacharacters are escaped with `\`, `\0`, `\r`, `\n`, `\t` are kept and other bytes (including space) are `\xHH`.
+fn sf_escape(response: &[u8]) -> String {
+    let mut ret = String::new();
+    for &b in response {
+        match b {
+            0x00 => ret += "\\0",
+            b'\r' => ret += "\\r",
+            b'\n' => ret += "\\n",
+            b'\t' => ret += "\\t",
+            b'\\' | b'^' | b'$' | b'.' | b'|' | b'?' | b'*' | b'+' | b'(' | b')' | b'[' | b']'
+            | b'{' | b'}' | b'"' => {
+                ret.push('\\');
+                ret.push(b as char);
+            }
+            0x21..=0x7e => ret.push(b as char),
+            _ => ret += &format!("\\x{:02X}", b),
+        }
+    }
+    ret
+}
+
+/// The service fingerprint of nmap for the unrecognized service, like
+/// `SF-Port21-TCP:V=PISTOL%I=7%D=10/18%Time=652F1B2A%P=RUST%r(NULL,5,"220\r\n");`
+/// every response is `%r(<probename>,<length in hex>,"<escaped response>")` and it is truncated to `SF_RESPONSE_MAX_LEN`,
+/// the lines after the first one start with `SF:` like the nmap output.
+pub fn service_fingerprint(
+    dst_port: u16,
+    protocol: ProbesProtocol,
+    in_tunnel: bool,
+    intensity: usize,
+    responses: &[ProbeResponse],
+) -> String {
+    let protocol = match protocol {
+        ProbesProtocol::Tcp => "TCP",
+        ProbesProtocol::Udp => "UDP",
+    };
+    // Version number (V), same as the os fingerprint.
+    let v = "PISTOL";
+    let t = if in_tunnel { "%T=SSL" } else { "" };
+    let now: DateTime<Local> = Local::now();
+    let date = format!("{}", now.format("%-m/%-d"));
+    let now: DateTime<Utc> = Utc::now();
+    let tm = format!("{:X}", now.timestamp());
+    let p = "RUST";
+    let mut fp = format!(
+        "SF-Port{}-{}:V={}{}%I={}%D={}%Time={}%P={}",
+        dst_port, protocol, v, t, intensity, date, tm, p
+    );
+    for r in responses {
+        let len = r.response.len().min(SF_RESPONSE_MAX_LEN);
+        fp += &format!(
+            "%r({},{:X},\"{}\")",
+            r.probename,
+            len,
+            sf_escape(&r.response[..len])
+        );
+    }
+    fp += ";";
+
+    let chars: Vec<char> = fp.chars().collect();
+    let mut lines = Vec::new();
+    let mut start = 0;
+    while start < chars.len() {
+        let (prefix, width) = if start == 0 {
+            ("", SF_LINE_WIDTH)
+        } else {
+            ("SF:", SF_LINE_WIDTH - 3)
+        };
+        let end = (start + width).min(chars.len());
+        let line: String = chars[start..end].iter().collect();
+        lines.push(format!("{}{}", prefix, line));
+        start = end;
+    }
+    lines.join("\n")
+}
+
+/// Detect the service on the tcp or udp port, the udp probes are only sent to the udp port.
+/// If no match is found but the service returned data, the service fingerprint is returned too (see `service_fingerprint`).
+/// Returns `None` if the port is closed.
 pub fn vs_probe(
     dst_addr: IpAddr,
     dst_port: u16,
+    protocol: ProbesProtocol,
     only_null_probe: bool,
     only_tcp_recommended: bool,
     only_udp_recommended: bool,
     intensity: usize,
     service_probes: &[ServiceProbe],
     timeout: Duration,
-) -> Result<Vec<Match>> {
-    // If the port is TCP, Nmap starts by connecting to it.
-    let tcp_dst_addr = SocketAddr::new(dst_addr, dst_port);
-    match TcpStream::connect_timeout(&tcp_dst_addr, timeout) {
-        Ok(mut stream) => {
-            // println!("{}", tcp_dst_addr);
-            // stream.set_nonblocking(false)?;
-            // Once the TCP connection is made, Nmap listens for roughly five seconds.
-            let five_seconds = Duration::from_secs(5);
-            stream.set_read_timeout(Some(five_seconds))?;
-            stream.set_write_timeout(Some(timeout))?;
-
-            // If the connection succeeds and the port had been in the open|filtered state, it is changed to open.
-            // Ignore this step here.
-            let null_probe_ret = tcp_null_probe(&mut stream, service_probes)?;
-            if null_probe_ret.len() > 0 {
-                Ok(null_probe_ret)
+) -> Result<Option<ServiceResult>> {
+    let fingerprint =
+        |pm: &Option<ProbeMatch>, in_tunnel: bool, responses: &[ProbeResponse]| -> Option<String> {
+            let hard_matched = match pm {
+                Some(pm) => pm.is_hard(),
+                None => false,
+            };
+            if !hard_matched && responses.len() > 0 {
+                Some(service_fingerprint(
+                    dst_port, protocol, in_tunnel, intensity, responses,
+                ))
             } else {
-                if !only_null_probe {
-                    // Start TCP continue probe.
-                    // println!("TCP CONTINUE PROBE");
-                    let tcp_ret = tcp_continue_probe(
-                        &mut stream,
-                        dst_port,
-                        only_tcp_recommended,
-                        intensity,
-                        service_probes,
-                    )?;
-                    if tcp_ret.len() > 0 {
-                        Ok(tcp_ret)
-                    } else {
-                        // This point is where Nmap starts for UDP probes,
-                        // and TCP connections continue here if the NULL probe described above fails or soft-matches.
-                        let udp_ret = udp_probe(
-                            dst_addr,
-                            dst_port,
-                            only_udp_recommended,
-                            intensity,
-                            service_probes,
-                            timeout,
-                        )?;
-                        Ok(udp_ret)
-                    }
-                } else {
-                    Ok(vec![])
+                None
+            }
+        };
+
+    let mut responses = Vec::new();
+    if protocol == ProbesProtocol::Udp {
+        // The udp service has no banner, so the NULL probe is meaningless.
+        if only_null_probe {
+            return Ok(None);
+        }
+        let pm = match udp_probe(
+            dst_addr,
+            dst_port,
+            only_udp_recommended,
+            intensity,
+            service_probes,
+            timeout,
+            &mut responses,
+        ) {
+            Ok(pm) => pm,
+            Err(e) => match e.downcast_ref::<std::io::Error>() {
+                // ignore closed port here (icmp port unreachable)
+                Some(io_err) if io_err.kind() == ErrorKind::ConnectionRefused => return Ok(None),
+                _ => return Err(e),
+            },
+        };
+        let fp = fingerprint(&pm, false, &responses);
+        return Ok(Some(service_result(dst_port, protocol, pm, fp)));
+    }
+
+    let pm = match tcp_probe(
+        dst_addr,
+        dst_port,
+        only_null_probe,
+        only_tcp_recommended,
+        false,
+        intensity,
+        service_probes,
+        timeout,
+        &mut responses,
+    ) {
+        Ok(pm) => pm,
+        Err(_) => return Ok(None), // ignore closed port here
+    };
+
+    // The service is wrapped in ssl/tls if the SSLSessionReq probe matched,
+    // or nothing matched on a port that is often used to wrap a service in ssl.
+    let ssl_matched = match &pm {
+        Some(pm) => pm.m.service == "ssl",
+        None => false,
+    };
+    let ssl_port = service_probes.iter().any(|sp| match &sp.sslports {
+        Some(s) => s.contains(&dst_port),
+        None => false,
+    });
+    if ssl_matched || (pm.is_none() && ssl_port) {
+        let mut tls_responses = Vec::new();
+        match tls_probe(
+            dst_addr,
+            dst_port,
+            only_null_probe,
+            only_tcp_recommended,
+            intensity,
+            service_probes,
+            timeout,
+            &mut tls_responses,
+        ) {
+            Ok(tls_pm) => {
+                // the fingerprint of the service inside the tunnel is more useful
+                if tls_pm.is_some() || tls_responses.len() > 0 {
+                    let fp = fingerprint(&tls_pm, true, &tls_responses);
+                    let tls_pm = if tls_pm.is_some() { tls_pm } else { pm };
+                    return Ok(Some(service_result(dst_port, protocol, tls_pm, fp)));
                 }
             }
+            Err(_) => (), // handshake failed, not a ssl/tls service
         }
-        Err(_) => Ok(vec![]), // ignore closed port here
     }
+    let fp = fingerprint(&pm, false, &responses);
+    Ok(Some(service_result(dst_port, protocol, pm, fp)))
 }
 
 #[cfg(test)]
@@ -288,86 +852,56 @@ mod tests {
     }
     #[test]
     fn test_tls() {
-        // use rustls;
-        // use rustls::client::danger::HandshakeSignatureValid;
-        // use rustls::client::danger::ServerCertVerified;
-        // use rustls::client::danger::ServerCertVerifier;
-        // use rustls::pki_types::CertificateDer;
-        // use rustls::pki_types::ServerName;
-        // use rustls::pki_types::UnixTime;
-        // use rustls::DigitallySignedStruct;
-        // use rustls::SignatureScheme;
-        // use std::sync::Arc;
-        // #[derive(Debug)]
-        // struct SkipServerVerification;
-
-        // impl SkipServerVerification {
-        //     fn new() -> std::sync::Arc<Self> {
-        //         std::sync::Arc::new(Self)
-        //     }
-        // }
-
-        // impl ServerCertVerifier for SkipServerVerification {
-        //     fn verify_server_cert(
-        //         &self,
-        //         _end_entity: &CertificateDer,
-        //         _intermediates: &[CertificateDer],
-        //         _server_name: &ServerName,
-        //         _ocsp_response: &[u8],
-        //         _now: UnixTime,
-        //     ) -> Result<ServerCertVerified, rustls::Error> {
-        //         Ok(ServerCertVerified::assertion())
-        //     }
-        //     fn verify_tls12_signature(
-        //         &self,
-        //         _message: &[u8],
-        //         _cert: &CertificateDer,
-        //         _dss: &DigitallySignedStruct,
-        //     ) -> Result<HandshakeSignatureValid, rustls::Error> {
-        //         Ok(HandshakeSignatureValid::assertion())
-        //     }
-        //     fn verify_tls13_signature(
-        //         &self,
-        //         _message: &[u8],
-        //         _cert: &CertificateDer,
-        //         _dss: &DigitallySignedStruct,
-        //     ) -> Result<HandshakeSignatureValid, rustls::Error> {
-        //         Ok(HandshakeSignatureValid::assertion())
-        //     }
-        //     fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
-        //         vec![]
-        //     }
-        // }
-
-        // let config = rustls::ClientConfig::builder()
-        //     .dangerous()
-        //     .with_custom_certificate_verifier(SkipServerVerification::new())
-        //     .with_no_client_auth();
-
-        // let server_name = "www.rust-lang.org".try_into().unwrap();
-        // let mut conn = rustls::ClientConnection::new(Arc::new(config), server_name).unwrap();
-        // let mut sock = TcpStream::connect("www.rust-lang.org:443").unwrap();
-        // let mut tls = rustls::Stream::new(&mut conn, &mut sock);
-        // tls.write_all(
-        //     concat!(
-        //         "GET / HTTP/1.1\r\n",
-        //         "Host: www.rust-lang.org\r\n",
-        //         "Connection: close\r\n",
-        //         "Accept-Encoding: identity\r\n",
-        //         "\r\n"
-        //     )
-        //     .as_bytes(),
-        // )
-        // .unwrap();
-        // let ciphersuite = tls.conn.negotiated_cipher_suite().unwrap();
-        // writeln!(
-        //     &mut std::io::stderr(),
-        //     "Current ciphersuite: {:?}",
-        //     ciphersuite.suite()
-        // )
-        // .unwrap();
-        // let mut plaintext = Vec::new();
-        // tls.read_to_end(&mut plaintext).unwrap();
-        // println!("{:?}", String::from_utf8_lossy(&plaintext));
+        let addr = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 51));
+        let timeout = Duration::new(3, 0);
+        let mut stream = tls_connect(addr, 443, timeout).unwrap();
+        stream.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+        let (buff, _) = read_response(&mut stream, timeout, |_| false);
+        println!("{}", String::from_utf8_lossy(&buff));
+    }
+    #[test]
+    fn test_udp_closed_port() {
+        use crate::vs::dbparser::nsp_parser;
+        let lines = vec![
+            String::from("Probe UDP Help q|help\\r\\n\\r\\n|"),
+            String::from("rarity 3"),
+        ];
+        let service_probes = nsp_parser(&lines).unwrap();
+        // bind and drop a socket to get a port that nobody listens on
+        let port = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let timeout = Duration::from_millis(500);
+        let ret = vs_probe(
+            addr,
+            port,
+            ProbesProtocol::Udp,
+            false,
+            false,
+            false,
+            9,
+            &service_probes,
+            timeout,
+        )
+        .unwrap();
+        assert!(ret.is_none());
+    }
+    #[test]
+    fn test_service_fingerprint() {
+        let responses = vec![ProbeResponse::new(
+            "NULL",
+            b"hi there.\r\n\x00\xff".to_vec(),
+        )];
+        let fp = service_fingerprint(31337, ProbesProtocol::Tcp, true, 7, &responses);
+        println!("{}", fp);
+        assert!(fp.starts_with("SF-Port31337-TCP:V=PISTOL%T=SSL%I=7%D="));
+        let fp_join: String = fp
+            .lines()
+            .map(|l| l.strip_prefix("SF:").unwrap_or(l))
+            .collect();
+        assert!(fp_join.ends_with(r#"%r(NULL,D,"hi\x20there\.\r\n\0\xFF");"#));
     }
 }